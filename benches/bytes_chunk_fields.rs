@@ -0,0 +1,21 @@
+//! Benchmarks `bytes_chunk_fields` on a 2048-bit signature.
+//!
+//! The bit-by-bit implementation it replaced is kept as a reference in the unit tests of
+//! `converters`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::RngCore;
+use relayer_utils::bytes_chunk_fields;
+
+fn bench_bytes_chunk_fields(c: &mut Criterion) {
+    // A 2048-bit signature, as used for nullifiers and commitment randomness
+    let mut signature = vec![0u8; 256];
+    rand::thread_rng().fill_bytes(&mut signature);
+
+    c.bench_function("bytes_chunk_fields", |b| {
+        b.iter(|| bytes_chunk_fields(black_box(&signature), 121, 2, 17))
+    });
+}
+
+criterion_group!(benches, bench_bytes_chunk_fields);
+criterion_main!(benches);
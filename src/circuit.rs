@@ -1,20 +1,43 @@
 use anyhow::{anyhow, Result};
-use num_bigint::BigInt;
+use base64::{engine::general_purpose, Engine};
+use futures::stream::{self, StreamExt};
+use halo2curves::ff::PrimeField;
+use indexmap::IndexMap;
+use num_bigint::{BigInt, Sign};
+use poseidon_rs::Fr;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::{cmp, collections::VecDeque};
+use serde_json::Value;
+use std::{
+    cmp,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+};
 use zk_regex_apis::extract_substrs::{
-    extract_substr_idxes, DecomposedRegexConfig, RegexPartConfig,
+    extract_email_addr_idxes, extract_substr_idxes, DecomposedRegexConfig, RegexPartConfig,
 };
 
 use crate::{
-    field_to_hex, find_index_in_body, generate_partial_sha, hex_to_u256,
-    remove_quoted_printable_soft_breaks, sha256_pad, string_to_circom_bigint_bytes,
-    to_circom_bigint_bytes, vec_u8_to_bigint, AccountCode, PaddedEmailAddr, ParsedEmail,
-    MAX_BODY_PADDED_BYTES, MAX_HEADER_PADDED_BYTES,
+    bytes_to_circom_bigint_bytes, bytes_to_fields, eth_address_to_u256, field_to_hex,
+    find_index_in_body, fr_to_bytes32, generate_partial_sha, generate_partial_sha_at_index,
+    hex_to_field, poseidon_modular, remove_quoted_printable_soft_breaks,
+    remove_quoted_printable_soft_breaks_from, sha256_pad, to_circom_bigint_bytes,
+    validate_email_input, vec_u8_to_bigint, AccountCode, AccountSalt, EmailPart, PaddedEmailAddr,
+    ParsedEmail, PartialShaError, CIRCOM_BIGINT_K, CIRCOM_BIGINT_N, MAX_BODY_PADDED_BYTES,
+    MAX_EMAIL_ADDR_BYTES, MAX_HEADER_PADDED_BYTES,
 };
 
+const REGEX_INPUT_PREVIEW_CHARS: usize = 200; // Number of input chars shown when a decomposed regex matches nothing
+
+/// The layout of the inputs generated by `generate_email_circuit_input`, emitted as
+/// `input_schema_version` on request. Bumped whenever a field is added or its packing changes.
+pub const CIRCUIT_INPUT_SCHEMA_VERSION: &str = "email-auth/v2";
+
+/// The layout of the inputs generated by
+/// `generate_circuit_inputs_with_decomposed_regexes_and_external_inputs`, emitted as
+/// `inputSchemaVersion` on request. Bumped whenever a field is added or its packing changes.
+pub const DECOMPOSED_CIRCUIT_INPUT_SCHEMA_VERSION: &str = "decomposed/v1";
+
 #[derive(Serialize, Deserialize)]
 struct EmailCircuitInput {
     padded_header: Vec<u8>,           // The padded version of the email header
@@ -25,55 +48,218 @@ struct EmailCircuitInput {
     padded_header_len: usize,         // The length of the padded header
     padded_body_len: Option<usize>,   // The length of the padded body, if present
     precomputed_sha: Option<Vec<u8>>, // The precomputed SHA-256 hash of part of the body, if needed
-    account_code: String,             // The account code associated with the email
-    from_addr_idx: usize,             // The index of the sender's address in header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    precomputed_header_sha: Option<Vec<u8>>, // The precomputed SHA-256 state of the header before its selector, if one is given
+    account_code: String, // The account code associated with the email
+    from_addr_idx: usize, // The index of the sender's address in header
     #[serde(skip_serializing_if = "Option::is_none")]
     subject_idx: Option<usize>, // The index of the email subject in header
-    domain_idx: usize,                // The index of the email domain in header
-    timestamp_idx: usize,             // The index of the timestamp in header
-    code_idx: usize,                  // The index of the invitation code in header or body
-    command_idx: usize,               // The index of the command in body
-    padded_cleaned_body: Option<Vec<u8>>, // The padded body after removing quoted-printable soft breaks, if needed
+    domain_idx: usize,    // The index of the email domain in header
+    timestamp_idx: usize, // The index of the timestamp in header
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "optional_decimal_string"
+    )]
+    timestamp: Option<u64>, // The timestamp at `timestamp_idx`, if one was found
+    code_idx: usize,      // The index of the invitation code in header or body
+    command_idx: usize,   // The index of the command in body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    padded_cleaned_body: Option<Vec<u8>>, // The padded body after removing quoted-printable soft breaks, if they are removed; body indices are then relative to it rather than to `padded_body`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient_email_idx: Option<usize>, // The index of the recipient's address in the command, or 0 if it has none; only if recipients are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient_commitment: Option<String>, // The commitment to the recipient's address, or zero if the command has none; only if recipients are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_location: Option<CommandLocation>, // Where the command was taken from, `Subject` or `Body`; only if a location was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email_body_hash: Option<BodyHashDebug>, // The computed and signed body hashes; only if body hash debugging was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padded_header_fields: Option<Vec<String>>, // The padded header packed 31 bytes per field element, as decimal strings; only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padded_header_hash: Option<String>, // The `PoseidonModular` hash of `padded_header_fields`; only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_schema_version: Option<String>, // `CIRCUIT_INPUT_SCHEMA_VERSION`; only if requested
 }
 
-#[derive(Serialize, Deserialize)]
+/// (De)serializes an optional `u64` as a decimal string, as circuit inputs expect large numbers.
+mod optional_decimal_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_str(&value.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct EmailCircuitParams {
     pub ignore_body_hash_check: Option<bool>, // Flag to ignore the body hash check
     pub max_header_length: Option<usize>,     // The maximum length of the email header
     pub max_body_length: Option<usize>,       // The maximum length of the email body
-    pub sha_precompute_selector: Option<String>, // Regex selector for SHA-256 precomputation
+    pub sha_precompute_selector: Option<ShaPrecomputeSelector>, // Selector for SHA-256 precomputation
+    pub header_sha_precompute_selector: Option<String>, // Regex selector for SHA-256 precomputation of the header
+    pub precompute_selector_on_cleaned_body: Option<bool>, // Flag to locate the selector in the body without soft line breaks
+    pub search_invitation_code_everywhere: Option<bool>, // Flag to look for the invitation code in the header and then the body
+    pub reject_partially_signed_body: Option<bool>, // Flag to refuse emails whose DKIM signature covers only part of the body
+    pub recipient_enabled: Option<bool>, // Flag to emit the index of and commitment to the email address in the command
+    pub strict_indices: Option<bool>, // Flag to fail instead of using 0 when the code, timestamp, or command index is missing
+    pub remove_soft_line_breaks: Option<bool>, // Flag to emit the body without soft line breaks and index into it, true by default
+    pub command_location: Option<CommandLocation>, // Where to look for the command, the body by default
+    pub include_body_hash_debug: Option<bool>, // Flag to emit the computed and signed body hashes for debugging
+    pub emit_poseidon_header_fields: Option<bool>, // Flag to emit the padded header packed into field elements and their Poseidon hash
+    pub include_input_schema_version: Option<bool>, // Flag to emit `input_schema_version`, off by default so that the output stays unchanged
 }
 
-#[derive(Serialize, Deserialize)]
-struct ClaimCircuitInput {
-    email_addr: Vec<u8>,  // The email address in byte format
-    cm_rand: String,      // Random string used for commitment randomness
-    account_code: String, // The account code as a string
+/// Where `generate_email_circuit_input` looks for the command.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandLocation {
+    /// The subject without its leading `Re:` and `Fwd:` prefixes, indexed in the padded header.
+    Subject,
+    /// The body; the command is empty if the body hash check is ignored.
+    Body,
+    /// The body if it is checked and contains a command, and the subject otherwise.
+    Auto,
+}
+
+/// Where the body is cut for SHA-256 precomputation: the cutoff is the start of the 64-byte
+/// block holding the start of the match.
+///
+/// A plain string is deserialized as `Regex`, so existing JSON keeps working.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ShaPrecomputeSelector {
+    /// A regex whose first match in the body marks the cutoff.
+    Regex(String),
+    /// A decomposed regex run with `extract_substr_idxes`, as in the circuit, whose first public
+    /// part marks the cutoff.
+    Decomposed(DecomposedRegexConfig),
+}
+
+/// Shows the selector as the quoted regex it matches, the parts of a decomposed regex joined.
+impl fmt::Display for ShaPrecomputeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Regex(selector) => write!(f, "{:?}", selector),
+            Self::Decomposed(config) => write!(
+                f,
+                "{:?}",
+                config
+                    .parts
+                    .iter()
+                    .map(|part| part.regex_def.as_str())
+                    .collect::<String>()
+            ),
+        }
+    }
+}
+
+impl From<String> for ShaPrecomputeSelector {
+    fn from(selector: String) -> Self {
+        ShaPrecomputeSelector::Regex(selector)
+    }
+}
+
+impl From<&str> for ShaPrecomputeSelector {
+    fn from(selector: &str) -> Self {
+        ShaPrecomputeSelector::Regex(selector.to_string())
+    }
 }
 
-struct CircuitInput {
+/// The body hashes emitted by the circuit input generators when `include_body_hash_debug` is set.
+///
+/// The two differ when the body was altered after signing or canonicalized differently than
+/// by the signer. Neither is a circuit signal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BodyHashDebug {
+    pub computed: String, // The SHA-256 hash of the signed part of the canonicalized body, as 0x-prefixed hex
+    pub signed: String, // The body hash decoded from the `bh=` tag of the DKIM signature, as 0x-prefixed hex
+}
+
+impl BodyHashDebug {
+    /// Computes the body hash of the email and decodes the one in its DKIM signature.
+    fn new(parsed_email: &ParsedEmail) -> Result<Self> {
+        let (start, end) = parsed_email.get_body_hash_idxes()?;
+        let signed = general_purpose::STANDARD
+            .decode(&parsed_email.canonicalized_header[start..end])
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to decode the body hash of the DKIM signature: {}",
+                    e
+                )
+            })?;
+        Ok(BodyHashDebug {
+            computed: format!(
+                "0x{}",
+                hex::encode(hmac_sha256::Hash::hash(parsed_email.signed_body()?))
+            ),
+            signed: format!("0x{}", hex::encode(signed)),
+        })
+    }
+}
+
+/// The inputs of the claim circuit, as returned by `generate_claim_circuit_input`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClaimCircuitInput {
+    pub email_addr: Vec<u8>,  // The padded email address in byte format
+    pub cm_rand: String,      // The commitment randomness as a canonical hex field element
+    pub account_code: String, // The account code as a canonical hex field element
+}
+
+/// The generic circuit inputs produced by `generate_circuit_inputs`.
+///
+/// These are the padded header and body, the precomputed SHA-256 state, and the
+/// circom-formatted RSA key and signature, independent of any particular circuit layout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitInput {
     pub header_padded: Vec<u8>, // The padded version of the email header
     pub pubkey: Vec<String>,    // The public key in string format
     pub signature: Vec<String>, // The signature in string format
     pub header_len_padded_bytes: usize, // The length of the padded header in bytes
     pub precomputed_sha: Option<Vec<u8>>, // The precomputed SHA-256 hash of the body, if present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precomputed_header_sha: Option<Vec<u8>>, // The precomputed SHA-256 state of the header before its selector, if one is given
     pub body_padded: Option<Vec<u8>>, // The padded version of the email body, if present
     pub body_len_padded_bytes: Option<usize>, // The length of the padded body in bytes, if present
     pub body_hash_idx: Option<usize>, // The index in header where the body hash is stored
 }
 
+/// The smallest circuit lengths that fit an email, as returned by `compute_recommended_lengths`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RecommendedLengths {
+    pub header_length: usize,     // The length of the canonicalized header
+    pub body_length: usize,       // The length of the signed part of the canonicalized body
+    pub max_header_length: usize, // The smallest valid `max_header_length`, a multiple of 64
+    pub max_body_length: usize, // The smallest valid `max_body_length` after the selector cutoff, a multiple of 64
+}
+
 #[derive(Debug, Clone)]
 pub struct CircuitInputParams {
-    body: Vec<u8>,                           // The email body in bytes
-    header: Vec<u8>,                         // The email header in bytes
-    body_hash_idx: usize,                    // The index of the body hash within the circuit
-    rsa_signature: BigInt,                   // The RSA signature as a BigInt
-    rsa_public_key: BigInt,                  // The RSA public key as a BigInt
-    sha_precompute_selector: Option<String>, // Regex Selector for SHA-256 precomputation
-    max_header_length: usize,                // The maximum length of the email header
-    max_body_length: usize,                  // The maximum length of the email body
-    ignore_body_hash_check: bool,            // Flag to ignore the body hash check
+    body: Vec<u8>,                                          // The email body in bytes
+    header: Vec<u8>,                                        // The email header in bytes
+    body_hash_idx: usize,   // The index of the body hash within the circuit
+    rsa_signature: BigInt,  // The RSA signature as a BigInt
+    rsa_public_key: BigInt, // The RSA public key as a BigInt
+    sha_precompute_selector: Option<ShaPrecomputeSelector>, // Selector for SHA-256 precomputation
+    header_sha_precompute_selector: Option<String>, // Regex selector for SHA-256 precomputation of the header
+    precompute_selector_on_cleaned_body: bool, // Flag to locate the selector in the body without soft line breaks
+    max_header_length: usize,                  // The maximum length of the email header
+    max_body_length: usize,                    // The maximum length of the email body
+    ignore_body_hash_check: bool,              // Flag to ignore the body hash check
 }
 
 pub struct CircuitParams {
@@ -84,11 +270,14 @@ pub struct CircuitParams {
     pub rsa_public_key: BigInt, // The RSA public key as a BigInt
 }
 
+#[derive(Default)]
 pub struct CircuitOptions {
-    pub sha_precompute_selector: Option<String>, // Selector for SHA-256 precomputation
-    pub max_header_length: Option<usize>,        // The maximum length of the email header
-    pub max_body_length: Option<usize>,          // The maximum length of the email body
-    pub ignore_body_hash_check: Option<bool>,    // Flag to ignore the body hash check
+    pub sha_precompute_selector: Option<ShaPrecomputeSelector>, // Selector for SHA-256 precomputation
+    pub header_sha_precompute_selector: Option<String>, // Selector for SHA-256 precomputation of the header
+    pub precompute_selector_on_cleaned_body: Option<bool>, // Flag to locate the selector in the body without soft line breaks
+    pub max_header_length: Option<usize>,                  // The maximum length of the email header
+    pub max_body_length: Option<usize>,                    // The maximum length of the email body
+    pub ignore_body_hash_check: Option<bool>,              // Flag to ignore the body hash check
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -97,15 +286,216 @@ pub struct ExternalInput {
     pub name: String,          // The name of the external input
     pub value: Option<String>, // The optional value of the external input
     pub max_length: usize,     // The maximum length of the input value
+    #[serde(default)]
+    pub encoding: ExternalInputEncoding, // How the value is converted into circuit signals
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// How the value of an `ExternalInput` is converted into circuit signals.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalInputEncoding {
+    /// The UTF-8 bytes of the value, packed 31 bytes per field element.
+    #[default]
+    Utf8,
+    /// The bytes of a hex value, with or without a `0x` prefix, packed 31 bytes per field element.
+    Hex,
+    /// A decimal value, used as a single field element.
+    Decimal,
+}
+
+impl ExternalInput {
+    /// Converts the value into circuit signals according to its encoding.
+    ///
+    /// The signals are padded with zeros to `compute_signal_length(max_length)`, and a missing
+    /// value becomes all-zero signals.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signals as decimal strings, or an error naming the input if
+    /// the value is not valid for its encoding or does not fit in `max_length`.
+    pub fn to_signals(&self) -> Result<Vec<String>> {
+        let signal_length = compute_signal_length(self.max_length);
+        let value = match self.value.as_deref() {
+            Some(value) => value,
+            None => return Ok(vec!["0".to_string(); signal_length]),
+        };
+        match self.encoding {
+            ExternalInputEncoding::Utf8 => {
+                self.check_byte_length(value.len())?;
+                pack_bytes_into_fields(value.as_bytes(), self.max_length)
+            }
+            ExternalInputEncoding::Hex => {
+                let bytes =
+                    hex::decode(value.strip_prefix("0x").unwrap_or(value)).map_err(|e| {
+                        anyhow!(
+                            "External input {} has an invalid hex value {:?}: {}",
+                            self.name,
+                            value,
+                            e
+                        )
+                    })?;
+                self.check_byte_length(bytes.len())?;
+                pack_bytes_into_fields(&bytes, self.max_length)
+            }
+            ExternalInputEncoding::Decimal => {
+                // The value must be a valid element of the scalar field
+                let modulus =
+                    BigInt::parse_bytes(Fr::MODULUS.trim_start_matches("0x").as_bytes(), 16)
+                        .ok_or_else(|| anyhow!("Invalid field modulus"))?;
+                let decimal = match BigInt::parse_bytes(value.as_bytes(), 10) {
+                    Some(decimal) if decimal.sign() != Sign::Minus && decimal < modulus => decimal,
+                    _ => return Err(anyhow!(
+                        "External input {} has an invalid decimal value {:?}: it must be a non-negative integer below the field modulus",
+                        self.name,
+                        value
+                    )),
+                };
+                if signal_length == 0 {
+                    return Err(anyhow!(
+                        "External input {} needs 1 signal, but its max length {} allows none",
+                        self.name,
+                        self.max_length
+                    ));
+                }
+
+                // Pad the signals to the signal length
+                let mut signals = vec![decimal.to_string()];
+                signals.resize(signal_length, "0".to_string());
+                Ok(signals)
+            }
+        }
+    }
+
+    /// Checks that a value of the given byte length fits in `max_length`.
+    fn check_byte_length(&self, len: usize) -> Result<()> {
+        if len > self.max_length {
+            return Err(anyhow!(
+                "External input {} is {} bytes long, but its max length is {}",
+                self.name,
+                len,
+                self.max_length
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The part of the email a decomposed regex is searched in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RegexLocation {
+    /// The padded header.
+    Header,
+    /// The remaining body, without soft line breaks if `remove_soft_lines_breaks` is set.
+    #[default]
+    Body,
+    /// The remaining body without soft line breaks, regardless of `remove_soft_lines_breaks`.
+    CleanedBody,
+}
+
+impl fmt::Display for RegexLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header => write!(f, "header"),
+            Self::Body => write!(f, "body"),
+            Self::CleanedBody => write!(f, "cleanedBody"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct DecomposedRegex {
     pub parts: Vec<RegexPartConfig>, // The parts of the regex configuration
     pub name: String,                // The name of the decomposed regex
     pub max_length: usize,           // The maximum length of the regex match
-    pub location: String, // The location where the regex is applied (e.g., header or body)
+    pub location: RegexLocation,     // The location where the regex is applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_index: Option<usize>, // Which match to use for `{name}RegexIdx`, the first one by default
+    #[serde(default)]
+    pub is_sender_address: bool, // Flag marking the regex whose selected match is the address `accountSalt` is computed over
+    #[serde(default)]
+    pub reveal_private: bool, // Flag to also return the spans of the private parts, passed to `extract_substr_idxes`
+}
+
+/// The circuit inputs generated by
+/// `generate_circuit_inputs_with_decomposed_regexes_and_external_inputs`.
+///
+/// Use `to_json_string` to get the JSON submitted to provers. Its fields come in a fixed order,
+/// followed by the regex indices and the external inputs in the order the caller supplied them.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DecomposedRegexCircuitInput {
+    pub email_header: Vec<u8>,      // The padded email header
+    pub email_header_length: usize, // The length of the header after SHA-256 padding
+    pub pubkey: Vec<String>,        // The RSA modulus as circom bigint chunks
+    pub signature: Vec<String>,     // The RSA signature as circom bigint chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_hash_index: Option<usize>, // The index of the body hash in the header, unless the body hash check is ignored
+    #[serde(rename = "precomputedSHA", skip_serializing_if = "Option::is_none")]
+    pub precomputed_sha: Option<Vec<u8>>, // The SHA-256 state after the precomputed part of the body, unless the body hash check is ignored
+    #[serde(
+        rename = "precomputedHeaderSHA",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub precomputed_header_sha: Option<Vec<u8>>, // The SHA-256 state after the precomputed part of the header, if a header selector is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_body: Option<Vec<u8>>, // The padded remaining body, unless the body hash check is ignored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_body_length: Option<usize>, // The length of the padded remaining body, unless the body hash check is ignored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoded_email_body_in: Option<Vec<u8>>, // The remaining body without soft line breaks, if requested; `body` regex indices are then relative to it rather than to `email_body`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_mask: Option<Vec<u8>>, // 1 for every header byte matched by a public regex part, if header masking is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_mask: Option<Vec<u8>>, // 1 for every body byte matched by a public regex part, if body masking is enabled
+    #[serde(rename = "proverETHAddress")]
+    pub prover_eth_address: String, // The prover's Ethereum address as a decimal string, or "0"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_salt: Option<String>, // The account salt of the sender address as a hex field element, if an account code is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_body_hash: Option<BodyHashDebug>, // The computed and signed body hashes, if body hash debugging is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_schema_version: Option<String>, // `DECOMPOSED_CIRCUIT_INPUT_SCHEMA_VERSION`, if requested
+    #[serde(flatten)]
+    pub regex_idxes: IndexMap<String, usize>, // The match indices, keyed by `{name}RegexIdx` and `{name}RegexIdx{i}`, in the order of the regexes
+    #[serde(flatten)]
+    pub external_inputs: IndexMap<String, Vec<String>>, // The padded external inputs, keyed by name, in the order of the inputs
+    #[serde(skip)]
+    pub regex_all_idxes: BTreeMap<String, Vec<usize>>, // The start index of every match, keyed by `{name}RegexAllIdx`; not a circuit signal, so never serialized
+    #[serde(skip)]
+    pub regex_signal_lengths: BTreeMap<String, usize>, // The packed reveal signal count of each regex, keyed by name; not a circuit signal, so never serialized
+    #[serde(skip)]
+    pub regex_matches: BTreeMap<String, RegexMatchDebug>, // What each regex matched, keyed by name, if debugging is enabled; never serialized
+}
+
+/// What a decomposed regex matched, recorded in debug mode to diagnose failing proofs.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegexMatchDebug {
+    pub location: RegexLocation,    // The location of the regex
+    pub searched: String, // The input that was searched: the header, the padded body, or the cleaned body
+    pub spans: Vec<(usize, usize)>, // The (start, end) span of every public match in the searched input
+    pub substrings: Vec<String>,    // The substring of every public match
+}
+
+impl DecomposedRegexCircuitInput {
+    /// Converts the inputs to the JSON object submitted to provers.
+    ///
+    /// The keys are sorted, exactly as in the object previously returned by
+    /// `generate_circuit_inputs_with_decomposed_regexes_and_external_inputs`.
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    /// Serializes the inputs to the JSON string submitted to provers.
+    ///
+    /// Unlike `to_json`, this keeps the field order of the struct and the order in which the
+    /// regexes and external inputs were supplied, so the same inputs always produce the same
+    /// string.
+    pub fn to_json_string(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,7 +506,58 @@ pub struct CircuitInputWithDecomposedRegexesAndExternalInputsParams {
     pub max_body_length: usize,             // The maximum length of the email body
     pub ignore_body_hash_check: bool,       // Flag to ignore the body hash check
     pub remove_soft_lines_breaks: bool,     // Flag to remove soft line breaks from the body
-    pub sha_precompute_selector: Option<String>, // Optional regex selector for SHA-256 precomputation
+    #[serde(default)]
+    pub enable_header_masking: bool, // Flag to emit `headerMask`, revealing only the bytes matched by public regex parts
+    #[serde(default)]
+    pub enable_body_masking: bool, // Flag to emit `bodyMask`, revealing only the bytes matched by public regex parts
+    pub sha_precompute_selector: Option<ShaPrecomputeSelector>, // Optional selector for SHA-256 precomputation
+    #[serde(default)]
+    pub header_sha_precompute_selector: Option<String>, // Optional regex selector for SHA-256 precomputation of the header
+    #[serde(default)]
+    pub precompute_selector_on_cleaned_body: bool, // Flag to locate the selector in the body without soft line breaks
+    #[serde(default)]
+    pub debug: bool, // Flag to record what each regex matched in `regex_matches`
+    /// An RSA modulus as a 0x-prefixed hex string, used in place of the key of the email.
+    ///
+    /// For testing the shape of circuit inputs against a fixed test key only: the DKIM key of the
+    /// email is then neither resolved nor checked, and proofs generated with an override do not
+    /// verify against the real DKIM key of the sender domain.
+    #[serde(default)]
+    pub public_key_override: Option<String>,
+    /// An RSA signature as a 0x-prefixed hex string, used in place of the signature of the email.
+    ///
+    /// For testing the shape of circuit inputs only, like `public_key_override`.
+    #[serde(default)]
+    pub signature_override: Option<String>,
+    #[serde(default)]
+    pub account_code: Option<String>, // The account code as 0x-prefixed hex, to emit `accountSalt` over the address matched by the `is_sender_address` regex
+    #[serde(default)]
+    pub include_body_hash_debug: bool, // Flag to emit `emailBodyHash` with the computed and signed body hashes for debugging
+    #[serde(default)]
+    pub include_input_schema_version: bool, // Flag to emit `inputSchemaVersion`, off by default so that the output stays unchanged
+}
+
+impl Default for CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+    fn default() -> Self {
+        Self {
+            prover_eth_address: None,
+            max_header_length: MAX_HEADER_PADDED_BYTES,
+            max_body_length: MAX_BODY_PADDED_BYTES,
+            ignore_body_hash_check: false,
+            remove_soft_lines_breaks: false,
+            enable_header_masking: false,
+            enable_body_masking: false,
+            sha_precompute_selector: None,
+            header_sha_precompute_selector: None,
+            precompute_selector_on_cleaned_body: false,
+            debug: false,
+            public_key_override: None,
+            signature_override: None,
+            account_code: None,
+            include_body_hash_debug: false,
+            include_input_schema_version: false,
+        }
+    }
 }
 
 impl CircuitInputParams {
@@ -133,6 +574,8 @@ impl CircuitInputParams {
     ///
     /// * `options` - A `CircuitOptions` struct containing optional parameters:
     ///   * `sha_precompute_selector`: Selector for SHA-256 precomputation.
+    ///   * `header_sha_precompute_selector`: Selector for SHA-256 precomputation of the header.
+    ///   * `precompute_selector_on_cleaned_body`: Flag to locate the selector in the body without soft line breaks, defaults to false if not provided.
     ///   * `max_header_length`: Maximum length of the email header, with a default value if not provided.
     ///   * `max_body_length`: Maximum length of the email body, with a default value if not provided.
     ///   * `ignore_body_hash_check`: Flag to ignore the body hash check, defaults to false if not provided.
@@ -148,6 +591,11 @@ impl CircuitInputParams {
             rsa_signature: params.rsa_signature,
             rsa_public_key: params.rsa_public_key,
             sha_precompute_selector: options.sha_precompute_selector,
+            header_sha_precompute_selector: options.header_sha_precompute_selector,
+            // Use the provided precompute_selector_on_cleaned_body or default to false
+            precompute_selector_on_cleaned_body: options
+                .precompute_selector_on_cleaned_body
+                .unwrap_or(false),
             // Use the provided max_header_length or default to MAX_HEADER_PADDED_BYTES
             max_header_length: options.max_header_length.unwrap_or(MAX_HEADER_PADDED_BYTES),
             // Use the provided max_body_length or default to MAX_BODY_PADDED_BYTES
@@ -178,7 +626,7 @@ fn find_selector_in_clean_content(
     position_map: &[usize],
 ) -> Result<(String, usize, usize)> {
     let clean_string = String::from_utf8_lossy(clean_content);
-    let re = Regex::new(selector).unwrap();
+    let re = Regex::new(selector)?;
     if let Some(m) = re.find(&clean_string) {
         let selector_start_index = m.start();
         let selector_end_index = m.end();
@@ -205,6 +653,27 @@ fn find_selector_in_clean_content(
     }
 }
 
+/// Finds where a decomposed regex selector starts in some content.
+///
+/// # Arguments
+/// * `content` - The content to search, as a slice of bytes.
+/// * `config` - The decomposed regex, run with `extract_substr_idxes` as in the circuit.
+///
+/// # Returns
+/// The byte index in `content` where the first public part of the match starts.
+///
+/// # Errors
+/// Returns an error if the decomposed regex matches nothing in the content.
+fn find_decomposed_selector(content: &[u8], config: &DecomposedRegexConfig) -> Result<usize> {
+    let input = RegexInput::new(content);
+    match extract_substr_idxes(&input.text, config, false) {
+        Ok(idxes) if !idxes.is_empty() => Ok(input.byte_offsets[idxes[0].0]),
+        _ => Err(anyhow!(
+            "Decomposed SHA precompute selector not found in body"
+        )),
+    }
+}
+
 /// Gets the adjusted selector string that accounts for potential soft line breaks in QP encoding.
 /// If the selector exists in the original body, returns it as-is. Otherwise, finds it in cleaned
 /// content and maps it back to the original format, including any soft line breaks.
@@ -258,68 +727,218 @@ fn get_adjusted_selector(
 ///
 /// # Returns
 ///
-/// A `Result` which is either a `CircuitInput` struct on success or an error on failure.
-///
-/// # Panics
-///
-/// This function panics if the partial SHA-256 generation fails.
-fn generate_circuit_inputs(params: CircuitInputParams) -> Result<CircuitInput> {
-    // Pad the header to the specified maximum length or the default
-    let (header_padded, header_padded_len) =
-        sha256_pad(params.header.clone(), params.max_header_length);
+/// A `Result` which is either a `CircuitInput` struct on success or an error on failure,
+/// including when a maximum length is invalid for the email or the partial SHA-256
+/// precomputation fails.
+pub fn generate_circuit_inputs(params: CircuitInputParams) -> Result<CircuitInput> {
+    // Take the buffers out of the parameters so that each is padded without being copied
+    let CircuitInputParams {
+        body,
+        header,
+        body_hash_idx,
+        rsa_signature,
+        rsa_public_key,
+        sha_precompute_selector,
+        header_sha_precompute_selector,
+        precompute_selector_on_cleaned_body,
+        max_header_length,
+        max_body_length,
+        ignore_body_hash_check,
+    } = params;
+    let body_len = body.len();
+    let header_len = header.len();
+
+    // Check the maxima before padding, which cannot report what is wrong with them; with a
+    // header selector, only the part of the header after the cutoff must fit
+    let min_header_length = if header_sha_precompute_selector.is_some() {
+        64
+    } else {
+        sha_padded_length(header_len)
+    };
+    check_max_length("max_header_length", max_header_length, min_header_length)?;
+    if !ignore_body_hash_check {
+        // With a selector, the part of the body that must fit is only known after the cutoff
+        let min_body_length = if sha_precompute_selector.is_some() {
+            64
+        } else {
+            sha_padded_length(body_len)
+        };
+        check_max_length("max_body_length", max_body_length, min_body_length)?;
+    }
+
+    // Details attached to header selector errors so that a bad selector can be diagnosed
+    let header_selector_context = || {
+        format!(
+            "Failed to generate partial header SHA (selector: {:?}, header length: {}, max header length: {})",
+            header_sha_precompute_selector, header_len, max_header_length
+        )
+    };
+    // Locate the header selector; the header does not end with a line break, so it is searched
+    // as a whole rather than with the body's trimming of the padding
+    let header_selector_index = match header_sha_precompute_selector.as_deref() {
+        Some(selector) => {
+            let pattern = Regex::new(selector)
+                .map_err(|e| anyhow!("{}: {}", header_selector_context(), e))?;
+            let matched = pattern
+                .find(&String::from_utf8_lossy(&header))
+                .map(|matched| matched.start());
+            Some(matched.ok_or_else(|| {
+                anyhow!(
+                    "{}: Selector {} not found in the header",
+                    header_selector_context(),
+                    selector
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    // Pad the header to the specified maximum length or the default, keeping only the part
+    // after the 64-byte block holding the selector if one is given
+    let (header_padded, header_padded_len, precomputed_header_sha) = match header_selector_index {
+        Some(selector_index) => {
+            let (header_padded, header_padded_len) = sha256_pad(
+                header,
+                cmp::max(max_header_length, sha_padded_length(header_len)),
+            )?;
+            let (precomputed_header_sha, header_remaining, header_remaining_length) =
+                generate_partial_sha_at_index(
+                    header_padded,
+                    header_padded_len,
+                    selector_index,
+                    max_header_length,
+                )
+                .map_err(|e| {
+                    partial_sha_error(header_selector_context(), "max_header_length", e)
+                })?;
+            (
+                header_remaining,
+                header_remaining_length,
+                Some(precomputed_header_sha),
+            )
+        }
+        None => {
+            let (header_padded, header_padded_len) = sha256_pad(header, max_header_length)?;
+            (header_padded, header_padded_len, None)
+        }
+    };
+    // The number of header bytes in the precomputed part, which indices into the header skip
+    let header_cutoff = sha_padded_length(header_len) - header_padded_len;
 
     // Initialize the circuit input with the padded header and RSA information
     let mut circuit_input = CircuitInput {
         header_padded,
-        pubkey: to_circom_bigint_bytes(params.rsa_public_key),
-        signature: to_circom_bigint_bytes(params.rsa_signature),
+        pubkey: to_circom_bigint_bytes(rsa_public_key),
+        signature: to_circom_bigint_bytes(rsa_signature),
         header_len_padded_bytes: header_padded_len,
         precomputed_sha: None,
+        precomputed_header_sha,
         body_padded: None,
         body_len_padded_bytes: None,
         body_hash_idx: None,
     };
 
     // If body hash check is not ignored, include the precomputed SHA and body information
-    if !params.ignore_body_hash_check {
+    if !ignore_body_hash_check {
         // Calculate the length needed for SHA-256 padding of the body
-        let body_sha_length = ((params.body.len() + 63 + 65) / 64) * 64;
+        let body_sha_length = body_sha_length(body_len);
         // Pad the body to the maximum length or the calculated SHA-256 padding length
-        let (body_padded, body_padded_len) = sha256_pad(
-            params.body.clone(),
-            cmp::max(params.max_body_length, body_sha_length),
-        );
+        let (body_padded, body_padded_len) =
+            sha256_pad(body, cmp::max(max_body_length, body_sha_length))?;
 
-        let mut adjusted_selector = params.sha_precompute_selector;
+        // Details attached to selector errors so that a bad selector can be diagnosed
+        let selector_context = || {
+            format!(
+                "Failed to generate partial SHA (selector: {}, body length: {}, max body length: {})",
+                sha_precompute_selector
+                    .as_ref()
+                    .map_or_else(|| "none".to_string(), ToString::to_string),
+                body_len,
+                max_body_length
+            )
+        };
+
+        // The regex selector adjusted for soft line breaks
+        let mut adjusted_selector = None;
+        // The index in the padded body that must stay outside the precomputed part
+        let mut selector_index = None;
 
-        if adjusted_selector.is_some() {
+        if let Some(selector) = &sha_precompute_selector {
+            if body_len == 0 {
+                return Err(anyhow!(
+                    "A SHA precompute selector was given, but the email has no body"
+                ));
+            }
             let (cleaned_body, position_map) =
                 remove_quoted_printable_soft_breaks(body_padded.clone());
-            adjusted_selector = Some(get_adjusted_selector(
-                &params.body,
-                &adjusted_selector.as_ref().unwrap(),
-                &cleaned_body,
-                &position_map,
-            )?);
+            match selector {
+                ShaPrecomputeSelector::Regex(selector) if precompute_selector_on_cleaned_body => {
+                    // Locate the selector in the cleaned body and map the match back to the padded body
+                    let (_, original_start_index, _) =
+                        find_selector_in_clean_content(&cleaned_body, selector, &position_map)
+                            .map_err(|e| anyhow!("{}: {}", selector_context(), e))?;
+                    selector_index = Some(original_start_index);
+                }
+                ShaPrecomputeSelector::Regex(selector) => {
+                    // The padded body starts with the unpadded body
+                    adjusted_selector = Some(
+                        get_adjusted_selector(
+                            &body_padded[..body_len],
+                            selector,
+                            &cleaned_body,
+                            &position_map,
+                        )
+                        .map_err(|e| anyhow!("{}: {}", selector_context(), e))?,
+                    );
+                }
+                ShaPrecomputeSelector::Decomposed(config) => {
+                    let index = if precompute_selector_on_cleaned_body {
+                        let cleaned_index = find_decomposed_selector(&cleaned_body, config)
+                            .map_err(|e| anyhow!("{}: {}", selector_context(), e))?;
+                        match position_map.get(cleaned_index) {
+                            Some(&index) if index != usize::MAX => index,
+                            _ => {
+                                return Err(anyhow!(
+                                    "{}: Failed to map selector position to original body",
+                                    selector_context()
+                                ))
+                            }
+                        }
+                    } else {
+                        find_decomposed_selector(&body_padded[..body_len], config)
+                            .map_err(|e| anyhow!("{}: {}", selector_context(), e))?
+                    };
+                    selector_index = Some(index);
+                }
+            }
         }
 
-        // Ensure that the error type returned by `generate_partial_sha` is sized
-        // by converting it into an `anyhow::Error` if it's not already.
-        let result = generate_partial_sha(
-            body_padded,
-            body_padded_len,
-            adjusted_selector,
-            params.max_body_length,
-        );
-
-        // Use match to handle the result and convert any error into an anyhow::Error
-        let (precomputed_sha, body_remaining, body_remaining_length) = match result {
-            Ok((sha, remaining, len)) => (sha, remaining, len),
-            Err(e) => panic!("Failed to generate partial SHA: {:?}", e),
-        };
+        // Explain the error returned by `generate_partial_sha` in terms of the parameters
+        let (precomputed_sha, body_remaining, body_remaining_length) = match selector_index {
+            Some(selector_index) => generate_partial_sha_at_index(
+                body_padded,
+                body_padded_len,
+                selector_index,
+                max_body_length,
+            ),
+            None => generate_partial_sha(
+                body_padded,
+                body_padded_len,
+                adjusted_selector,
+                max_body_length,
+            ),
+        }
+        .map_err(|e| partial_sha_error(selector_context(), "max_body_length", e))?;
 
         circuit_input.precomputed_sha = Some(precomputed_sha);
-        circuit_input.body_hash_idx = Some(params.body_hash_idx);
+        circuit_input.body_hash_idx =
+            Some(body_hash_idx.checked_sub(header_cutoff).ok_or_else(|| {
+                anyhow!(
+                    "{}: the body hash at index {} is in the precomputed part of the header",
+                    header_selector_context(),
+                    body_hash_idx
+                )
+            })?);
         circuit_input.body_padded = Some(body_remaining);
         circuit_input.body_len_padded_bytes = Some(body_remaining_length);
     }
@@ -327,6 +946,146 @@ fn generate_circuit_inputs(params: CircuitInputParams) -> Result<CircuitInput> {
     Ok(circuit_input)
 }
 
+/// Returns the length that `generate_circuit_inputs` pads a body of the given length to at least.
+fn body_sha_length(body_len: usize) -> usize {
+    (body_len + 65).div_ceil(64) * 64
+}
+
+/// Returns the length of a message after SHA-256 padding, before any padding with zeros.
+fn sha_padded_length(len: usize) -> usize {
+    // The message is followed by 0x80 and its 8-byte bit length, rounded up to whole blocks
+    (len + 9).div_ceil(64) * 64
+}
+
+/// Checks that a maximum length is a positive multiple of 64 that fits the padded input.
+///
+/// # Arguments
+///
+/// * `name` - The name of the parameter holding the maximum length.
+/// * `max_length` - The maximum length that was provided.
+/// * `min_length` - The smallest maximum length that fits the input, a multiple of 64.
+///
+/// # Returns
+///
+/// A `Result` that is an error stating the provided value and the smallest one that would work
+/// if the maximum length is invalid.
+fn check_max_length(name: &str, max_length: usize, min_length: usize) -> Result<()> {
+    if max_length == 0 || max_length % 64 != 0 || max_length < min_length {
+        return Err(anyhow!(
+            "Invalid {} {}: it must be a positive multiple of 64 and at least {} for this email",
+            name,
+            max_length,
+            min_length
+        ));
+    }
+    Ok(())
+}
+
+/// Turns a partial SHA error into an error that explains it in terms of the circuit input
+/// parameters.
+///
+/// The `PartialShaError` stays available through `downcast_ref`, so that callers can react to
+/// specific variants, such as retrying with a larger maximum length on `RemainingTooLong`.
+///
+/// # Arguments
+///
+/// * `context` - The selector and lengths the partial SHA was computed with.
+/// * `max_length_name` - The name of the parameter holding the maximum remaining length.
+/// * `error` - The error returned by the partial SHA computation.
+fn partial_sha_error(
+    context: String,
+    max_length_name: &str,
+    error: PartialShaError,
+) -> anyhow::Error {
+    let message = match &error {
+        PartialShaError::RemainingTooLong { actual, max } => format!(
+            "{}; {} must be at least {} instead of {}",
+            error, max_length_name, actual, max
+        ),
+        PartialShaError::SelectorNotFound { .. } => format!(
+            "{}; the selector must match the signed part of the email",
+            error
+        ),
+        PartialShaError::InvalidPadding | PartialShaError::InvalidSelector { .. } => {
+            error.to_string()
+        }
+    };
+    anyhow::Error::new(error).context(format!("{}: {}", context, message))
+}
+
+/// Asynchronously computes the smallest header and body lengths a circuit needs for an email.
+///
+/// The body is cut at the SHA precompute selector exactly as in `generate_circuit_inputs`, so
+/// the recommended `max_body_length` only has to hold the part of the body after the cutoff.
+///
+/// # Arguments
+///
+/// * `email` - A string slice that holds the raw email data.
+/// * `sha_precompute_selector` - The regex selector for SHA-256 precomputation, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the `RecommendedLengths` of the email, or an error if the email cannot
+/// be parsed or the selector cannot be applied.
+pub async fn compute_recommended_lengths(
+    email: &str,
+    sha_precompute_selector: Option<&str>,
+) -> Result<RecommendedLengths> {
+    let parsed_email = ParsedEmail::new_from_raw_email(email).await?;
+    recommended_lengths(&parsed_email, sha_precompute_selector)
+}
+
+/// Computes the smallest header and body lengths a circuit needs for an email that has already
+/// been parsed and verified.
+///
+/// # Arguments
+///
+/// * `parsed_email` - The parsed email.
+/// * `sha_precompute_selector` - The regex selector for SHA-256 precomputation, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the `RecommendedLengths` of the email, or an error if the selector
+/// cannot be applied.
+fn recommended_lengths(
+    parsed_email: &ParsedEmail,
+    sha_precompute_selector: Option<&str>,
+) -> Result<RecommendedLengths> {
+    let header = parsed_email.canonicalized_header.as_bytes();
+    let body = parsed_email.signed_body()?;
+    let max_header_length = sha_padded_length(header.len());
+
+    // Run the selector through the real pipeline with a body length that always fits
+    let circuit_input = generate_circuit_inputs(CircuitInputParams::new(
+        CircuitParams {
+            body: body.to_vec(),
+            header: header.to_vec(),
+            body_hash_idx: 0,
+            rsa_signature: BigInt::from(0),
+            rsa_public_key: BigInt::from(0),
+        },
+        CircuitOptions {
+            sha_precompute_selector: sha_precompute_selector.map(ShaPrecomputeSelector::from),
+            header_sha_precompute_selector: None,
+            precompute_selector_on_cleaned_body: None,
+            max_header_length: Some(max_header_length),
+            max_body_length: Some(body_sha_length(body.len())),
+            ignore_body_hash_check: Some(false),
+        },
+    ))?;
+
+    // The precomputed part is what the remaining body lacks from the whole padded body, and the
+    // remaining body is at least as long as the body padding minus that part
+    let cutoff =
+        sha_padded_length(body.len()) - circuit_input.body_len_padded_bytes.unwrap_or_default();
+    Ok(RecommendedLengths {
+        header_length: header.len(),
+        body_length: body.len(),
+        max_header_length,
+        max_body_length: body_sha_length(body.len()) - cutoff,
+    })
+}
+
 /// Asynchronously generates the circuit input for an email.
 ///
 /// This function processes an email and its associated account code along with optional
@@ -349,14 +1108,169 @@ pub async fn generate_email_circuit_input(
 ) -> Result<String> {
     // Parse the raw email to extract canonicalized body and header, and other components
     let parsed_email = ParsedEmail::new_from_raw_email(email).await?;
+    generate_email_circuit_input_from_parsed(&parsed_email, account_code, params)
+}
+
+/// Generates the circuit input for an email that has already been parsed and verified.
+///
+/// Unlike `generate_email_circuit_input`, this neither parses the email nor resolves its DKIM
+/// public key again.
+///
+/// # Arguments
+///
+/// * `parsed_email` - The parsed email.
+/// * `account_code` - A reference to the `AccountCode` associated with the email.
+/// * `params` - Optional parameters for the circuit input generation encapsulated in `EmailCircuitParams`.
+///
+/// # Returns
+///
+/// A `Result` which is either a JSON string of the `EmailCircuitInput` on success or an error on failure.
+pub fn generate_email_circuit_input_from_parsed(
+    parsed_email: &ParsedEmail,
+    account_code: &AccountCode,
+    params: Option<EmailCircuitParams>,
+) -> Result<String> {
+    let email_auth_input = build_email_circuit_input(parsed_email, account_code, params)?;
+
+    // Serialize the email circuit input to JSON and return
+    Ok(serde_json::to_string(&email_auth_input)?)
+}
+
+/// Asynchronously generates the circuit inputs for several emails.
+///
+/// The emails are processed concurrently, at most `DEFAULT_BATCH_CONCURRENCY` at a time.
+/// See `generate_email_circuit_inputs_batch_with_concurrency` to choose the limit.
+///
+/// # Arguments
+///
+/// * `emails` - The raw emails, each with its associated `AccountCode`.
+/// * `params` - Optional parameters for the circuit input generation, shared by all emails.
+///
+/// # Returns
+///
+/// One `Result` per email, in the order of `emails`, holding either the JSON string of the
+/// `EmailCircuitInput` or the error for that email.
+pub async fn generate_email_circuit_inputs_batch(
+    emails: &[(&str, AccountCode)],
+    params: Option<EmailCircuitParams>,
+) -> Vec<Result<String>> {
+    generate_email_circuit_inputs_batch_with_concurrency(emails, params, DEFAULT_BATCH_CONCURRENCY)
+        .await
+}
+
+/// Asynchronously generates the circuit inputs for several emails with a concurrency limit.
+///
+/// # Arguments
+///
+/// * `emails` - The raw emails, each with its associated `AccountCode`.
+/// * `params` - Optional parameters for the circuit input generation, shared by all emails.
+/// * `concurrency` - The maximum number of emails processed at once; 0 is treated as 1.
+///
+/// # Returns
+///
+/// One `Result` per email, in the order of `emails`, holding either the JSON string of the
+/// `EmailCircuitInput` or the error for that email.
+pub async fn generate_email_circuit_inputs_batch_with_concurrency(
+    emails: &[(&str, AccountCode)],
+    params: Option<EmailCircuitParams>,
+    concurrency: usize,
+) -> Vec<Result<String>> {
+    stream::iter(emails.iter().map(|(email, account_code)| {
+        let params = params.clone();
+        async move { generate_email_circuit_input(email, account_code, params).await }
+    }))
+    // `buffered` keeps the results in the order of the emails
+    .buffered(cmp::max(concurrency, 1))
+    .collect()
+    .await
+}
+
+/// The remaining body in the forms the circuit input generators emit and search.
+///
+/// Both `build_email_circuit_input` and
+/// `build_circuit_inputs_with_decomposed_regexes_and_external_inputs` build their body inputs
+/// with it, so an email gets the same bodies and body indices from either generator. Body
+/// indices are relative to `search_body`: the cleaned body if soft line breaks are removed, and
+/// the padded body otherwise.
+struct BodyInputs {
+    padded: Option<Vec<u8>>, // The padded remaining body, which the circuit hashes
+    cleaned: Option<Vec<u8>>, // The padded remaining body without quoted-printable soft line breaks
+    remove_soft_line_breaks: bool, // Flag set if the circuit works on the cleaned body
+}
+
+impl BodyInputs {
+    /// The cleaned body is only computed if the circuit works on it or `search_cleaned` is set.
+    fn new(padded: Option<Vec<u8>>, remove_soft_line_breaks: bool, search_cleaned: bool) -> Self {
+        let cleaned = padded
+            .as_ref()
+            .filter(|_| remove_soft_line_breaks || search_cleaned)
+            .map(|padded| remove_quoted_printable_soft_breaks_from(padded).0);
+        BodyInputs {
+            padded,
+            cleaned,
+            remove_soft_line_breaks,
+        }
+    }
+
+    /// Splits the inputs into the padded body, the cleaned body emitted as a circuit input if the
+    /// circuit works on it, and otherwise the cleaned body that is only searched.
+    fn into_parts(self) -> (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>) {
+        if self.remove_soft_line_breaks {
+            (self.padded, self.cleaned, None)
+        } else {
+            (self.padded, None, self.cleaned)
+        }
+    }
+
+    /// The body that body indices are relative to.
+    fn search_body(&self) -> Option<&Vec<u8>> {
+        if self.remove_soft_line_breaks {
+            self.cleaned.as_ref()
+        } else {
+            self.padded.as_ref()
+        }
+    }
+}
+
+/// Builds the circuit input for an email that has already been parsed and verified.
+///
+/// Emails without a body are supported when the body hash check is ignored, in which case no
+/// body inputs are generated.
+///
+/// # Arguments
+///
+/// * `parsed_email` - The parsed email.
+/// * `account_code` - A reference to the `AccountCode` associated with the email.
+/// * `params` - Optional parameters for the circuit input generation encapsulated in `EmailCircuitParams`.
+///
+/// # Returns
+///
+/// A `Result` which is either the `EmailCircuitInput` on success or an error on failure.
+fn build_email_circuit_input(
+    parsed_email: &ParsedEmail,
+    account_code: &AccountCode,
+    params: Option<EmailCircuitParams>,
+) -> Result<EmailCircuitInput> {
+    if let Some(length) = parsed_email.signed_body_length()? {
+        if params
+            .as_ref()
+            .and_then(|p| p.reject_partially_signed_body)
+            .unwrap_or(false)
+        {
+            return Err(anyhow!(
+                "The DKIM signature only covers the first {} bytes of the body (l= tag)",
+                length
+            ));
+        }
+    }
 
     // Clone the fields that are used by value before the move occurs
-    let public_key = parsed_email.public_key.clone();
+    let public_key = parsed_email.public_key.rsa_modulus()?.to_vec();
     let signature = parsed_email.signature.clone();
 
-    // Create a CircuitParams struct from the parsed email
+    // Create a CircuitParams struct from the parsed email, keeping only the signed part of the body
     let circuit_params = CircuitParams {
-        body: parsed_email.canonicalized_body.as_bytes().to_vec(),
+        body: parsed_email.signed_body()?.to_vec(),
         header: parsed_email.canonicalized_header.as_bytes().to_vec(),
         body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
         rsa_signature: vec_u8_to_bigint(signature),
@@ -368,6 +1282,12 @@ pub async fn generate_email_circuit_input(
         sha_precompute_selector: params
             .as_ref()
             .and_then(|p| p.sha_precompute_selector.clone()),
+        header_sha_precompute_selector: params
+            .as_ref()
+            .and_then(|p| p.header_sha_precompute_selector.clone()),
+        precompute_selector_on_cleaned_body: params
+            .as_ref()
+            .and_then(|p| p.precompute_selector_on_cleaned_body),
         max_header_length: params.as_ref().and_then(|p| p.max_header_length),
         max_body_length: params.as_ref().and_then(|p| p.max_body_length),
         ignore_body_hash_check: params.as_ref().and_then(|p| p.ignore_body_hash_check),
@@ -375,9 +1295,10 @@ pub async fn generate_email_circuit_input(
 
     // Create circuit input parameters from the CircuitParams and CircuitOptions structs
     let circuit_input_params = CircuitInputParams::new(circuit_params, circuit_options);
+    let ignore_body_hash_check = circuit_input_params.ignore_body_hash_check;
 
     // Generate the circuit inputs from the parameters
-    let email_circuit_inputs = generate_circuit_inputs(circuit_input_params.clone())?;
+    let email_circuit_inputs = generate_circuit_inputs(circuit_input_params)?;
 
     // Extract indices for various email components
     let from_addr_idx = parsed_email.get_from_addr_idxes()?.0;
@@ -387,49 +1308,217 @@ pub async fn generate_email_circuit_input(
     } else {
         None
     };
-    // Handle optional indices with default fallbacks
-    let mut code_idx = match parsed_email.get_invitation_code_idxes(
-        params
-            .as_ref()
-            .map_or(false, |p| p.ignore_body_hash_check.unwrap_or(false)),
-    ) {
-        Ok(indexes) => indexes.0,
-        Err(_) => 0,
+    // Handle optional indices with default fallbacks, unless strict indices are requested
+    let strict_indices = params
+        .as_ref()
+        .and_then(|p| p.strict_indices)
+        .unwrap_or(false);
+    let body_search_input = if ignore_body_hash_check {
+        &parsed_email.canonicalized_header
+    } else {
+        &parsed_email.cleaned_body
     };
-    let timestamp_idx = match parsed_email.get_timestamp_idxes() {
-        Ok(indexes) => indexes.0,
-        Err(_) => 0,
+    let or_missing = |idxes: Result<usize>, field: &str, searched: &[u8]| match idxes {
+        Ok(idx) => Ok(idx),
+        Err(e) if strict_indices => Err(missing_index_error(field, searched, e)),
+        Err(_) => Ok(0),
     };
-    let mut command_idx =
-        match parsed_email.get_command_idxes(circuit_input_params.ignore_body_hash_check) {
-            Ok(indexes) => indexes.0,
-            Err(_) => 0,
-        };
-
-    // Clean the body
-    let padded_cleaned_body = email_circuit_inputs
-        .body_padded
-        .clone()
-        .map(remove_quoted_printable_soft_breaks);
-
-    if email_circuit_inputs.precomputed_sha.is_some() {
-        let code = parsed_email
-            .get_invitation_code(circuit_input_params.ignore_body_hash_check)
+    // Whether the code was found in the body, where its index is relative to the body inputs
+    let (mut code_idx, code, code_in_body) = if params
+        .as_ref()
+        .and_then(|p| p.search_invitation_code_everywhere)
+        .unwrap_or(false)
+    {
+        match parsed_email.find_invitation_code() {
+            Ok(location) => (
+                location.idxes.0,
+                location.code,
+                location.part == EmailPart::Body,
+            ),
+            Err(e) if strict_indices => {
+                return Err(missing_index_error(
+                    "invitation code",
+                    parsed_email.canonicalized_header.as_bytes(),
+                    e,
+                ))
+            }
+            Err(_) => (0, String::new(), false),
+        }
+    } else {
+        (
+            or_missing(
+                parsed_email
+                    .get_invitation_code_idxes(ignore_body_hash_check)
+                    .map(|indexes| indexes.0),
+                "invitation code",
+                body_search_input.as_bytes(),
+            )?,
+            parsed_email
+                .get_invitation_code(ignore_body_hash_check)
+                .unwrap_or_default(),
+            !ignore_body_hash_check,
+        )
+    };
+    // Take the timestamp from the same span as its index, so that both always agree
+    let timestamp_idxes = parsed_email.get_timestamp_idxes();
+    let timestamp = timestamp_idxes.as_ref().ok().and_then(|(start, end)| {
+        parsed_email.canonicalized_header[*start..*end]
+            .parse::<u64>()
+            .ok()
+    });
+    let timestamp_idx = or_missing(
+        timestamp_idxes.map(|indexes| indexes.0),
+        "timestamp",
+        parsed_email.canonicalized_header.as_bytes(),
+    )?;
+    // Resolve where the command is taken from
+    let requested_command_location = params.as_ref().and_then(|p| p.command_location);
+    let subject_command = match requested_command_location {
+        Some(CommandLocation::Subject) => true,
+        Some(CommandLocation::Auto) => {
+            ignore_body_hash_check || parsed_email.get_command_idxes(false).is_err()
+        }
+        Some(CommandLocation::Body) | None => false,
+    };
+    let (mut command_idx, command) = if subject_command {
+        let idxes = parsed_email.get_subject_command_idxes();
+        let command = idxes
+            .as_ref()
+            .map(|(start, end)| parsed_email.canonicalized_header[*start..*end].to_string())
             .unwrap_or_default();
-        let command = parsed_email.get_command(circuit_input_params.ignore_body_hash_check)?;
+        (
+            or_missing(
+                idxes.map(|indexes| indexes.0),
+                "subject command",
+                parsed_email.canonicalized_header.as_bytes(),
+            )?,
+            command,
+        )
+    } else {
+        (
+            or_missing(
+                parsed_email
+                    .get_command_idxes(ignore_body_hash_check)
+                    .map(|indexes| indexes.0),
+                "command",
+                body_search_input.as_bytes(),
+            )?,
+            parsed_email.get_command(ignore_body_hash_check)?,
+        )
+    };
 
-        // Body is padded and cleaned, so use it for search
-        if let Some((search_body, _)) = padded_cleaned_body.as_ref() {
-            // Find indices for the code and command in the body
-            code_idx = find_index_in_body(Some(search_body), &code);
-            command_idx = find_index_in_body(Some(search_body), &command);
-        } else {
-            // Handle the case where padded_cleaned_body is None
-            code_idx = 0; // or some other default value
-            command_idx = 0; // or some other default value
+    // Indices into the header skip the part of it that was precomputed, if any; 0 marks a
+    // missing index and stays 0
+    let header_cutoff = sha_padded_length(parsed_email.canonicalized_header.len())
+        - email_circuit_inputs.header_len_padded_bytes;
+    let in_remaining_header = |field: &str, idx: usize| {
+        if idx == 0 {
+            return Ok(0);
+        }
+        idx.checked_sub(header_cutoff).ok_or_else(|| {
+            anyhow!(
+                "The {} at index {} is in the part of the header precomputed before the header selector",
+                field,
+                idx
+            )
+        })
+    };
+    // The domain index is relative to the sender address, so it is not shifted
+    let from_addr_idx = in_remaining_header("sender address", from_addr_idx)?;
+    let subject_idx = subject_idx
+        .map(|idx| in_remaining_header("subject", idx))
+        .transpose()?;
+    let timestamp_idx = in_remaining_header("timestamp", timestamp_idx)?;
+    if !code_in_body {
+        code_idx = in_remaining_header("invitation code", code_idx)?;
+    }
+    if subject_command {
+        command_idx = in_remaining_header("command", command_idx)?;
+    }
+
+    // The circuit works on the cleaned body, unless soft line breaks are kept
+    let body_inputs = BodyInputs::new(
+        email_circuit_inputs.body_padded,
+        params
+            .as_ref()
+            .and_then(|p| p.remove_soft_line_breaks)
+            .unwrap_or(true),
+        false,
+    );
+
+    if email_circuit_inputs.precomputed_sha.is_some() {
+        // Search the same padded body the circuit indexes into
+        if let Some(search_body) = body_inputs.search_body() {
+            // The code and command may have been cut off by the precomputed part of the body
+            if code_in_body && !code.is_empty() {
+                code_idx = or_missing(
+                    find_index_in_body(search_body, &code),
+                    "invitation code",
+                    search_body,
+                )?;
+            }
+            // A subject command keeps its index in the header
+            if !subject_command {
+                command_idx = or_missing(
+                    find_index_in_body(search_body, &command),
+                    "command",
+                    search_body,
+                )?;
+            }
         }
     }
 
+    // Commit to the recipient's address in the command, using the email's signature as randomness
+    let (recipient_email_idx, recipient_commitment) = if params
+        .as_ref()
+        .and_then(|p| p.recipient_enabled)
+        .unwrap_or(false)
+    {
+        let recipient = extract_email_addr_idxes(&command)
+            .ok()
+            .and_then(|idxes| idxes.first().copied());
+        match recipient {
+            Some((start, end)) => {
+                let commitment = PaddedEmailAddr::try_from_email_addr(&command[start..end])?
+                    .to_commitment_with_signature(&parsed_email.signature)
+                    .map_err(|e| anyhow!("Failed to compute the recipient commitment: {}", e))?;
+                (Some(start), Some(field_to_hex(&commitment)))
+            }
+            // Commands without an address get zero values, which the circuit ignores
+            None => (Some(0), Some(field_to_hex(&Fr::zero()))),
+        }
+    } else {
+        (None, None)
+    };
+
+    // Pack the padded header into field elements the way the circuit does, and hash them in
+    // chunks as `PoseidonModular` does, since a padded header has more fields than one Poseidon
+    // hash takes
+    let (padded_header_fields, padded_header_hash) = if params
+        .as_ref()
+        .and_then(|p| p.emit_poseidon_header_fields)
+        .unwrap_or(false)
+    {
+        let fields = bytes_to_fields(&email_circuit_inputs.header_padded);
+        let hash = poseidon_modular(&fields)
+            .map_err(|e| anyhow!("Failed to hash the padded header fields: {}", e))?;
+        // Emit exactly the fields that were hashed
+        let fields = fields
+            .iter()
+            .map(|field| {
+                fr_to_bytes32(field)
+                    .map(|bytes| BigInt::from_bytes_be(Sign::Plus, &bytes).to_string())
+                    .map_err(|e| anyhow!("Failed to convert a padded header field: {}", e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        (Some(fields), Some(field_to_hex(&hash)))
+    } else {
+        (None, None)
+    };
+
+    // Only the cleaned body the circuit works on is emitted, and nothing else searches it
+    let (padded_body, padded_cleaned_body, _) = body_inputs.into_parts();
+
     // Construct the email circuit input from the generated data
     let email_auth_input = EmailCircuitInput {
         padded_header: email_circuit_inputs.header_padded,
@@ -441,17 +1530,229 @@ pub async fn generate_email_circuit_input(
         subject_idx,
         domain_idx,
         timestamp_idx,
+        timestamp,
         code_idx,
-        padded_body: email_circuit_inputs.body_padded,
+        padded_cleaned_body,
+        padded_body,
         body_hash_idx: email_circuit_inputs.body_hash_idx,
         padded_body_len: email_circuit_inputs.body_len_padded_bytes,
         precomputed_sha: email_circuit_inputs.precomputed_sha,
+        precomputed_header_sha: email_circuit_inputs.precomputed_header_sha,
         command_idx,
-        padded_cleaned_body: padded_cleaned_body.map(|(cleaned_body, _)| cleaned_body),
+        recipient_email_idx,
+        recipient_commitment,
+        command_location: requested_command_location.map(|_| {
+            if subject_command {
+                CommandLocation::Subject
+            } else {
+                CommandLocation::Body
+            }
+        }),
+        email_body_hash: match params.as_ref().and_then(|p| p.include_body_hash_debug) {
+            Some(true) => Some(BodyHashDebug::new(parsed_email)?),
+            _ => None,
+        },
+        padded_header_fields,
+        padded_header_hash,
+        input_schema_version: params
+            .as_ref()
+            .and_then(|p| p.include_input_schema_version)
+            .filter(|include| *include)
+            .map(|_| CIRCUIT_INPUT_SCHEMA_VERSION.to_string()),
     };
 
-    // Serialize the email circuit input to JSON and return
-    Ok(serde_json::to_string(&email_auth_input)?)
+    Ok(email_auth_input)
+}
+
+/// Builds the error returned in strict mode when the index of a field cannot be found.
+///
+/// # Arguments
+///
+/// * `field` - The name of the missing field.
+/// * `searched` - The input the field was searched in.
+/// * `cause` - The error returned by the extraction.
+fn missing_index_error(field: &str, searched: &[u8], cause: anyhow::Error) -> anyhow::Error {
+    anyhow!(
+        "The {} index could not be found ({}); first {} chars of the searched input were: {:?}",
+        field,
+        cause,
+        REGEX_INPUT_PREVIEW_CHARS,
+        String::from_utf8_lossy(searched)
+            .chars()
+            .take(REGEX_INPUT_PREVIEW_CHARS)
+            .collect::<String>()
+    )
+}
+
+/// A problem found in circuit inputs by `verify_email_circuit_input`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct InputWarning {
+    pub field: String,   // The key of the input the problem was found in
+    pub message: String, // What is wrong with the input
+}
+
+/// Checks the shape of the circuit inputs returned by `generate_email_circuit_input` before
+/// they are sent to a prover.
+///
+/// # Arguments
+///
+/// * `json` - The circuit inputs as a JSON string.
+/// * `params` - The `EmailCircuitParams` the inputs were generated with.
+///
+/// # Returns
+///
+/// A `Result` containing one `InputWarning` per problem found, which is empty if the inputs are
+/// well-formed, or an error if the JSON does not have the shape of the circuit inputs at all.
+pub fn verify_email_circuit_input(
+    json: &str,
+    params: &EmailCircuitParams,
+) -> Result<Vec<InputWarning>> {
+    let input: EmailCircuitInput = serde_json::from_str(json)
+        .map_err(|e| anyhow!("The circuit inputs are malformed: {}", e))?;
+    let max_header_length = params.max_header_length.unwrap_or(MAX_HEADER_PADDED_BYTES);
+    let max_body_length = params.max_body_length.unwrap_or(MAX_BODY_PADDED_BYTES);
+
+    let mut warnings = Vec::new();
+    let mut warn = |field: &str, message: String| {
+        warnings.push(InputWarning {
+            field: field.to_string(),
+            message,
+        })
+    };
+
+    // The padded inputs and their padded lengths must be whole SHA-256 blocks
+    let mut check_padded =
+        |field: &str, len_field: &str, padded: &[u8], padded_len: usize, max_length: usize| {
+            if padded.len() % 64 != 0 || padded.len() > max_length {
+                warn(
+                    field,
+                    format!(
+                        "The length {} is not a multiple of 64 of at most {}",
+                        padded.len(),
+                        max_length
+                    ),
+                );
+            }
+            if padded_len % 64 != 0 || padded_len > padded.len() {
+                warn(
+                    len_field,
+                    format!(
+                        "The padded length {} is not a multiple of 64 of at most {}",
+                        padded_len,
+                        padded.len()
+                    ),
+                );
+            }
+        };
+    check_padded(
+        "padded_header",
+        "padded_header_len",
+        &input.padded_header,
+        input.padded_header_len,
+        max_header_length,
+    );
+    match (&input.padded_body, input.padded_body_len) {
+        (Some(padded_body), Some(padded_body_len)) => check_padded(
+            "padded_body",
+            "padded_body_len",
+            padded_body,
+            padded_body_len,
+            max_body_length,
+        ),
+        (None, None) => {}
+        _ => warn(
+            "padded_body",
+            "The padded body and its padded length must be given together".to_string(),
+        ),
+    }
+    if let Some(precomputed_sha) = &input.precomputed_sha {
+        if precomputed_sha.len() != 32 {
+            warn(
+                "precomputed_sha",
+                format!(
+                    "The SHA-256 state is {} bytes long instead of 32",
+                    precomputed_sha.len()
+                ),
+            );
+        }
+    }
+
+    // The header indices must point into the header, and the others into the input they index
+    let header_len = input.padded_header_len;
+    let body_len = input
+        .padded_cleaned_body
+        .as_ref()
+        .or(input.padded_body.as_ref())
+        .map_or(header_len, |body| cmp::max(body.len(), header_len));
+    let idxes = [
+        ("body_hash_idx", input.body_hash_idx, header_len),
+        ("from_addr_idx", Some(input.from_addr_idx), header_len),
+        ("subject_idx", input.subject_idx, header_len),
+        ("domain_idx", Some(input.domain_idx), header_len),
+        ("timestamp_idx", Some(input.timestamp_idx), header_len),
+        ("code_idx", Some(input.code_idx), body_len),
+        ("command_idx", Some(input.command_idx), body_len),
+    ];
+    for (field, idx, len) in idxes.iter() {
+        if let Some(idx) = idx {
+            if *idx >= *len {
+                warn(
+                    field,
+                    format!("The index {} is outside the {} bytes it indexes", idx, len),
+                );
+            }
+        }
+    }
+
+    // The key and signature must be circom bigints of `CIRCOM_BIGINT_K` chunks
+    let chunk_bound = BigInt::from(1) << CIRCOM_BIGINT_N;
+    for (field, chunks) in [
+        ("public_key", &input.public_key),
+        ("signature", &input.signature),
+    ]
+    .iter()
+    {
+        if chunks.len() != CIRCOM_BIGINT_K {
+            warn(
+                field,
+                format!(
+                    "There are {} chunks instead of {}",
+                    chunks.len(),
+                    CIRCOM_BIGINT_K
+                ),
+            );
+        }
+        if let Some(chunk) = chunks.iter().find(|chunk| {
+            !chunk.bytes().all(|b| b.is_ascii_digit())
+                || !matches!(
+                    BigInt::parse_bytes(chunk.as_bytes(), 10),
+                    Some(value) if value < chunk_bound
+                )
+        }) {
+            warn(
+                field,
+                format!(
+                    "The chunk {:?} is not a decimal of at most {} bits",
+                    chunk, CIRCOM_BIGINT_N
+                ),
+            );
+        }
+    }
+
+    // The account code must be a field element in the form `field_to_hex` returns
+    match hex_to_field(&input.account_code) {
+        Ok(account_code) if field_to_hex(&account_code) == input.account_code => {}
+        _ => warn(
+            "account_code",
+            format!(
+                "{:?} is not a canonical hex field element",
+                input.account_code
+            ),
+        ),
+    }
+
+    Ok(warnings)
 }
 
 /// Asynchronously generates the circuit input for a claim.
@@ -468,32 +1769,82 @@ pub async fn generate_email_circuit_input(
 ///
 /// # Returns
 ///
-/// A `Result` which is either a JSON string of the `ClaimCircuitInput` on success or an error on failure.
+/// A `Result` which is either a JSON string of the `ClaimCircuitInput` on success or an error on
+/// failure, as described in `generate_claim_circuit_input`.
 pub async fn generate_claim_input(
     email_address: &str,
     email_address_rand: &str,
     account_code: &str,
 ) -> Result<String> {
+    let claim_input =
+        generate_claim_circuit_input(email_address, email_address_rand, account_code)?;
+
+    // Serialize the claim circuit input to JSON and return
+    Ok(serde_json::to_string(&claim_input)?)
+}
+
+/// Generates the circuit input for a claim.
+///
+/// # Arguments
+///
+/// * `email_address` - A string slice that holds the email address.
+/// * `email_address_rand` - The commitment randomness as 0x-prefixed hex, at most 32 bytes.
+/// * `account_code` - The account code as 0x-prefixed hex, at most 32 bytes.
+///
+/// # Returns
+///
+/// A `Result` which is either the `ClaimCircuitInput`, with the randomness and account code
+/// zero-padded to 32 bytes in lowercase, or an error if the email address is invalid or does
+/// not fit in the circuit, or if the randomness or account code is not a field element.
+pub fn generate_claim_circuit_input(
+    email_address: &str,
+    email_address_rand: &str,
+    account_code: &str,
+) -> Result<ClaimCircuitInput> {
+    validate_email_input(email_address)?;
+
     // Convert the email address to a padded format
-    let padded_email_address = PaddedEmailAddr::from_email_addr(email_address);
-    // Collect the padded bytes into a vector
-    let padded_email_addr_bytes = padded_email_address.padded_bytes;
+    let padded_email_address = PaddedEmailAddr::try_from_email_addr(email_address)?;
 
     // Construct the claim circuit input
-    let claim_input = ClaimCircuitInput {
-        email_addr: padded_email_addr_bytes,
-        cm_rand: email_address_rand.to_string(),
-        account_code: account_code.to_string(),
-    };
+    Ok(ClaimCircuitInput {
+        email_addr: padded_email_address.padded_bytes,
+        cm_rand: normalize_field_hex("email_address_rand", email_address_rand)?,
+        account_code: normalize_field_hex("account_code", account_code)?,
+    })
+}
 
-    // Serialize the claim circuit input to JSON and return
-    Ok(serde_json::to_string(&claim_input)?)
+/// Converts a 0x-prefixed hex value of at most 32 bytes to the canonical hex of its field element.
+///
+/// # Arguments
+///
+/// * `name` - The name of the parameter holding the value.
+/// * `value` - The hex value.
+///
+/// # Returns
+///
+/// A `Result` containing the zero-padded lowercase hex, or an error naming the parameter if the
+/// value is not a field element.
+fn normalize_field_hex(name: &str, value: &str) -> Result<String> {
+    let digits = value
+        .strip_prefix("0x")
+        .ok_or_else(|| anyhow!("Invalid {} {:?}: it must start with 0x", name, value))?;
+    if digits.is_empty() || digits.len() > 64 {
+        return Err(anyhow!(
+            "Invalid {} {:?}: it must have between 1 and 64 hex digits",
+            name,
+            value
+        ));
+    }
+    let field = hex_to_field(&format!("0x{:0>64}", digits))
+        .map_err(|e| anyhow!("Invalid {} {:?}: {}", name, value, e))?;
+    Ok(field_to_hex(&field))
 }
 
 /// Asynchronously generates circuit inputs with decomposed regexes and external inputs.
 ///
 /// This function processes an email, applies decomposed regexes, and incorporates external inputs
-/// to produce a JSON object representing the inputs for a zero-knowledge proof circuit.
+/// to produce the inputs for a zero-knowledge proof circuit.
 ///
 /// # Arguments
 ///
@@ -504,32 +1855,94 @@ pub async fn generate_claim_input(
 ///
 /// # Returns
 ///
-/// A `Result` which is either a JSON object of the circuit inputs on success or an error on failure.
+/// A `Result` which is either the `DecomposedRegexCircuitInput` on success or an error on failure.
 pub async fn generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
     email: &str,
     decomposed_regexes: Vec<DecomposedRegex>,
     external_inputs: Vec<ExternalInput>,
     params: CircuitInputWithDecomposedRegexesAndExternalInputsParams,
-) -> Result<Value> {
-    // Parse the raw email to extract canonicalized body and header, and other components
-    let parsed_email = ParsedEmail::new_from_raw_email(email).await?;
+) -> Result<DecomposedRegexCircuitInput> {
+    // Parse the raw email to extract canonicalized body and header, and other components; with a
+    // key override, the key of the email is neither resolved nor used to verify it
+    let parsed_email = match params.public_key_override.as_deref() {
+        Some(public_key) => {
+            let (_, modulus) = parse_hex_override("public_key_override", public_key)?.to_bytes_be();
+            ParsedEmail::new_from_raw_email_with_public_key_unverified(email, &modulus)?
+        }
+        None => ParsedEmail::new_from_raw_email(email).await?,
+    };
 
-    // Clone the fields that are used by value before the move occurs
-    let public_key = parsed_email.public_key.clone();
-    let signature = parsed_email.signature.clone();
+    build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+        &parsed_email,
+        decomposed_regexes,
+        external_inputs,
+        params,
+    )
+}
+
+/// Builds the circuit inputs with decomposed regexes and external inputs for an email that has
+/// already been parsed and verified.
+///
+/// # Arguments
+///
+/// * `parsed_email` - The parsed email.
+/// * `decomposed_regexes` - A vector of `DecomposedRegex` structs for regex processing.
+/// * `external_inputs` - A vector of `ExternalInput` structs for additional inputs.
+/// * `params` - Parameters for circuit input generation encapsulated in `CircuitInputWithDecomposedRegexesAndExternalInputsParams`.
+///
+/// # Returns
+///
+/// A `Result` which is either the `DecomposedRegexCircuitInput` on success or an error on failure.
+fn build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+    parsed_email: &ParsedEmail,
+    decomposed_regexes: Vec<DecomposedRegex>,
+    external_inputs: Vec<ExternalInput>,
+    params: CircuitInputWithDecomposedRegexesAndExternalInputsParams,
+) -> Result<DecomposedRegexCircuitInput> {
+    // The account salt is computed over the match of exactly one regex
+    let account_code = match params.account_code.as_deref() {
+        Some(account_code) => {
+            let sender_address_regexes = decomposed_regexes
+                .iter()
+                .filter(|regex| regex.is_sender_address)
+                .count();
+            if sender_address_regexes != 1 {
+                return Err(anyhow!(
+                    "An account code needs exactly one regex with isSenderAddress set to match the sender address, but {} regexes have it",
+                    sender_address_regexes
+                ));
+            }
+            let account_code = AccountCode::try_from_hex(account_code)
+                .map_err(|e| anyhow!("Invalid account_code {:?}: {}", account_code, e))?;
+            Some(account_code)
+        }
+        None => None,
+    };
 
-    // Create a CircuitParams struct from the parsed email
+    // Use the overrides, if any, in place of the key and signature of the email
+    let public_key = match params.public_key_override.as_deref() {
+        Some(public_key) => parse_hex_override("public_key_override", public_key)?,
+        None => vec_u8_to_bigint(parsed_email.public_key.rsa_modulus()?.to_vec()),
+    };
+    let signature = match params.signature_override.as_deref() {
+        Some(signature) => parse_hex_override("signature_override", signature)?,
+        None => vec_u8_to_bigint(parsed_email.signature.clone()),
+    };
+
+    // Create a CircuitParams struct from the parsed email, keeping only the signed part of the body
     let circuit_params = CircuitParams {
-        body: parsed_email.canonicalized_body.as_bytes().to_vec(),
+        body: parsed_email.signed_body()?.to_vec(),
         header: parsed_email.canonicalized_header.as_bytes().to_vec(),
         body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
-        rsa_signature: vec_u8_to_bigint(signature),
-        rsa_public_key: vec_u8_to_bigint(public_key),
+        rsa_signature: signature,
+        rsa_public_key: public_key,
     };
 
     // Create a CircuitOptions struct from the optional parameters
     let circuit_options = CircuitOptions {
         sha_precompute_selector: params.sha_precompute_selector,
+        header_sha_precompute_selector: params.header_sha_precompute_selector,
+        precompute_selector_on_cleaned_body: Some(params.precompute_selector_on_cleaned_body),
         max_header_length: Some(params.max_header_length),
         max_body_length: Some(params.max_body_length),
         ignore_body_hash_check: Some(params.ignore_body_hash_check),
@@ -539,36 +1952,90 @@ pub async fn generate_circuit_inputs_with_decomposed_regexes_and_external_inputs
     let circuit_input_params = CircuitInputParams::new(circuit_params, circuit_options);
 
     // Generate the circuit inputs from the parameters
-    let email_circuit_inputs = generate_circuit_inputs(circuit_input_params.clone())?;
-
-    // Create a JSON object to hold the circuit inputs
-    let mut circuit_inputs = json!({
-        "emailHeader": email_circuit_inputs.header_padded,
-        "emailHeaderLength": email_circuit_inputs.header_len_padded_bytes,
-        "pubkey": email_circuit_inputs.pubkey,
-        "signature": email_circuit_inputs.signature,
-    });
+    let email_circuit_inputs = generate_circuit_inputs(circuit_input_params)?;
 
-    // Include body-related inputs if the body hash check is not ignored
-    if !params.ignore_body_hash_check {
-        circuit_inputs["bodyHashIndex"] = email_circuit_inputs.body_hash_idx.into();
-        circuit_inputs["precomputedSHA"] = email_circuit_inputs.precomputed_sha.into();
-        circuit_inputs["emailBody"] = email_circuit_inputs.body_padded.clone().into();
-        circuit_inputs["emailBodyLength"] = email_circuit_inputs.body_len_padded_bytes.into();
-    }
+    // The circuit works on the cleaned body if soft line breaks are to be removed
+    let body = BodyInputs::new(
+        email_circuit_inputs.body_padded,
+        params.remove_soft_lines_breaks,
+        decomposed_regexes
+            .iter()
+            .any(|regex| regex.location == RegexLocation::CleanedBody),
+    );
 
-    // Clean the body by removing quoted-printable soft breaks if necessary
-    let cleaned_body = email_circuit_inputs
-        .body_padded
-        .clone()
-        .map(remove_quoted_printable_soft_breaks);
+    // Include body-related inputs if the body hash check is not ignored
+    let body_inputs = !params.ignore_body_hash_check;
+    // Start with masks that hide everything; the positions refer to the same padded or cleaned
+    // representation as the regex indices
+    let header_mask = Some(vec![0; email_circuit_inputs.header_padded.len()])
+        .filter(|_| params.enable_header_masking);
+    let body_mask = body
+        .padded
+        .as_ref()
+        .map(|body_padded| vec![0; body_padded.len()])
+        .filter(|_| body_inputs && params.enable_body_masking);
+    let remove_soft_line_breaks = body.remove_soft_line_breaks;
+    let (body_padded, decoded_email_body_in, searched_cleaned_body) = body.into_parts();
+    let mut circuit_inputs = DecomposedRegexCircuitInput {
+        email_header: email_circuit_inputs.header_padded,
+        email_header_length: email_circuit_inputs.header_len_padded_bytes,
+        pubkey: email_circuit_inputs.pubkey,
+        signature: email_circuit_inputs.signature,
+        body_hash_index: email_circuit_inputs.body_hash_idx.filter(|_| body_inputs),
+        precomputed_sha: email_circuit_inputs.precomputed_sha.filter(|_| body_inputs),
+        precomputed_header_sha: email_circuit_inputs.precomputed_header_sha,
+        email_body: body_padded.filter(|_| body_inputs),
+        email_body_length: email_circuit_inputs
+            .body_len_padded_bytes
+            .filter(|_| body_inputs),
+        decoded_email_body_in,
+        header_mask,
+        body_mask,
+        prover_eth_address: match params.prover_eth_address.as_deref() {
+            Some(prover_eth_address) => eth_address_to_u256(prover_eth_address)
+                .map_err(|e| anyhow!("Invalid prover_eth_address {:?}: {}", prover_eth_address, e))?
+                .to_string(),
+            None => "0".to_string(),
+        },
+        account_salt: None,
+        email_body_hash: if params.include_body_hash_debug {
+            Some(BodyHashDebug::new(parsed_email)?)
+        } else {
+            None
+        },
+        input_schema_version: Some(DECOMPOSED_CIRCUIT_INPUT_SCHEMA_VERSION.to_string())
+            .filter(|_| params.include_input_schema_version),
+        regex_idxes: IndexMap::new(),
+        external_inputs: IndexMap::new(),
+        regex_all_idxes: BTreeMap::new(),
+        regex_signal_lengths: BTreeMap::new(),
+        regex_matches: BTreeMap::new(),
+    };
 
-    // Add the cleaned body to the circuit inputs if soft line breaks are to be removed
-    if params.remove_soft_lines_breaks {
-        if let Some((cleaned_body_vec, _)) = cleaned_body.clone() {
-            circuit_inputs["decodedEmailBodyIn"] = cleaned_body_vec.into();
+    // Convert the inputs the regexes search to strings once, and only if a regex searches them
+    let searched_location = |location: RegexLocation| match location {
+        RegexLocation::Body if remove_soft_line_breaks => RegexLocation::CleanedBody,
+        location => location,
+    };
+    let regex_input = |location: RegexLocation, bytes: Option<&Vec<u8>>| {
+        if decomposed_regexes
+            .iter()
+            .any(|regex| searched_location(regex.location) == location)
+        {
+            bytes.map(|v| RegexInput::new(v)).unwrap_or_default()
+        } else {
+            RegexInput::default()
         }
-    }
+    };
+    let header_input = regex_input(RegexLocation::Header, Some(&circuit_inputs.email_header));
+    let padded_body_input = regex_input(RegexLocation::Body, circuit_inputs.email_body.as_ref());
+    let cleaned_body_input = regex_input(
+        RegexLocation::CleanedBody,
+        circuit_inputs
+            .decoded_email_body_in
+            .as_ref()
+            .or(searched_cleaned_body.as_ref()),
+    );
 
     // Process each decomposed regex and add the resulting indices to the circuit inputs
     for decomposed_regex in decomposed_regexes {
@@ -580,64 +2047,217 @@ pub async fn generate_circuit_inputs_with_decomposed_regexes_and_external_inputs
         }
 
         // Determine the input string based on the regex location
-        let input = if decomposed_regex.location == "header" {
-            String::from_utf8_lossy(&email_circuit_inputs.header_padded.clone()).into_owned()
-        } else if decomposed_regex.location == "body" && params.remove_soft_lines_breaks {
-            cleaned_body
-                .as_ref()
-                .map(|(v, _)| String::from_utf8_lossy(v).into_owned())
-                .unwrap_or_else(|| String::new())
-        } else {
-            email_circuit_inputs
-                .body_padded
-                .as_ref()
-                .map(|v| String::from_utf8_lossy(v).into_owned())
-                .unwrap_or_else(|| String::new())
+        let (input, searched) = match searched_location(decomposed_regex.location) {
+            RegexLocation::Header => (&header_input, "header"),
+            RegexLocation::Body => (&padded_body_input, "padded body"),
+            RegexLocation::CleanedBody => (&cleaned_body_input, "cleaned body"),
+        };
+
+        // Extract substring indices using the decomposed regex configuration, treating a
+        // failed or empty match as an error that shows what the regex was run against
+        let text_idxes: Vec<(usize, usize)> =
+            match extract_substr_idxes(
+                &input.text,
+                &decomposed_regex_config,
+                decomposed_regex.reveal_private,
+            ) {
+                Ok(idxes) if !idxes.is_empty() => idxes,
+                _ => {
+                    return Err(anyhow!(
+                        "regex '{}' (location: {}) matched nothing in the {}; first {} chars of the searched input were: {:?}",
+                        decomposed_regex.name,
+                        decomposed_regex.location,
+                        searched,
+                        REGEX_INPUT_PREVIEW_CHARS,
+                        input.text.chars().take(REGEX_INPUT_PREVIEW_CHARS).collect::<String>()
+                    ))
+                }
+            };
+        // The circuit receives the bytes, so the indices must be byte offsets in them
+        let idxes = input.byte_spans(&text_idxes);
+
+        // Reject matches that do not fit in the reveal length configured for the circuit
+        if let Some((start, end)) = idxes
+            .iter()
+            .find(|(start, end)| end - start > decomposed_regex.max_length)
+        {
+            return Err(anyhow!(
+                "Regex {} matched {} bytes, but its max length is {}",
+                decomposed_regex.name,
+                end - start,
+                decomposed_regex.max_length
+            ));
+        }
+        circuit_inputs.regex_signal_lengths.insert(
+            decomposed_regex.name.clone(),
+            compute_signal_length(decomposed_regex.max_length),
+        );
+        if params.debug {
+            circuit_inputs.regex_matches.insert(
+                decomposed_regex.name.clone(),
+                RegexMatchDebug {
+                    location: decomposed_regex.location,
+                    searched: searched.to_string(),
+                    spans: idxes.clone(),
+                    substrings: text_idxes
+                        .iter()
+                        .map(|(start, end)| {
+                            input.text.get(*start..*end).unwrap_or_default().to_string()
+                        })
+                        .collect(),
+                },
+            );
+        }
+
+        // Reveal the bytes matched by the public parts in the mask of the searched input
+        let mask = match decomposed_regex.location {
+            RegexLocation::Header => circuit_inputs.header_mask.as_mut(),
+            RegexLocation::Body | RegexLocation::CleanedBody => circuit_inputs.body_mask.as_mut(),
         };
+        if let Some(mask) = mask {
+            for (start, end) in &idxes {
+                let end = cmp::min(*end, mask.len());
+                for bit in mask.iter_mut().take(end).skip(*start) {
+                    *bit = 1;
+                }
+            }
+        }
 
-        // Extract substring indices using the decomposed regex configuration
-        let idxes: Vec<(usize, usize)> =
-            extract_substr_idxes(&input, &decomposed_regex_config, false)?;
+        // Add the selected match, the first one by default, to the circuit inputs
+        let match_index = decomposed_regex.match_index.unwrap_or(0);
+        let selected = idxes.get(match_index).ok_or_else(|| {
+            anyhow!(
+                "Regex {} has no match at index {}, only {} matches were found",
+                decomposed_regex.name,
+                match_index,
+                idxes.len()
+            )
+        })?;
+        circuit_inputs
+            .regex_idxes
+            .insert(format!("{}RegexIdx", decomposed_regex.name), selected.0);
 
-        // Add the first index to the circuit inputs
-        circuit_inputs[format!("{}RegexIdx", decomposed_regex.name)] = idxes[0].0.into();
+        // Compute the account salt over the selected match, which must be a whole email address
+        if let (true, Some(account_code)) = (decomposed_regex.is_sender_address, account_code) {
+            let (start, end) = text_idxes[match_index];
+            let email_addr = input.text.get(start..end).unwrap_or_default();
+            validate_email_input(email_addr).map_err(|e| {
+                anyhow!(
+                    "Regex {} must match the sender address to compute the account salt: {}",
+                    decomposed_regex.name,
+                    e
+                )
+            })?;
+            if email_addr.len() > MAX_EMAIL_ADDR_BYTES {
+                return Err(anyhow!(
+                    "Regex {} matched a {} byte sender address, but at most {} bytes fit in the account salt",
+                    decomposed_regex.name,
+                    email_addr.len(),
+                    MAX_EMAIL_ADDR_BYTES
+                ));
+            }
+            let account_salt = AccountSalt::new(
+                &PaddedEmailAddr::try_from_email_addr(email_addr)?,
+                account_code,
+            )
+            .map_err(|e| anyhow!("Failed to compute the account salt: {}", e))?;
+            circuit_inputs.account_salt = Some(field_to_hex(&account_salt.0));
+        }
+        circuit_inputs.regex_all_idxes.insert(
+            format!("{}RegexAllIdx", decomposed_regex.name),
+            idxes.iter().map(|idx| idx.0).collect(),
+        );
 
         for (i, idx) in idxes.iter().enumerate().skip(1) {
             // Add the remaining indices to the circuit inputs
-            circuit_inputs[format!("{}RegexIdx{}", decomposed_regex.name, i)] = idx.0.into();
+            circuit_inputs
+                .regex_idxes
+                .insert(format!("{}RegexIdx{}", decomposed_regex.name, i), idx.0);
         }
     }
 
     // Process each external input and add it to the circuit inputs
     for external_input in external_inputs {
-        let mut value =
-            string_to_circom_bigint_bytes(&external_input.value.as_deref().unwrap_or(""))?;
-        let signal_length = compute_signal_length(external_input.max_length);
-
-        // Pad the value to the signal length
-        if value.len() < signal_length {
-            value.extend(
-                vec![0; signal_length - value.len()]
-                    .into_iter()
-                    .map(|num| num.to_string()),
-            );
-        }
+        let value = external_input.to_signals()?;
 
         // Add the external input to the circuit inputs
-        circuit_inputs[external_input.name] = value.into();
+        circuit_inputs
+            .external_inputs
+            .insert(external_input.name, value);
     }
 
-    if params.prover_eth_address.is_some() {
-        circuit_inputs["proverETHAddress"] =
-            hex_to_u256(params.prover_eth_address.as_deref().unwrap_or(""))?
-                .to_string()
-                .into();
-    } else {
-        circuit_inputs["proverETHAddress"] = "0".into();
+    Ok(circuit_inputs)
+}
+
+/// Parses an RSA key or signature override given as a 0x-prefixed hex string.
+fn parse_hex_override(name: &str, value: &str) -> Result<BigInt> {
+    value
+        .strip_prefix("0x")
+        .filter(|digits| !digits.is_empty())
+        .and_then(|digits| BigInt::parse_bytes(digits.as_bytes(), 16))
+        .filter(|value| value.sign() != Sign::Minus)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid {} {:?}: it must be a 0x-prefixed hex string",
+                name,
+                value
+            )
+        })
+}
+
+/// An input searched by decomposed regexes, as UTF-8 text that maps back to the original bytes.
+///
+/// Invalid UTF-8 sequences are replaced by U+FFFD as in `String::from_utf8_lossy`, which takes
+/// three bytes of text, so the offsets in the text differ from those in the bytes after them.
+#[derive(Default)]
+struct RegexInput {
+    text: String,             // The text the regexes are run on
+    byte_offsets: Vec<usize>, // The offset in the bytes of each text offset, including the end
+}
+
+impl RegexInput {
+    /// Decodes the bytes, recording the byte offset of each text offset.
+    fn new(bytes: &[u8]) -> Self {
+        let mut text = String::with_capacity(bytes.len());
+        let mut byte_offsets = Vec::with_capacity(bytes.len() + 1);
+        let mut offset = 0;
+        let mut rest = bytes;
+        loop {
+            let (valid, invalid_len) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, 0),
+                Err(e) => (
+                    std::str::from_utf8(&rest[..e.valid_up_to()])
+                        .expect("The bytes before the error are valid UTF-8"),
+                    e.error_len().unwrap_or(rest.len() - e.valid_up_to()),
+                ),
+            };
+            text.push_str(valid);
+            byte_offsets.extend(offset..offset + valid.len());
+            offset += valid.len();
+            if invalid_len == 0 {
+                break;
+            }
+
+            // Every byte of the replacement character maps to the start of the invalid sequence
+            text.push(char::REPLACEMENT_CHARACTER);
+            byte_offsets.extend(std::iter::repeat_n(
+                offset,
+                char::REPLACEMENT_CHARACTER.len_utf8(),
+            ));
+            offset += invalid_len;
+            rest = &bytes[offset..];
+        }
+        byte_offsets.push(offset);
+        RegexInput { text, byte_offsets }
     }
 
-    // Return the circuit inputs as a JSON object
-    Ok(circuit_inputs)
+    /// Maps spans of offsets in the text to spans of offsets in the bytes.
+    fn byte_spans(&self, text_spans: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        text_spans
+            .iter()
+            .map(|(start, end)| (self.byte_offsets[*start], self.byte_offsets[*end]))
+            .collect()
+    }
 }
 
 /// Computes the signal length required for a given maximum length.
@@ -657,21 +2277,60 @@ pub fn compute_signal_length(max_length: usize) -> usize {
     (max_length / 31) + if max_length % 31 != 0 { 1 } else { 0 }
 }
 
+/// Packs bytes into the field elements of a signal for an input of at most `max_length` bytes.
+///
+/// The bytes are packed 31 bytes per field element, each field element is converted into its
+/// decimal string, and the result is padded with zeros to `compute_signal_length(max_length)`
+/// elements, the same packing the circuits use for external inputs.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to pack.
+/// * `max_length` - The maximum number of bytes the signal holds.
+///
+/// # Returns
+///
+/// A `Result` containing the field elements as decimal strings, or an error if `bytes` is
+/// longer than `max_length`.
+pub fn pack_bytes_into_fields(bytes: &[u8], max_length: usize) -> Result<Vec<String>> {
+    if bytes.len() > max_length {
+        return Err(anyhow!(
+            "Cannot pack {} bytes into a signal with a max length of {}",
+            bytes.len(),
+            max_length
+        ));
+    }
+    let mut fields = bytes_to_circom_bigint_bytes(bytes);
+    fields.resize(compute_signal_length(max_length), "0".to_string());
+    Ok(fields)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::test_fixtures::{fixture, MIME_FIXTURES_PUBLIC_KEY};
+    use crate::{partial_sha, BodyCanon, HeaderCanon, DEFAULT_MAX_RAW_EMAIL_BYTES};
+    use poseidon_rs::poseidon_fields;
     use std::path::PathBuf;
 
-    #[tokio::test]
-    async fn test_generate_regex_inputs() -> Result<()> {
-        // Get the test file path relative to the project root
-        let test_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("fixtures")
-            .join("test.eml");
+    /// Builds an unsigned email with the given body and a body hash that matches it.
+    fn parsed_email_with_body(body: &str) -> ParsedEmail {
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(body.as_bytes()));
+        ParsedEmail::from_parts(
+            format!(
+                "from:alice@example.com\r\nsubject:Hello\r\ndkim-signature:v=1; a=rsa-sha256; d=example.com; s=s1; bh={}; b=",
+                body_hash
+            ),
+            body.to_string(),
+            vec![1; 256],
+            vec![0xff; 256],
+        )
+    }
 
-        let email = std::fs::read_to_string(test_file)?;
+    #[test]
+    fn test_generate_regex_inputs() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Hi!\r\n");
 
         let mut decomposed_regexes = Vec::new();
         let part_1 = RegexPartConfig {
@@ -687,53 +2346,46 @@ mod tests {
             parts: vec![part_1, part_2],
             name: "hi".to_string(),
             max_length: 64,
-            location: "body".to_string(),
+            location: RegexLocation::Body,
+            ..Default::default()
         });
 
         let external_inputs = vec![];
 
-        let input = generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
-            &email,
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
             decomposed_regexes,
             external_inputs,
             CircuitInputWithDecomposedRegexesAndExternalInputsParams {
                 max_body_length: 2816,
-                max_header_length: 1024,
-                ignore_body_hash_check: false,
                 remove_soft_lines_breaks: true,
-                sha_precompute_selector: None,
                 prover_eth_address: Some("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e".to_string()),
+                ..Default::default()
             },
-        )
-        .await?;
-
-        // Save the input to a file in the test output directory
-        let output_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("outputs")
-            .join("input.json");
+        )?;
 
-        // Create the output directory if it doesn't exist
-        if let Some(parent) = output_file.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
+        // The header is padded to the maximum length, its padded length to a multiple of 64
+        assert_eq!(input.email_header.len(), 1024);
+        assert_eq!(input.email_header_length % 64, 0);
+        assert_eq!(
+            input.email_header[parsed_email.canonicalized_header.len()],
+            0x80
+        );
 
-        // Save the input to a file
-        let input_str = serde_json::to_string_pretty(&input)?;
-        std::fs::write(output_file, input_str)?;
+        // The body hash index points at the `bh=` value
+        let body_hash_idx = input.body_hash_index.unwrap();
+        assert!(parsed_email.canonicalized_header[body_hash_idx..]
+            .starts_with(&general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(b"Hi!\r\n"))));
 
+        // The body is followed by the SHA-256 padding, and the regex index points at the "!"
+        assert_eq!(&input.email_body.unwrap()[..6], b"Hi!\r\n\x80");
+        assert_eq!(input.regex_idxes["hiRegexIdx"], 2);
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_generate_regex_inputs_with_external_inputs() -> Result<()> {
-        // Get the test file path relative to the project root
-        let test_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("fixtures")
-            .join("test.eml");
-
-        let email = std::fs::read_to_string(test_file)?;
+    #[test]
+    fn test_generate_regex_inputs_with_external_inputs() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Hi!\r\n");
 
         let mut decomposed_regexes = Vec::new();
         let part_1 = RegexPartConfig {
@@ -749,80 +2401,781 @@ mod tests {
             parts: vec![part_1, part_2],
             name: "hi".to_string(),
             max_length: 64,
-            location: "body".to_string(),
+            location: RegexLocation::Body,
+            ..Default::default()
         });
 
         let external_inputs = vec![ExternalInput {
             name: "address".to_string(),
             value: Some("testerman@zkemail.com".to_string()),
             max_length: 64,
+            encoding: ExternalInputEncoding::Utf8,
         }];
 
-        let input = generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
-            &email,
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
             decomposed_regexes,
             external_inputs,
             CircuitInputWithDecomposedRegexesAndExternalInputsParams {
                 max_body_length: 2816,
-                max_header_length: 1024,
-                ignore_body_hash_check: false,
                 remove_soft_lines_breaks: true,
-                sha_precompute_selector: None,
                 prover_eth_address: Some("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e".to_string()),
+                ..Default::default()
             },
-        )
-        .await?;
-
-        // Save the input to a file in the test output directory
-        let output_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("outputs")
-            .join("input.json");
-
-        // Create the output directory if it doesn't exist
-        if let Some(parent) = output_file.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        // Save the input to a file
-        let input_str = serde_json::to_string_pretty(&input)?;
-        std::fs::write(output_file, input_str)?;
+        )?;
 
+        // The external input is padded to the number of 31-byte chunks of its maximum length
+        assert_eq!(
+            input.external_inputs["address"].len(),
+            compute_signal_length(64)
+        );
+        // The prover address is passed to the circuit as a decimal number
+        assert_eq!(
+            input.prover_eth_address,
+            eth_address_to_u256("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e")?.to_string()
+        );
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_generate_regex_inputs_with_external_inputs_with_sha_precompute_selector(
-    ) -> Result<()> {
-        // Get the test file path relative to the project root
-        let test_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("fixtures")
-            .join("x.eml");
-
-        let email = std::fs::read_to_string(test_file)?;
-
-        let mut decomposed_regexes = Vec::new();
-        let part_1 = RegexPartConfig {
-            is_public: false,
-            regex_def: "email was meant for @".to_string(),
+    #[test]
+    fn test_generate_regex_inputs_with_match_index() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Send 5 ETH, then 7 ETH\r\n");
+        let amount_regex = |match_index| DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: " ETH".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            match_index,
+            ..Default::default()
         };
-        let part_2 = RegexPartConfig {
-            is_public: true,
-            regex_def: "[a-zA-Z0-9_]+".to_string(),
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            remove_soft_lines_breaks: true,
+            ..Default::default()
         };
 
-        decomposed_regexes.push(DecomposedRegex {
-            parts: vec![part_1, part_2],
-            name: "handle".to_string(),
-            max_length: 64,
-            location: "body".to_string(),
-        });
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(Some(1))],
+            vec![],
+            params.clone(),
+        )?;
+        assert_eq!(input.regex_idxes["amountRegexIdx"], 17);
+        assert_eq!(input.regex_all_idxes["amountRegexAllIdx"], vec![5, 17]);
+        assert_eq!(input.regex_signal_lengths["amount"], 3);
+        assert!(input.regex_matches.is_empty());
+
+        let debug_input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(Some(1))],
+            vec![],
+            CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                debug: true,
+                ..params.clone()
+            },
+        )?;
+        assert_eq!(
+            debug_input.regex_matches["amount"],
+            RegexMatchDebug {
+                location: RegexLocation::Body,
+                searched: "cleaned body".to_string(),
+                spans: vec![(5, 6), (17, 18)],
+                substrings: vec!["5".to_string(), "7".to_string()],
+            }
+        );
+        // Debugging leaves the proof-facing JSON unchanged
+        assert_eq!(debug_input.to_json_string()?, input.to_json_string()?);
+        assert!(!input
+            .to_json()?
+            .as_object()
+            .unwrap()
+            .contains_key("amountRegexAllIdx"));
+
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(Some(2))],
+            vec![],
+            params.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Regex amount has no match at index 2, only 2 matches were found"
+        );
+
+        let mut short_regex = amount_regex(None);
+        short_regex.max_length = 0;
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![short_regex],
+            vec![],
+            params,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Regex amount matched 1 bytes, but its max length is 0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_with_masks() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Send 5 ETH, then 7 ETH\r\n");
+        let subject_regex = DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: "subject:".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[A-Za-z]+".to_string(),
+                },
+            ],
+            name: "subject".to_string(),
+            max_length: 64,
+            location: RegexLocation::Header,
+            ..Default::default()
+        };
+        let amount_regex = DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: " ETH".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            remove_soft_lines_breaks: true,
+            enable_header_masking: true,
+            enable_body_masking: true,
+            ..Default::default()
+        };
+
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![subject_regex, amount_regex],
+            vec![],
+            params,
+        )?;
+        let revealed = |mask: &[u8]| {
+            mask.iter()
+                .enumerate()
+                .filter(|(_, bit)| **bit == 1)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+
+        let header_mask = input.header_mask.clone().unwrap();
+        assert_eq!(header_mask.len(), input.email_header.len());
+        let subject_idx = input.regex_idxes["subjectRegexIdx"];
+        assert_eq!(
+            revealed(&header_mask),
+            (subject_idx..subject_idx + "Hello".len()).collect::<Vec<_>>()
+        );
+
+        let body_mask = input.body_mask.clone().unwrap();
+        assert_eq!(body_mask.len(), input.email_body.as_ref().unwrap().len());
+        assert_eq!(
+            revealed(&body_mask),
+            vec![
+                input.regex_idxes["amountRegexIdx"],
+                input.regex_idxes["amountRegexIdx1"]
+            ]
+        );
+
+        let json = input.to_json()?;
+        assert!(json.get("headerMask").is_some());
+        assert!(json.get("bodyMask").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_input_encodings() {
+        let external_input = |value: &str, encoding| ExternalInput {
+            name: "salt".to_string(),
+            value: Some(value.to_string()),
+            max_length: 31,
+            encoding,
+        };
+
+        assert_eq!(
+            external_input("0x0102", ExternalInputEncoding::Hex)
+                .to_signals()
+                .unwrap(),
+            vec![(1 + 2 * 256).to_string()]
+        );
+        assert_eq!(
+            external_input("0102", ExternalInputEncoding::Hex)
+                .to_signals()
+                .unwrap(),
+            external_input("0x0102", ExternalInputEncoding::Hex)
+                .to_signals()
+                .unwrap()
+        );
+        assert_eq!(
+            external_input("12345", ExternalInputEncoding::Decimal)
+                .to_signals()
+                .unwrap(),
+            vec!["12345".to_string()]
+        );
+        assert_eq!(
+            external_input("ab", ExternalInputEncoding::Utf8)
+                .to_signals()
+                .unwrap(),
+            vec![(97 + 98 * 256).to_string()]
+        );
+
+        let err = external_input("0xzz", ExternalInputEncoding::Hex)
+            .to_signals()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("External input salt has an invalid hex value \"0xzz\""));
+        for value in [
+            "-1",
+            "1.5",
+            "21888242871839275222246405745257275088548364400416034343698204186575808495617",
+        ]
+        .iter()
+        {
+            assert!(external_input(value, ExternalInputEncoding::Decimal)
+                .to_signals()
+                .is_err());
+        }
+
+        // Inputs without an encoding keep using UTF-8
+        let external_input: ExternalInput =
+            serde_json::from_str(r#"{"name":"salt","value":"ab","maxLength":32}"#).unwrap();
+        assert_eq!(external_input.encoding, ExternalInputEncoding::Utf8);
+    }
+
+    #[test]
+    fn test_external_input_max_length() {
+        let external_input = |value: Option<String>, max_length| ExternalInput {
+            name: "memo".to_string(),
+            value,
+            max_length,
+            encoding: ExternalInputEncoding::Utf8,
+        };
+
+        // 31 bytes fit in a single signal
+        let signals = external_input(Some("a".repeat(31)), 31)
+            .to_signals()
+            .unwrap();
+        assert_eq!(signals.len(), 1);
+
+        // 32 bytes need a second signal
+        let signals = external_input(Some("a".repeat(32)), 32)
+            .to_signals()
+            .unwrap();
+        assert_eq!(signals.len(), 2);
+        assert_eq!(signals[1], "97");
+
+        let err = external_input(Some("a".repeat(33)), 32)
+            .to_signals()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "External input memo is 33 bytes long, but its max length is 32"
+        );
+
+        // A missing value becomes zero signals of the full length
+        assert_eq!(
+            external_input(None, 64).to_signals().unwrap(),
+            vec!["0".to_string(); 3]
+        );
+    }
+
+    #[test]
+    fn test_pack_bytes_into_fields() {
+        // The bytes are packed little-endian into 31-byte field elements and padded with zeros
+        let fields = pack_bytes_into_fields(&[b'a'; 32], 64).unwrap();
+        assert_eq!(fields.len(), compute_signal_length(64));
+        assert_eq!(fields[..2], bytes_to_circom_bigint_bytes(&[b'a'; 32])[..]);
+        assert_eq!(fields[2], "0");
+
+        assert_eq!(
+            pack_bytes_into_fields(&[], 31).unwrap(),
+            vec!["0".to_string()]
+        );
+        assert_eq!(
+            pack_bytes_into_fields(&[1; 33], 32)
+                .unwrap_err()
+                .to_string(),
+            "Cannot pack 33 bytes into a signal with a max length of 32"
+        );
+
+        // External inputs use the same packing
+        let external_input = ExternalInput {
+            name: "memo".to_string(),
+            value: Some("hello".to_string()),
+            max_length: 40,
+            encoding: ExternalInputEncoding::Utf8,
+        };
+        assert_eq!(
+            external_input.to_signals().unwrap(),
+            pack_bytes_into_fields(b"hello", 40).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_regex_location_deserialization() {
+        let location = |value: &str| serde_json::from_value::<RegexLocation>(Value::from(value));
+        assert_eq!(location("header").unwrap(), RegexLocation::Header);
+        assert_eq!(location("body").unwrap(), RegexLocation::Body);
+        assert_eq!(location("cleanedBody").unwrap(), RegexLocation::CleanedBody);
+        assert!(location("Body").is_err());
+        assert!(location("subject").is_err());
+        assert_eq!(
+            serde_json::to_value(RegexLocation::CleanedBody).unwrap(),
+            Value::from(RegexLocation::CleanedBody.to_string())
+        );
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_with_reveal_private() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Send 5 ETH\r\n");
+        let amount_regex = |reveal_private| DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: "Send ".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            reveal_private,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            ..Default::default()
+        };
+
+        // Only the public part is returned by default
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(false)],
+            vec![],
+            params.clone(),
+        )?;
+        assert_eq!(input.regex_all_idxes["amountRegexAllIdx"], vec![5]);
+
+        // The private part is returned as well when revealed
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(true)],
+            vec![],
+            params,
+        )?;
+        let idxes = &input.regex_all_idxes["amountRegexAllIdx"];
+        assert!(idxes.contains(&0));
+        assert!(idxes.contains(&5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_in_cleaned_body() -> Result<()> {
+        // The soft line break splits the amount in the remaining body
+        let parsed_email = parsed_email_with_body("Send 1=\r\n2 ETH\r\n");
+        let amount_regex = |location| DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: "Send ".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: " ETH".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            ..Default::default()
+        };
+
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(RegexLocation::CleanedBody)],
+            vec![],
+            params.clone(),
+        )?;
+        assert_eq!(input.regex_idxes["amountRegexIdx"], 5);
+
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex(RegexLocation::Body)],
+            vec![],
+            params,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("matched nothing in the padded body"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_after_invalid_utf8() -> Result<()> {
+        // The invalid byte would shift the match by two bytes in a lossy string
+        let mut parsed_email = parsed_email_with_body("");
+        parsed_email.canonicalized_body_bytes = b"Caf\xff: Send 5 ETH\r\n".to_vec();
+        let amount_regex = DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: "Send ".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            enable_body_masking: true,
+            debug: true,
+            ..Default::default()
+        };
+
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![amount_regex],
+            vec![],
+            params,
+        )?;
+        let body = input.email_body.unwrap();
+        let idx = input.regex_idxes["amountRegexIdx"];
+        assert_eq!(idx, 11);
+        assert_eq!(body[idx], b'5');
+        assert_eq!(input.body_mask.unwrap()[idx..idx + 2], [1, 0]);
+        assert_eq!(input.regex_matches["amount"].spans, vec![(11, 12)]);
+        assert_eq!(input.regex_matches["amount"].substrings, vec!["5"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_with_key_and_signature_overrides() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Hi!\r\n");
+        let params =
+            |public_key_override: &str| CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                max_body_length: 1024,
+                public_key_override: Some(public_key_override.to_string()),
+                signature_override: Some("0x0102".to_string()),
+                ..Default::default()
+            };
+
+        let public_key_override = format!("0x{}", "c3".repeat(256));
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![],
+            vec![],
+            params(&public_key_override),
+        )?;
+        assert_eq!(
+            input.pubkey,
+            to_circom_bigint_bytes(vec_u8_to_bigint(vec![0xc3; 256]))
+        );
+        assert_eq!(
+            input.signature,
+            to_circom_bigint_bytes(BigInt::from(0x0102))
+        );
+
+        for invalid in ["c3c3", "0x", "0xzz"].iter() {
+            let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+                &parsed_email,
+                vec![],
+                vec![],
+                params(invalid),
+            )
+            .unwrap_err();
+            assert!(err.to_string().starts_with("Invalid public_key_override"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_with_account_salt() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Sender alice@example.com\r\nAmount 5\r\n");
+        let regex = |name: &str, prefix: &str, public: &str, is_sender_address| DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: prefix.to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: public.to_string(),
+                },
+            ],
+            name: name.to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            is_sender_address,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            account_code: Some(format!("0x{:0>64}", "01")),
+            ..Default::default()
+        };
+
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![
+                regex("sender", "Sender ", "[a-z@.]+", true),
+                regex("amount", "Amount ", "[0-9]+", false),
+            ],
+            vec![],
+            params.clone(),
+        )?;
+        let account_salt = AccountSalt::new(
+            &PaddedEmailAddr::try_from_email_addr("alice@example.com")?,
+            AccountCode::from(hex_to_field("0x01")?),
+        )
+        .unwrap();
+        assert_eq!(input.account_salt, Some(field_to_hex(&account_salt.0)));
+        assert!(input.to_json()?.get("accountSalt").is_some());
+
+        // The account code needs exactly one sender address regex
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![regex("amount", "Amount ", "[0-9]+", false)],
+            vec![],
+            params.clone(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("but 0 regexes have it"));
+
+        // The sender address regex must match an email address
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![regex("amount", "Amount ", "[0-9]+", true)],
+            vec![],
+            params,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Regex amount must match the sender address"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_input_byte_offsets() {
+        let input = RegexInput::new(b"a\xffb\xe2\x82c\xf0");
+        assert_eq!(input.text, "a\u{fffd}b\u{fffd}c\u{fffd}");
+        assert_eq!(
+            input.byte_spans(&[(0, 1), (1, 4), (4, 5), (8, 9), (9, 12)]),
+            vec![(0, 1), (1, 2), (2, 3), (5, 6), (6, 7)]
+        );
+
+        let input = RegexInput::new("héllo".as_bytes());
+        assert_eq!(input.byte_spans(&[(1, 3), (0, 6)]), vec![(1, 3), (0, 6)]);
+    }
+
+    #[test]
+    fn test_generate_regex_inputs_without_match() {
+        let parsed_email = parsed_email_with_body("Send 5 ETH\r\n");
+        let decomposed_regex = DecomposedRegex {
+            parts: vec![
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: "Send ".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: true,
+                    regex_def: "[0-9]+".to_string(),
+                },
+                RegexPartConfig {
+                    is_public: false,
+                    regex_def: " DAI".to_string(),
+                },
+            ],
+            name: "amount".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            ..Default::default()
+        };
+        let params = CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+            max_body_length: 1024,
+            remove_soft_lines_breaks: true,
+            ..Default::default()
+        };
+
+        let err = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![decomposed_regex],
+            vec![],
+            params,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.starts_with("regex 'amount' (location: body) matched nothing in the cleaned body")
+        );
+        assert!(err.contains("\"Send 5 ETH\\r\\n"));
+    }
+
+    #[test]
+    fn test_decomposed_regex_circuit_input_json_snapshot() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Hi!\r\n");
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email,
+            vec![DecomposedRegex {
+                parts: vec![
+                    RegexPartConfig {
+                        is_public: false,
+                        regex_def: "from:".to_string(),
+                    },
+                    RegexPartConfig {
+                        is_public: true,
+                        regex_def: "[a-z]+".to_string(),
+                    },
+                ],
+                name: "from".to_string(),
+                max_length: 64,
+                location: RegexLocation::Header,
+                ..Default::default()
+            }],
+            vec![ExternalInput {
+                name: "address".to_string(),
+                value: Some("ab".to_string()),
+                max_length: 64,
+                encoding: ExternalInputEncoding::Utf8,
+            }],
+            CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                max_body_length: 1024,
+                max_header_length: 256,
+                prover_eth_address: Some("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e".to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        // The JSON must stay byte-identical to the object the generator used to build by hand
+        let snapshot = std::fs::read_to_string(fixture("decomposed_regex_circuit_input.json"))?;
+        assert_eq!(
+            serde_json::to_string(&input.to_json()?)?,
+            snapshot.trim_end()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decomposed_regex_circuit_input_key_order() -> Result<()> {
+        let parsed_email = parsed_email_with_body("Send 5 ETH\r\n");
+        let regex = |name: &str, regex_def: &str| DecomposedRegex {
+            parts: vec![RegexPartConfig {
+                is_public: true,
+                regex_def: regex_def.to_string(),
+            }],
+            name: name.to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            ..Default::default()
+        };
+        let external_input = |name: &str| ExternalInput {
+            name: name.to_string(),
+            value: Some("a".to_string()),
+            max_length: 31,
+            encoding: ExternalInputEncoding::Utf8,
+        };
+        let generate = || {
+            build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+                &parsed_email,
+                vec![regex("zeta", "[0-9]+"), regex("alpha", "ETH")],
+                vec![external_input("zed"), external_input("abc")],
+                CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                    max_body_length: 1024,
+                    ..Default::default()
+                },
+            )?
+            .to_json_string()
+        };
+
+        let json = generate()?;
+        assert_eq!(json, generate()?);
+
+        // The fields come first, then the regexes and external inputs in the supplied order
+        let position = |key: &str| json.find(&format!("\"{}\":", key)).unwrap();
+        assert_eq!(position("emailHeader"), 1);
+        assert!(position("proverETHAddress") < position("zetaRegexIdx"));
+        assert!(position("zetaRegexIdx") < position("alphaRegexIdx"));
+        assert!(position("alphaRegexIdx") < position("zed"));
+        assert!(position("zed") < position("abc"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_regex_inputs_with_external_inputs_with_sha_precompute_selector(
+    ) -> Result<()> {
+        // Get the test file path relative to the project root
+        let test_file = fixture("x.eml");
+
+        let email = std::fs::read_to_string(test_file)?;
+
+        let mut decomposed_regexes = Vec::new();
+        let part_1 = RegexPartConfig {
+            is_public: false,
+            regex_def: "email was meant for @".to_string(),
+        };
+        let part_2 = RegexPartConfig {
+            is_public: true,
+            regex_def: "[a-zA-Z0-9_]+".to_string(),
+        };
+
+        decomposed_regexes.push(DecomposedRegex {
+            parts: vec![part_1, part_2],
+            name: "handle".to_string(),
+            max_length: 64,
+            location: RegexLocation::Body,
+            ..Default::default()
+        });
 
         let external_inputs = vec![ExternalInput {
             name: "address".to_string(),
             max_length: 64,
             value: Some("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e".to_string()),
+            encoding: ExternalInputEncoding::Utf8,
         }];
 
         let input = generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
@@ -831,11 +3184,10 @@ mod tests {
             external_inputs,
             CircuitInputWithDecomposedRegexesAndExternalInputsParams {
                 max_body_length: 3136,
-                max_header_length: 1024,
-                ignore_body_hash_check: false,
                 remove_soft_lines_breaks: true,
-                sha_precompute_selector: Some(">Not my account<".to_string()),
+                sha_precompute_selector: Some(">Not my account<".into()),
                 prover_eth_address: Some("0x9401296121FC9B78F84fc856B1F8dC88f4415B2e".to_string()),
+                ..Default::default()
             },
         )
         .await?;
@@ -857,4 +3209,1162 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_latin1_body() -> Result<()> {
+        let test_file = fixture("latin1.eml");
+        let email = std::fs::read(test_file)?;
+
+        // The key that signed the fixture, published as test._domainkey.tickets.example.org
+        let public_key = general_purpose::STANDARD.decode("MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnysjRxxWRiS0wSfQUB3sbOZwM8VANrs2XL/dexgYX3GVpCpzyce+vJsnLky2GhsLrBcxG8aM/r1OwWQHpGA8RJ+r4I/YHXIX4C0+jPfuC1Z5ZNJHYcNwZRRuN0yL2bht/DAIVJPUEuREiImgx/JvueJllNeZ6+eqEi9MT8iaMMQOsfEKbrrkO4cv9O79Mv3NDBIIqVUfV3AjEs7Q2trgQtBO008zCdcx0Zgz7ELj2yaVV7wSkTX8ADeRj5/vV9Hc86VA4yb1K05hHeFjFJIcZpIDmkLQuu2r0zpS69fjWmYez/6tqvApaEj3UbdYC2JOxS94UmzotObDcUXikyZzQwIDAQAB")?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        assert!(String::from_utf8(parsed_email.canonicalized_body_bytes.clone()).is_err());
+
+        let circuit_params = CircuitParams {
+            body: parsed_email.canonicalized_body_bytes.clone(),
+            header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+            body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
+            rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+            rsa_public_key: vec_u8_to_bigint(parsed_email.public_key.rsa_modulus()?.to_vec()),
+        };
+        let circuit_options = CircuitOptions {
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ignore_body_hash_check: Some(false),
+            ..Default::default()
+        };
+        let circuit_inputs =
+            generate_circuit_inputs(CircuitInputParams::new(circuit_params, circuit_options))?;
+
+        let body_padded = circuit_inputs.body_padded.unwrap();
+        assert_eq!(
+            &body_padded[..parsed_email.canonicalized_body_bytes.len()],
+            parsed_email.canonicalized_body_bytes.as_slice()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_simple_canonicalization() -> Result<()> {
+        let test_file = fixture("simple.eml");
+        let email = std::fs::read(test_file)?;
+
+        // The key that signed the fixture, published as test._domainkey.simple.example.net
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        assert_eq!(
+            parsed_email.canonicalization(),
+            (HeaderCanon::Simple, BodyCanon::Simple)
+        );
+
+        let body_hash_idx = parsed_email.get_body_hash_idxes()?.0;
+        let circuit_params = CircuitParams {
+            body: parsed_email.canonicalized_body_bytes.clone(),
+            header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+            body_hash_idx,
+            rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+            rsa_public_key: vec_u8_to_bigint(parsed_email.public_key.rsa_modulus()?.to_vec()),
+        };
+        let circuit_options = CircuitOptions {
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ignore_body_hash_check: Some(false),
+            ..Default::default()
+        };
+        let circuit_inputs =
+            generate_circuit_inputs(CircuitInputParams::new(circuit_params, circuit_options))?;
+
+        // The body hash index must point at the base64 body hash in the padded header
+        let header_padded = circuit_inputs.header_padded;
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(
+            &parsed_email.canonicalized_body_bytes,
+        ));
+        assert_eq!(
+            &header_padded[body_hash_idx..body_hash_idx + body_hash.len()],
+            body_hash.as_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_signed_body_length() -> Result<()> {
+        let test_file = fixture("body_length.eml");
+        let email = std::fs::read(test_file)?;
+
+        // The key that signed the fixture, published as test._domainkey.lists.example.com
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        let signed_body = parsed_email.signed_body()?.to_vec();
+        assert!(signed_body.len() < parsed_email.canonicalized_body_bytes.len());
+
+        let circuit_params = CircuitParams {
+            body: signed_body.clone(),
+            header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+            body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
+            rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+            rsa_public_key: vec_u8_to_bigint(parsed_email.public_key.rsa_modulus()?.to_vec()),
+        };
+        let circuit_options = CircuitOptions {
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ignore_body_hash_check: Some(false),
+            ..Default::default()
+        };
+        let circuit_inputs =
+            generate_circuit_inputs(CircuitInputParams::new(circuit_params, circuit_options))?;
+
+        // The padded body ends with the SHA-256 padding right after the signed bytes
+        let body_padded = circuit_inputs.body_padded.unwrap();
+        assert_eq!(&body_padded[..signed_body.len()], signed_body.as_slice());
+        assert_eq!(body_padded[signed_body.len()], 0x80);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_default_options() -> Result<()> {
+        let body = b"Hello world\r\n".to_vec();
+        let circuit_params = CircuitParams {
+            body: body.clone(),
+            header: b"subject:Hello\r\n".to_vec(),
+            body_hash_idx: 3,
+            rsa_signature: BigInt::from(2),
+            rsa_public_key: BigInt::from(3),
+        };
+        let circuit_options = CircuitOptions::default();
+        let circuit_input =
+            generate_circuit_inputs(CircuitInputParams::new(circuit_params, circuit_options))?;
+
+        // The header and body are padded to the default maximum lengths
+        assert_eq!(circuit_input.header_padded.len(), MAX_HEADER_PADDED_BYTES);
+        assert_eq!(circuit_input.header_len_padded_bytes, 64);
+        let body_padded = circuit_input.body_padded.clone().unwrap();
+        assert_eq!(body_padded.len(), MAX_BODY_PADDED_BYTES);
+        assert_eq!(&body_padded[..body.len()], body.as_slice());
+        assert_eq!(circuit_input.body_len_padded_bytes, Some(64));
+        assert_eq!(circuit_input.body_hash_idx, Some(3));
+        assert_eq!(
+            circuit_input.pubkey,
+            to_circom_bigint_bytes(BigInt::from(3))
+        );
+
+        let json = serde_json::to_value(&circuit_input)?;
+        assert!(json.get("headerPadded").is_some());
+        assert_eq!(serde_json::from_value::<CircuitInput>(json)?, circuit_input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_selector_on_cleaned_body() -> Result<()> {
+        let test_file = fixture("soft_break_selector.eml");
+        let email = std::fs::read(test_file)?;
+
+        // The key that signed the fixture, published as test._domainkey.lists.example.com
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        let body = parsed_email.canonicalized_body_bytes.clone();
+
+        // The selector only occurs in the body once the soft line break is removed
+        let selector_start = body
+            .windows(b">Not my=\r\n account<".len())
+            .position(|window| window == b">Not my=\r\n account<")
+            .unwrap();
+        assert!(selector_start >= 64);
+
+        let circuit_params = CircuitParams {
+            body: body.clone(),
+            header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+            body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
+            rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+            rsa_public_key: vec_u8_to_bigint(parsed_email.public_key.rsa_modulus()?.to_vec()),
+        };
+        let circuit_options = CircuitOptions {
+            sha_precompute_selector: Some(">Not my account<".into()),
+            precompute_selector_on_cleaned_body: Some(true),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ignore_body_hash_check: Some(false),
+            ..Default::default()
+        };
+        let circuit_inputs =
+            generate_circuit_inputs(CircuitInputParams::new(circuit_params, circuit_options))?;
+
+        // The cutoff is the start of the 64-byte block holding the mapped selector position
+        let cutoff = (selector_start / 64) * 64;
+        let body_padded = circuit_inputs.body_padded.unwrap();
+        assert_eq!(&body_padded[..body.len() - cutoff], &body[cutoff..]);
+        assert_eq!(
+            circuit_inputs.precomputed_sha,
+            Some(partial_sha(&body[..cutoff], cutoff))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_decomposed_sha_precompute_selector() -> Result<()> {
+        let test_file = fixture("x.eml");
+        let email = std::fs::read_to_string(test_file)?;
+        // The cutoff only depends on the body, so the signature is not verified
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_with_public_key_unverified(&email, &public_key)?;
+
+        let generate = |selector: ShaPrecomputeSelector, cleaned: bool| {
+            generate_circuit_inputs(CircuitInputParams::new(
+                CircuitParams {
+                    body: parsed_email.canonicalized_body_bytes.clone(),
+                    header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+                    body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
+                    rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+                    rsa_public_key: vec_u8_to_bigint(
+                        parsed_email.public_key.rsa_modulus()?.to_vec(),
+                    ),
+                },
+                CircuitOptions {
+                    sha_precompute_selector: Some(selector),
+                    precompute_selector_on_cleaned_body: Some(cleaned),
+                    max_header_length: Some(1024),
+                    max_body_length: Some(14336),
+                    ignore_body_hash_check: Some(false),
+                    ..Default::default()
+                },
+            ))
+        };
+
+        for (selector, cleaned) in [("ignore this email", false), (">Not my account<", true)] {
+            // A plain string keeps deserializing as a regex, and a config as a decomposed regex
+            let regex: ShaPrecomputeSelector = serde_json::from_value(serde_json::json!(selector))?;
+            assert!(matches!(regex, ShaPrecomputeSelector::Regex(_)));
+            let decomposed: ShaPrecomputeSelector = serde_json::from_value(serde_json::json!({
+                "parts": [{ "is_public": true, "regex_def": selector }]
+            }))?;
+            assert!(matches!(decomposed, ShaPrecomputeSelector::Decomposed(_)));
+            assert_eq!(decomposed.to_string(), regex.to_string());
+
+            let regex_inputs = generate(regex, cleaned)?;
+            let decomposed_inputs = generate(decomposed, cleaned)?;
+            assert!(
+                regex_inputs.body_len_padded_bytes
+                    < Some(sha_padded_length(
+                        parsed_email.canonicalized_body_bytes.len()
+                    ))
+            );
+            assert_eq!(decomposed_inputs, regex_inputs);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_matches_reference_padding() -> Result<()> {
+        // The key that signed both fixtures
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+
+        // The inputs generated for each fixture and selector, checked in as reference
+        let references: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(fixture(
+            "circuit_inputs_reference.json",
+        ))?)?;
+        assert_eq!(references.len(), 3);
+        for reference in references {
+            let name = reference["fixture"].as_str().unwrap();
+            let selector = reference["selector"].as_str();
+            let expected: CircuitInput = serde_json::from_value(reference["input"].clone())?;
+
+            let email = std::fs::read(fixture(name))?;
+            let parsed_email =
+                ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+            let circuit_inputs = generate_circuit_inputs(CircuitInputParams::new(
+                CircuitParams {
+                    body: parsed_email.canonicalized_body_bytes.clone(),
+                    header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+                    body_hash_idx: parsed_email.get_body_hash_idxes()?.0,
+                    rsa_signature: vec_u8_to_bigint(parsed_email.signature.clone()),
+                    rsa_public_key: vec_u8_to_bigint(
+                        parsed_email.public_key.rsa_modulus()?.to_vec(),
+                    ),
+                },
+                CircuitOptions {
+                    sha_precompute_selector: selector.map(ShaPrecomputeSelector::from),
+                    max_header_length: Some(1024),
+                    max_body_length: Some(1024),
+                    ignore_body_hash_check: Some(false),
+                    ..Default::default()
+                },
+            ))?;
+            assert_eq!(circuit_inputs, expected, "{} ({:?})", name, selector);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_header_sha_precompute_selector() -> Result<()> {
+        let header = format!(
+            "arc-seal:i=1; {}\r\nfrom:alice@example.com\r\ndkim-signature:v=1; a=rsa-sha256; bh=abc; b=",
+            "a".repeat(100)
+        )
+        .into_bytes();
+        let selector_start = header
+            .windows(b"dkim-signature:".len())
+            .position(|window| window == b"dkim-signature:")
+            .unwrap();
+        let body_hash_idx = header.len() - "abc; b=".len();
+        let circuit_params = |selector: &str, max_header_length| {
+            CircuitInputParams::new(
+                CircuitParams {
+                    body: b"Hello\r\n".to_vec(),
+                    header: header.clone(),
+                    body_hash_idx,
+                    rsa_signature: BigInt::from(1),
+                    rsa_public_key: BigInt::from(1),
+                },
+                CircuitOptions {
+                    header_sha_precompute_selector: Some(selector.to_string()),
+                    max_header_length: Some(max_header_length),
+                    max_body_length: Some(64),
+                    ignore_body_hash_check: Some(false),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // The header is precomputed up to the block holding the dkim-signature line
+        let circuit_input = generate_circuit_inputs(circuit_params("dkim-signature:", 128))?;
+        let cutoff = (selector_start / 64) * 64;
+        assert_eq!(cutoff, 128);
+        let (header_padded, header_padded_len) = sha256_pad(header.clone(), 256)?;
+        assert_eq!(
+            circuit_input.precomputed_header_sha,
+            Some(partial_sha(&header[..cutoff], cutoff))
+        );
+        assert_eq!(circuit_input.header_padded, header_padded[cutoff..]);
+        assert_eq!(
+            circuit_input.header_len_padded_bytes,
+            header_padded_len - cutoff
+        );
+        assert_eq!(circuit_input.body_hash_idx, Some(body_hash_idx - cutoff));
+
+        // Without a header selector the whole header is kept
+        let circuit_input = generate_circuit_inputs(CircuitInputParams {
+            header_sha_precompute_selector: None,
+            max_header_length: 256,
+            ..circuit_params("", 256)
+        })?;
+        assert!(circuit_input.precomputed_header_sha.is_none());
+        assert_eq!(circuit_input.header_padded, header_padded);
+
+        let err =
+            generate_circuit_inputs(circuit_params("arc-message-signature:", 128)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Selector arc-message-signature: not found in the header"));
+        let err = generate_circuit_inputs(circuit_params("from:", 64)).unwrap_err();
+        assert!(err.to_string().contains("longer than max"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recommended_lengths() -> Result<()> {
+        let body = format!("{}\r\nFooter\r\n", "x".repeat(128));
+        let parsed_email = parsed_email_with_body(&body);
+
+        let lengths = recommended_lengths(&parsed_email, None)?;
+        assert_eq!(
+            lengths.header_length,
+            parsed_email.canonicalized_header.len()
+        );
+        assert_eq!(lengths.body_length, body.len());
+        assert_eq!(lengths.max_header_length % 64, 0);
+        assert!(lengths.max_header_length >= lengths.header_length + 9);
+        assert!(lengths.max_header_length < lengths.header_length + 9 + 64);
+        assert_eq!(lengths.max_body_length, 256);
+
+        // The selector cuts off the first two blocks of the body
+        let with_selector = recommended_lengths(&parsed_email, Some("Footer"))?;
+        assert_eq!(with_selector.max_body_length, 128);
+
+        // The recommended lengths produce inputs of exactly those lengths
+        let circuit_input = generate_circuit_inputs(CircuitInputParams::new(
+            CircuitParams {
+                body: body.into_bytes(),
+                header: parsed_email.canonicalized_header.as_bytes().to_vec(),
+                body_hash_idx: 0,
+                rsa_signature: BigInt::from(1),
+                rsa_public_key: BigInt::from(1),
+            },
+            CircuitOptions {
+                sha_precompute_selector: Some("Footer".into()),
+                max_header_length: Some(with_selector.max_header_length),
+                max_body_length: Some(with_selector.max_body_length),
+                ignore_body_hash_check: Some(false),
+                ..Default::default()
+            },
+        ))?;
+        assert_eq!(
+            circuit_input.header_padded.len(),
+            with_selector.max_header_length
+        );
+        assert_eq!(
+            circuit_input.body_padded.unwrap().len(),
+            with_selector.max_body_length
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_invalid_sha_precompute_selector() {
+        let circuit_params = |selector: &str, max_body_length| {
+            CircuitInputParams::new(
+                CircuitParams {
+                    body: format!("Hello world\r\n{}\r\n", "x".repeat(64)).into_bytes(),
+                    header: b"subject:Hello\r\n".to_vec(),
+                    body_hash_idx: 0,
+                    rsa_signature: BigInt::from(1),
+                    rsa_public_key: BigInt::from(1),
+                },
+                CircuitOptions {
+                    sha_precompute_selector: Some(selector.into()),
+                    max_header_length: Some(64),
+                    max_body_length: Some(max_body_length),
+                    ignore_body_hash_check: Some(false),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // The selector does not occur in the body
+        let err = generate_circuit_inputs(circuit_params("Goodbye", 128)).unwrap_err();
+        assert!(err.to_string().contains("selector: \"Goodbye\""));
+        assert!(err
+            .to_string()
+            .contains("body length: 79, max body length: 128"));
+
+        // The body remaining after the selector does not fit in the max body length
+        let err = generate_circuit_inputs(circuit_params("world", 64)).unwrap_err();
+        assert!(err.to_string().contains("max body length: 64"));
+        assert!(err.to_string().contains("longer than max"));
+        assert!(err
+            .to_string()
+            .contains("max_body_length must be at least 128 instead of 64"));
+        assert!(matches!(
+            err.downcast_ref::<PartialShaError>(),
+            Some(PartialShaError::RemainingTooLong {
+                actual: 128,
+                max: 64
+            })
+        ));
+    }
+
+    #[test]
+    fn test_generate_claim_circuit_input() -> Result<()> {
+        let account_code = field_to_hex(&AccountCode::new(rand::thread_rng()).0);
+        let input = generate_claim_circuit_input("alice@example.com", "0x0A", &account_code)?;
+        assert_eq!(&input.email_addr[..17], b"alice@example.com");
+        assert_eq!(input.email_addr.len(), MAX_EMAIL_ADDR_BYTES);
+        assert_eq!(input.cm_rand, format!("0x{:0>64}", "0a"));
+        assert_eq!(input.account_code, account_code);
+        assert_eq!(
+            generate_claim_circuit_input(
+                "alice@example.com",
+                "0x0A",
+                &account_code.to_uppercase().replacen("0X", "0x", 1)
+            )?,
+            input
+        );
+
+        for (email_address, rand) in [
+            ("alice", "0x01"),
+            ("alice@example.com bob@example.com", "0x01"),
+            ("alice@example.com", "01"),
+            ("alice@example.com", "0x"),
+            ("alice@example.com", "0xzz"),
+            // The field modulus itself is not a field element
+            (
+                "alice@example.com",
+                "0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001",
+            ),
+        ]
+        .iter()
+        {
+            assert!(generate_claim_circuit_input(email_address, rand, &account_code).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_circuit_inputs_with_invalid_max_lengths() {
+        let circuit_params = |max_header_length, max_body_length| {
+            CircuitInputParams::new(
+                CircuitParams {
+                    body: vec![b'x'; 100],
+                    header: vec![b'h'; 100],
+                    body_hash_idx: 0,
+                    rsa_signature: BigInt::from(1),
+                    rsa_public_key: BigInt::from(1),
+                },
+                CircuitOptions {
+                    max_header_length: Some(max_header_length),
+                    max_body_length: Some(max_body_length),
+                    ignore_body_hash_check: Some(false),
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(generate_circuit_inputs(circuit_params(128, 128)).is_ok());
+        let err = generate_circuit_inputs(circuit_params(1000, 128)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid max_header_length 1000: it must be a positive multiple of 64 and at least 128 for this email"
+        );
+        let err = generate_circuit_inputs(circuit_params(128, 64)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid max_body_length 64: it must be a positive multiple of 64 and at least 128 for this email"
+        );
+        assert!(generate_circuit_inputs(circuit_params(0, 128)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_generate_email_circuit_inputs_batch_isolates_failures() -> Result<()> {
+        let email = std::fs::read_to_string(fixture("x.eml"))?;
+        let oversized_email = "a".repeat(DEFAULT_MAX_RAW_EMAIL_BYTES + 1);
+        let emails = vec![
+            (
+                "From: alice@example.com\r\nSubject: Hello\r\n\r\nHi\r\n",
+                AccountCode::new(rand::thread_rng()),
+            ),
+            (email.as_str(), AccountCode::new(rand::thread_rng())),
+            (
+                oversized_email.as_str(),
+                AccountCode::new(rand::thread_rng()),
+            ),
+        ];
+
+        let results = generate_email_circuit_inputs_batch_with_concurrency(
+            &emails,
+            Some(EmailCircuitParams {
+                ignore_body_hash_check: Some(true),
+                ..Default::default()
+            }),
+            0,
+        )
+        .await;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err());
+        // The failures around it do not affect the email that verifies
+        let input: Value = serde_json::from_str(results[1].as_ref().unwrap())?;
+        assert_eq!(input["account_code"], field_to_hex(&emails[1].1 .0));
+        assert!(results[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("exceeds the maximum"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_inputs_agree_between_generators() -> Result<()> {
+        // The soft line break comes before the command, so that it is found in either body
+        let parsed_email = parsed_email_with_body(
+            "<div id=3D\"zk=\r\nemail\">Send 1 ETH to bob@example.com</div>\r\n",
+        );
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        for remove_soft_line_breaks in [true, false] {
+            let email_input = build_email_circuit_input(
+                &parsed_email,
+                &account_code,
+                Some(EmailCircuitParams {
+                    ignore_body_hash_check: Some(false),
+                    max_header_length: Some(1024),
+                    max_body_length: Some(1024),
+                    strict_indices: Some(true),
+                    remove_soft_line_breaks: Some(remove_soft_line_breaks),
+                    ..Default::default()
+                }),
+            )?;
+            let decomposed_input =
+                build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+                    &parsed_email,
+                    vec![DecomposedRegex {
+                        parts: vec![RegexPartConfig {
+                            is_public: true,
+                            regex_def: "Send 1 ETH".to_string(),
+                        }],
+                        name: "command".to_string(),
+                        max_length: 64,
+                        location: RegexLocation::Body,
+                        ..Default::default()
+                    }],
+                    vec![],
+                    CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                        max_body_length: 1024,
+                        remove_soft_lines_breaks: remove_soft_line_breaks,
+                        ..Default::default()
+                    },
+                )?;
+
+            assert_eq!(email_input.padded_body, decomposed_input.email_body);
+            assert_eq!(
+                email_input.padded_body_len,
+                decomposed_input.email_body_length
+            );
+            assert_eq!(
+                email_input.precomputed_sha,
+                decomposed_input.precomputed_sha
+            );
+            assert_eq!(
+                email_input.padded_cleaned_body,
+                decomposed_input.decoded_email_body_in
+            );
+            assert_eq!(
+                email_input.padded_cleaned_body.is_some(),
+                remove_soft_line_breaks
+            );
+            // The command index is relative to the same body in both
+            assert_eq!(
+                email_input.command_idx,
+                decomposed_input.regex_idxes["commandRegexIdx"]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_indices_and_padding() -> Result<()> {
+        let body = "<div id=3D\"zkemail\">Send 1 ETH</div>\r\n";
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(body.as_bytes()));
+        let header = format!(
+            "from:alice@example.com\r\nsubject:Hello\r\ndkim-signature:v=1; a=rsa-sha256; d=example.com; s=s1; t=1717408860; bh={}; b=",
+            body_hash
+        );
+        let parsed_email = ParsedEmail::from_parts(
+            header.clone(),
+            body.to_string(),
+            vec![1; 256],
+            vec![0xff; 256],
+        );
+        let params = EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ..Default::default()
+        };
+        let input = build_email_circuit_input(
+            &parsed_email,
+            &AccountCode::new(rand::thread_rng()),
+            Some(params),
+        )?;
+
+        // The header and body are SHA-256 padded and then zero-padded to the maximum lengths
+        assert_eq!(input.padded_header.len(), 1024);
+        assert_eq!(input.padded_header_len, sha_padded_length(header.len()));
+        assert_eq!(&input.padded_header[..header.len()], header.as_bytes());
+        assert_eq!(input.padded_header[header.len()], 0x80);
+        let padded_body = input.padded_body.as_ref().unwrap();
+        assert_eq!(padded_body.len(), 1024);
+        assert_eq!(input.padded_body_len, Some(sha_padded_length(body.len())));
+        assert_eq!(padded_body[body.len()], 0x80);
+        assert!(input.precomputed_sha.is_none());
+
+        // The header indices point at the values in the padded header
+        let at = |idx: usize| &input.padded_header[idx..];
+        assert!(at(input.from_addr_idx).starts_with(b"alice@example.com"));
+        // The domain index is relative to the sender address
+        assert!(at(input.from_addr_idx + input.domain_idx).starts_with(b"example.com"));
+        assert!(at(input.timestamp_idx).starts_with(b"1717408860"));
+        assert_eq!(input.timestamp, Some(1717408860));
+        assert!(at(input.body_hash_idx.unwrap()).starts_with(body_hash.as_bytes()));
+
+        // The command index points into the body without soft line breaks
+        let cleaned_body = input.padded_cleaned_body.as_ref().unwrap();
+        assert!(cleaned_body[input.command_idx..].starts_with(b"Send 1 ETH"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_recipient() -> Result<()> {
+        let params = EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            recipient_enabled: Some(true),
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        let parsed_email =
+            parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH to bob@example.com</div>\r\n");
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params.clone()))?;
+        assert_eq!(input.recipient_email_idx, Some("Send 1 ETH to ".len()));
+        let commitment = PaddedEmailAddr::try_from_email_addr("bob@example.com")?
+            .to_commitment_with_signature(&parsed_email.signature)
+            .unwrap();
+        assert_eq!(input.recipient_commitment, Some(field_to_hex(&commitment)));
+
+        // Commands without an address get zero values
+        let parsed_email =
+            parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH to 0x00</div>\r\n");
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params))?;
+        assert_eq!(input.recipient_email_idx, Some(0));
+        assert_eq!(input.recipient_commitment, Some(field_to_hex(&Fr::zero())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_body_hash_debug() -> Result<()> {
+        let params = |include_body_hash_debug| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            include_body_hash_debug,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+        let body = "<div id=3D\"zkemail\">Send 1 ETH</div>\r\n";
+        let body_hash = format!(
+            "0x{}",
+            hex::encode(hmac_sha256::Hash::hash(body.as_bytes()))
+        );
+
+        let mut parsed_email = parsed_email_with_body(body);
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params(None)))?;
+        assert!(input.email_body_hash.is_none());
+        assert!(serde_json::to_value(&input)?
+            .get("email_body_hash")
+            .is_none());
+
+        let input =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(true))))?;
+        assert_eq!(
+            input.email_body_hash,
+            Some(BodyHashDebug {
+                computed: body_hash.clone(),
+                signed: body_hash.clone(),
+            })
+        );
+
+        // A body altered after signing no longer has the signed hash
+        parsed_email.canonicalized_body_bytes =
+            b"<div id=3D\"zkemail\">Send 9 ETH</div>\r\n".to_vec();
+        let input =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(true))))?;
+        let email_body_hash = input.email_body_hash.unwrap();
+        assert_ne!(email_body_hash.computed, body_hash);
+        assert_eq!(email_body_hash.signed, body_hash);
+
+        // The decomposed regex inputs emit the same hashes
+        let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+            &parsed_email_with_body(body),
+            vec![],
+            vec![],
+            CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                max_body_length: 1024,
+                include_body_hash_debug: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            input.to_json()?["emailBodyHash"],
+            serde_json::json!({ "computed": body_hash, "signed": body_hash })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_header_sha_precompute_selector() -> Result<()> {
+        let body = "<div id=3D\"zkemail\">Send 1 ETH</div>\r\n";
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(body.as_bytes()));
+        let parsed_email = ParsedEmail::from_parts(
+            format!(
+                "arc-seal:i=1; {}\r\nfrom:alice@example.com\r\nsubject:Hello\r\ndkim-signature:v=1; a=rsa-sha256; d=example.com; s=s1; bh={}; b=",
+                "a".repeat(100),
+                body_hash
+            ),
+            body.to_string(),
+            vec![1; 256],
+            vec![0xff; 256],
+        );
+        let params = |header_sha_precompute_selector: &str| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            header_sha_precompute_selector: Some(header_sha_precompute_selector.to_string()),
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        // The header indices are relative to the part of the header after the cutoff
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params("from:")))?;
+        assert!(input.precomputed_header_sha.is_some());
+        assert!(input.padded_header[input.from_addr_idx..].starts_with(b"alice@example.com"));
+        assert!(
+            input.padded_header[input.from_addr_idx + input.domain_idx..]
+                .starts_with(b"example.com")
+        );
+        assert!(
+            input.padded_header[input.body_hash_idx.unwrap()..].starts_with(body_hash.as_bytes())
+        );
+
+        // The sender address cannot be indexed once it is precomputed
+        let err = build_email_circuit_input(
+            &parsed_email,
+            &account_code,
+            Some(params("dkim-signature:")),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("The sender address at index"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_poseidon_header_fields() -> Result<()> {
+        let params = |emit_poseidon_header_fields| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            emit_poseidon_header_fields,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+        let parsed_email = parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH</div>\r\n");
+
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params(None)))?;
+        assert!(input.padded_header_fields.is_none());
+        assert!(input.padded_header_hash.is_none());
+
+        let input =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(true))))?;
+        let fields = input.padded_header_fields.unwrap();
+        // A full 1024-byte header packs into 34 fields, more than one Poseidon hash takes
+        assert_eq!(fields.len(), compute_signal_length(1024));
+        assert_eq!(fields.len(), 34);
+        let packed = bytes_to_fields(&input.padded_header);
+        assert_eq!(
+            fields,
+            packed
+                .iter()
+                .map(
+                    |field| BigInt::from_bytes_be(Sign::Plus, &fr_to_bytes32(field).unwrap())
+                        .to_string()
+                )
+                .collect::<Vec<_>>()
+        );
+
+        // The fields are hashed in chunks of 16 and the chunk hashes chained, as in the circuit
+        let chunk_hashes = packed
+            .chunks(16)
+            .map(|chunk| poseidon_fields(chunk).unwrap())
+            .collect::<Vec<_>>();
+        let expected = poseidon_fields(&[
+            poseidon_fields(&[chunk_hashes[0], chunk_hashes[1]]).unwrap(),
+            chunk_hashes[2],
+        ])
+        .unwrap();
+        assert_eq!(input.padded_header_hash, Some(field_to_hex(&expected)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_schema_version() -> Result<()> {
+        let parsed_email = parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH</div>\r\n");
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        for include in [false, true] {
+            let input = build_email_circuit_input(
+                &parsed_email,
+                &account_code,
+                Some(EmailCircuitParams {
+                    ignore_body_hash_check: Some(false),
+                    max_header_length: Some(1024),
+                    max_body_length: Some(1024),
+                    include_input_schema_version: Some(include),
+                    ..Default::default()
+                }),
+            )?;
+            let json = serde_json::to_value(&input)?;
+            assert_eq!(
+                json.get("input_schema_version"),
+                Some(&Value::from(CIRCUIT_INPUT_SCHEMA_VERSION)).filter(|_| include)
+            );
+
+            let input = build_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+                &parsed_email,
+                vec![],
+                vec![],
+                CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                    max_body_length: 1024,
+                    remove_soft_lines_breaks: true,
+                    include_input_schema_version: include,
+                    ..Default::default()
+                },
+            )?;
+            let json: Value = serde_json::from_str(&input.to_json_string()?)?;
+            assert_eq!(
+                json.get("inputSchemaVersion"),
+                Some(&Value::from(DECOMPOSED_CIRCUIT_INPUT_SCHEMA_VERSION)).filter(|_| include)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_email_circuit_input() -> Result<()> {
+        let params = EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+        let parsed_email = parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH</div>\r\n");
+        let json = generate_email_circuit_input_from_parsed(
+            &parsed_email,
+            &account_code,
+            Some(params.clone()),
+        )?;
+        assert_eq!(verify_email_circuit_input(&json, &params)?, vec![]);
+
+        // Every malformed input is reported by its key
+        let mut input: Value = serde_json::from_str(&json)?;
+        input["from_addr_idx"] = Value::from(5000);
+        input["public_key"].as_array_mut().unwrap().pop();
+        input["signature"][0] = Value::from("-1");
+        input["padded_body_len"] = Value::from(65);
+        input["account_code"] = Value::from(input["account_code"].as_str().unwrap().to_uppercase());
+        let warnings = verify_email_circuit_input(&input.to_string(), &params)?;
+        let fields = warnings
+            .iter()
+            .map(|warning| warning.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                "padded_body_len",
+                "from_addr_idx",
+                "public_key",
+                "signature",
+                "account_code"
+            ]
+        );
+
+        // Inputs generated for larger maxima do not fit
+        let small_params = EmailCircuitParams {
+            max_header_length: Some(512),
+            ..params.clone()
+        };
+        let warnings = verify_email_circuit_input(&json, &small_params)?;
+        assert_eq!(warnings[0].field, "padded_header");
+
+        assert!(verify_email_circuit_input("{}", &params).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_strict_indices() {
+        let params = |strict_indices| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            strict_indices,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+        // The email has neither an invitation code nor a timestamp
+        let parsed_email = parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH</div>\r\n");
+
+        let input =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(None))).unwrap();
+        assert_eq!(input.code_idx, 0);
+        assert_eq!(input.timestamp_idx, 0);
+        assert_eq!(input.timestamp, None);
+
+        let err = build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(true))))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.starts_with("The invitation code index could not be found"));
+        assert!(err.contains("\"<div id=3D\\\"zkemail\\\">Send 1 ETH</div>"));
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_command_before_precompute_cutoff() {
+        let body = format!(
+            "Code 0123abcd\r\n<div id=3D\"zkemail\">Send 1 ETH</div>\r\n{}\r\nFooter\r\n",
+            "x".repeat(128)
+        );
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(body.as_bytes()));
+        let parsed_email = ParsedEmail::from_parts(
+            format!(
+                "from:alice@example.com\r\nsubject:Hello\r\ndkim-signature:v=1; a=rsa-sha256; d=example.com; s=s1; t=1717408860; bh={}; b=",
+                body_hash
+            ),
+            body,
+            vec![1; 256],
+            vec![0xff; 256],
+        );
+        let params = |strict_indices| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            sha_precompute_selector: Some("Footer".into()),
+            strict_indices,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        // The code and command are in the precomputed part, so the remaining body lacks them
+        let input =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(None))).unwrap();
+        assert_eq!(input.code_idx, 0);
+        assert_eq!(input.command_idx, 0);
+
+        let err = build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(true))))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.starts_with("The invitation code index could not be found"));
+        assert!(err.contains("Pattern \"0123abcd\" not found in the 1024 byte body"));
+
+        // The timestamp comes from the span its index points at
+        assert_eq!(input.timestamp, Some(1717408860));
+        assert!(parsed_email.canonicalized_header[input.timestamp_idx..].starts_with("1717408860"));
+        let json = serde_json::to_value(&input).unwrap();
+        assert_eq!(json["timestamp"], Value::from("1717408860"));
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_without_removing_soft_line_breaks() -> Result<()> {
+        let params = |remove_soft_line_breaks| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            remove_soft_line_breaks,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+        // The soft line break shifts the command by three bytes in the raw body
+        let parsed_email = parsed_email_with_body(
+            "Hello=\r\n world\r\n<div id=3D\"zkemail\">Send 1 ETH</div>\r\n",
+        );
+
+        let cleaned = build_email_circuit_input(&parsed_email, &account_code, Some(params(None)))?;
+        let cleaned_body = cleaned.padded_cleaned_body.unwrap();
+        assert!(cleaned_body[cleaned.command_idx..].starts_with(b"Send 1 ETH"));
+
+        let raw =
+            build_email_circuit_input(&parsed_email, &account_code, Some(params(Some(false))))?;
+        assert!(raw.padded_cleaned_body.is_none());
+        assert_eq!(raw.command_idx, cleaned.command_idx + 3);
+        assert!(raw.padded_body.as_ref().unwrap()[raw.command_idx..].starts_with(b"Send 1 ETH"));
+        assert!(serde_json::to_value(&raw)?
+            .get("padded_cleaned_body")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_with_command_location() -> Result<()> {
+        let body = "Hi!\r\n";
+        let body_hash = general_purpose::STANDARD.encode(hmac_sha256::Hash::hash(body.as_bytes()));
+        let parsed_email = ParsedEmail::from_parts(
+            format!(
+                "from:alice@example.com\r\nsubject:Re: Send 1 ETH\r\ndkim-signature:v=1; a=rsa-sha256; d=example.com; s=s1; bh={}; b=",
+                body_hash
+            ),
+            body.to_string(),
+            vec![1; 256],
+            vec![0xff; 256],
+        );
+        let params = |command_location| EmailCircuitParams {
+            ignore_body_hash_check: Some(false),
+            max_header_length: Some(1024),
+            max_body_length: Some(1024),
+            command_location,
+            ..Default::default()
+        };
+        let account_code = AccountCode::new(rand::thread_rng());
+
+        // The body has no command, so `Auto` falls back to the subject
+        for command_location in [CommandLocation::Subject, CommandLocation::Auto].iter() {
+            let input = build_email_circuit_input(
+                &parsed_email,
+                &account_code,
+                Some(params(Some(*command_location))),
+            )?;
+            assert!(input.padded_header[input.command_idx..].starts_with(b"Send 1 ETH\r\n"));
+            assert_eq!(input.command_location, Some(CommandLocation::Subject));
+        }
+
+        let input = build_email_circuit_input(
+            &parsed_email,
+            &account_code,
+            Some(params(Some(CommandLocation::Body))),
+        )?;
+        assert_eq!(input.command_idx, 0);
+        assert_eq!(input.command_location, Some(CommandLocation::Body));
+
+        // Without a requested location, none is emitted
+        let input = build_email_circuit_input(&parsed_email, &account_code, Some(params(None)))?;
+        assert!(serde_json::to_value(&input)?
+            .get("command_location")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_email_circuit_input_from_parsed() -> Result<()> {
+        let parsed_email = parsed_email_with_body("<div id=3D\"zkemail\">Send 1 ETH</div>\r\n");
+        let account_code = AccountCode::new(rand::thread_rng());
+        let json = generate_email_circuit_input_from_parsed(&parsed_email, &account_code, None)?;
+        assert_eq!(
+            json,
+            serde_json::to_string(&build_email_circuit_input(
+                &parsed_email,
+                &account_code,
+                None
+            )?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_circuit_input_for_header_only_email() -> Result<()> {
+        let test_file = fixture("header_only.eml");
+        let email = std::fs::read(test_file)?;
+
+        // The key that signed the fixture, published as test._domainkey.lists.example.com
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        assert!(parsed_email.canonicalized_body_bytes.is_empty());
+
+        let params = EmailCircuitParams {
+            ignore_body_hash_check: Some(true),
+            max_header_length: Some(1024),
+            command_location: Some(CommandLocation::Auto),
+            ..Default::default()
+        };
+        let input = build_email_circuit_input(
+            &parsed_email,
+            &AccountCode::new(rand::thread_rng()),
+            Some(params),
+        )?;
+        assert!(input.padded_body.is_none());
+        assert!(input.precomputed_sha.is_none());
+        assert!(input.padded_cleaned_body.is_none());
+
+        // The command is taken from the subject
+        let subject_idx = input.subject_idx.unwrap();
+        assert!(parsed_email.canonicalized_header[subject_idx..].starts_with("Send 1 ETH"));
+        assert_eq!(input.command_location, Some(CommandLocation::Subject));
+        assert_eq!(
+            input.command_idx,
+            parsed_email.get_subject_command_idxes()?.0
+        );
+        assert!(parsed_email.canonicalized_header[input.command_idx..].starts_with("Send 1 ETH"));
+        Ok(())
+    }
 }
@@ -102,6 +102,25 @@ pub fn extract_template_vals_from_command(
         input = &input[text_html_idx..];
     }
 
+    extract_template_vals_from_text(input, templates)
+}
+
+/// Extracts template values from plain text, such as a subject line.
+///
+/// Unlike `extract_template_vals_from_command`, the input is not searched for a text/html part.
+///
+/// # Arguments
+///
+/// * `input` - The text to extract values from.
+/// * `templates` - A vector of template strings.
+///
+/// # Returns
+///
+/// A `Result` containing a vector of `TemplateValue`s or an error.
+pub(crate) fn extract_template_vals_from_text(
+    input: &str,
+    templates: Vec<String>,
+) -> Result<Vec<TemplateValue>> {
     // Convert the template to a regex pattern, escaping necessary characters and replacing placeholders
     let pattern = templates
         .iter()
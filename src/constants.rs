@@ -3,6 +3,22 @@
 pub(crate) const MAX_HEADER_PADDED_BYTES: usize = 1024; // Maximum size of the header in bytes
 pub(crate) const MAX_BODY_PADDED_BYTES: usize = 1536; // Maximum size of the body in bytes
 pub(crate) const CIRCOM_BIGINT_N: usize = 121; // Bits per chunk
+pub(crate) const MAX_CIRCOM_BIGINT_N: usize = 126; // Most bits per chunk that still pack two chunks into a field element
 pub(crate) const CIRCOM_BIGINT_K: usize = 17; // Number of chunks
+pub(crate) const POSEIDON_MAX_INPUTS: usize = 16; // Most field elements a single Poseidon hash takes
+pub(crate) const EMAIL_NULLIFIER_V2_VERSION: u64 = 2; // Version tag hashed into `email_nullifier_v2`
+pub(crate) const MAX_CIRCOM_BIGINT_K: usize = 34; // Most chunks, enough for 4096-bit RSA values
 pub(crate) const MAX_EMAIL_ADDR_BYTES: usize = 256; // Maximum size of the email address in bytes
 pub(crate) const JSON_LOGGER_KEY: &str = "JSON_LOGGER"; // Key for the JSON_LOGGER env var
+pub(crate) const DKIM_ARCHIVE_API_URL: &str = "https://archive.zk.email/api/key"; // Endpoint of the DKIM key archive
+pub(crate) const DEFAULT_DNS_TIMEOUT_MS: u64 = 10_000; // Time limit for a single DKIM public key lookup
+pub(crate) const DKIM_API_URL_KEY: &str = "DKIM_API_URL"; // Key for the env var overriding the DKIM key archive endpoint
+pub(crate) const DKIM_FETCH_TIMEOUT_MS_KEY: &str = "DKIM_FETCH_TIMEOUT_MS"; // Key for the env var overriding the DKIM public key lookup time limit
+pub(crate) const DEFAULT_DKIM_CACHE_TTL_MS: u64 = 60 * 60 * 1000; // Time a fetched DKIM key record is reused
+pub(crate) const DEFAULT_DKIM_NEGATIVE_CACHE_TTL_MS: u64 = 5 * 60 * 1000; // Time a DKIM key lookup that found no key is remembered
+pub(crate) const DEFAULT_DKIM_CACHE_MAX_ENTRIES: usize = 10_000; // Maximum number of cached DKIM key lookups
+pub(crate) const DEFAULT_DKIM_FETCH_MAX_ATTEMPTS: u32 = 3; // Number of requests made to the DKIM key archive per lookup, including retries
+pub(crate) const DEFAULT_DKIM_FETCH_INITIAL_BACKOFF_MS: u64 = 200; // Wait before the first retry of a failed DKIM key archive request
+pub(crate) const DEFAULT_DKIM_FETCH_MAX_BACKOFF_MS: u64 = 2000; // Upper bound on the wait between retries of DKIM key archive requests
+pub(crate) const DEFAULT_MAX_RAW_EMAIL_BYTES: usize = 4 * 1024 * 1024; // Maximum size of a raw email accepted for parsing
+pub(crate) const DEFAULT_BATCH_CONCURRENCY: usize = 8; // Number of emails processed at once by the batch circuit input generator
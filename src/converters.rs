@@ -2,11 +2,12 @@
 
 use anyhow::{anyhow, Result};
 use ethers::types::{H160, U256};
+use ethers::utils::to_checksum;
 use halo2curves::ff::PrimeField;
 use itertools::Itertools;
 use num_bigint::BigInt;
 use poseidon_rs::Fr;
-use std::convert::TryInto;
+use std::{cmp, convert::TryInto};
 
 use crate::{CIRCOM_BIGINT_K, CIRCOM_BIGINT_N};
 
@@ -19,7 +20,7 @@ use crate::{CIRCOM_BIGINT_K, CIRCOM_BIGINT_N};
 /// `Result<Fr, anyhow::Error>` - The field element on success, or an error on failure.
 pub fn hex_to_field(input_hex: &str) -> Result<Fr> {
     // Check if the input string starts with "0x", which indicates a hex string
-    if &input_hex[0..2] != "0x" {
+    if !input_hex.starts_with("0x") {
         return Err(anyhow!(format!(
             "the input string {} must be hex string with 0x prefix",
             &input_hex
@@ -59,11 +60,13 @@ pub fn hex_to_field(input_hex: &str) -> Result<Fr> {
         }
     };
 
-    // Convert the array of bytes into a field element
-    let field = Fr::from_bytes(&bytes).expect("fail to convert bytes to a field value");
-
-    // Return the field element
-    Ok(field)
+    // Convert the array of bytes into a field element, which fails for values above the modulus
+    Option::from(Fr::from_bytes(&bytes)).ok_or_else(|| {
+        anyhow!(
+            "the input string {} is not a valid field element",
+            &input_hex
+        )
+    })
 }
 
 /// Converts a field element to a hexadecimal string.
@@ -113,8 +116,11 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 /// Converts a byte slice into a vector of `Fr` field elements, chunked by bit size.
 ///
 /// The input bytes are first ensured to be of the maximum size by padding with zeros if necessary.
-/// Then, each byte is split into bits, and chunks of bits are combined into words. These words
-/// are then grouped into field elements.
+/// Then, the bits of the input (least significant bit of each byte first) are grouped into words
+/// of `chunk_bit_size` bits, and these words are grouped into field elements.
+///
+/// Each word is assembled directly from the byte slice with 128-bit integer arithmetic, so
+/// `chunk_bit_size` must be less than 128.
 ///
 /// # Arguments
 /// * `bytes` - A byte slice to convert.
@@ -124,6 +130,11 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 ///
 /// # Returns
 /// A vector of `Fr` field elements.
+///
+/// # Panics
+/// Panics if `chunk_bit_size` is 0 or at least 128, or if `num_chunk_in_field` is 0. Callers
+/// taking these values from user input, such as `to_commitment_with_signature_params`, check
+/// them first.
 pub fn bytes_chunk_fields(
     bytes: &[u8],
     chunk_bit_size: usize,
@@ -131,30 +142,27 @@ pub fn bytes_chunk_fields(
     max_chunk_size: usize,
 ) -> Vec<Fr> {
     let max_bytes_size = max_chunk_size * chunk_bit_size / 8;
-    let mut bytes = bytes.to_vec();
-    // Ensure the bytes vector is of the maximum size, padding with zeros if necessary
-    if bytes.len() < max_bytes_size {
-        bytes.resize(max_bytes_size, 0);
-    }
-    // Convert bytes to bits
-    let bits = bytes
-        .iter()
-        .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1))
+    // Treat the bytes as if they were padded with zeros up to the maximum size
+    let padded_len = cmp::max(bytes.len(), max_bytes_size);
+    let total_bits = padded_len * 8;
+    let num_words = total_bits.div_ceil(chunk_bit_size);
+
+    // Assemble each word from the bytes that it spans
+    let words = (0..num_words)
+        .map(|word_idx| {
+            let start_bit = word_idx * chunk_bit_size;
+            let word_bits = cmp::min(chunk_bit_size, total_bits - start_bit);
+            Fr::from_u128(read_bits_le(bytes, start_bit, word_bits))
+        })
         .collect_vec();
 
-    // Combine bits into words and then into field elements
-    let words = bits
-        .chunks(chunk_bit_size)
-        .map(|chunk| {
-            chunk
-                .iter()
-                .enumerate()
-                .fold(Fr::zero(), |mut word, (i, &bit)| {
-                    if bit == 1 {
-                        word += Fr::from_u128(1u128 << i);
-                    }
-                    word
-                })
+    // Precompute the coefficients 2^(chunk_bit_size * i) used to combine words
+    let shift = Fr::from_u128(1u128 << chunk_bit_size);
+    let coeffs = (0..num_chunk_in_field)
+        .scan(Fr::one(), |coeff, _| {
+            let current = *coeff;
+            *coeff *= shift;
+            Some(current)
         })
         .collect_vec();
 
@@ -164,16 +172,32 @@ pub fn bytes_chunk_fields(
         .map(|chunk| {
             chunk
                 .iter()
-                .enumerate()
-                .fold((Fr::zero(), Fr::one()), |(mut input, coeff), (_, &word)| {
-                    input += coeff * word;
-                    (input, coeff * Fr::from_u128(1u128 << chunk_bit_size))
-                })
-                .0
+                .zip(coeffs.iter())
+                .fold(Fr::zero(), |input, (word, coeff)| input + *coeff * *word)
         })
         .collect_vec()
 }
 
+/// Reads `bit_len` bits starting at `start_bit` from a little-endian bit stream.
+///
+/// Bits are numbered from the least significant bit of `bytes[0]` upwards, and any bit beyond
+/// the end of `bytes` is treated as zero. `bit_len` must be at most 128.
+fn read_bits_le(bytes: &[u8], start_bit: usize, bit_len: usize) -> u128 {
+    let mut word = 0u128;
+    let mut read = 0;
+    while read < bit_len {
+        let pos = start_bit + read;
+        let offset = pos % 8;
+        // Take as many bits as possible from the current byte without crossing the word boundary
+        let take = cmp::min(8 - offset, bit_len - read);
+        let byte = bytes.get(pos / 8).copied().unwrap_or(0);
+        let bits = ((byte >> offset) as u128) & ((1u128 << take) - 1);
+        word |= bits << read;
+        read += take;
+    }
+    word
+}
+
 /// Converts a 64-bit integer to an array of 8 bytes in big-endian format.
 ///
 /// # Arguments
@@ -300,13 +324,58 @@ pub fn u256_to_hex(x: &U256) -> String {
 /// # Returns
 /// `Result<U256, hex::FromHexError>` - The `U256` on success, or an error on failure.
 pub fn hex_to_u256(hex: &str) -> Result<U256, hex::FromHexError> {
-    let bytes: Vec<u8> = hex::decode(&hex[2..])?;
+    let bytes: Vec<u8> = hex::decode(hex.get(2..).ok_or(hex::FromHexError::InvalidStringLength)?)?;
+    if bytes.len() > 32 {
+        return Err(hex::FromHexError::InvalidStringLength);
+    }
     let mut array = [0u8; 32];
     // Copy the bytes into the end of the array, padding with zeros at the start
     array[(32 - bytes.len())..].copy_from_slice(&bytes);
     Ok(U256::from_big_endian(&array))
 }
 
+/// Converts a 20-byte Ethereum address to a `U256`.
+///
+/// Mixed-case addresses must carry a valid EIP-55 checksum, while all-lowercase and
+/// all-uppercase addresses are accepted as they are.
+///
+/// # Arguments
+/// * `address` - The address as 40 hexadecimal digits with "0x" prefix.
+///
+/// # Returns
+/// `Result<U256>` - The address left-padded with zeros to 32 bytes, or an error describing why
+/// the address is invalid.
+pub fn eth_address_to_u256(address: &str) -> Result<U256> {
+    let digits = address
+        .strip_prefix("0x")
+        .ok_or_else(|| anyhow!("the address must start with 0x"))?;
+    if digits.len() != 40 {
+        return Err(anyhow!(
+            "the address must have 40 hex digits, but it has {}",
+            digits.len()
+        ));
+    }
+    let bytes = hex::decode(digits).map_err(|e| anyhow!("the address is not valid hex: {}", e))?;
+
+    // Only mixed-case addresses carry a checksum
+    let has_lowercase = digits.chars().any(|c| c.is_ascii_lowercase());
+    let has_uppercase = digits.chars().any(|c| c.is_ascii_uppercase());
+    if has_lowercase && has_uppercase {
+        let checksummed = to_checksum(&H160::from_slice(&bytes), None);
+        if checksummed != address {
+            return Err(anyhow!(
+                "the address has an invalid EIP-55 checksum, expected {}",
+                checksummed
+            ));
+        }
+    }
+
+    // The address occupies the low 20 bytes of the 32-byte word
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(&bytes);
+    Ok(U256::from_big_endian(&word))
+}
+
 /// Converts a field element `Fr` to a 32-byte array.
 ///
 /// # Arguments
@@ -461,11 +530,25 @@ pub fn uint_to_decimal_string(uint: u128, decimal: usize) -> String {
 ///
 /// A `Result` containing a vector of strings, each representing a big integer, or an error on failure.
 pub fn string_to_circom_bigint_bytes(input: &str) -> Result<Vec<String>> {
-    // Convert the input string to UTF-8 bytes
-    let utf8_bytes = input.as_bytes();
+    // Convert the input string to UTF-8 bytes and pack them into big integer strings
+    Ok(bytes_to_circom_bigint_bytes(input.as_bytes()))
+}
 
+/// Converts bytes to a vector of big integer strings for Circom circuits.
+///
+/// The bytes are packed into field elements of 31 bytes each, and each field element is
+/// converted into its decimal string representation.
+///
+/// # Arguments
+///
+/// * `input` - A byte slice to be converted.
+///
+/// # Returns
+///
+/// A vector of strings, each representing a big integer.
+pub fn bytes_to_circom_bigint_bytes(input: &[u8]) -> Vec<String> {
     // Convert the bytes to field elements
-    let frs = bytes_to_fields(utf8_bytes);
+    let frs = bytes_to_fields(input);
 
     // Convert each field element to a big integer string
     let num_strings: Vec<String> = frs
@@ -479,5 +562,136 @@ pub fn string_to_circom_bigint_bytes(input: &str) -> Result<Vec<String>> {
         .collect();
 
     // Return the vector of big integer strings
-    Ok(num_strings)
+    num_strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    /// The original bit-by-bit implementation of `bytes_chunk_fields`, kept as a reference.
+    fn bytes_chunk_fields_reference(
+        bytes: &[u8],
+        chunk_bit_size: usize,
+        num_chunk_in_field: usize,
+        max_chunk_size: usize,
+    ) -> Vec<Fr> {
+        let max_bytes_size = max_chunk_size * chunk_bit_size / 8;
+        let mut bytes = bytes.to_vec();
+        if bytes.len() < max_bytes_size {
+            bytes.resize(max_bytes_size, 0);
+        }
+        let bits = bytes
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1))
+            .collect_vec();
+        let words = bits
+            .chunks(chunk_bit_size)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(Fr::zero(), |mut word, (i, &bit)| {
+                        if bit == 1 {
+                            word += Fr::from_u128(1u128 << i);
+                        }
+                        word
+                    })
+            })
+            .collect_vec();
+        words
+            .chunks(num_chunk_in_field)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .fold((Fr::zero(), Fr::one()), |(mut input, coeff), &word| {
+                        input += coeff * word;
+                        (input, coeff * Fr::from_u128(1u128 << chunk_bit_size))
+                    })
+                    .0
+            })
+            .collect_vec()
+    }
+
+    #[test]
+    fn test_eth_address_to_u256() {
+        let address = "0x9401296121FC9B78F84fc856B1F8dC88f4415B2e";
+        let value = eth_address_to_u256(address).unwrap();
+        assert_eq!(value, hex_to_u256(address).unwrap());
+        assert_eq!(eth_address_to_u256(&address.to_lowercase()).unwrap(), value);
+        assert_eq!(
+            eth_address_to_u256(&format!("0x{}", address[2..].to_uppercase())).unwrap(),
+            value
+        );
+
+        // A changed letter case breaks the checksum
+        let err = eth_address_to_u256("0x9401296121fC9B78F84fc856B1F8dC88f4415B2e").unwrap_err();
+        assert!(err.to_string().contains("invalid EIP-55 checksum"));
+        for address in [
+            "0x123",
+            "9401296121FC9B78F84fc856B1F8dC88f4415B2e",
+            "0x",
+            "",
+        ]
+        .iter()
+        {
+            assert!(eth_address_to_u256(address).is_err());
+        }
+        assert!(eth_address_to_u256("0x9401296121fc9b78f84fc856b1f8dc88f4415bzz").is_err());
+
+        // Short and overlong inputs are errors rather than panics
+        assert!(hex_to_u256("0").is_err());
+        assert!(hex_to_u256(&format!("0x{}", "00".repeat(33))).is_err());
+    }
+
+    #[test]
+    fn test_bytes_chunk_fields_matches_reference() {
+        // Every supported chunk size, with inputs both shorter and longer than the maximum size
+        let mut rng = StdRng::seed_from_u64(0);
+        let cases = [(0, 1), (1, 17), (33, 5), (256, 17), (300, 40)];
+        for chunk_bit_size in 1..128 {
+            for num_chunk_in_field in 1..4 {
+                for &(len, max_chunk_size) in cases.iter() {
+                    let mut bytes = vec![0u8; len];
+                    rng.fill_bytes(&mut bytes);
+                    assert_eq!(
+                        bytes_chunk_fields(
+                            &bytes,
+                            chunk_bit_size,
+                            num_chunk_in_field,
+                            max_chunk_size
+                        ),
+                        bytes_chunk_fields_reference(
+                            &bytes,
+                            chunk_bit_size,
+                            num_chunk_in_field,
+                            max_chunk_size
+                        ),
+                        "mismatch for len={} chunk_bit_size={} num_chunk_in_field={} max_chunk_size={}",
+                        len,
+                        chunk_bit_size,
+                        num_chunk_in_field,
+                        max_chunk_size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes_chunk_fields_public_key_vector() {
+        let mut public_key_n = hex::decode("cfb0520e4ad78c4adb0deb5e605162b6469349fc1fde9269b88d596ed9f3735c00c592317c982320874b987bcc38e8556ac544bdee169b66ae8fe639828ff5afb4f199017e3d8e675a077f21cd9e5c526c1866476e7ba74cd7bb16a1c3d93bc7bb1d576aedb4307c6b948d5b8c29f79307788d7a8ebf84585bf53994827c23a5").unwrap();
+        public_key_n.reverse();
+        let fields = bytes_chunk_fields(&public_key_n, 121, 2, 17);
+        assert_eq!(
+            fields,
+            bytes_chunk_fields_reference(&public_key_n, 121, 2, 17)
+        );
+        // The Poseidon hash of the fields is the public key hash of the 2048-bit circuits
+        assert_eq!(
+            field_to_hex(&poseidon_rs::poseidon_fields(&fields).unwrap()),
+            "0x181ab950d973ee53838532ecb1b8b11528f6ea7ab08e2868fb3218464052f953"
+        );
+    }
 }
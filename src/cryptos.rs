@@ -1,43 +1,140 @@
 //! Cryptographic functions.
 
-use crate::EmailHeaders;
-use crate::{field_to_hex, hex_to_field};
-use anyhow::Result;
+use crate::parse_email::{find_dkim_body_hash_idxes, find_dkim_signature_tags, ParseEmailOptions};
+use crate::runtime::{now_ms, sleep_ms, timeout_ms};
+use crate::{bytes32_to_fr, field_to_hex, hex_to_field};
+use crate::{
+    EmailHeaders, DEFAULT_DKIM_CACHE_MAX_ENTRIES, DEFAULT_DKIM_CACHE_TTL_MS,
+    DEFAULT_DKIM_FETCH_INITIAL_BACKOFF_MS, DEFAULT_DKIM_FETCH_MAX_ATTEMPTS,
+    DEFAULT_DKIM_FETCH_MAX_BACKOFF_MS, DEFAULT_DKIM_NEGATIVE_CACHE_TTL_MS, DKIM_API_URL_KEY,
+    DKIM_ARCHIVE_API_URL,
+};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ethers::abi::{self, Token};
 use ethers::types::Bytes;
 use halo2curves::ff::Field;
+use lazy_static::lazy_static;
 use poseidon_rs::{poseidon_bytes, poseidon_fields, Fr, PoseidonError};
+use rand::Rng;
 use rand_core::RngCore;
-use regex::Regex;
+use rsa::pkcs1::DecodeRsaPublicKey;
 use rsa::pkcs8::DecodePublicKey;
 use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
 use std::{
-    collections::hash_map::DefaultHasher,
-    error::Error,
+    collections::{hash_map::DefaultHasher, HashMap},
+    convert::TryInto,
     fmt,
     hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
+use zk_regex_apis::extract_substrs::extract_email_addr_idxes;
 use zk_regex_apis::padding::pad_string;
 
 use crate::{
     converters::{
         bytes_chunk_fields, bytes_to_fields, int64_to_bytes, int8_to_bytes, merge_u8_arrays,
     },
-    MAX_EMAIL_ADDR_BYTES,
+    CIRCOM_BIGINT_K, CIRCOM_BIGINT_N, EMAIL_NULLIFIER_V2_VERSION, MAX_CIRCOM_BIGINT_K,
+    MAX_CIRCOM_BIGINT_N, MAX_EMAIL_ADDR_BYTES, POSEIDON_MAX_INPUTS,
 };
 
 type ShaResult = Vec<u8>; // The result of a SHA-256 hash operation.
 type RemainingBody = Vec<u8>; // The remaining part of a message after a SHA-256 hash operation.
 type RemainingBodyLength = usize; // The length of the remaining message body in bytes.
-type PartialShaResult = Result<(ShaResult, RemainingBody, RemainingBodyLength), Box<dyn Error>>; // The result of a partial SHA-256 hash operation, including the hash, remaining body, and its length, or an error.
+type PartialShaResult = Result<(ShaResult, RemainingBody, RemainingBodyLength), PartialShaError>; // The result of a partial SHA-256 hash operation, including the hash, remaining body, and its length, or an error.
+type PartialShaRangeResult = Result<(ShaResult, RemainingRange), PartialShaError>; // The result of a partial SHA-256 hash operation, including the hash and where the remaining body is, or an error.
+
+const RSA_DEFAULT_EXPONENT: u32 = 65537; // The public exponent assumed for RSA keys given as a bare modulus
+const SHA256_DIGEST_INFO_PREFIX: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+]; // The DER prefix of a PKCS#1 v1.5 DigestInfo for SHA-256
 
 #[derive(Debug, Clone, Copy)]
 /// `RelayerRand` is a single field element representing a random value.
 pub struct RelayerRand(pub Fr);
 
+impl<'de> Deserialize<'de> for RelayerRand {
+    /// Deserializes a string into a `RelayerRand`.
+    ///
+    /// # Arguments
+    ///
+    /// * `deserializer` - The deserializer to use for converting the string into a `RelayerRand`.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either a `RelayerRand` or a deserialization error.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RelayerRandVisitor;
+
+        impl<'de> Visitor<'de> for RelayerRandVisitor {
+            type Value = RelayerRand;
+
+            /// Describes what the visitor expects to receive.
+            ///
+            /// # Arguments
+            ///
+            /// * `formatter` - A formatter to write the expected type description.
+            ///
+            /// # Returns
+            ///
+            /// A `fmt::Result` indicating success or failure.
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a valid field element for RelayerRand")
+            }
+
+            /// Visits a string and attempts to convert it into a `RelayerRand`.
+            ///
+            /// # Arguments
+            ///
+            /// * `value` - The string value to convert.
+            ///
+            /// # Returns
+            ///
+            /// A result that is either a `RelayerRand` or a deserialization error.
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                RelayerRand::from_hex(value).map_err(de::Error::custom)
+            }
+        }
+
+        // Deserialize the string using the RelayerRandVisitor
+        deserializer.deserialize_str(RelayerRandVisitor)
+    }
+}
+
+impl Serialize for RelayerRand {
+    /// Serializes a `RelayerRand` into a string.
+    ///
+    /// # Arguments
+    ///
+    /// * `serializer` - The serializer to use for converting the `RelayerRand` into a string.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either a serialized string or a serialization error.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
 impl RelayerRand {
     /// Constructs a new `RelayerRand` using a random number generator.
     ///
@@ -74,6 +171,89 @@ impl RelayerRand {
     pub fn hash(&self) -> Result<Fr, PoseidonError> {
         poseidon_fields(&[self.0])
     }
+
+    /// Parses a `RelayerRand` from its 0x-prefixed hex form, as produced by `to_hex`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rand_hex` - The 0x-prefixed hex string of the field element.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either the `RelayerRand` or an error if the string is not a field element.
+    pub fn from_hex(rand_hex: &str) -> Result<Self> {
+        Ok(RelayerRand(hex_to_field(rand_hex)?))
+    }
+
+    /// Returns the 0x-prefixed hex form of the randomness, which is also its serialized form.
+    pub fn to_hex(&self) -> String {
+        field_to_hex(&self.0)
+    }
+}
+
+/// Checks that the input is a single email address, as recognized by the email address regex
+/// used in the circuits.
+///
+/// # Arguments
+///
+/// * `email_addr` - A string slice representing the email address.
+///
+/// # Returns
+///
+/// A `Result` that is an error naming the input if it is not exactly one email address.
+pub fn validate_email_input(email_addr: &str) -> Result<()> {
+    match extract_email_addr_idxes(email_addr) {
+        Ok(idxes) if idxes == vec![(0, email_addr.len())] => Ok(()),
+        _ => Err(anyhow!("{:?} is not a valid email address", email_addr)),
+    }
+}
+
+/// How an email address is normalized before it is padded.
+///
+/// The default leaves the address byte-exact, so existing account salts are unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NormalizationPolicy {
+    pub trim_whitespace: bool,      // Whether surrounding whitespace is removed
+    pub lowercase_local_part: bool, // Whether the part before the `@` is lowercased
+    pub lowercase_domain: bool,     // Whether the part after the `@` is lowercased
+}
+
+impl NormalizationPolicy {
+    /// Normalizes an email address according to the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `email_addr` - A string slice representing the email address to be normalized.
+    ///
+    /// # Returns
+    ///
+    /// The normalized email address. An address without an `@` is treated as a local part.
+    pub fn normalize(&self, email_addr: &str) -> String {
+        let email_addr = if self.trim_whitespace {
+            email_addr.trim()
+        } else {
+            email_addr
+        };
+        let (local_part, domain) = match email_addr.rsplit_once('@') {
+            Some((local_part, domain)) => (local_part, Some(domain)),
+            None => (email_addr, None),
+        };
+        let mut normalized = if self.lowercase_local_part {
+            local_part.to_lowercase()
+        } else {
+            local_part.to_string()
+        };
+        if let Some(domain) = domain {
+            normalized.push('@');
+            if self.lowercase_domain {
+                normalized.push_str(&domain.to_lowercase());
+            } else {
+                normalized.push_str(domain);
+            }
+        }
+        normalized
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -93,7 +273,55 @@ impl PaddedEmailAddr {
     /// # Returns
     ///
     /// A new instance of `PaddedEmailAddr`.
+    #[deprecated(note = "use `try_from_email_addr`, which rejects addresses that do not fit")]
     pub fn from_email_addr(email_addr: &str) -> Self {
+        Self::pad(email_addr)
+    }
+
+    /// Creates a new `PaddedEmailAddr` from a given email address, checking that it fits.
+    ///
+    /// # Arguments
+    ///
+    /// * `email_addr` - A string slice representing the email address to be padded.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either a new instance of `PaddedEmailAddr` or an error if the address
+    /// is empty or longer than `MAX_EMAIL_ADDR_BYTES`.
+    pub fn try_from_email_addr(email_addr: &str) -> Result<Self> {
+        if email_addr.is_empty() {
+            return Err(anyhow!("The email address must not be empty"));
+        }
+        if email_addr.len() > MAX_EMAIL_ADDR_BYTES {
+            return Err(anyhow!(
+                "The email address is {} bytes long, but at most {} bytes fit in a padded email address",
+                email_addr.len(),
+                MAX_EMAIL_ADDR_BYTES
+            ));
+        }
+        Ok(Self::pad(email_addr))
+    }
+
+    /// Creates a new `PaddedEmailAddr` from an email address normalized according to `policy`.
+    ///
+    /// # Arguments
+    ///
+    /// * `email_addr` - A string slice representing the email address to be padded.
+    /// * `policy` - How the email address is normalized before it is padded.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either a new instance of `PaddedEmailAddr` or an error if the normalized
+    /// address is empty or longer than `MAX_EMAIL_ADDR_BYTES`.
+    pub fn from_email_addr_normalized(
+        email_addr: &str,
+        policy: NormalizationPolicy,
+    ) -> Result<Self> {
+        Self::try_from_email_addr(&policy.normalize(email_addr))
+    }
+
+    /// Pads an email address to `MAX_EMAIL_ADDR_BYTES` without checking its length.
+    fn pad(email_addr: &str) -> Self {
         let email_addr_len = email_addr.as_bytes().len();
         let padded_bytes = pad_string(email_addr, MAX_EMAIL_ADDR_BYTES);
         Self {
@@ -126,6 +354,27 @@ impl PaddedEmailAddr {
         poseidon_fields(&inputs)
     }
 
+    /// Creates a commitment to the padded email address using a 32-byte nonce, e.g. one chosen by
+    /// a contract.
+    ///
+    /// The nonce is read as a big-endian integer, as `bytes32` in Solidity, and committed to as in
+    /// `to_commitment`.
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - A reference to the 32-byte nonce, which must be below the field modulus.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either the commitment as a field element or an error if the nonce is not
+    /// a field element or the Poseidon hash fails.
+    pub fn to_commitment_with_nonce_bytes(&self, nonce: &[u8; 32]) -> Result<Fr> {
+        let nonce_field = bytes32_to_fr(nonce)
+            .map_err(|_| anyhow!("The nonce 0x{} is not a field element", hex::encode(nonce)))?;
+        self.to_commitment(&nonce_field)
+            .map_err(|e| anyhow!("Failed to commit to the email address: {}", e))
+    }
+
     /// Creates a commitment to the padded email address using a signature to extract randomness.
     ///
     /// # Arguments
@@ -134,10 +383,40 @@ impl PaddedEmailAddr {
     ///
     /// # Returns
     ///
-    /// A result that is either the commitment as a field element or a `PoseidonError`.
-    pub fn to_commitment_with_signature(&self, signature: &[u8]) -> Result<Fr, PoseidonError> {
-        let cm_rand = extract_rand_from_signature(signature)?;
+    /// A result that is either the commitment as a field element or an error if a Poseidon hash
+    /// fails.
+    pub fn to_commitment_with_signature(&self, signature: &[u8]) -> Result<Fr> {
+        let mut signature_le = signature.to_vec();
+        signature_le.reverse();
+        let num_chunks = default_chunk_count(&signature_le, "signature")?;
+        self.to_commitment_with_signature_params(signature, CIRCOM_BIGINT_N, num_chunks)
+    }
+
+    /// Creates a commitment to the padded email address using randomness extracted from a
+    /// signature split into `num_chunks` chunks of `chunk_bits` bits.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - A byte slice representing the signature from which randomness is extracted.
+    /// * `chunk_bits` - The number of bits per chunk, at most 126 so that two chunks fit in a
+    ///   field element.
+    /// * `num_chunks` - The number of chunks the circuit splits the signature into.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either the commitment as a field element or an error if the parameters
+    /// are not supported, the signature does not fit in the chunks or a Poseidon hash fails.
+    pub fn to_commitment_with_signature_params(
+        &self,
+        signature: &[u8],
+        chunk_bits: usize,
+        num_chunks: usize,
+    ) -> Result<Fr> {
+        let mut signature = signature.to_vec();
+        signature.reverse();
+        let cm_rand = extract_rand_from_le_signature(&signature, chunk_bits, num_chunks)?;
         poseidon_fields(&[vec![cm_rand], self.to_email_addr_fields()].concat())
+            .map_err(|e| anyhow!("Failed to commit to the email address: {}", e))
     }
 }
 
@@ -190,9 +469,7 @@ impl<'de> Deserialize<'de> for AccountCode {
             where
                 E: de::Error,
             {
-                // Convert the string to a field element
-                let fr_value = hex_to_field(value).map_err(de::Error::custom)?;
-                Ok(AccountCode(fr_value))
+                AccountCode::try_from_hex(value).map_err(de::Error::custom)
             }
         }
 
@@ -215,10 +492,7 @@ impl Serialize for AccountCode {
     where
         S: Serializer,
     {
-        // Convert the field element to a hexadecimal string
-        let hex_value = field_to_hex(&self.0);
-        // Serialize the hexadecimal string
-        serializer.serialize_str(&hex_value)
+        serializer.serialize_str(&self.to_hex())
     }
 }
 
@@ -249,6 +523,36 @@ impl AccountCode {
         Self(elem)
     }
 
+    /// Parses an account code from hex, as produced by `to_hex`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account_code` - The account code as 32 bytes of big-endian hex, with or without a `0x`
+    ///   prefix.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either the `AccountCode` or an error if the input is not hex, not 32
+    /// bytes long, or not below the field modulus.
+    pub fn try_from_hex(account_code: &str) -> Result<Self> {
+        let account_code_hex = account_code.strip_prefix("0x").unwrap_or(account_code);
+        let mut bytes = hex::decode(account_code_hex)
+            .map_err(|e| anyhow!("Account code {} is not valid hex: {}", account_code, e))?;
+        // Field elements are little-endian
+        bytes.reverse();
+        let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            anyhow!("Account code must be 32 bytes but is {} bytes", bytes.len())
+        })?;
+        Option::<Fr>::from(Fr::from_bytes(&bytes))
+            .map(Self)
+            .ok_or_else(|| anyhow!("Account code {} is not a field element", account_code))
+    }
+
+    /// Returns the account code as 0x-prefixed hex, in the format of `field_to_hex`.
+    pub fn to_hex(&self) -> String {
+        field_to_hex(&self.0)
+    }
+
     /// Creates a commitment to the account code using the padded email address and a hash of the relayer's randomness.
     ///
     /// # Arguments
@@ -296,6 +600,60 @@ impl Serialize for AccountSalt {
     }
 }
 
+impl<'de> Deserialize<'de> for AccountSalt {
+    /// Deserializes a string into an `AccountSalt`.
+    ///
+    /// # Arguments
+    ///
+    /// * `deserializer` - The deserializer to use for converting the string into an `AccountSalt`.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either an `AccountSalt` or a deserialization error.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AccountSaltVisitor;
+
+        impl<'de> Visitor<'de> for AccountSaltVisitor {
+            type Value = AccountSalt;
+
+            /// Describes what the visitor expects to receive.
+            ///
+            /// # Arguments
+            ///
+            /// * `formatter` - A formatter to write the expected type description.
+            ///
+            /// # Returns
+            ///
+            /// A `fmt::Result` indicating success or failure.
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a valid field element for AccountSalt")
+            }
+
+            /// Visits a string and attempts to convert it into an `AccountSalt`.
+            ///
+            /// # Arguments
+            ///
+            /// * `value` - The string value to convert.
+            ///
+            /// # Returns
+            ///
+            /// A result that is either an `AccountSalt` or a deserialization error.
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                AccountSalt::from_hex(value).map_err(de::Error::custom)
+            }
+        }
+
+        // Deserialize the string using the AccountSaltVisitor
+        deserializer.deserialize_str(AccountSaltVisitor)
+    }
+}
+
 impl AccountSalt {
     /// Creates a new `AccountSalt` using the padded email address and account code.
     ///
@@ -339,125 +697,447 @@ impl AccountSalt {
 
         Ok(AccountSalt(salt))
     }
+
+    /// Parses an `AccountSalt` from its 0x-prefixed hex form, as produced by `to_hex`.
+    ///
+    /// # Arguments
+    ///
+    /// * `salt_hex` - The 0x-prefixed hex string of the field element.
+    ///
+    /// # Returns
+    ///
+    /// A result that is either the `AccountSalt` or an error if the string is not a field element.
+    pub fn from_hex(salt_hex: &str) -> Result<Self> {
+        Ok(AccountSalt(hex_to_field(salt_hex)?))
+    }
+
+    /// Returns the 0x-prefixed hex form of the account salt, which is also its serialized form.
+    pub fn to_hex(&self) -> String {
+        field_to_hex(&self.0)
+    }
 }
 
 /// Extracts a random field element from a signature.
 ///
+/// The number of chunks is chosen from the size of the signature as in `public_key_hash`.
+///
 /// # Arguments
 ///
 /// * `signature` - A byte slice representing the signature.
 ///
 /// # Returns
 ///
-/// A result that is either a random field element or a `PoseidonError`.
-pub fn extract_rand_from_signature(signature: &[u8]) -> Result<Fr, PoseidonError> {
+/// A result that is either a random field element or an error if the Poseidon hash fails.
+pub fn extract_rand_from_signature(signature: &[u8]) -> Result<Fr> {
     let mut signature = signature.to_vec();
     signature.reverse();
-    let mut inputs = bytes_chunk_fields(&signature, 121, 2, 17);
-    inputs.push(Fr::one());
-    let cm_rand = poseidon_fields(&inputs)?;
-    Ok(cm_rand)
+    let k = default_chunk_count(&signature, "signature")?;
+    extract_rand_from_le_signature(&signature, CIRCOM_BIGINT_N, k)
 }
 
-/// Computes the Poseidon hash of a public key.
+/// Extracts a random field element from a signature split into `k` chunks.
 ///
 /// # Arguments
 ///
-/// * `public_key_n` - A byte slice representing the public key in little endian format.
+/// * `signature` - A byte slice representing the signature.
+/// * `k` - The number of `CIRCOM_BIGINT_N`-bit chunks the circuit splits the signature into.
 ///
 /// # Returns
 ///
-/// A result that is either the Poseidon hash of the public key or a `PoseidonError`.
-pub fn public_key_hash(public_key_n: &[u8]) -> Result<Fr, PoseidonError> {
-    let inputs = bytes_chunk_fields(public_key_n, 121, 2, 17);
-    poseidon_fields(&inputs)
+/// A result that is either a random field element or an error if the signature does not fit in
+/// `k` chunks or the Poseidon hash fails.
+pub fn extract_rand_from_signature_with_k(signature: &[u8], k: usize) -> Result<Fr> {
+    let mut signature = signature.to_vec();
+    signature.reverse();
+    extract_rand_from_le_signature(&signature, CIRCOM_BIGINT_N, k)
 }
 
-/// Computes the Poseidon hash to generate an email nullifier.
+/// Extracts a random field element from a little-endian signature split into `k` chunks of
+/// `chunk_bits` bits.
+fn extract_rand_from_le_signature(signature: &[u8], chunk_bits: usize, k: usize) -> Result<Fr> {
+    if signature.is_empty() {
+        return Err(anyhow!("The signature must not be empty"));
+    }
+    let mut inputs = bigint_chunk_fields(signature, chunk_bits, k, "signature")?;
+    inputs.push(Fr::one());
+    poseidon_modular(&inputs).map_err(|e| anyhow!("Failed to hash the signature: {}", e))
+}
+
+/// Computes the Poseidon hash of a public key.
+///
+/// Moduli of up to 2048 bits are split into `CIRCOM_BIGINT_K` chunks of `CIRCOM_BIGINT_N` bits,
+/// as in the 2048-bit circuits, and larger moduli into as many chunks as their byte length needs,
+/// e.g. 34 chunks for 4096-bit moduli. The packed chunks are hashed as in `poseidon_modular`.
 ///
 /// # Arguments
 ///
-/// * `signature` - A byte slice representing the signature in little endian format.
+/// * `public_key_n` - A byte slice representing the public key in little endian format.
 ///
 /// # Returns
 ///
-/// A result that is either the Poseidon hash of the signature or a `PoseidonError`.
-pub fn email_nullifier(signature: &[u8]) -> Result<Fr, PoseidonError> {
-    let inputs = bytes_chunk_fields(signature, 121, 2, 17);
-    let sign_rand = poseidon_fields(&inputs)?;
-    poseidon_fields(&[sign_rand])
+/// A result that is either the Poseidon hash of the public key or an error if the hash fails.
+pub fn public_key_hash(public_key_n: &[u8]) -> Result<Fr> {
+    public_key_hash_with_k(
+        public_key_n,
+        default_chunk_count(public_key_n, "public key")?,
+    )
 }
 
-/// Pads the given data to be a valid SHA-256 message block and extends it to a specified maximum length.
+/// Computes the Poseidon hash of a public key split into `k` chunks.
 ///
 /// # Arguments
 ///
-/// * `data` - The original data to be padded.
-/// * `max_sha_bytes` - The maximum length in bytes to which the data should be extended.
+/// * `public_key_n` - A byte slice representing the public key in little endian format.
+/// * `k` - The number of `CIRCOM_BIGINT_N`-bit chunks the circuit splits the modulus into.
 ///
 /// # Returns
 ///
-/// A tuple containing the padded data and the length of the original data before padding.
-pub fn sha256_pad(mut data: Vec<u8>, max_sha_bytes: usize) -> (Vec<u8>, usize) {
-    let length_bits = data.len() * 8; // Convert length from bytes to bits
-    let length_in_bytes = int64_to_bytes(length_bits as u64);
-
-    // Add the bit '1' to the end of the data
-    data = merge_u8_arrays(data, int8_to_bytes(0x80));
-
-    while (data.len() * 8 + length_in_bytes.len() * 8) % 512 != 0 {
-        data = merge_u8_arrays(data, int8_to_bytes(0));
-    }
-
-    // Append the original length in bits at the end of the data
-    data = merge_u8_arrays(data, length_in_bytes);
-
-    // Ensure that the padding is complete
-    assert!(
-        (data.len() * 8) % 512 == 0,
-        "Padding did not complete properly!"
-    );
-
-    let message_len = data.len();
-
-    // Pad the data to the specified maximum length with zeros
-    while data.len() < max_sha_bytes {
-        data = merge_u8_arrays(data, int64_to_bytes(0));
-    }
-
-    // Ensure that the data is padded to the maximum length
-    assert!(
-        data.len() == max_sha_bytes,
-        "Padding to max length did not complete properly! Your padded message is {} long but max is {}!",
-        data.len(),
-        max_sha_bytes
-    );
-
-    (data, message_len)
+/// A result that is either the Poseidon hash of the public key or an error if the modulus does
+/// not fit in `k` chunks or the hash fails.
+pub fn public_key_hash_with_k(public_key_n: &[u8], k: usize) -> Result<Fr> {
+    let inputs = bigint_chunk_fields(public_key_n, CIRCOM_BIGINT_N, k, "public key")?;
+    poseidon_modular(&inputs).map_err(|e| anyhow!("Failed to hash the public key: {}", e))
 }
 
-/// Computes the SHA-256 hash of a message up to a specified length.
+/// Computes the Poseidon hash to generate an email nullifier.
+///
+/// The number of chunks is chosen from the size of the signature as in `public_key_hash`.
 ///
 /// # Arguments
 ///
-/// * `msg` - The message to be hashed.
-/// * `msg_len` - The length of the message to consider for the hash.
+/// * `signature` - A byte slice representing the signature in little endian format.
 ///
 /// # Returns
 ///
-/// A vector containing the SHA-256 hash of the message.
-pub fn partial_sha(msg: &[u8], msg_len: usize) -> Vec<u8> {
-    let mut hasher = hmac_sha256::Hash::new();
-    hasher.update(&msg[..msg_len]);
-    let result = hasher.cache_state();
-    result.to_vec()
+/// A result that is either the Poseidon hash of the signature or an error if the hash fails.
+pub fn email_nullifier(signature: &[u8]) -> Result<Fr> {
+    email_nullifier_with_k(signature, default_chunk_count(signature, "signature")?)
 }
 
-/// Generates a partial SHA-256 hash of a message up to the point of a selector string, if provided.
+/// Computes the Poseidon hash to generate an email nullifier from a signature split into `k`
+/// chunks.
 ///
 /// # Arguments
 ///
-/// * `body` - The message body as a vector of bytes.
+/// * `signature` - A byte slice representing the signature in little endian format.
+/// * `k` - The number of `CIRCOM_BIGINT_N`-bit chunks the circuit splits the signature into.
+///
+/// # Returns
+///
+/// A result that is either the Poseidon hash of the signature or an error if the signature does
+/// not fit in `k` chunks or the hash fails.
+pub fn email_nullifier_with_k(signature: &[u8], k: usize) -> Result<Fr> {
+    let inputs = bigint_chunk_fields(signature, CIRCOM_BIGINT_N, k, "signature")?;
+    poseidon_modular(&inputs)
+        .and_then(|sign_rand| poseidon_fields(&[sign_rand]).map_err(|e| anyhow!("{}", e)))
+        .map_err(|e| anyhow!("Failed to hash the signature: {}", e))
+}
+
+/// Computes a domain-separated email nullifier, so that the same email yields unrelated
+/// nullifiers in different applications.
+///
+/// The signature is chunked as in `email_nullifier` and hashed into `sign_rand`, and the
+/// nullifier is `poseidon([2, domain_tag, sign_rand])`, where `2` is the version of this
+/// derivation. Circuits must hash the inputs in exactly this order.
+///
+/// # Arguments
+///
+/// * `signature` - A byte slice representing the signature in little endian format.
+/// * `domain_tag` - A field element identifying the application.
+///
+/// # Returns
+///
+/// A result that is either the nullifier or an error if the signature is not supported or a
+/// hash fails.
+pub fn email_nullifier_v2(signature: &[u8], domain_tag: &Fr) -> Result<Fr> {
+    let k = default_chunk_count(signature, "signature")?;
+    let inputs = bigint_chunk_fields(signature, CIRCOM_BIGINT_N, k, "signature")?;
+    poseidon_modular(&inputs)
+        .and_then(|sign_rand| {
+            poseidon_fields(&[Fr::from(EMAIL_NULLIFIER_V2_VERSION), *domain_tag, sign_rand])
+                .map_err(|e| anyhow!("{}", e))
+        })
+        .map_err(|e| anyhow!("Failed to hash the signature: {}", e))
+}
+
+/// Computes the Poseidon hash of any number of field elements the way the `PoseidonModular`
+/// circuit template does.
+///
+/// The fields are hashed in chunks of 16, the most a single Poseidon hash takes, and the chunk
+/// hashes are chained as `poseidon([previous, chunk_hash])`. Up to 16 fields, this is the plain
+/// Poseidon hash of the fields.
+///
+/// # Arguments
+///
+/// * `fields` - The field elements to hash.
+///
+/// # Returns
+///
+/// A result that is either the hash or an error if there are no fields to hash.
+pub fn poseidon_modular(fields: &[Fr]) -> Result<Fr> {
+    let mut chunk_hashes = fields.chunks(POSEIDON_MAX_INPUTS).map(poseidon_fields);
+    let first = chunk_hashes
+        .next()
+        .ok_or_else(|| anyhow!("There are no fields to hash"))?;
+    first
+        .and_then(|first| {
+            chunk_hashes.try_fold(first, |hash, chunk_hash| {
+                poseidon_fields(&[hash, chunk_hash?])
+            })
+        })
+        .map_err(|e| anyhow!("Failed to hash the fields: {}", e))
+}
+
+/// Returns the number of significant bits of a little-endian integer.
+fn significant_bits(bytes_le: &[u8]) -> usize {
+    match bytes_le.iter().rposition(|byte| *byte != 0) {
+        Some(top) => top * 8 + (8 - bytes_le[top].leading_zeros() as usize),
+        None => 0,
+    }
+}
+
+/// Chooses the number of chunks for a little-endian RSA value from its byte length, so that
+/// leading zero bytes do not change it: `CIRCOM_BIGINT_K` for values of up to 2048 bits, and
+/// otherwise as many as the byte length needs, up to `MAX_CIRCOM_BIGINT_K`.
+fn default_chunk_count(bytes_le: &[u8], name: &str) -> Result<usize> {
+    let needed = (bytes_le.len() * 8).div_ceil(CIRCOM_BIGINT_N);
+    if needed > MAX_CIRCOM_BIGINT_K {
+        return Err(anyhow!(
+            "The {} has {} bytes, but at most {} bytes are supported",
+            name,
+            bytes_le.len(),
+            MAX_CIRCOM_BIGINT_K * CIRCOM_BIGINT_N / 8
+        ));
+    }
+    Ok(needed.max(CIRCOM_BIGINT_K))
+}
+
+/// Splits a little-endian RSA value into `k` chunks of `chunk_bits` bits, packed two per field
+/// element, as the circuits do before hashing it.
+fn bigint_chunk_fields(
+    bytes_le: &[u8],
+    chunk_bits: usize,
+    k: usize,
+    name: &str,
+) -> Result<Vec<Fr>> {
+    // Two chunks must fit below the 254-bit field modulus
+    if chunk_bits == 0 || chunk_bits > MAX_CIRCOM_BIGINT_N {
+        return Err(anyhow!(
+            "Chunks of {} bits are not supported, the chunk size must be between 1 and {} bits",
+            chunk_bits,
+            MAX_CIRCOM_BIGINT_N
+        ));
+    }
+    if k == 0 {
+        return Err(anyhow!(
+            "The {} must be split into at least one chunk",
+            name
+        ));
+    }
+    if k > MAX_CIRCOM_BIGINT_K {
+        return Err(anyhow!(
+            "The {} cannot be split into {} chunks, at most {} chunks are supported",
+            name,
+            k,
+            MAX_CIRCOM_BIGINT_K
+        ));
+    }
+    let bits = significant_bits(bytes_le);
+    if bits > k * chunk_bits {
+        return Err(anyhow!(
+            "The {} has {} bits, but {} chunks of {} bits hold at most {} bits",
+            name,
+            bits,
+            k,
+            chunk_bits,
+            k * chunk_bits
+        ));
+    }
+    // Leading zeros are dropped, so only the chunks that can be non-zero are produced
+    let mut fields = bytes_chunk_fields(&bytes_le[..bits.div_ceil(8)], chunk_bits, 2, k);
+    fields.truncate(k.div_ceil(2));
+    Ok(fields)
+}
+
+/// Errors returned by `sha256_pad`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sha256PadError {
+    /// The data with its SHA-256 padding is longer than the maximum length.
+    TooLong {
+        padded_length: usize,
+        max_sha_bytes: usize,
+    },
+}
+
+impl fmt::Display for Sha256PadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong {
+                padded_length,
+                max_sha_bytes,
+            } => write!(
+                f,
+                "The padded message is {} bytes long, which exceeds the maximum of {} bytes",
+                padded_length, max_sha_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Sha256PadError {}
+
+/// Pads the given data to be a valid SHA-256 message block and extends it to a specified maximum length.
+///
+/// # Arguments
+///
+/// * `data` - The original data to be padded.
+/// * `max_sha_bytes` - The maximum length in bytes to which the data should be extended.
+///
+/// # Returns
+///
+/// A `Result` containing a tuple of the padded data and its length before it was extended, or a
+/// `Sha256PadError::TooLong` error if the data with its SHA-256 padding exceeds `max_sha_bytes`.
+pub fn sha256_pad(mut data: Vec<u8>, max_sha_bytes: usize) -> Result<(Vec<u8>, usize)> {
+    let length_bits = data.len() * 8; // Convert length from bytes to bits
+    let length_in_bytes = int64_to_bytes(length_bits as u64);
+
+    // Add the bit '1' to the end of the data
+    data = merge_u8_arrays(data, int8_to_bytes(0x80));
+
+    while (data.len() * 8 + length_in_bytes.len() * 8) % 512 != 0 {
+        data = merge_u8_arrays(data, int8_to_bytes(0));
+    }
+
+    // Append the original length in bits at the end of the data
+    data = merge_u8_arrays(data, length_in_bytes);
+
+    // The loop above always completes the last block
+    debug_assert!((data.len() * 8) % 512 == 0);
+
+    let message_len = data.len();
+    if message_len > max_sha_bytes {
+        return Err(Sha256PadError::TooLong {
+            padded_length: message_len,
+            max_sha_bytes,
+        }
+        .into());
+    }
+
+    // Pad the data to the specified maximum length with zeros
+    data.resize(max_sha_bytes, 0);
+
+    Ok((data, message_len))
+}
+
+/// Computes the SHA-256 hash of a message up to a specified length.
+///
+/// # Arguments
+///
+/// * `msg` - The message to be hashed.
+/// * `msg_len` - The length of the message to consider for the hash.
+///
+/// # Returns
+///
+/// A vector containing the SHA-256 hash of the message.
+pub fn partial_sha(msg: &[u8], msg_len: usize) -> Vec<u8> {
+    let mut hasher = PartialShaHasher::new();
+    hasher.update(&msg[..msg_len]);
+    hasher.finalize_state()
+}
+
+/// Computes the SHA-256 state of a message fed in chunks, as `partial_sha` does for a whole
+/// message, so that large messages need not be held in one buffer.
+pub struct PartialShaHasher {
+    hasher: hmac_sha256::Hash, // The hasher holding the state after the chunks fed so far
+}
+
+impl PartialShaHasher {
+    /// Creates a hasher that has not been fed any data.
+    pub fn new() -> Self {
+        PartialShaHasher {
+            hasher: hmac_sha256::Hash::new(),
+        }
+    }
+
+    /// Feeds the next chunk of the message.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The bytes following the ones fed so far.
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+    }
+
+    /// Returns the SHA-256 state after the chunks fed so far, without finalizing the hash.
+    ///
+    /// The state is only meaningful to a circuit if a multiple of 64 bytes was fed.
+    pub fn finalize_state(self) -> Vec<u8> {
+        self.hasher.cache_state().to_vec()
+    }
+}
+
+impl Default for PartialShaHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the remaining body is in a padded message after a partial SHA-256 precomputation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemainingRange {
+    pub start: usize,  // The offset of the remaining body, a multiple of 64
+    pub length: usize, // The SHA-256 padded length of the remaining body
+}
+
+/// Errors returned by `generate_partial_sha` and `generate_partial_sha_at_index`.
+#[derive(Debug, Clone)]
+pub enum PartialShaError {
+    /// The selector matches nothing in the message.
+    SelectorNotFound { selector: String },
+    /// The message after the cutoff is longer than the maximum remaining length.
+    RemainingTooLong { actual: usize, max: usize },
+    /// The message is not SHA-256 padded to whole 64-byte blocks.
+    InvalidPadding,
+    /// The selector is not a valid regex.
+    InvalidSelector {
+        selector: String,
+        source: regex::Error,
+    },
+}
+
+impl fmt::Display for PartialShaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SelectorNotFound { selector } => {
+                write!(f, "Selector {} not found in the body", selector)
+            }
+            Self::RemainingTooLong { actual, max } => write!(
+                f,
+                "Remaining body {} after the selector is longer than max ({})",
+                actual, max
+            ),
+            Self::InvalidPadding => write!(f, "Body is not SHA-256 padded to 64-byte blocks"),
+            Self::InvalidSelector { selector, source } => {
+                write!(f, "Selector {} is not a valid regex: {}", selector, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PartialShaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidSelector { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Generates a partial SHA-256 hash of a message up to the point of a selector string, if provided.
+///
+/// # Arguments
+///
+/// * `body` - The message body as a vector of bytes.
 /// * `body_length` - The length of the message body to consider.
 /// * `selector_regex` - An optional string which is a regex selector to find in the body to split the message.
 /// * `max_remaining_body_length` - The maximum length allowed for the remaining body after the selector.
@@ -465,76 +1145,190 @@ pub fn partial_sha(msg: &[u8], msg_len: usize) -> Vec<u8> {
 /// # Returns
 ///
 /// A tuple containing the SHA-256 hash of the pre-selector part of the message, the remaining body after the selector, and its length.
-/// If an error occurs, it is returned as a `Box<dyn Error>`.
+/// If an error occurs, it is returned as a `PartialShaError`.
 pub fn generate_partial_sha(
     body: Vec<u8>,
     body_length: usize,
     selector_regex: Option<String>,
     max_remaining_body_length: usize,
 ) -> PartialShaResult {
+    let (precomputed_sha, range) = generate_partial_sha_range(
+        &body,
+        body_length,
+        selector_regex.as_deref(),
+        max_remaining_body_length,
+    )?;
+    Ok((
+        precomputed_sha,
+        remaining_body(body, range, max_remaining_body_length),
+        range.length,
+    ))
+}
+
+/// Generates a partial SHA-256 hash of a message up to the point of a selector string, if
+/// provided, without copying the message.
+///
+/// # Arguments
+///
+/// * `body` - The message body as a slice of bytes.
+/// * `body_length` - The length of the message body to consider.
+/// * `selector_regex` - An optional regex selector to find in the body to split the message.
+/// * `max_remaining_body_length` - The maximum length allowed for the remaining body after the selector.
+///
+/// # Returns
+///
+/// A tuple containing the SHA-256 hash of the pre-selector part of the message and the range of
+/// the remaining body in `body`, which the caller copies if needed.
+/// If an error occurs, it is returned as a `PartialShaError`.
+pub fn generate_partial_sha_range(
+    body: &[u8],
+    body_length: usize,
+    selector_regex: Option<&str>,
+    max_remaining_body_length: usize,
+) -> PartialShaRangeResult {
     let mut selector_index = 0;
 
     // Check if a selector is provided
     if let Some(selector) = selector_regex {
-        // Create a regex pattern from the selector
-        let pattern = regex::Regex::new(&selector).unwrap();
-        let body_str = {
-            // Undo SHA padding
-            let mut trimmed_body = body.clone();
-            while !(trimmed_body.last() == Some(&10)
-                && trimmed_body.get(trimmed_body.len() - 2) == Some(&13))
-            {
-                trimmed_body.pop();
+        // Create a regex pattern from the selector, matched over bytes as bodies need not be UTF-8
+        let pattern = regex::bytes::Regex::new(selector).map_err(|source| {
+            PartialShaError::InvalidSelector {
+                selector: selector.to_string(),
+                source,
             }
-
-            String::from_utf8(trimmed_body).unwrap()
+        })?;
+        let message = {
+            // Undo SHA padding, whose last 8 bytes hold the length of the message in bits, after
+            // a 0x80 marker and zero fill
+            let message_length = body_length
+                .checked_sub(8)
+                .filter(|_| body_length % 64 == 0 && body_length <= body.len())
+                .and_then(|start| body[start..body_length].try_into().ok())
+                .map(|bits| (u64::from_be_bytes(bits) / 8) as usize)
+                .filter(|length| length + 9 <= body_length)
+                .filter(|&length| {
+                    body[length] == 0x80
+                        && body[length + 1..body_length - 8].iter().all(|&b| b == 0)
+                })
+                .ok_or(PartialShaError::InvalidPadding)?;
+
+            &body[..message_length]
         };
 
-        // Find the index of the selector in the body
-        if let Some(matched) = pattern.find(&body_str) {
+        // Find the byte index of the selector in the body
+        if let Some(matched) = pattern.find(message) {
             selector_index = matched.start();
         } else {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Selector {} not found in the body", selector),
-            )));
+            return Err(PartialShaError::SelectorNotFound {
+                selector: selector.to_string(),
+            });
         }
     };
 
+    generate_partial_sha_range_at_index(
+        body,
+        body_length,
+        selector_index,
+        max_remaining_body_length,
+    )
+}
+
+/// Generates a partial SHA-256 hash of a message up to the 64-byte block containing the given index.
+///
+/// # Arguments
+///
+/// * `body` - The message body as a vector of bytes.
+/// * `body_length` - The length of the message body to consider.
+/// * `selector_index` - The index in the body that must remain in the part that is not precomputed.
+/// * `max_remaining_body_length` - The maximum length allowed for the remaining body after the selector.
+///
+/// # Returns
+///
+/// A tuple containing the SHA-256 hash of the pre-selector part of the message, the remaining body after the selector, and its length.
+/// If an error occurs, it is returned as a `PartialShaError`.
+pub fn generate_partial_sha_at_index(
+    body: Vec<u8>,
+    body_length: usize,
+    selector_index: usize,
+    max_remaining_body_length: usize,
+) -> PartialShaResult {
+    let (precomputed_sha, range) = generate_partial_sha_range_at_index(
+        &body,
+        body_length,
+        selector_index,
+        max_remaining_body_length,
+    )?;
+    Ok((
+        precomputed_sha,
+        remaining_body(body, range, max_remaining_body_length),
+        range.length,
+    ))
+}
+
+/// Generates a partial SHA-256 hash of a message up to the 64-byte block containing the given
+/// index, without copying the message.
+///
+/// # Arguments
+///
+/// * `body` - The message body as a slice of bytes.
+/// * `body_length` - The length of the message body to consider.
+/// * `selector_index` - The index in the body that must remain in the part that is not precomputed.
+/// * `max_remaining_body_length` - The maximum length allowed for the remaining body after the selector.
+///
+/// # Returns
+///
+/// A tuple containing the SHA-256 hash of the pre-selector part of the message and the range of
+/// the remaining body in `body`, which the caller copies if needed.
+/// If an error occurs, it is returned as a `PartialShaError`.
+pub fn generate_partial_sha_range_at_index(
+    body: &[u8],
+    body_length: usize,
+    selector_index: usize,
+    max_remaining_body_length: usize,
+) -> PartialShaRangeResult {
     // Calculate the cutoff index for SHA-256 block size (64 bytes)
     let sha_cutoff_index = (selector_index / 64) * 64;
-    let precompute_text = &body[..sha_cutoff_index];
-    let mut body_remaining = body[sha_cutoff_index..].to_vec();
+    if sha_cutoff_index > body_length || body_length > body.len() {
+        return Err(PartialShaError::InvalidPadding);
+    }
 
-    let body_remaining_length = body_length - precompute_text.len();
+    let body_remaining_length = body_length - sha_cutoff_index;
 
     // Check if the remaining body length exceeds the maximum allowed length
     if body_remaining_length > max_remaining_body_length {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!(
-                "Remaining body {} after the selector is longer than max ({})",
-                body_remaining_length, max_remaining_body_length
-            ),
-        )));
+        return Err(PartialShaError::RemainingTooLong {
+            actual: body_remaining_length,
+            max: max_remaining_body_length,
+        });
     }
 
     // Ensure the remaining body is padded correctly to 64-byte blocks
-    if body_remaining.len() % 64 != 0 {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Remaining body was not padded correctly with int64s",
-        )));
-    }
-
-    // Pad the remaining body to the maximum length with zeros
-    while body_remaining.len() < max_remaining_body_length {
-        body_remaining.push(0);
+    if (body.len() - sha_cutoff_index) % 64 != 0 {
+        return Err(PartialShaError::InvalidPadding);
     }
 
     // Compute the SHA-256 hash of the pre-selector part of the message
-    let precomputed_sha = partial_sha(precompute_text, sha_cutoff_index);
-    Ok((precomputed_sha, body_remaining, body_remaining_length))
+    let precomputed_sha = partial_sha(body, sha_cutoff_index);
+    Ok((
+        precomputed_sha,
+        RemainingRange {
+            start: sha_cutoff_index,
+            length: body_remaining_length,
+        },
+    ))
+}
+
+/// Moves the remaining body out of a padded message, padding it with zeros to the maximum length.
+fn remaining_body(
+    mut body: Vec<u8>,
+    range: RemainingRange,
+    max_remaining_body_length: usize,
+) -> Vec<u8> {
+    body.drain(..range.start);
+    if body.len() < max_remaining_body_length {
+        body.resize(max_remaining_body_length, 0);
+    }
+    body
 }
 
 /// Computes the Keccak-256 hash of the given data.
@@ -550,142 +1344,2243 @@ pub fn keccak256(data: &[u8]) -> Bytes {
     Bytes::from(ethers::utils::keccak256(data))
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::field_to_hex;
-
-    use super::*;
-
-    #[test]
-    fn test_public_key_hash() {
-        let mut public_key_n = hex::decode("cfb0520e4ad78c4adb0deb5e605162b6469349fc1fde9269b88d596ed9f3735c00c592317c982320874b987bcc38e8556ac544bdee169b66ae8fe639828ff5afb4f199017e3d8e675a077f21cd9e5c526c1866476e7ba74cd7bb16a1c3d93bc7bb1d576aedb4307c6b948d5b8c29f79307788d7a8ebf84585bf53994827c23a5").unwrap();
-        public_key_n.reverse();
-        let hash_field = public_key_hash(&public_key_n).unwrap();
-        let expected_hash = format!(
-            "0x{}",
-            hex::encode([
-                24, 26, 185, 80, 217, 115, 238, 83, 131, 133, 50, 236, 177, 184, 177, 21, 40, 246,
-                234, 122, 176, 142, 40, 104, 251, 50, 24, 70, 64, 82, 249, 83
-            ])
-        );
-        assert_eq!(field_to_hex(&hash_field), expected_hash);
-    }
-}
-
-/// Calculates a default hash for the given input string.
+/// Computes the Keccak-256 hash of an EIP-191 personal message, as signed by `eth_sign` and
+/// checked on chain against `"\x19Ethereum Signed Message:\n" + len(data) + data`.
 ///
 /// # Arguments
 ///
-/// * `input` - The input string to hash.
+/// * `data` - A byte slice representing the message.
 ///
 /// # Returns
 ///
-/// A string representation of the calculated hash.
-pub fn calculate_default_hash(input: &str) -> String {
-    let mut hasher = DefaultHasher::new();
-    input.hash(&mut hasher);
-    let hash_code = hasher.finish();
-
-    hash_code.to_string()
+/// The Keccak-256 hash of the prefixed message as a `Bytes` object.
+pub fn keccak256_eth_signed_message(data: &[u8]) -> Bytes {
+    // The length is written in decimal
+    let mut message = format!("\x19Ethereum Signed Message:\n{}", data.len()).into_bytes();
+    message.extend_from_slice(data);
+    keccak256(&message)
 }
 
-/// Calculates the account salt based on the email address and account code.
+/// Computes the Keccak-256 hash of tokens encoded as Solidity's `abi.encodePacked` does.
 ///
 /// # Arguments
 ///
-/// * `email_addr` - The email address string.
-/// * `account_code` - The account code string.
+/// * `tokens` - A slice of the tokens to encode.
 ///
 /// # Returns
 ///
-/// A string representation of the calculated account salt.
-pub fn calculate_account_salt(email_addr: &str, account_code: &str) -> String {
-    // Pad the email address
-    let padded_email_addr = PaddedEmailAddr::from_email_addr(email_addr);
-
-    // Convert account code to field element
-    let account_code = if account_code.starts_with("0x") {
-        hex_to_field(account_code).unwrap()
-    } else {
-        hex_to_field(&format!("0x{}", account_code)).unwrap()
-    };
-    let account_code = AccountCode::from(account_code);
-
-    // Generate account salt
-    let account_salt = AccountSalt::new(&padded_email_addr, account_code).unwrap();
-
-    // Convert account salt to hexadecimal representation
-    field_to_hex(&account_salt.0)
+/// A result that is either the Keccak-256 hash as a `Bytes` object or an error if the tokens
+/// cannot be packed, e.g. nested dynamic arrays.
+pub fn keccak256_packed(tokens: &[Token]) -> Result<Bytes> {
+    let packed =
+        abi::encode_packed(tokens).map_err(|e| anyhow!("Failed to pack the tokens: {}", e))?;
+    Ok(keccak256(&packed))
 }
 
-/// Fetches the public key from DNS records using the DKIM signature in the email headers.
+/// Computes the Keccak-256 hash of field elements, each encoded as 32 big-endian bytes, i.e.
+/// `keccak256(abi.encodePacked(uint256(f0), uint256(f1), ...))` in Solidity.
 ///
 /// # Arguments
 ///
-/// * `email_headers` - An `EmailHeaders` object containing the headers of the email.
+/// * `fields` - A slice of the field elements to hash.
 ///
 /// # Returns
 ///
-/// A `Result` containing a vector of bytes representing the public key, or an error if the key is not found.
-pub async fn fetch_public_key(email_headers: EmailHeaders) -> Result<Vec<u8>> {
-    let mut selector = String::new();
-    let mut domain = String::new();
-
-    // Extract the selector and domain from the DKIM-Signature header
-    if let Some(headers) = email_headers.get_header("DKIM-Signature") {
-        if let Some(header) = headers.first() {
-            let s_re = Regex::new(r"s=([^;]+);").unwrap();
-            let d_re = Regex::new(r"d=([^;]+);").unwrap();
-
-            selector = s_re
-                .captures(header)
-                .and_then(|cap| cap.get(1))
-                .map_or("", |m| m.as_str())
-                .to_string();
-            domain = d_re
-                .captures(header)
-                .and_then(|cap| cap.get(1))
-                .map_or("", |m| m.as_str())
-                .to_string();
-        }
-    }
-
-    // Fetch the DNS TXT record for the domain key
-    let response = reqwest::get(format!(
-        "https://archive.zk.email/api/key?domain={}&selector={}",
-        domain, selector
-    ))
-    .await?;
-    let data: serde_json::Value = response.json().await?;
-
-    // Extract the 'p' value from the first record
-    let p_value = data
-        .as_array()
-        .and_then(|arr| arr.first())
-        .and_then(|record| record.get("value"))
-        .and_then(|value| value.as_str())
-        .and_then(|data| {
-            data.split(';')
-                .find(|part| part.trim().starts_with("p="))
-                .map(|part| part.trim()[2..].to_string())
-        });
-
-    if let Some(public_key_b64) = p_value {
-        // Decode the base64 string to get the public key bytes
-        let public_key_bytes = base64::decode(public_key_b64)?;
-
-        // Load the public key from DER format
-        let public_key: rsa::RsaPublicKey =
-            rsa::RsaPublicKey::from_public_key_der(&public_key_bytes)?;
+/// The Keccak-256 hash as a `Bytes` object.
+pub fn keccak256_of_fields(fields: &[Fr]) -> Bytes {
+    let bytes = fields
+        .iter()
+        .flat_map(|field| {
+            // `to_bytes` is little-endian
+            let mut bytes = field.to_bytes();
+            bytes.reverse();
+            bytes
+        })
+        .collect::<Vec<u8>>();
+    keccak256(&bytes)
+}
 
-        // Extract the modulus from the public key
-        let modulus = public_key.n();
+#[cfg(test)]
+mod tests {
+    use crate::field_to_hex;
 
-        // Convert the modulus to a byte array in big-endian order
-        let modulus_bytes: Vec<u8> = modulus.to_bytes_be();
+    use super::*;
 
-        Ok(modulus_bytes)
-    } else {
-        Err(anyhow::anyhow!("Public key not found"))
+    #[test]
+    fn test_public_key_hash() {
+        let mut public_key_n = hex::decode("cfb0520e4ad78c4adb0deb5e605162b6469349fc1fde9269b88d596ed9f3735c00c592317c982320874b987bcc38e8556ac544bdee169b66ae8fe639828ff5afb4f199017e3d8e675a077f21cd9e5c526c1866476e7ba74cd7bb16a1c3d93bc7bb1d576aedb4307c6b948d5b8c29f79307788d7a8ebf84585bf53994827c23a5").unwrap();
+        public_key_n.reverse();
+        let hash_field = public_key_hash(&public_key_n).unwrap();
+        let expected_hash = format!(
+            "0x{}",
+            hex::encode([
+                24, 26, 185, 80, 217, 115, 238, 83, 131, 133, 50, 236, 177, 184, 177, 21, 40, 246,
+                234, 122, 176, 142, 40, 104, 251, 50, 24, 70, 64, 82, 249, 83
+            ])
+        );
+        assert_eq!(field_to_hex(&hash_field), expected_hash);
+    }
+
+    #[test]
+    fn test_poseidon_modular() {
+        let fields = (1..=34u64).map(Fr::from).collect::<Vec<_>>();
+        // Up to 16 fields, it is the plain Poseidon hash
+        assert_eq!(
+            field_to_hex(&poseidon_modular(&fields[..16]).unwrap()),
+            "0x16159a551cbb66108281a48099fff949ae08afd7f1f2ec06de2ffb96b919b765"
+        );
+        assert_eq!(
+            poseidon_modular(&fields[..16]).unwrap(),
+            poseidon_fields(&fields[..16]).unwrap()
+        );
+        // The 34 fields of a 1024-byte header are hashed in chunks of 16, 16 and 2
+        assert_eq!(
+            field_to_hex(&poseidon_modular(&fields).unwrap()),
+            "0x0c8dedf7847c5a1873b24e2754d1084c9c842b732f4ac5de4b27dc8f6fcdb899"
+        );
+        assert!(poseidon_modular(&[]).is_err());
+    }
+
+    #[test]
+    fn test_public_key_hash_key_sizes() {
+        // A 1024-bit modulus is zero-padded into the 17 chunks of the 2048-bit circuits
+        let mut public_key_1024 = hex::decode("cfb0520e4ad78c4adb0deb5e605162b6469349fc1fde9269b88d596ed9f3735c00c592317c982320874b987bcc38e8556ac544bdee169b66ae8fe639828ff5afb4f199017e3d8e675a077f21cd9e5c526c1866476e7ba74cd7bb16a1c3d93bc7bb1d576aedb4307c6b948d5b8c29f79307788d7a8ebf84585bf53994827c23a5").unwrap();
+        public_key_1024.reverse();
+        let expected = "0x181ab950d973ee53838532ecb1b8b11528f6ea7ab08e2868fb3218464052f953";
+        assert_eq!(
+            field_to_hex(&public_key_hash(&public_key_1024).unwrap()),
+            expected
+        );
+        assert_eq!(
+            field_to_hex(&public_key_hash_with_k(&public_key_1024, CIRCOM_BIGINT_K).unwrap()),
+            expected
+        );
+        // Leading zero bytes within 2048 bits do not change the hash
+        let mut padded = public_key_1024.clone();
+        padded.resize(256, 0);
+        assert_eq!(field_to_hex(&public_key_hash(&padded).unwrap()), expected);
+
+        // Larger moduli are split into as many chunks as their byte length needs
+        let public_key_3872 = vec![0xabu8; 484];
+        assert_eq!(
+            default_chunk_count(&public_key_3872, "public key").unwrap(),
+            32
+        );
+        assert_eq!(
+            field_to_hex(&public_key_hash(&public_key_3872).unwrap()),
+            "0x2db2d1e5d649da3315485a774aaef4f825b2e261ba52be3a9eea5552d27a7d10"
+        );
+
+        // A 4096-bit modulus is split into 34 chunks, hashed in two chained Poseidon blocks
+        let public_key_4096 = vec![0xffu8; 512];
+        assert_eq!(
+            default_chunk_count(&public_key_4096, "public key").unwrap(),
+            MAX_CIRCOM_BIGINT_K
+        );
+        let expected = "0x07e245af8df7c98e6327f3718179bb91e28c0c83fbe58ba177dc6db94c47b68d";
+        assert_eq!(
+            field_to_hex(&public_key_hash(&public_key_4096).unwrap()),
+            expected
+        );
+        assert_eq!(
+            field_to_hex(&public_key_hash_with_k(&public_key_4096, 34).unwrap()),
+            expected
+        );
+        let expected = "0x0c1480c020dffc62fb0553e427041d4ad2bea0be7c84f435ac809ced2449304f";
+        assert_eq!(
+            field_to_hex(&email_nullifier(&public_key_4096).unwrap()),
+            expected
+        );
+        assert_eq!(
+            field_to_hex(&email_nullifier_with_k(&public_key_4096, 34).unwrap()),
+            expected
+        );
+
+        let err = public_key_hash_with_k(&public_key_4096, CIRCOM_BIGINT_K).unwrap_err();
+        assert!(err.to_string().contains(
+            "The public key has 4096 bits, but 17 chunks of 121 bits hold at most 2057 bits"
+        ));
+        let err = public_key_hash_with_k(&public_key_4096, 35).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The public key cannot be split into 35 chunks, at most 34 chunks are supported"
+        );
+        let err = public_key_hash(&[0xff; 515]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The public key has 515 bytes, but at most 514 bytes are supported"
+        );
+    }
+
+    #[test]
+    fn test_extract_rand_from_signature_sizes() {
+        // Signatures of 1024-bit keys fit in the 17 chunks of the 2048-bit circuits
+        let signature_1024 = vec![0xabu8; 128];
+        assert_eq!(
+            field_to_hex(&extract_rand_from_signature(&signature_1024).unwrap()),
+            "0x1b496320f9446d38fd9080dadaff53bc652414a689a23ff6d26c0b504879c40d"
+        );
+        assert_eq!(
+            extract_rand_from_signature(&signature_1024).unwrap(),
+            extract_rand_from_signature_with_k(&signature_1024, CIRCOM_BIGINT_K).unwrap()
+        );
+        assert_eq!(
+            field_to_hex(&email_nullifier(&signature_1024).unwrap()),
+            "0x138e5433803be64ac7c8fc115d02aefe53df8aa6f88ab2d0ec1b0868cce725cf"
+        );
+
+        // With the extra input, more than 30 chunks spill into a second chained Poseidon block
+        assert_eq!(
+            field_to_hex(&extract_rand_from_signature(&[0xab; 453]).unwrap()),
+            "0x08208a832d90e1a713099df6e1b9e1f048255313fe02f627fb07a4627a29cbb3"
+        );
+        let signature_3872 = vec![0xabu8; 484];
+        assert_eq!(
+            field_to_hex(&extract_rand_from_signature(&signature_3872).unwrap()),
+            "0x0d7f148b02065e53cc59dc425deba6bd2ac500301dcbc4b3fd9d91fcd85a92ad"
+        );
+        assert_eq!(
+            field_to_hex(&email_nullifier(&signature_3872).unwrap()),
+            "0x09d8b6792f359e22e910ac5b98384558106c9971f2551d5e673d483084c0850c"
+        );
+        // Signatures share the chunk limit of public keys, which covers 4096-bit keys
+        let signature_4096 = vec![0xffu8; 512];
+        assert_eq!(
+            field_to_hex(&extract_rand_from_signature(&signature_4096).unwrap()),
+            "0x28195d6927122a0ecbdc2a279a3278bbb016415be3682a2abb5da29262d0309a"
+        );
+        let err = extract_rand_from_signature_with_k(&signature_4096, 35).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The signature cannot be split into 35 chunks, at most 34 chunks are supported"
+        );
+
+        let err = extract_rand_from_signature(&[0xff; 1024]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The signature has 1024 bytes, but at most 514 bytes are supported"));
+        assert!(email_nullifier(&[0xff; 1024]).is_err());
+        assert!(extract_rand_from_signature(&[]).is_err());
+    }
+
+    #[test]
+    fn test_keccak256_helpers() {
+        let message = b"hello world";
+        let hash = keccak256_eth_signed_message(message);
+        assert_eq!(
+            hash.to_vec(),
+            ethers::utils::hash_message(message).as_bytes().to_vec()
+        );
+        assert_eq!(
+            hex::encode(&hash),
+            "d9eba16ed0ecae432b71fe008c98cc872bb4cc214d3220a36f365326cf807d68"
+        );
+        assert_eq!(
+            keccak256_eth_signed_message(&[]).to_vec(),
+            ethers::utils::hash_message([]).as_bytes().to_vec()
+        );
+        // The length prefix has more than one digit
+        let long_message = vec![b'a'; 100];
+        assert_eq!(
+            keccak256_eth_signed_message(&long_message).to_vec(),
+            ethers::utils::hash_message(&long_message)
+                .as_bytes()
+                .to_vec()
+        );
+
+        let tokens = vec![
+            Token::Address(ethers::types::Address::from_low_u64_be(1)),
+            Token::Uint(ethers::types::U256::from(1)),
+            Token::String("abc".to_string()),
+            Token::Bool(true),
+            Token::FixedBytes(vec![0xaa, 0xbb]),
+        ];
+        assert_eq!(
+            hex::encode(keccak256_packed(&tokens).unwrap()),
+            "852221d90b426a9bbe32dd8d5589a674dea0595f89c5dc1368bd5ace65268ea5"
+        );
+        assert_eq!(
+            keccak256_packed(&tokens).unwrap(),
+            keccak256(&abi::encode_packed(&tokens).unwrap())
+        );
+
+        let fields = [Fr::from(1u64), Fr::from(2u64)];
+        assert_eq!(
+            hex::encode(keccak256_of_fields(&fields)),
+            "e90b7bceb6e7df5418fb78d8ee546e97c83a08bbccc01a0644d599ccd2a7c2e0"
+        );
+        assert_eq!(
+            keccak256_of_fields(&fields),
+            keccak256_packed(&[
+                Token::Uint(ethers::types::U256::from(1)),
+                Token::Uint(ethers::types::U256::from(2)),
+            ])
+            .unwrap()
+        );
+        assert_eq!(
+            hex::encode(keccak256_of_fields(&[])),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn test_to_commitment_with_nonce_bytes() {
+        let padded_email_addr = PaddedEmailAddr::try_from_email_addr("alice@example.com").unwrap();
+
+        // The nonce is a big-endian bytes32, so these are the field elements 1 and p - 1
+        let mut nonce_one = [0u8; 32];
+        nonce_one[31] = 1;
+        assert_eq!(
+            field_to_hex(
+                &padded_email_addr
+                    .to_commitment_with_nonce_bytes(&nonce_one)
+                    .unwrap()
+            ),
+            "0x1804890235503973a1c55e5e1c867652ded674ec16f9d15ed6a2d58df0862dc6"
+        );
+        assert_eq!(
+            padded_email_addr
+                .to_commitment_with_nonce_bytes(&nonce_one)
+                .unwrap(),
+            padded_email_addr.to_commitment(&Fr::one()).unwrap()
+        );
+        let nonce_max: [u8; 32] =
+            hex::decode("30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000000")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            field_to_hex(
+                &padded_email_addr
+                    .to_commitment_with_nonce_bytes(&nonce_max)
+                    .unwrap()
+            ),
+            "0x2534bf86f9671c140165f2b798cfeca6d70d57d97d180030b2348a5aac250c1d"
+        );
+        assert_eq!(
+            padded_email_addr
+                .to_commitment_with_nonce_bytes(&nonce_max)
+                .unwrap(),
+            padded_email_addr.to_commitment(&-Fr::one()).unwrap()
+        );
+        assert_eq!(
+            field_to_hex(
+                &padded_email_addr
+                    .to_commitment_with_nonce_bytes(&[0u8; 32])
+                    .unwrap()
+            ),
+            "0x00c1deaee63d7644c26ee5f682f07f1b49425d43302fa934208c0ccf896e6801"
+        );
+
+        // The modulus and values above it are not canonical
+        let mut modulus = nonce_max;
+        modulus[31] = 1;
+        let err = padded_email_addr
+            .to_commitment_with_nonce_bytes(&modulus)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The nonce 0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001 is not a field element"
+        );
+        assert!(padded_email_addr
+            .to_commitment_with_nonce_bytes(&[0xff; 32])
+            .is_err());
+    }
+
+    #[test]
+    fn test_to_commitment_with_signature_params() {
+        let padded_email_addr = PaddedEmailAddr::try_from_email_addr("alice@example.com").unwrap();
+        let signature = vec![0xabu8; 256];
+        let cm = padded_email_addr
+            .to_commitment_with_signature(&signature)
+            .unwrap();
+        assert_eq!(
+            cm,
+            padded_email_addr
+                .to_commitment_with_signature_params(&signature, CIRCOM_BIGINT_N, CIRCOM_BIGINT_K)
+                .unwrap()
+        );
+        assert_eq!(
+            cm,
+            padded_email_addr
+                .to_commitment(&extract_rand_from_signature(&signature).unwrap())
+                .unwrap()
+        );
+
+        // A 4096-bit signature does not fit in the chunks of the 2048-bit circuits
+        let err = padded_email_addr
+            .to_commitment_with_signature_params(&[0xab; 512], CIRCOM_BIGINT_N, CIRCOM_BIGINT_K)
+            .unwrap_err();
+        assert!(err.to_string().contains("The signature has 4096 bits"));
+        let err = padded_email_addr
+            .to_commitment_with_signature_params(&signature, 127, CIRCOM_BIGINT_K)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Chunks of 127 bits are not supported"));
+        assert!(padded_email_addr
+            .to_commitment_with_signature_params(&signature, CIRCOM_BIGINT_N, 0)
+            .is_err());
+        assert!(padded_email_addr
+            .to_commitment_with_signature(&[0xff; 1024])
+            .is_err());
+        assert!(padded_email_addr.to_commitment_with_signature(&[]).is_err());
+    }
+
+    #[test]
+    fn test_email_nullifier_v2() {
+        let signature = vec![0xabu8; 256];
+        let tag = Fr::from(7u64);
+        let nullifier = email_nullifier_v2(&signature, &tag).unwrap();
+        assert_eq!(
+            field_to_hex(&nullifier),
+            "0x2924d1bd1a262bfdfa83618f93b73ddeb2ad71814733e48af29e70d19b00addf"
+        );
+
+        // The documented input ordering
+        let sign_rand = poseidon_fields(&bytes_chunk_fields(&signature, 121, 2, 17)).unwrap();
+        assert_eq!(
+            nullifier,
+            poseidon_fields(&[Fr::from(2u64), tag, sign_rand]).unwrap()
+        );
+
+        // Different applications get unrelated nullifiers, also unrelated to the first version
+        assert_eq!(
+            field_to_hex(&email_nullifier_v2(&signature, &Fr::from(8u64)).unwrap()),
+            "0x2323c4a5513b9f3aef3558a3df087eb2150d39b57ffd2a77cb3c870a80d4c7e7"
+        );
+        assert_ne!(email_nullifier(&signature).unwrap(), nullifier);
+    }
+
+    #[test]
+    fn test_account_code_try_from_hex() {
+        let account_code = AccountCode::try_from_hex(&"01".repeat(32)).unwrap();
+        assert_eq!(account_code.to_hex(), format!("0x{}", "01".repeat(32)));
+        assert_eq!(
+            AccountCode::try_from_hex(&account_code.to_hex()).unwrap().0,
+            account_code.0
+        );
+        assert_eq!(
+            account_code.to_hex(),
+            field_to_hex(&hex_to_field(&account_code.to_hex()).unwrap())
+        );
+
+        let err = AccountCode::try_from_hex("0x0102").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Account code must be 32 bytes but is 2 bytes"
+        );
+        assert!(AccountCode::try_from_hex("0xzz").is_err());
+        assert!(AccountCode::try_from_hex(&"ff".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_calculate_account_salt() {
+        let account_code = format!("0x{}", "01".repeat(32));
+        let expected = field_to_hex(
+            &AccountSalt::new(
+                &PaddedEmailAddr::try_from_email_addr("alice@example.com").unwrap(),
+                AccountCode::from(hex_to_field(&account_code).unwrap()),
+            )
+            .unwrap()
+            .0,
+        );
+        assert_eq!(
+            calculate_account_salt("alice@example.com", &account_code).unwrap(),
+            expected
+        );
+        assert_eq!(
+            calculate_account_salt("alice@example.com", &account_code[2..]).unwrap(),
+            expected
+        );
+
+        let err = calculate_account_salt("alice@example.com", "0x0102").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Account code must be 32 bytes but is 2 bytes"
+        );
+        let err = calculate_account_salt("alice@example.com", "0xzz").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Account code 0xzz is not valid hex"));
+        let err = calculate_account_salt("alice@example.com", &"ff".repeat(32)).unwrap_err();
+        assert!(err.to_string().contains("is not a field element"));
+        let err = calculate_account_salt("", &account_code).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid email address for the account salt"));
+    }
+
+    #[test]
+    fn test_calculate_account_salt_normalization() {
+        let account_code = format!("0x{}", "01".repeat(32));
+        let canonical = calculate_account_salt("alice@gmail.com", &account_code).unwrap();
+
+        // The default stays byte-exact
+        assert_ne!(
+            calculate_account_salt(" Alice@Gmail.com ", &account_code).unwrap(),
+            canonical
+        );
+        assert_ne!(
+            calculate_account_salt_with_policy(
+                "Alice@Gmail.com",
+                &account_code,
+                NormalizationPolicy::default()
+            )
+            .unwrap(),
+            canonical
+        );
+
+        let policy = NormalizationPolicy {
+            trim_whitespace: true,
+            lowercase_local_part: true,
+            lowercase_domain: true,
+        };
+        assert_eq!(
+            calculate_account_salt_with_policy(" Alice@Gmail.com\t", &account_code, policy)
+                .unwrap(),
+            canonical
+        );
+
+        let domain_only = NormalizationPolicy {
+            lowercase_domain: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            domain_only.normalize(" Alice@Gmail.com"),
+            " Alice@gmail.com"
+        );
+        let padded =
+            PaddedEmailAddr::from_email_addr_normalized("Alice@Gmail.com ", domain_only).unwrap();
+        assert_eq!(padded.email_addr_len, "Alice@gmail.com ".len());
+        assert!(PaddedEmailAddr::from_email_addr_normalized("   ", policy).is_err());
+    }
+
+    #[test]
+    fn test_calculate_default_hash() {
+        assert_eq!(calculate_default_hash(""), "16406829232824261652");
+        assert_eq!(calculate_default_hash("hello"), "3238736544897475342");
+        assert_eq!(
+            calculate_default_hash("alice@example.com"),
+            "18414541688758211263"
+        );
+    }
+
+    #[test]
+    fn test_parse_dkim_public_key_record() {
+        // The ed25519 key from RFC 8463, Appendix A.2
+        let ed25519_key = parse_dkim_public_key_record(
+            "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        )
+        .unwrap();
+        assert!(matches!(ed25519_key, EmailPublicKey::Ed25519(ref key) if key.len() == 32));
+        assert!(ed25519_key.rsa_modulus().is_err());
+
+        // The RSA key from RFC 8463, Appendix A.2
+        let rsa_key = parse_dkim_public_key_record(
+            "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB",
+        )
+        .unwrap();
+        assert_eq!(rsa_key.rsa_modulus().unwrap().len(), 128);
+        assert_eq!(
+            parse_dkim_public_key_exponent(
+                "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB"
+            )
+            .unwrap(),
+            vec![0x01, 0x00, 0x01]
+        );
+        assert!(parse_dkim_public_key_exponent(
+            "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+        )
+        .unwrap()
+        .is_empty());
+
+        // The same RSA key as a PKCS#1 RSAPublicKey
+        let pkcs1_record = "v=DKIM1; p=MIGJAoGBAOQeU5CgFPNZGIazlXo2k/eJ1jpaTTxrmqF1HrDLlt04pvaMtCJj8nXoliLRC/H9vJjMI1vdb3XLcW60AIN/PBD8EL97/y4GwJH7LPUvGP48vqUe+owqszesbiGy1PlCO8c70/OjFnJVgvMF87YR4Lcincb7aSvI5MgpP6X8rjDdAgMBAAE=";
+        assert_eq!(parse_dkim_public_key_record(pkcs1_record).unwrap(), rsa_key);
+        assert_eq!(
+            parse_dkim_public_key_exponent(pkcs1_record).unwrap(),
+            vec![0x01, 0x00, 0x01]
+        );
+
+        // An ed25519 key published without a k= tag
+        assert_eq!(
+            parse_dkim_public_key_record("v=DKIM1; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=")
+                .unwrap(),
+            ed25519_key
+        );
+        assert!(parse_dkim_public_key_record(
+            "v=DKIM1; k=rsa; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+        )
+        .is_err());
+
+        assert!(parse_dkim_public_key_record("v=DKIM1; k=dsa; p=AAAA").is_err());
+        assert!(parse_dkim_public_key_record("v=DKIM1; k=rsa").is_err());
+    }
+
+    #[test]
+    fn test_verify_dkim_header_signature_with_exponent() {
+        // A 1024-bit key with the public exponent 3, signing a header with a=rsa-sha256
+        let modulus = hex::decode("b6a982497fe2913a67e2c43fcd083305e095c13ae5f91730ea1189c6b47fbaed5f4f034ec97bf5c121cb8de028506e613da67e24c96cf5a82842ffcb860ffd51c478a85055402e79924c1d89e60f26351ed27f8de62ff9b885640f33575c7ef2c2e4b379ee41a18d3715c59b54f0977d3a4cdd6f2ee319fe2382d2f6fe9fc683").unwrap();
+        let signature = hex::decode("874fa78aca0704dee795c49eb01ac17e91e0d0486e75929ebbdde202bba65ecd677404884177c7c4b5a75ba85565370f91457c22e93dc47cb234c6ade95498d4323b6cd0ce89536fcd86a8e74fd7f402da413a182898b75a8b494b05d18bd9489e9cbdb540da16d8bddfdab31314f114b1da7c24a3ae8c90fb3889aea50a5f92").unwrap();
+        let header = "from:alice@example.com\r\ndkim-signature:v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=e3; h=from; bh=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=; b=";
+        let public_key = EmailPublicKey::Rsa(modulus);
+
+        assert!(verify_dkim_header_signature(header, &signature, &public_key, &[3]).is_ok());
+        // Assuming the default exponent 65537 must not verify
+        assert!(verify_dkim_header_signature(header, &signature, &public_key, &[]).is_err());
+        assert!(
+            verify_dkim_header_signature(header, &signature, &public_key, &[0x01, 0x00, 0x01])
+                .is_err()
+        );
+
+        // The a= tag selects the algorithm, and rsa-sha1 is not accepted
+        let sha1_header = header.replace("a=rsa-sha256", "a=rsa-sha1");
+        assert!(verify_dkim_header_signature(&sha1_header, &signature, &public_key, &[3]).is_err());
+        let ed25519_header = header.replace("a=rsa-sha256", "a=ed25519-sha256");
+        assert!(
+            verify_dkim_header_signature(&ed25519_header, &signature, &public_key, &[3]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_public_key() {
+        assert_eq!(
+            DkimAlgorithm::from_tag("rsa-sha256").unwrap(),
+            DkimAlgorithm::RsaSha256
+        );
+        assert_eq!(
+            DkimAlgorithm::from_tag("ed25519-sha256").unwrap(),
+            DkimAlgorithm::Ed25519Sha256
+        );
+        assert!(DkimAlgorithm::from_tag("rsa-sha1").is_err());
+        assert!(DkimAlgorithm::from_tag("").is_err());
+
+        // The RSA key from RFC 8463, Appendix A.2
+        let rsa_der = general_purpose::STANDARD.decode("MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB").unwrap();
+        let rsa_key = decode_public_key(&rsa_der, DkimAlgorithm::RsaSha256).unwrap();
+        assert_eq!(rsa_key.rsa_modulus().unwrap().len(), 128);
+        assert_eq!(
+            decode_public_key_exponent(&rsa_der, DkimAlgorithm::RsaSha256),
+            vec![0x01, 0x00, 0x01]
+        );
+        assert!(decode_public_key(&rsa_der, DkimAlgorithm::Ed25519Sha256).is_err());
+
+        // A bare modulus is an RSA key for an RSA signature, whatever its length
+        let modulus = vec![0xab; 32];
+        assert_eq!(
+            decode_public_key(&modulus, DkimAlgorithm::RsaSha256).unwrap(),
+            EmailPublicKey::Rsa(modulus.clone())
+        );
+        assert_eq!(
+            decode_public_key_exponent(&modulus, DkimAlgorithm::RsaSha256),
+            vec![0x01, 0x00, 0x01]
+        );
+        assert_eq!(
+            decode_public_key(&modulus, DkimAlgorithm::Ed25519Sha256).unwrap(),
+            EmailPublicKey::Ed25519(modulus.clone())
+        );
+        assert!(decode_public_key_exponent(&modulus, DkimAlgorithm::Ed25519Sha256).is_empty());
+
+        assert!(decode_public_key(&[], DkimAlgorithm::RsaSha256).is_err());
+    }
+
+    #[test]
+    fn test_generate_partial_sha_without_trailing_crlf() {
+        // A single line body, as simple canonicalization can produce
+        let body = b"Hello, please ignore this email and do not reply".repeat(2);
+        let (padded, padded_length) = sha256_pad(body.clone(), 192).unwrap();
+        let (precomputed_sha, remaining, remaining_length) =
+            generate_partial_sha(padded, padded_length, Some("reply$".to_string()), 192).unwrap();
+        let cutoff = (body.len() - "reply".len()) / 64 * 64;
+        assert_eq!(cutoff, 64);
+        assert_eq!(precomputed_sha, partial_sha(&body[..cutoff], cutoff));
+        assert_eq!(&remaining[..body.len() - cutoff], &body[cutoff..]);
+        assert_eq!(remaining_length, padded_length - cutoff);
+
+        // A buffer that is not SHA-256 padded is an error rather than a panic
+        assert!(matches!(
+            generate_partial_sha(vec![0; 64], 64, Some("a".to_string()), 64),
+            Err(PartialShaError::InvalidPadding)
+        ));
+        assert!(matches!(
+            generate_partial_sha(vec![], 0, Some("a".to_string()), 64),
+            Err(PartialShaError::InvalidPadding)
+        ));
+
+        // The length field alone does not make a buffer padded
+        let (padded, padded_length) = sha256_pad(b"Hello\r\n".to_vec(), 64).unwrap();
+        let mut without_marker = padded.clone();
+        without_marker[7] = 0;
+        assert!(matches!(
+            generate_partial_sha(without_marker, padded_length, Some("a".to_string()), 64),
+            Err(PartialShaError::InvalidPadding)
+        ));
+        let mut without_zero_fill = padded;
+        without_zero_fill[8] = b'a';
+        assert!(matches!(
+            generate_partial_sha(without_zero_fill, padded_length, Some("a".to_string()), 64),
+            Err(PartialShaError::InvalidPadding)
+        ));
+    }
+
+    #[test]
+    fn test_generate_partial_sha_with_invalid_utf8() {
+        // A Windows-1252 curly quote, which is not valid UTF-8, ahead of the selector
+        let mut body = b"Don\x92t reply".to_vec();
+        body.extend(vec![b'.'; 64]);
+        body.extend(b" Not my account\r\n");
+        let (padded, padded_length) = sha256_pad(body.clone(), 192).unwrap();
+        let (precomputed_sha, remaining, _) = generate_partial_sha(
+            padded,
+            padded_length,
+            Some("Not my account".to_string()),
+            192,
+        )
+        .unwrap();
+        assert_eq!(precomputed_sha, partial_sha(&body[..64], 64));
+        assert_eq!(&remaining[..body.len() - 64], &body[64..]);
+    }
+
+    #[test]
+    fn test_generate_partial_sha_with_invalid_selector() {
+        let (padded, padded_length) = sha256_pad(b"Hello\r\n".to_vec(), 64).unwrap();
+        let err = generate_partial_sha(padded, padded_length, Some("(".to_string()), 64)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Selector ( is not a valid regex"));
+        assert!(err.contains("unclosed group"));
+    }
+
+    #[test]
+    fn test_generate_partial_sha_remaining_too_long() {
+        let (padded, padded_length) = sha256_pad(vec![b'a'; 100], 128).unwrap();
+        assert!(matches!(
+            generate_partial_sha(padded, padded_length, None, 64),
+            Err(PartialShaError::RemainingTooLong {
+                actual: 128,
+                max: 64
+            })
+        ));
+    }
+
+    #[test]
+    fn test_partial_sha_hasher() {
+        let message = b"0123456789abcdef".repeat(16);
+        let mut hasher = PartialShaHasher::new();
+        for chunk in message.chunks(100) {
+            hasher.update(chunk);
+        }
+        assert_eq!(
+            hasher.finalize_state(),
+            partial_sha(&message, message.len())
+        );
+    }
+
+    #[test]
+    fn test_generate_partial_sha_range() {
+        let body = b"Hello\r\n".repeat(20);
+        let (padded, padded_length) = sha256_pad(body, 256).unwrap();
+        let (precomputed_sha, range) =
+            generate_partial_sha_range(&padded, padded_length, Some("Hello\r\n$"), 128).unwrap();
+        assert_eq!(
+            range,
+            RemainingRange {
+                start: 128,
+                length: padded_length - 128
+            }
+        );
+
+        // The copying variant returns the same region, padded to the maximum length
+        let (copied_sha, remaining, remaining_length) = generate_partial_sha(
+            padded.clone(),
+            padded_length,
+            Some("Hello\r\n$".to_string()),
+            128,
+        )
+        .unwrap();
+        assert_eq!(copied_sha, precomputed_sha);
+        assert_eq!(remaining_length, range.length);
+        assert_eq!(remaining, padded[range.start..]);
+    }
+
+    #[test]
+    fn test_join_txt_strings() {
+        let strings: Vec<Box<[u8]>> = vec![
+            b"v=DKIM1; k=rsa; p=MIIBIjAN".to_vec().into_boxed_slice(),
+            b"BgkqhkiG9w0BAQEFAAOCAQ8A".to_vec().into_boxed_slice(),
+        ];
+        let record = join_txt_strings(&strings);
+        assert_eq!(
+            parse_dkim_tags(&record).get("p").map(String::as_str),
+            Some("MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_public_key_record_falls_back_to_dns() {
+        // A closed port makes the archive lookup fail immediately, and a mock name server
+        // answers the DNS lookup with a record split into two strings
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let resolver = CustomResolverConfig {
+            archive_url: format!("http://{}/api/key", unreachable),
+            dns_fallback: true,
+            dns_server: Some(start_mock_name_server(&["v=DKIM1; k=rsa; p=MIIB", "AQAB"])),
+            cache: None,
+            retry: DkimRetryConfig::default(),
+        };
+        let timeout = Duration::from_millis(2000);
+        let record = fetch_public_key_record_with_timeout("example.com", "s1", &resolver, timeout)
+            .await
+            .unwrap();
+        assert_eq!(record, "v=DKIM1; k=rsa; p=MIIBAQAB");
+
+        // If the name server has no record either, both failures are reported
+        let resolver = CustomResolverConfig {
+            dns_server: Some(start_mock_name_server(&[])),
+            ..resolver
+        };
+        let err = fetch_public_key_record_with_timeout("example.com", "s1", &resolver, timeout)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("DKIM key archive request failed"));
+        assert!(
+            err.contains("DNS fallback for s1._domainkey.example.com failed: Public key not found")
+        );
+
+        // DNS is not queried unless the fallback is enabled, which it is not by default
+        assert!(!CustomResolverConfig::default().dns_fallback);
+        let resolver = CustomResolverConfig {
+            dns_fallback: false,
+            ..resolver
+        };
+        let err = fetch_public_key_record_with_timeout("example.com", "s1", &resolver, timeout)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains("DNS fallback"));
+    }
+
+    /// Starts a mock name server that answers each query with a TXT record made of `strings`, or
+    /// with NXDOMAIN if there are none, and returns its address.
+    fn start_mock_name_server(strings: &'static [&'static str]) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut query = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut query) {
+                // The question is the name up to its empty label, then the type and class
+                let question_end = match query[12..len].iter().position(|byte| *byte == 0) {
+                    Some(name_len) => 12 + name_len + 5,
+                    None => continue,
+                };
+                let found = !strings.is_empty();
+                let mut response = query[..2].to_vec();
+                response.extend_from_slice(if found { &[0x81, 0x80] } else { &[0x81, 0x83] });
+                response.extend_from_slice(&[0, 1, 0, found as u8, 0, 0, 0, 0]);
+                response.extend_from_slice(&query[12..question_end]);
+                if found {
+                    let rdata = strings
+                        .iter()
+                        .flat_map(|string| {
+                            std::iter::once(string.len() as u8).chain(string.bytes())
+                        })
+                        .collect::<Vec<u8>>();
+                    // The question name, type TXT, class IN, and a TTL of 60 seconds
+                    response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60]);
+                    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                    response.extend_from_slice(&rdata);
+                }
+                let _ = socket.send_to(&response, peer);
+            }
+        });
+        addr
+    }
+
+    /// Starts a mock DKIM key archive that answers each request with the status and JSON body
+    /// returned by `respond` for the request count so far and the request line, and returns its
+    /// URL and the request counter.
+    fn start_mock_archive(
+        respond: fn(usize, &str) -> (u16, &'static str),
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+        use std::sync::atomic::Ordering;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for mut socket in listener.incoming().flatten() {
+                let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let (status, body) = respond(count, request.lines().next().unwrap_or_default());
+                let _ = write!(
+                    socket,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (format!("http://{}/api/key", addr), requests)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_public_key_record_caches_lookups() {
+        use std::sync::atomic::Ordering;
+
+        // A mock archive that knows a key only for found.cache.test
+        let (archive_url, requests) = start_mock_archive(|_, request| {
+            if request.contains("domain=found.cache.test") {
+                (200, r#"[{"value":"v=DKIM1; k=rsa; p=MIIB"}]"#)
+            } else {
+                (200, "[]")
+            }
+        });
+        // The resolver owns its cache, so other tests cannot fill or clear it
+        let store = DkimKeyCache::new();
+        let resolver = CustomResolverConfig {
+            archive_url,
+            dns_fallback: false,
+            cache: Some(DkimCacheConfig {
+                store: store.clone(),
+                ..DkimCacheConfig::default()
+            }),
+            ..CustomResolverConfig::default()
+        };
+        let timeout = Duration::from_millis(2000);
+
+        for _ in 0..2 {
+            let record =
+                fetch_public_key_record_with_timeout("found.cache.test", "s1", &resolver, timeout)
+                    .await
+                    .unwrap();
+            assert_eq!(record, "v=DKIM1; k=rsa; p=MIIB");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // A lookup that found no key is cached too
+        for _ in 0..2 {
+            let err = fetch_public_key_record_with_timeout(
+                "missing.cache.test",
+                "s1",
+                &resolver,
+                timeout,
+            )
+            .await
+            .unwrap_err();
+            assert!(err.is::<PublicKeyNotFound>());
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Without a cache every lookup reaches the archive
+        let uncached = CustomResolverConfig {
+            cache: None,
+            ..resolver.clone()
+        };
+        fetch_public_key_record_with_timeout("found.cache.test", "s1", &uncached, timeout)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // A resolver that may find a different key does not share the cached lookup
+        let with_fallback = CustomResolverConfig {
+            dns_fallback: true,
+            ..resolver.clone()
+        };
+        fetch_public_key_record_with_timeout("found.cache.test", "s1", &with_fallback, timeout)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert_eq!(store.len(), 3);
+
+        store.clear();
+        fetch_public_key_record_with_timeout("found.cache.test", "s1", &resolver, timeout)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_public_key_records_retries_transient_failures() {
+        use std::sync::atomic::Ordering;
+
+        let retry = DkimRetryConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        // A server error is retried
+        let (archive_url, requests) = start_mock_archive(|count, _| match count {
+            1 => (503, "{}"),
+            _ => (200, r#"[{"value":"v=DKIM1; p=MIIB"}]"#),
+        });
+        let resolver = CustomResolverConfig {
+            archive_url,
+            dns_fallback: false,
+            dns_server: None,
+            cache: None,
+            retry: retry.clone(),
+        };
+        let records = fetch_public_key_records("example.com", "s1", &resolver)
+            .await
+            .unwrap();
+        assert_eq!(records[0].public_key.as_deref(), Some("MIIB"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // The attempts are reported once they are exhausted
+        let (archive_url, requests) = start_mock_archive(|_, _| (429, "{}"));
+        let resolver = CustomResolverConfig {
+            archive_url,
+            ..resolver
+        };
+        let err = fetch_public_key_records("example.com", "s1", &resolver)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("DKIM key archive request failed after 3 attempts"));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Other client errors are not retried
+        let (archive_url, requests) = start_mock_archive(|_, _| (404, "[]"));
+        let resolver = CustomResolverConfig {
+            archive_url,
+            ..resolver
+        };
+        assert!(fetch_public_key_records("example.com", "s1", &resolver)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_padded_email_addr_try_from_email_addr() {
+        let padded = PaddedEmailAddr::try_from_email_addr("alice@example.com").unwrap();
+        assert_eq!(padded.email_addr_len, 17);
+        assert_eq!(padded.padded_bytes.len(), MAX_EMAIL_ADDR_BYTES);
+        assert_eq!(&padded.padded_bytes[..17], b"alice@example.com");
+
+        let longest = format!("{}@example.com", "a".repeat(MAX_EMAIL_ADDR_BYTES - 12));
+        assert!(PaddedEmailAddr::try_from_email_addr(&longest).is_ok());
+        let err = PaddedEmailAddr::try_from_email_addr(&format!("a{}", longest)).unwrap_err();
+        assert!(err.to_string().contains("is 257 bytes long"));
+        assert!(PaddedEmailAddr::try_from_email_addr("").is_err());
+    }
+
+    #[test]
+    fn test_relayer_rand_serde_roundtrip() {
+        let relayer_rand = RelayerRand::new_from_seed(b"x").unwrap();
+        let json = serde_json::to_string(&relayer_rand).unwrap();
+        assert_eq!(json, format!("\"{}\"", relayer_rand.to_hex()));
+
+        let restored: RelayerRand = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.0, relayer_rand.0);
+        assert_eq!(restored.hash().unwrap(), relayer_rand.hash().unwrap());
+        assert_eq!(
+            RelayerRand::from_hex(&relayer_rand.to_hex()).unwrap().0,
+            relayer_rand.0
+        );
+    }
+
+    #[test]
+    fn test_account_salt_serde_roundtrip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let salt = AccountSalt(Fr::random(&mut rng));
+            let json = serde_json::to_string(&salt).unwrap();
+            assert_eq!(json, format!("\"{}\"", salt.to_hex()));
+            let deserialized: AccountSalt = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized.0, salt.0);
+            assert_eq!(AccountSalt::from_hex(&salt.to_hex()).unwrap().0, salt.0);
+        }
+
+        assert!(serde_json::from_str::<AccountSalt>("\"1234\"").is_err());
+        assert!(AccountSalt::from_hex("0xzz").is_err());
+    }
+
+    #[test]
+    fn test_dkim_dns_record() {
+        let record = DkimDnsRecord::new(
+            "football.example.com",
+            "brisbane",
+            "v=DKIM1; k=ed25519; t=y; p=11qYAYKxCrfVS/7TyWQ\r\n HOg7hcvPapiMlrwIaaPcHURo=",
+        );
+        assert_eq!(
+            record.public_key.as_deref(),
+            Some("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=")
+        );
+        assert_eq!(record.key_type.as_deref(), Some("ed25519"));
+        assert_eq!(record.flags.as_deref(), Some("y"));
+        assert!(matches!(
+            record.parse_public_key().unwrap(),
+            EmailPublicKey::Ed25519(_)
+        ));
+
+        let revoked = DkimDnsRecord::new("example.com", "s1", "v=DKIM1; p=");
+        assert_eq!(revoked.public_key.as_deref(), Some(""));
+        assert_eq!(revoked.key_type, None);
+    }
+
+    #[test]
+    fn test_sha256_pad() {
+        let (padded, message_len) = sha256_pad(b"abc".to_vec(), 128).unwrap();
+        assert_eq!(message_len, 64);
+        assert_eq!(padded.len(), 128);
+        assert_eq!(&padded[..4], b"abc\x80");
+        assert_eq!(&padded[56..64], &24u64.to_be_bytes());
+        assert!(padded[64..].iter().all(|byte| *byte == 0));
+
+        // 56 bytes leave no room for the length in their block, so they need two blocks
+        let err = sha256_pad(vec![0; 56], 64).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Sha256PadError>(),
+            Some(&Sha256PadError::TooLong {
+                padded_length: 128,
+                max_sha_bytes: 64,
+            })
+        );
+    }
+}
+
+/// The algorithm of `calculate_default_hash`: the first 8 bytes of the SHA-256 hash of the
+/// UTF-8 input, read as a big-endian `u64` and formatted in decimal.
+pub const DEFAULT_HASH_ALGORITHM: &str = "sha256-u64be-decimal";
+
+/// Calculates a default hash for the given input string.
+///
+/// The hash follows `DEFAULT_HASH_ALGORITHM`, so it is stable across Rust releases and can be
+/// persisted as an identifier.
+///
+/// # Arguments
+///
+/// * `input` - The input string to hash.
+///
+/// # Returns
+///
+/// A string representation of the calculated hash.
+pub fn calculate_default_hash(input: &str) -> String {
+    let hash = hmac_sha256::Hash::hash(input.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(prefix).to_string()
+}
+
+/// Calculates the hash that `calculate_default_hash` returned before it followed
+/// `DEFAULT_HASH_ALGORITHM`, to migrate identifiers persisted with it.
+///
+/// This uses the standard library's `DefaultHasher`, whose algorithm may change between Rust
+/// releases.
+///
+/// # Arguments
+///
+/// * `input` - The input string to hash.
+///
+/// # Returns
+///
+/// A string representation of the calculated hash.
+pub fn calculate_default_hash_legacy(input: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    input.hash(&mut hasher);
+    let hash_code = hasher.finish();
+
+    hash_code.to_string()
+}
+
+/// Calculates the account salt based on the email address and account code.
+///
+/// # Arguments
+///
+/// * `email_addr` - The email address string.
+/// * `account_code` - The account code as 32 bytes of big-endian hex, with or without a `0x`
+///   prefix.
+///
+/// # Returns
+///
+/// A result that is either the account salt as 0x-prefixed hex or an error naming the input that
+/// is invalid or the hash that failed.
+pub fn calculate_account_salt(email_addr: &str, account_code: &str) -> Result<String> {
+    calculate_account_salt_with_policy(email_addr, account_code, NormalizationPolicy::default())
+}
+
+/// Calculates the account salt based on the email address, normalized according to `policy`, and
+/// the account code.
+///
+/// # Arguments
+///
+/// * `email_addr` - The email address string.
+/// * `account_code` - The account code as 32 bytes of big-endian hex, with or without a `0x`
+///   prefix.
+/// * `policy` - How the email address is normalized before it is padded.
+///
+/// # Returns
+///
+/// A result that is either the account salt as 0x-prefixed hex or an error naming the input that
+/// is invalid or the hash that failed.
+pub fn calculate_account_salt_with_policy(
+    email_addr: &str,
+    account_code: &str,
+    policy: NormalizationPolicy,
+) -> Result<String> {
+    // Pad the email address
+    let padded_email_addr = PaddedEmailAddr::from_email_addr_normalized(email_addr, policy)
+        .map_err(|e| anyhow!("Invalid email address for the account salt: {}", e))?;
+
+    let account_code = AccountCode::try_from_hex(account_code)?;
+
+    // Generate account salt
+    let account_salt = AccountSalt::new(&padded_email_addr, account_code)
+        .map_err(|e| anyhow!("Failed to hash the account salt: {}", e))?;
+
+    // Convert account salt to hexadecimal representation
+    Ok(field_to_hex(&account_salt.0))
+}
+
+/// A DKIM public key, as published in the signer's DNS record.
+///
+/// An RSA key is serialized as its bare modulus bytes, as before ed25519 keys were supported, and
+/// an ed25519 key as `{ "type": "ed25519", "bytes": [...] }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "EmailPublicKeyRepr", into = "EmailPublicKeyRepr")]
+pub enum EmailPublicKey {
+    /// The modulus of an RSA public key, in big-endian order.
+    Rsa(Vec<u8>),
+    /// A raw 32-byte ed25519 public key.
+    Ed25519(Vec<u8>),
+}
+
+/// The serialized form of an `EmailPublicKey`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EmailPublicKeyRepr {
+    Modulus(Vec<u8>),
+    Tagged(TaggedEmailPublicKey),
+}
+
+/// An `EmailPublicKey` serialized with its key type.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "bytes", rename_all = "camelCase")]
+enum TaggedEmailPublicKey {
+    Rsa(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+impl From<EmailPublicKeyRepr> for EmailPublicKey {
+    fn from(repr: EmailPublicKeyRepr) -> Self {
+        match repr {
+            EmailPublicKeyRepr::Modulus(modulus)
+            | EmailPublicKeyRepr::Tagged(TaggedEmailPublicKey::Rsa(modulus)) => Self::Rsa(modulus),
+            EmailPublicKeyRepr::Tagged(TaggedEmailPublicKey::Ed25519(key)) => Self::Ed25519(key),
+        }
+    }
+}
+
+impl From<EmailPublicKey> for EmailPublicKeyRepr {
+    fn from(public_key: EmailPublicKey) -> Self {
+        match public_key {
+            EmailPublicKey::Rsa(modulus) => Self::Modulus(modulus),
+            EmailPublicKey::Ed25519(key) => Self::Tagged(TaggedEmailPublicKey::Ed25519(key)),
+        }
+    }
+}
+
+impl EmailPublicKey {
+    /// Returns the raw key bytes, i.e. the RSA modulus or the ed25519 public key.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Rsa(bytes) | Self::Ed25519(bytes) => bytes,
+        }
+    }
+
+    /// Returns the RSA modulus in big-endian order.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the modulus bytes, or an error if the key is not an RSA key.
+    pub fn rsa_modulus(&self) -> Result<&[u8]> {
+        match self {
+            Self::Rsa(modulus) => Ok(modulus),
+            Self::Ed25519(_) => Err(anyhow!(
+                "ed25519 DKIM keys are not supported by the email circuits, an RSA key is required"
+            )),
+        }
+    }
+}
+
+/// A DKIM signing algorithm, as given in the `a=` tag of a DKIM-Signature header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DkimAlgorithm {
+    /// `rsa-sha256`, as specified by RFC 6376.
+    RsaSha256,
+    /// `ed25519-sha256`, as specified by RFC 8463.
+    Ed25519Sha256,
+}
+
+impl DkimAlgorithm {
+    /// Parses the value of an `a=` tag.
+    ///
+    /// `rsa-sha1` is rejected along with unknown algorithms, since RFC 8301 forbids verifiers
+    /// from accepting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The value of the `a=` tag.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `DkimAlgorithm`, or an error if the algorithm is not supported.
+    pub fn from_tag(tag: &str) -> Result<Self> {
+        match tag.trim() {
+            "rsa-sha256" => Ok(Self::RsaSha256),
+            "ed25519-sha256" => Ok(Self::Ed25519Sha256),
+            other => Err(anyhow!("Unsupported DKIM signing algorithm: {}", other)),
+        }
+    }
+
+    /// Hashes the signed data with the hash function of the algorithm.
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::RsaSha256 | Self::Ed25519Sha256 => hmac_sha256::Hash::hash(data).to_vec(),
+        }
+    }
+}
+
+/// Parses the value of a DKIM key TXT record (e.g. `v=DKIM1; k=rsa; p=...`).
+///
+/// The key type defaults to RSA when no `k=` tag is present, as specified by RFC 6376. RSA keys
+/// may be published as a `SubjectPublicKeyInfo` or as a PKCS#1 `RSAPublicKey`. A record without
+/// a `k=` tag whose key is not RSA but is 32 bytes long is taken to be an ed25519 key.
+///
+/// # Arguments
+///
+/// * `record` - The content of the DKIM key TXT record.
+///
+/// # Returns
+///
+/// A `Result` containing the `EmailPublicKey`, or an error if the record has no key or uses an
+/// unsupported key type.
+pub fn parse_dkim_public_key_record(record: &str) -> Result<EmailPublicKey> {
+    let (key_type, public_key_bytes) = split_dkim_public_key_record(record)?;
+
+    match key_type {
+        None | Some("rsa") => match decode_rsa_public_key_der(&public_key_bytes) {
+            // Convert the modulus to a byte array in big-endian order
+            Ok(public_key) => Ok(EmailPublicKey::Rsa(public_key.n().to_bytes_be())),
+            // Some signers publish ed25519 keys without a k= tag
+            Err(_) if key_type.is_none() && public_key_bytes.len() == 32 => {
+                Ok(EmailPublicKey::Ed25519(public_key_bytes))
+            }
+            Err(e) => Err(e),
+        },
+        Some("ed25519") => {
+            // RFC 8463 publishes the raw key rather than a DER structure
+            if public_key_bytes.len() != 32 {
+                return Err(anyhow!(
+                    "ed25519 public key must be 32 bytes but is {} bytes",
+                    public_key_bytes.len()
+                ));
+            }
+            Ok(EmailPublicKey::Ed25519(public_key_bytes))
+        }
+        Some(other) => Err(anyhow!("Unsupported DKIM key type: {}", other)),
+    }
+}
+
+/// Parses the RSA public exponent out of a DKIM key TXT record.
+///
+/// # Arguments
+///
+/// * `record` - The content of the DKIM key TXT record.
+///
+/// # Returns
+///
+/// A `Result` containing the big-endian exponent bytes, which are empty for non-RSA keys, or an
+/// error if the record has no key or its RSA key cannot be decoded.
+pub fn parse_dkim_public_key_exponent(record: &str) -> Result<Vec<u8>> {
+    if !matches!(
+        parse_dkim_public_key_record(record)?,
+        EmailPublicKey::Rsa(_)
+    ) {
+        return Ok(Vec::new());
+    }
+    let (_, public_key_bytes) = split_dkim_public_key_record(record)?;
+    Ok(decode_rsa_public_key_der(&public_key_bytes)?
+        .e()
+        .to_bytes_be())
+}
+
+/// Splits a DKIM key TXT record into its key type, if a `k=` tag is present, and decoded public
+/// key bytes.
+fn split_dkim_public_key_record(record: &str) -> Result<(Option<&str>, Vec<u8>)> {
+    let mut key_type = None;
+    let mut public_key_b64 = None;
+    for tag in record.split(';') {
+        let tag = tag.trim();
+        if let Some(value) = tag.strip_prefix("k=") {
+            key_type = Some(value.trim());
+        } else if let Some(value) = tag.strip_prefix("p=") {
+            public_key_b64 = Some(value.split_whitespace().collect::<String>());
+        }
+    }
+
+    let public_key_b64 = public_key_b64.ok_or_else(|| anyhow!("Public key not found"))?;
+    // Decode the base64 string to get the public key bytes
+    let public_key_bytes = general_purpose::STANDARD.decode(public_key_b64)?;
+    Ok((key_type, public_key_bytes))
+}
+
+/// Decodes a DER-encoded RSA public key, either a `SubjectPublicKeyInfo` or a PKCS#1
+/// `RSAPublicKey`.
+fn decode_rsa_public_key_der(public_key_der: &[u8]) -> Result<RsaPublicKey> {
+    RsaPublicKey::from_public_key_der(public_key_der)
+        .or_else(|_| RsaPublicKey::from_pkcs1_der(public_key_der))
+        .map_err(|e| anyhow!("Invalid RSA public key: {}", e))
+}
+
+/// Decodes a caller-supplied DKIM public key for a signature with the given algorithm.
+///
+/// For `rsa-sha256` the bytes may be a DER-encoded `SubjectPublicKeyInfo` or PKCS#1
+/// `RSAPublicKey`, or a bare big-endian modulus. For `ed25519-sha256` they must be the raw
+/// 32-byte public key.
+///
+/// # Arguments
+///
+/// * `public_key_der_or_modulus` - The encoded public key.
+/// * `algorithm` - The algorithm from the `a=` tag of the signature the key belongs to.
+///
+/// # Returns
+///
+/// A `Result` containing the `EmailPublicKey`, or an error if the bytes are empty or are not a
+/// valid key for the algorithm.
+pub fn decode_public_key(
+    public_key_der_or_modulus: &[u8],
+    algorithm: DkimAlgorithm,
+) -> Result<EmailPublicKey> {
+    if public_key_der_or_modulus.is_empty() {
+        return Err(anyhow!("Public key must not be empty"));
+    }
+    match algorithm {
+        DkimAlgorithm::RsaSha256 => Ok(EmailPublicKey::Rsa(
+            match decode_rsa_public_key_der(public_key_der_or_modulus) {
+                Ok(public_key) => public_key.n().to_bytes_be(),
+                Err(_) => public_key_der_or_modulus.to_vec(),
+            },
+        )),
+        DkimAlgorithm::Ed25519Sha256 => {
+            if public_key_der_or_modulus.len() != 32 {
+                return Err(anyhow!(
+                    "ed25519 public key must be 32 bytes but is {} bytes",
+                    public_key_der_or_modulus.len()
+                ));
+            }
+            Ok(EmailPublicKey::Ed25519(public_key_der_or_modulus.to_vec()))
+        }
+    }
+}
+
+/// Decodes the RSA public exponent of a caller-supplied DKIM public key.
+///
+/// The bytes are interpreted as in `decode_public_key`. A bare modulus is assumed to use the
+/// exponent 65537.
+///
+/// # Arguments
+///
+/// * `public_key_der_or_modulus` - The encoded public key.
+/// * `algorithm` - The algorithm from the `a=` tag of the signature the key belongs to.
+///
+/// # Returns
+///
+/// The big-endian exponent bytes, which are empty for ed25519 keys.
+pub fn decode_public_key_exponent(
+    public_key_der_or_modulus: &[u8],
+    algorithm: DkimAlgorithm,
+) -> Vec<u8> {
+    match algorithm {
+        DkimAlgorithm::RsaSha256 => match decode_rsa_public_key_der(public_key_der_or_modulus) {
+            Ok(public_key) => public_key.e().to_bytes_be(),
+            Err(_) => BigUint::from(RSA_DEFAULT_EXPONENT).to_bytes_be(),
+        },
+        DkimAlgorithm::Ed25519Sha256 => Vec::new(),
+    }
+}
+
+/// Verifies a DKIM signature against the canonicalized header and body it covers.
+///
+/// The body hash in the `bh=` tag is checked against the canonicalized body, and the signature
+/// is checked against the canonicalized header using the algorithm in the `a=` tag.
+///
+/// # Arguments
+///
+/// * `canonicalized_header` - The canonicalized header, including the signing DKIM-Signature header.
+/// * `canonicalized_body` - The canonicalized body.
+/// * `signature` - The signature bytes from the `b=` tag.
+/// * `public_key` - The DKIM public key of the signer.
+/// * `public_key_e` - The big-endian RSA public exponent, or empty to assume 65537.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok(())` if the signature verifies, or an error describing the failure.
+pub fn verify_dkim_signature(
+    canonicalized_header: &str,
+    canonicalized_body: &[u8],
+    signature: &[u8],
+    public_key: &EmailPublicKey,
+    public_key_e: &[u8],
+) -> Result<()> {
+    // Check the body hash first, since the signature only covers the header
+    verify_dkim_body_hash(canonicalized_header, canonicalized_body)?;
+    verify_dkim_header_signature(canonicalized_header, signature, public_key, public_key_e)
+}
+
+/// Checks the body hash in the `bh=` tag of the canonicalized header against the canonicalized
+/// body.
+///
+/// If the signature has an `l=` tag, only that many bytes of the body are hashed.
+///
+/// # Arguments
+///
+/// * `canonicalized_header` - The canonicalized header, including the signing DKIM-Signature header.
+/// * `canonicalized_body` - The canonicalized body.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok(())` if the body hash matches, or an error describing the failure.
+pub fn verify_dkim_body_hash(canonicalized_header: &str, canonicalized_body: &[u8]) -> Result<()> {
+    let idxes = find_dkim_body_hash_idxes(canonicalized_header)?;
+    let body_hash = general_purpose::STANDARD.decode(&canonicalized_header[idxes.0..idxes.1])?;
+    let signed_body = match find_dkim_signature_tags(canonicalized_header)?.get("l") {
+        Some(length) => {
+            let length: usize = length
+                .parse()
+                .map_err(|_| anyhow!("Invalid DKIM body length tag l={}", length))?;
+            &canonicalized_body[..length.min(canonicalized_body.len())]
+        }
+        None => canonicalized_body,
+    };
+    if body_hash != hmac_sha256::Hash::hash(signed_body) {
+        return Err(anyhow!("DKIM body hash does not match the email body"));
+    }
+    Ok(())
+}
+
+/// Verifies the DKIM signature over the canonicalized header, without checking the body hash.
+///
+/// The header is hashed and verified with the algorithm in the `a=` tag, which must match the
+/// type of the public key.
+///
+/// # Arguments
+///
+/// * `canonicalized_header` - The canonicalized header, including the signing DKIM-Signature header.
+/// * `signature` - The signature bytes from the `b=` tag.
+/// * `public_key` - The DKIM public key of the signer.
+/// * `public_key_e` - The big-endian RSA public exponent, or empty to assume 65537.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok(())` if the signature verifies, or an error describing the failure.
+pub fn verify_dkim_header_signature(
+    canonicalized_header: &str,
+    signature: &[u8],
+    public_key: &EmailPublicKey,
+    public_key_e: &[u8],
+) -> Result<()> {
+    let tags = find_dkim_signature_tags(canonicalized_header)?;
+    let algorithm = DkimAlgorithm::from_tag(
+        tags.get("a")
+            .ok_or_else(|| anyhow!("DKIM signature has no a= tag"))?,
+    )?;
+    let header_hash = algorithm.hash(canonicalized_header.as_bytes());
+    match (algorithm, public_key) {
+        (DkimAlgorithm::RsaSha256, EmailPublicKey::Rsa(modulus)) => {
+            let exponent = if public_key_e.is_empty() {
+                BigUint::from(RSA_DEFAULT_EXPONENT)
+            } else {
+                BigUint::from_bytes_be(public_key_e)
+            };
+            let public_key = RsaPublicKey::new(BigUint::from_bytes_be(modulus), exponent)?;
+            let scheme = Pkcs1v15Sign {
+                hash_len: Some(header_hash.len()),
+                prefix: SHA256_DIGEST_INFO_PREFIX.into(),
+            };
+            public_key
+                .verify(scheme, &header_hash, signature)
+                .map_err(|e| anyhow!("DKIM signature verification failed: {}", e))
+        }
+        (DkimAlgorithm::Ed25519Sha256, EmailPublicKey::Ed25519(key)) => {
+            let key: [u8; 32] = key
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("ed25519 public key must be 32 bytes"))?;
+            let public_key = VerifyingKey::from_bytes(&key)?;
+            let signature = Signature::from_slice(signature)?;
+            public_key
+                .verify(&header_hash, &signature)
+                .map_err(|e| anyhow!("DKIM signature verification failed: {}", e))
+        }
+        (algorithm, _) => Err(anyhow!(
+            "DKIM signature algorithm {:?} does not match the type of the public key",
+            algorithm
+        )),
+    }
+}
+
+/// Parses the `tag=value` list of a DKIM-Signature header.
+///
+/// Tag names are returned as written and values are trimmed of surrounding whitespace.
+///
+/// # Arguments
+///
+/// * `header` - The value of the DKIM-Signature header.
+///
+/// # Returns
+///
+/// A map from tag names to their values.
+pub fn parse_dkim_tags(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Fetches the public key from DNS records using the DKIM signature in the email headers.
+///
+/// Only the first DKIM-Signature header is considered.
+///
+/// # Arguments
+///
+/// * `email_headers` - An `EmailHeaders` object containing the headers of the email.
+///
+/// # Returns
+///
+/// A `Result` containing the `EmailPublicKey`, or an error if the key is not found or its type
+/// is not supported.
+pub async fn fetch_public_key(email_headers: EmailHeaders) -> Result<EmailPublicKey> {
+    let mut selector = String::new();
+    let mut domain = String::new();
+
+    // Extract the selector and domain from the DKIM-Signature header
+    if let Some(headers) = email_headers.get_header("DKIM-Signature") {
+        if let Some(header) = headers.first() {
+            let tags = parse_dkim_tags(header);
+            selector = tags.get("s").cloned().unwrap_or_default();
+            domain = tags.get("d").cloned().unwrap_or_default();
+        }
+    }
+
+    // The archive and time limit can be configured as for `ParsedEmail::new_from_raw_email`
+    let options = ParseEmailOptions::from_env()?;
+    let record = fetch_public_key_record_with_timeout(
+        &domain,
+        &selector,
+        &options.resolver.unwrap_or_default(),
+        options.dns_timeout,
+    )
+    .await?;
+    parse_dkim_public_key_record(&record)
+}
+
+/// A DKIM key TXT record of a DKIM signer, with the tags that describe its key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DkimDnsRecord {
+    pub domain: String,             // The signing domain the record was looked up for
+    pub selector: String,           // The selector the record was looked up for
+    pub value: String,              // The content of the TXT record
+    pub public_key: Option<String>, // The base64 public key from the `p=` tag, empty if the key was revoked
+    pub key_type: Option<String>,   // The key type from the `k=` tag, RSA if absent
+    pub flags: Option<String>, // The flags from the `t=` tag, e.g. `y` for a domain testing DKIM
+}
+
+impl DkimDnsRecord {
+    /// Creates a record from the content of a DKIM key TXT record, extracting its tags.
+    ///
+    /// # Arguments
+    ///
+    /// * `domain` - The signing domain the record was looked up for.
+    /// * `selector` - The selector the record was looked up for.
+    /// * `value` - The content of the TXT record.
+    pub fn new(domain: &str, selector: &str, value: &str) -> Self {
+        let mut tags = parse_dkim_tags(value);
+        Self {
+            domain: domain.to_string(),
+            selector: selector.to_string(),
+            value: value.to_string(),
+            public_key: tags
+                .remove("p")
+                .map(|p| p.split_whitespace().collect::<String>()),
+            key_type: tags.remove("k"),
+            flags: tags.remove("t"),
+        }
+    }
+
+    /// Parses the public key of the record.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `EmailPublicKey`, or an error if the record has no key or uses
+    /// an unsupported key type.
+    pub fn parse_public_key(&self) -> Result<EmailPublicKey> {
+        parse_dkim_public_key_record(&self.value)
+    }
+}
+
+/// Where DKIM public keys are resolved from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomResolverConfig {
+    pub archive_url: String, // Endpoint of the DKIM key archive, queried with `domain` and `selector` parameters
+    pub dns_fallback: bool, // Whether to look the key up directly in DNS if the archive fails, off by default; not available in wasm
+    pub dns_server: Option<SocketAddr>, // Name server queried by the DNS fallback; Google Public DNS if `None`
+    pub cache: Option<DkimCacheConfig>, // How looked up key records are cached; `None` disables caching
+    pub retry: DkimRetryConfig,         // How failed requests to the archive are retried
+}
+
+impl Default for CustomResolverConfig {
+    fn default() -> Self {
+        Self {
+            archive_url: DKIM_ARCHIVE_API_URL.to_string(),
+            dns_fallback: false,
+            dns_server: None,
+            cache: Some(DkimCacheConfig::default()),
+            retry: DkimRetryConfig::default(),
+        }
+    }
+}
+
+/// How DKIM key records are cached between lookups.
+///
+/// Lookups are keyed by the archive URL and DNS fallback settings of the resolver as well as by
+/// signing domain and selector, so resolvers that may find different keys never share entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimCacheConfig {
+    pub ttl: Duration,          // How long a found key record is reused
+    pub negative_ttl: Duration, // How long a lookup that found no key is remembered
+    pub max_entries: usize, // Maximum number of cached lookups; the entries expiring first are evicted
+    pub store: DkimKeyCache, // Where lookups are kept; the store shared by the whole process by default
+}
+
+impl Default for DkimCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_millis(DEFAULT_DKIM_CACHE_TTL_MS),
+            negative_ttl: Duration::from_millis(DEFAULT_DKIM_NEGATIVE_CACHE_TTL_MS),
+            max_entries: DEFAULT_DKIM_CACHE_MAX_ENTRIES,
+            store: DkimKeyCache::default(),
+        }
+    }
+}
+
+/// How failed requests to the DKIM key archive are retried.
+///
+/// Only connection errors, timeouts, and `429` or `5xx` responses are retried. The wait before
+/// each retry doubles, up to `max_backoff`, and is randomly shortened by up to half so that
+/// clients do not retry in lockstep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimRetryConfig {
+    pub max_attempts: u32, // Maximum number of requests per lookup, including the first; 0 is treated as 1
+    pub initial_backoff: Duration, // Wait before the first retry
+    pub max_backoff: Duration, // Upper bound on the wait between retries
+}
+
+impl Default for DkimRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_DKIM_FETCH_MAX_ATTEMPTS,
+            initial_backoff: Duration::from_millis(DEFAULT_DKIM_FETCH_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_millis(DEFAULT_DKIM_FETCH_MAX_BACKOFF_MS),
+        }
+    }
+}
+
+impl DkimRetryConfig {
+    /// Returns the jittered wait before the given retry, counting from 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        let max_backoff = self.max_backoff.as_millis() as u64;
+        let backoff = (self.initial_backoff.as_millis() as u64)
+            .saturating_mul(1u64.checked_shl(retry - 1).unwrap_or(u64::MAX))
+            .min(max_backoff);
+        let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+        Duration::from_millis(backoff - jitter)
+    }
+}
+
+/// The error of a DKIM key lookup that completed but found no key for the signer, as opposed to
+/// one that failed to complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKeyNotFound;
+
+impl fmt::Display for PublicKeyNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Public key not found")
+    }
+}
+
+impl std::error::Error for PublicKeyNotFound {}
+
+/// A cached DKIM key lookup: the record, or the message of the not-found error.
+struct CachedKeyRecord {
+    record: std::result::Result<String, String>,
+    expires_at_ms: u64,
+}
+
+type DkimKeyCacheKey = (String, bool, Option<SocketAddr>, String, String); // The archive URL, whether DNS fallback is used, its name server, the signing domain, and the selector
+
+lazy_static! {
+    static ref DKIM_KEY_CACHE: Arc<Mutex<HashMap<DkimKeyCacheKey, CachedKeyRecord>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// A store of cached DKIM key lookups.
+///
+/// Clones share their entries. The default store is shared by the whole process, while
+/// `DkimKeyCache::new` creates one that only the resolvers it is given to use.
+#[derive(Clone)]
+pub struct DkimKeyCache(Arc<Mutex<HashMap<DkimKeyCacheKey, CachedKeyRecord>>>);
+
+impl Default for DkimKeyCache {
+    fn default() -> Self {
+        Self(DKIM_KEY_CACHE.clone())
+    }
+}
+
+impl fmt::Debug for DkimKeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DkimKeyCache").field(&self.len()).finish()
+    }
+}
+
+impl PartialEq for DkimKeyCache {
+    /// Two stores are equal if they share their entries.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for DkimKeyCache {}
+
+impl DkimKeyCache {
+    /// Creates an empty store that is not shared with the rest of the process.
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    /// Removes every cached lookup from the store.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Returns the number of cached lookups, including expired ones.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns whether the store has no cached lookups.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<DkimKeyCacheKey, CachedKeyRecord>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the unexpired cached lookup for a signer, if any.
+    fn get(&self, key: &DkimKeyCacheKey) -> Option<Result<String>> {
+        let cache = self.lock();
+        let cached = cache.get(key).filter(|c| c.expires_at_ms > now_ms())?;
+        Some(match &cached.record {
+            Ok(record) => Ok(record.clone()),
+            Err(message) => Err(anyhow::Error::new(PublicKeyNotFound).context(message.clone())),
+        })
+    }
+
+    /// Caches a lookup for a signer, evicting expired entries and then the entries expiring
+    /// first once the store is full.
+    fn insert(
+        &self,
+        key: DkimKeyCacheKey,
+        record: std::result::Result<String, String>,
+        ttl: Duration,
+        max_entries: usize,
+    ) {
+        if max_entries == 0 {
+            return;
+        }
+        let now = now_ms();
+        let mut cache = self.lock();
+        if !cache.contains_key(&key) && cache.len() >= max_entries {
+            cache.retain(|_, cached| cached.expires_at_ms > now);
+            while cache.len() >= max_entries {
+                let earliest = cache
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.expires_at_ms)
+                    .map(|(key, _)| key.clone());
+                match earliest {
+                    Some(earliest) => cache.remove(&earliest),
+                    None => break,
+                };
+            }
+        }
+        cache.insert(
+            key,
+            CachedKeyRecord {
+                record,
+                expires_at_ms: now.saturating_add(ttl.as_millis() as u64),
+            },
+        );
+    }
+}
+
+/// Removes every cached DKIM key lookup from the store shared by the whole process.
+pub fn clear_dkim_cache() {
+    DkimKeyCache::default().clear();
+}
+
+impl CustomResolverConfig {
+    /// Creates a resolver configuration that queries the archive named by the `DKIM_API_URL`
+    /// environment variable, or the default archive if it is not set.
+    pub fn from_env() -> Self {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    /// Creates a resolver configuration as `from_env` does, reading the variable with `lookup`.
+    pub(crate) fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        match lookup(DKIM_API_URL_KEY) {
+            Some(archive_url) if !archive_url.is_empty() => Self {
+                archive_url,
+                ..Self::default()
+            },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// Fetches the public key of a DKIM signer from the DKIM key archive.
+///
+/// # Arguments
+///
+/// * `domain` - The signing domain from the `d=` tag.
+/// * `selector` - The selector from the `s=` tag.
+/// * `resolver` - The resolver configuration naming the archive to query.
+///
+/// # Returns
+///
+/// A `Result` containing the `EmailPublicKey`, or an error if the key is not found or its type
+/// is not supported.
+pub async fn fetch_public_key_for_selector(
+    domain: &str,
+    selector: &str,
+    resolver: &CustomResolverConfig,
+) -> Result<EmailPublicKey> {
+    let record = fetch_public_key_record(domain, selector, resolver).await?;
+    parse_dkim_public_key_record(&record)
+}
+
+/// Fetches the DKIM key TXT record of a DKIM signer from the DKIM key archive, giving up after a
+/// time limit.
+///
+/// # Arguments
+///
+/// * `domain` - The signing domain from the `d=` tag.
+/// * `selector` - The selector from the `s=` tag.
+/// * `resolver` - The resolver configuration naming the archive to query.
+/// * `timeout` - The time limit for the lookup.
+///
+/// # Returns
+///
+/// A `Result` containing the record, or an error if no record is found or the lookup times out.
+/// A timeout error names the archive and the time limit, to tell a slow archive from a missing
+/// record. If the archive fails and `dns_fallback` is set, the record is looked up directly in
+/// DNS, at `dns_server` if it is set, with the same time limit, and an error then reports both failures. If no key is found,
+/// the error can be downcast to `PublicKeyNotFound`.
+///
+/// Unless `resolver.cache` is `None`, found records and lookups that found no key are cached,
+/// and a cached lookup is returned without querying the archive or DNS. Lookups that fail to
+/// complete are not cached.
+pub async fn fetch_public_key_record_with_timeout(
+    domain: &str,
+    selector: &str,
+    resolver: &CustomResolverConfig,
+    timeout: Duration,
+) -> Result<String> {
+    let cache = match &resolver.cache {
+        Some(cache) => cache,
+        None => return fetch_uncached_public_key_record(domain, selector, resolver, timeout).await,
+    };
+    // Resolvers may find different keys, and DNS names are case-insensitive
+    let key = (
+        resolver.archive_url.clone(),
+        resolver.dns_fallback,
+        resolver.dns_server,
+        domain.to_ascii_lowercase(),
+        selector.to_ascii_lowercase(),
+    );
+    if let Some(cached) = cache.store.get(&key) {
+        return cached;
+    }
+
+    let result = fetch_uncached_public_key_record(domain, selector, resolver, timeout).await;
+    match &result {
+        Ok(record) => cache
+            .store
+            .insert(key, Ok(record.clone()), cache.ttl, cache.max_entries),
+        Err(e) if e.is::<PublicKeyNotFound>() => cache.store.insert(
+            key,
+            Err(e.to_string()),
+            cache.negative_ttl,
+            cache.max_entries,
+        ),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Looks up the DKIM key TXT record of a DKIM signer as `fetch_public_key_record_with_timeout`
+/// does, without the cache.
+async fn fetch_uncached_public_key_record(
+    domain: &str,
+    selector: &str,
+    resolver: &CustomResolverConfig,
+    timeout: Duration,
+) -> Result<String> {
+    let timeout = timeout.as_millis() as u64;
+    let archive_error =
+        match timeout_ms(timeout, fetch_public_key_record(domain, selector, resolver)).await? {
+            Some(Ok(record)) => return Ok(record),
+            Some(Err(e)) => e,
+            None => anyhow!(
+                "Lookup of d={}, s={} timed out after {}ms at {}",
+                domain,
+                selector,
+                timeout,
+                resolver.archive_url
+            ),
+        };
+    if !resolver.dns_fallback {
+        return Err(archive_error);
+    }
+
+    let dns_lookup =
+        fetch_public_key_record_from_name_server(domain, selector, resolver.dns_server);
+    let dns_error = match timeout_ms(timeout, dns_lookup).await? {
+        Some(Ok(record)) => return Ok(record),
+        Some(Err(e)) => e,
+        None => anyhow!("timed out after {}ms", timeout),
+    };
+    let message = format!(
+        "{}; DNS fallback for {}._domainkey.{} failed: {}",
+        archive_error, selector, domain, dns_error
+    );
+    if archive_error.is::<PublicKeyNotFound>() && dns_error.is::<PublicKeyNotFound>() {
+        return Err(anyhow::Error::new(PublicKeyNotFound).context(message));
+    }
+    Err(anyhow!(message))
+}
+
+/// Looks up the DKIM key TXT record of a DKIM signer directly in DNS, at
+/// `{selector}._domainkey.{domain}`.
+///
+/// # Arguments
+///
+/// * `domain` - The signing domain from the `d=` tag.
+/// * `selector` - The selector from the `s=` tag.
+///
+/// # Returns
+///
+/// A `Result` containing the first record with a `p=` tag, its strings concatenated, or an error
+/// if the lookup fails, which is a `PublicKeyNotFound` error if no such record exists.
+pub async fn fetch_public_key_record_from_dns(domain: &str, selector: &str) -> Result<String> {
+    fetch_public_key_record_from_name_server(domain, selector, None).await
+}
+
+/// Looks up the DKIM key TXT record of a DKIM signer as `fetch_public_key_record_from_dns` does,
+/// at the given name server, or at Google Public DNS if it is `None`.
+#[cfg(not(target_arch = "wasm32"))]
+async fn fetch_public_key_record_from_name_server(
+    domain: &str,
+    selector: &str,
+    name_server: Option<SocketAddr>,
+) -> Result<String> {
+    use trust_dns_resolver::{
+        config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+        error::ResolveErrorKind,
+        TokioAsyncResolver,
+    };
+
+    let config = match name_server {
+        Some(addr) => ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+        ),
+        None => ResolverConfig::default(),
+    };
+    let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default())?;
+    let lookup = resolver
+        .txt_lookup(format!("{}._domainkey.{}", selector, domain))
+        .await
+        .map_err(|e| match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => anyhow::Error::new(PublicKeyNotFound),
+            _ => e.into(),
+        })?;
+    lookup
+        .iter()
+        .map(|txt| join_txt_strings(txt.txt_data()))
+        .find(|record| parse_dkim_tags(record).contains_key("p"))
+        .ok_or_else(|| anyhow::Error::new(PublicKeyNotFound))
+}
+
+/// Looks up the DKIM key TXT record of a DKIM signer directly in DNS, which is not possible in
+/// wasm.
+///
+/// # Returns
+///
+/// An error, always.
+#[cfg(target_arch = "wasm32")]
+async fn fetch_public_key_record_from_name_server(
+    _domain: &str,
+    _selector: &str,
+    _name_server: Option<SocketAddr>,
+) -> Result<String> {
+    Err(anyhow!("Direct DNS lookups are not available in wasm"))
+}
+
+/// Concatenates the strings of a TXT record, which splits values longer than 255 bytes.
+fn join_txt_strings(strings: &[Box<[u8]>]) -> String {
+    strings
+        .iter()
+        .map(|string| String::from_utf8_lossy(string))
+        .collect()
+}
+
+/// Fetches the DKIM key TXT record of a DKIM signer from the DKIM key archive.
+///
+/// # Arguments
+///
+/// * `domain` - The signing domain from the `d=` tag.
+/// * `selector` - The selector from the `s=` tag.
+/// * `resolver` - The resolver configuration naming the archive to query.
+///
+/// # Returns
+///
+/// A `Result` containing the record (e.g. `v=DKIM1; k=rsa; p=...`), or a `PublicKeyNotFound`
+/// error if no record is found.
+pub async fn fetch_public_key_record(
+    domain: &str,
+    selector: &str,
+    resolver: &CustomResolverConfig,
+) -> Result<String> {
+    fetch_public_key_records(domain, selector, resolver)
+        .await?
+        .into_iter()
+        .next()
+        .map(|record| record.value)
+        .ok_or_else(|| anyhow::Error::new(PublicKeyNotFound))
+}
+
+/// Fetches every DKIM key TXT record the DKIM key archive knows for a DKIM signer.
+///
+/// # Arguments
+///
+/// * `domain` - The signing domain from the `d=` tag.
+/// * `selector` - The selector from the `s=` tag.
+/// * `resolver` - The resolver configuration naming the archive to query.
+///
+/// # Returns
+///
+/// A `Result` containing the records in the order the archive lists them, which is empty if the
+/// archive knows none, or an error if the archive cannot be queried.
+pub async fn fetch_public_key_records(
+    domain: &str,
+    selector: &str,
+    resolver: &CustomResolverConfig,
+) -> Result<Vec<DkimDnsRecord>> {
+    // Fetch the DNS TXT records for the domain key, retrying transient failures
+    let client = reqwest::Client::new();
+    let max_attempts = resolver.retry.max_attempts.max(1);
+    let mut attempt = 1;
+    let response = loop {
+        let error = match client
+            .get(&resolver.archive_url)
+            .query(&[("domain", domain), ("selector", selector)])
+            .send()
+            .await
+        {
+            Ok(response)
+                if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || response.status().is_server_error() =>
+            {
+                anyhow!("DKIM key archive responded with {}", response.status())
+            }
+            Ok(response) => break response,
+            Err(e) if e.is_timeout() || e.is_connect() => e.into(),
+            Err(e) => return Err(e.into()),
+        };
+        if attempt >= max_attempts {
+            return Err(error.context(format!(
+                "DKIM key archive request failed after {} attempts",
+                attempt
+            )));
+        }
+        sleep_ms(resolver.retry.backoff(attempt).as_millis() as u64).await;
+        attempt += 1;
+    };
+    let data: serde_json::Value = response.json().await?;
+
+    Ok(data
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|record| record.get("value").and_then(|value| value.as_str()))
+        .map(|value| DkimDnsRecord::new(domain, selector, value))
+        .collect())
+}
+
+/// Fetches the DKIM key TXT records of every DKIM signature in the email headers from the DKIM
+/// key archive.
+///
+/// Signatures without a `d=` or `s=` tag are skipped.
+///
+/// # Arguments
+///
+/// * `email_headers` - The headers of the email.
+/// * `resolver` - The resolver configuration naming the archive to query.
+///
+/// # Returns
+///
+/// A `Result` containing the records of each signature in header order, or an error if the
+/// archive cannot be queried.
+pub async fn fetch_dkim_dns_records(
+    email_headers: &EmailHeaders,
+    resolver: &CustomResolverConfig,
+) -> Result<Vec<DkimDnsRecord>> {
+    let mut records = Vec::new();
+    for header in email_headers
+        .get_header("DKIM-Signature")
+        .unwrap_or_default()
+    {
+        let tags = parse_dkim_tags(&header);
+        if let (Some(domain), Some(selector)) = (tags.get("d"), tags.get("s")) {
+            records.extend(fetch_public_key_records(domain, selector, resolver).await?);
+        }
     }
+    Ok(records)
 }
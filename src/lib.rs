@@ -6,6 +6,9 @@ pub mod cryptos;
 pub mod logger;
 pub mod parse_email;
 pub mod proof;
+mod runtime;
+#[cfg(test)]
+mod test_fixtures;
 pub mod wasm;
 
 pub use circuit::*;
@@ -1,18 +1,32 @@
 //! This module contains the `ParsedEmail` struct and its implementation.
 
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap, convert::TryInto, fmt, time::Duration};
 
-use crate::cryptos::fetch_public_key;
-use anyhow::Result;
+use crate::command_templates::{extract_template_vals_from_text, TemplateValue};
+use crate::cryptos::{
+    decode_public_key, decode_public_key_exponent, fetch_public_key_record_with_timeout,
+    parse_dkim_public_key_exponent, parse_dkim_public_key_record, parse_dkim_tags,
+    verify_dkim_body_hash, verify_dkim_header_signature, CustomResolverConfig, DkimAlgorithm,
+    EmailPublicKey,
+};
+use crate::{
+    DEFAULT_DNS_TIMEOUT_MS, DEFAULT_MAX_RAW_EMAIL_BYTES, DKIM_API_URL_KEY,
+    DKIM_FETCH_TIMEOUT_MS_KEY,
+};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine};
 use cfdkim::canonicalize_signed_email;
 use hex;
+use idna::domain_to_ascii;
 use itertools::Itertools;
-use mailparse::{parse_mail, ParsedMail};
+use lazy_static::lazy_static;
+use mailparse::{body::Body, dateparse, parse_header, parse_mail, ParsedMail};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use zk_regex_apis::extract_substrs::{
-    extract_body_hash_idxes, extract_email_addr_idxes, extract_email_domain_idxes,
-    extract_from_addr_idxes, extract_message_id_idxes, extract_subject_all_idxes,
-    extract_substr_idxes, extract_timestamp_idxes, extract_to_addr_idxes,
+    extract_email_addr_idxes, extract_email_domain_idxes, extract_from_addr_idxes,
+    extract_subject_all_idxes, extract_substr_idxes, extract_timestamp_idxes,
+    DecomposedRegexConfig, ExtractSubstrssError,
 };
 
 /// `ParsedEmail` holds the canonicalized parts of an email along with its signature and public key.
@@ -21,57 +35,937 @@ use zk_regex_apis::extract_substrs::{
 pub struct ParsedEmail {
     /// The canonicalized email header.
     pub canonicalized_header: String,
-    /// The canonicalized email body.
+    /// The canonicalized email body, lossily converted to UTF-8.
     pub canonicalized_body: String,
+    /// The canonicalized email body bytes, exactly as covered by the DKIM body hash.
+    pub canonicalized_body_bytes: Vec<u8>,
     /// The email signature bytes.
     pub signature: Vec<u8>,
-    /// The public key bytes associated with the email.
-    pub public_key: Vec<u8>,
-    /// The cleaned email body.
+    /// The DKIM public key associated with the email.
+    pub public_key: EmailPublicKey,
+    /// The big-endian public exponent of an RSA DKIM key, empty for ed25519 keys.
+    pub public_key_e: Vec<u8>,
+    /// The cleaned email body, lossily converted to UTF-8.
     pub cleaned_body: String,
     /// The email headers.
     pub headers: EmailHeaders,
 }
 
+/// Options controlling how `ParsedEmail::new_from_raw_email_with_options` resolves DKIM keys.
+#[derive(Debug, Clone)]
+pub struct ParseEmailOptions {
+    pub dns_timeout: Duration, // Time limit for each DKIM public key lookup
+    pub resolver: Option<CustomResolverConfig>, // Resolver to use instead of the default DKIM key archive
+    pub verify_body_hash: bool, // Whether a DKIM body hash mismatch makes parsing fail
+    pub max_raw_email_size: usize, // Maximum size in bytes of the raw email, checked before canonicalization
+    pub strip_non_text_parts: bool, // Whether non-text MIME parts outside the signed body are dropped before canonicalization; only applies to signatures with an `l=` tag
+    pub multiple_from_policy: MultipleFromPolicy, // How a From header with several addresses is handled
+    pub third_party_signers: Option<Vec<String>>, // Signing domain suffixes, e.g. `amazonses.com`, accepted when not aligned with the From domain; `None` accepts any
+}
+
+impl Default for ParseEmailOptions {
+    fn default() -> Self {
+        Self {
+            dns_timeout: Duration::from_millis(DEFAULT_DNS_TIMEOUT_MS),
+            resolver: None,
+            verify_body_hash: true,
+            max_raw_email_size: DEFAULT_MAX_RAW_EMAIL_BYTES,
+            strip_non_text_parts: false,
+            multiple_from_policy: MultipleFromPolicy::default(),
+            third_party_signers: None,
+        }
+    }
+}
+
+/// How `ParsedEmail::new_from_raw_email_with_options` handles a From header that lists several
+/// addresses, e.g. `From: a@example.com, b@example.com` as sent by some calendar systems.
+///
+/// The From domain decides which DKIM signatures are tried first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MultipleFromPolicy {
+    /// The email is rejected.
+    #[default]
+    Reject,
+    /// The domain of the first address is used.
+    UseFirst,
+    /// The email is accepted if all addresses share a domain, which is then used.
+    RequireAllSameDomain,
+}
+
+impl ParseEmailOptions {
+    /// Creates the default options, with the DKIM key archive and lookup time limit taken from
+    /// the `DKIM_API_URL` and `DKIM_FETCH_TIMEOUT_MS` environment variables if they are set.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the options, or an error if `DKIM_FETCH_TIMEOUT_MS` is not a number
+    /// of milliseconds.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    /// Creates the options as `from_env` does, reading the variables with `lookup`.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut options = Self::default();
+        if let Some(timeout) = lookup(DKIM_FETCH_TIMEOUT_MS_KEY) {
+            let timeout = timeout.parse().map_err(|e| {
+                anyhow!("Invalid {} {:?}: {}", DKIM_FETCH_TIMEOUT_MS_KEY, timeout, e)
+            })?;
+            options.dns_timeout = Duration::from_millis(timeout);
+        }
+        if lookup(DKIM_API_URL_KEY).is_some() {
+            options.resolver = Some(CustomResolverConfig::from_lookup(&lookup));
+        }
+        Ok(options)
+    }
+}
+
+/// The canonicalization algorithm applied to the email header before signing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HeaderCanon {
+    /// Header fields are signed exactly as they appear.
+    Simple,
+    /// Header names are lowercased and whitespace is unfolded and compressed.
+    Relaxed,
+}
+
+/// The canonicalization algorithm applied to the email body before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BodyCanon {
+    /// Only trailing empty lines are removed.
+    Simple,
+    /// Trailing whitespace is removed and runs of whitespace are compressed.
+    Relaxed,
+}
+
+/// A part of an email that a value can be found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmailPart {
+    /// The canonicalized email header.
+    Header,
+    /// The cleaned email body.
+    Body,
+}
+
+/// An invitation code found by `ParsedEmail::find_invitation_code`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvitationCodeLocation {
+    pub code: String,          // The invitation code
+    pub part: EmailPart,       // The part of the email the code was found in
+    pub idxes: (usize, usize), // The index range of the code within that part
+}
+
+/// A DKIM-Signature header of an email, as listed by `ParsedEmail::all_dkim_signatures`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DkimSignatureInfo {
+    pub domain: Option<String>,    // The signing domain from the `d=` tag
+    pub selector: Option<String>,  // The selector from the `s=` tag
+    pub algorithm: Option<String>, // The signing algorithm from the `a=` tag
+    pub header_canonicalization: HeaderCanon, // The header canonicalization from the `c=` tag
+    pub body_canonicalization: BodyCanon, // The body canonicalization from the `c=` tag
+    pub verified: bool,            // Whether this is the signature the email was verified with
+}
+
+/// The DKIM key an email was verified with, along with the signature it verified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedDkimKey {
+    pub public_key: EmailPublicKey, // The DKIM public key that verified the signature
+    pub public_key_e: Vec<u8>, // The big-endian public exponent of an RSA key, empty for ed25519 keys
+    pub domain: String,        // The signing domain from the `d=` tag of the verified signature
+    pub selector: String,      // The selector from the `s=` tag of the verified signature
+    pub signature_index: usize, // The position of the verified signature among the DKIM-Signature headers
+    pub third_party_signer: bool, // Whether the signing domain is not aligned with the From domain, e.g. an email service signing on behalf of the sender
+}
+
+/// The outcome of `ParsedEmail::verify`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DkimVerificationReport {
+    pub header_hash_matched: bool, // Whether the signature verifies over the canonicalized header with the key
+    pub body_hash_matched: Option<bool>, // Whether the body hash matches the body, or `None` if not checked
+    pub algorithm: Option<String>,       // The signing algorithm from the `a=` tag
+    pub domain: Option<String>,          // The signing domain from the `d=` tag
+    pub selector: Option<String>,        // The selector from the `s=` tag
+}
+
+/// One DKIM signature tried by `ParsedEmail::new_from_raw_email_bytes_with_attempts`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DkimVerificationAttempt {
+    pub signature_index: usize, // The position of the signature among the DKIM-Signature headers
+    pub domain: String,         // The signing domain from the `d=` tag
+    pub selector: String,       // The selector from the `s=` tag
+    pub key_parsed: bool,       // Whether the public key was resolved and decoded
+    pub signature_verified: bool, // Whether the signature verifies over the canonicalized header with the key
+    pub body_hash_matched: Option<bool>, // Whether the body hash matches the body, or `None` if not checked
+    pub error: Option<String>,           // Why the signature could not be used, if it failed
+}
+
+impl DkimVerificationAttempt {
+    /// Creates an attempt for a signature that has not been checked yet.
+    fn new(signature: &DkimSignatureCandidate) -> Self {
+        Self {
+            signature_index: signature.index,
+            domain: signature.domain.clone(),
+            selector: signature.selector.clone(),
+            key_parsed: false,
+            signature_verified: false,
+            body_hash_matched: None,
+            error: None,
+        }
+    }
+}
+
+/// The DKIM signatures tried by `ParsedEmail::new_from_raw_email_bytes_with_attempts`, in the
+/// order they were tried.
+///
+/// When no signature verifies, this is the error, so it can be recovered with
+/// `anyhow::Error::downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DkimVerificationAttempts {
+    pub attempts: Vec<DkimVerificationAttempt>, // Every signature tried, the verified one last
+}
+
+impl DkimVerificationAttempts {
+    /// Returns the attempt that verified the email, if any.
+    pub fn verified(&self) -> Option<&DkimVerificationAttempt> {
+        self.attempts.iter().find(|attempt| attempt.error.is_none())
+    }
+}
+
+impl fmt::Display for DkimVerificationAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failures =
+            self.attempts
+                .iter()
+                .filter_map(|attempt| {
+                    attempt.error.as_ref().map(|error| {
+                        format!("d={}, s={}: {}", attempt.domain, attempt.selector, error)
+                    })
+                })
+                .collect_vec();
+        write!(
+            f,
+            "No DKIM signature could be verified: {}",
+            failures.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for DkimVerificationAttempts {}
+
+impl DkimVerificationReport {
+    /// Returns whether every check that was performed passed.
+    pub fn is_valid(&self) -> bool {
+        self.header_hash_matched && self.body_hash_matched != Some(false)
+    }
+}
+
+/// The decoded text of the MIME part of an email body that commands are matched against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedBody {
+    pub text: String,          // The decoded part, converted to UTF-8 from its charset
+    pub bytes: Vec<u8>,        // The decoded part before charset conversion
+    pub index_map: Vec<usize>, // For each byte of `bytes`, the index in the canonicalized body of the encoded data it came from
+}
+
+/// The timestamps an email carries, as returned by `ParsedEmail::get_timestamps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailTimestamps {
+    pub dkim_t: Option<u64>,      // The DKIM `t=` signature timestamp
+    pub date_header: Option<u64>, // The 'Date' header timestamp
+}
+
+impl EmailTimestamps {
+    /// Returns the number of seconds between the DKIM `t=` timestamp and the 'Date' header, or
+    /// `None` if either is missing.
+    pub fn drift_secs(&self) -> Option<u64> {
+        match (self.dkim_t, self.date_header) {
+            (Some(dkim_t), Some(date_header)) => Some(if dkim_t > date_header {
+                dkim_t - date_header
+            } else {
+                date_header - dkim_t
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the DKIM `t=` timestamp and the 'Date' header agree within a tolerance.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_drift_secs` - The largest accepted difference in seconds.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the timestamps differ by at most `max_drift_secs`, or if either is missing.
+    pub fn is_within_drift(&self, max_drift_secs: u64) -> bool {
+        self.drift_secs()
+            .is_none_or(|drift| drift <= max_drift_secs)
+    }
+}
+
+/// The values derived from a `ParsedEmail` by its getters, for consumers that cannot call them.
+///
+/// Each field is `None` when the corresponding getter fails, for example because the header is
+/// missing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParsedEmailView {
+    pub from_addr: Option<String>,                  // The 'From' address
+    pub from_addr_idxes: Option<(usize, usize)>, // Index range of the 'From' address in the header
+    pub email_domain: Option<String>,            // The domain of the 'From' address
+    pub email_domain_idxes: Option<(usize, usize)>, // Index range of the domain in the 'From' address
+    pub subject: Option<String>,                    // The raw subject line
+    pub subject_idxes: Option<(usize, usize)>,      // Index range of the subject line in the header
+    pub timestamp: Option<u64>, // The DKIM `t=` timestamp, or the 'Date' header as a fallback
+    pub timestamp_idxes: Option<(usize, usize)>, // Index range of the DKIM `t=` timestamp in the header
+    pub timestamps: Option<EmailTimestamps>, // Both the DKIM `t=` and the 'Date' header timestamps
+    pub message_id: Option<String>,          // The message ID
+    pub message_id_idxes: Option<(usize, usize)>, // Index range of the message ID in the header
+    pub header_canonicalization: HeaderCanon, // The header canonicalization of the verified signature
+    pub body_canonicalization: BodyCanon,     // The body canonicalization of the verified signature
+    pub dkim_signatures: Vec<DkimSignatureInfo>, // Every DKIM signature the email carries
+    pub dkim_domain: Option<String>,          // The signing domain of the verified signature
+    pub dkim_selector: Option<String>,        // The selector of the verified signature
+    pub public_key_exponent: String, // The RSA public exponent as a "0x"-prefixed hex string
+}
+
 impl ParsedEmail {
     /// Creates a new `ParsedEmail` from a raw email string.
     ///
     /// This function parses the raw email, extracts and canonicalizes the header and body,
     /// and retrieves the signature and public key.
     ///
+    /// If the email carries several DKIM-Signature headers, each one is tried in turn, starting
+    /// with those whose `d=` domain matches the From domain, and the first signature that
+    /// verifies is used. The keys are resolved with the options of `ParseEmailOptions::from_env`,
+    /// so by default each public key lookup is limited to 10 seconds.
+    ///
     /// # Arguments
     ///
     /// * `raw_email` - A string slice representing the raw email to be parsed.
     ///
     /// # Returns
     ///
-    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails.
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails, the
+    /// environment sets an invalid option, or no DKIM signature verifies.
     pub async fn new_from_raw_email(raw_email: &str) -> Result<Self> {
+        Self::new_from_raw_email_bytes(raw_email.as_bytes()).await
+    }
+
+    /// Creates a new `ParsedEmail` from a raw email string, resolving DKIM keys as configured.
+    ///
+    /// This behaves like `new_from_raw_email`, but lets the caller bound the time spent on each
+    /// public key lookup and choose where the keys are resolved from.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A string slice representing the raw email to be parsed.
+    /// * `options` - The DNS timeout and resolver to use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails or no
+    /// DKIM signature verifies.
+    pub async fn new_from_raw_email_with_options(
+        raw_email: &str,
+        options: &ParseEmailOptions,
+    ) -> Result<Self> {
+        Self::new_from_raw_email_bytes_with_options(raw_email.as_bytes(), options).await
+    }
+
+    /// Creates a new `ParsedEmail` from the raw bytes of an email.
+    ///
+    /// This behaves like `new_from_raw_email`, but also accepts emails whose body is not valid
+    /// UTF-8, such as latin-1 bodies sent without a transfer encoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A byte slice representing the raw email to be parsed.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails, the
+    /// environment sets an invalid option, or no DKIM signature verifies.
+    pub async fn new_from_raw_email_bytes(raw_email: &[u8]) -> Result<Self> {
+        Self::new_from_raw_email_bytes_with_options(raw_email, &ParseEmailOptions::from_env()?)
+            .await
+    }
+
+    /// Creates a new `ParsedEmail` from the raw bytes of an email, resolving DKIM keys as
+    /// configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A byte slice representing the raw email to be parsed.
+    /// * `options` - The DNS timeout and resolver to use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails or no
+    /// DKIM signature verifies.
+    pub async fn new_from_raw_email_bytes_with_options(
+        raw_email: &[u8],
+        options: &ParseEmailOptions,
+    ) -> Result<Self> {
+        Self::new_from_raw_email_bytes_with_attempts(raw_email, options)
+            .await
+            .map(|(parsed_email, _)| parsed_email)
+    }
+
+    /// Creates a new `ParsedEmail` from the raw bytes of an email, reporting each DKIM signature
+    /// that was tried.
+    ///
+    /// This behaves like `new_from_raw_email_bytes_with_options`, but also returns why the
+    /// signatures tried before the verified one were skipped. If no signature verifies, the error
+    /// is a `DkimVerificationAttempts` listing every signature tried.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A byte slice representing the raw email to be parsed.
+    /// * `options` - The DNS timeout and resolver to use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance, whose `public_key` is the key that
+    /// verified it, with the attempts, or an error if parsing fails or no DKIM signature
+    /// verifies.
+    pub async fn new_from_raw_email_bytes_with_attempts(
+        raw_email: &[u8],
+        options: &ParseEmailOptions,
+    ) -> Result<(Self, DkimVerificationAttempts)> {
+        check_raw_email_size(raw_email, options.max_raw_email_size)?;
+
         // Extract all headers
-        let parsed_mail = parse_mail(raw_email.as_bytes())?;
+        let parsed_mail = parse_mail(raw_email).context("Failed to parse email headers")?;
         let headers: EmailHeaders = EmailHeaders::new_from_mail(&parsed_mail);
 
-        let public_key = fetch_public_key(headers.clone()).await?;
+        let mut attempts = Vec::new();
+        for signature in dkim_signature_candidates(
+            &headers,
+            options.multiple_from_policy,
+            options.third_party_signers.as_deref(),
+        )? {
+            let mut attempt = DkimVerificationAttempt::new(&signature);
+            let result = Self::new_with_resolved_signature(
+                raw_email,
+                &headers,
+                &signature,
+                options,
+                &mut attempt,
+            )
+            .await;
+            match result {
+                Ok(parsed_email) => {
+                    attempts.push(attempt);
+                    return Ok((parsed_email, DkimVerificationAttempts { attempts }));
+                }
+                Err(e) => {
+                    attempt.error = Some(format!("{:#}", e));
+                    attempts.push(attempt);
+                }
+            }
+        }
+
+        Err(DkimVerificationAttempts { attempts }.into())
+    }
+
+    /// Creates a new `ParsedEmail` from a raw email string and a caller-supplied DKIM public key.
+    ///
+    /// No network access is performed. The email is canonicalized exactly as in
+    /// `new_from_raw_email`, and the first DKIM signature that the provided key verifies is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A string slice representing the raw email to be parsed.
+    /// * `public_key_der_or_modulus` - The DKIM public key, either DER-encoded, as a raw ed25519
+    ///   key, or as a bare RSA modulus.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails or the
+    /// key does not verify any DKIM signature.
+    pub fn new_from_raw_email_with_public_key(
+        raw_email: &str,
+        public_key_der_or_modulus: &[u8],
+    ) -> Result<Self> {
+        Self::new_from_raw_email_bytes_with_public_key(
+            raw_email.as_bytes(),
+            public_key_der_or_modulus,
+        )
+    }
+
+    /// Creates a new `ParsedEmail` from the raw bytes of an email and a caller-supplied DKIM
+    /// public key.
+    ///
+    /// This behaves like `new_from_raw_email_with_public_key`, but also accepts emails whose body
+    /// is not valid UTF-8.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A byte slice representing the raw email to be parsed.
+    /// * `public_key_der_or_modulus` - The DKIM public key, either DER-encoded, as a raw ed25519
+    ///   key, or as a bare RSA modulus.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails or the
+    /// key does not verify any DKIM signature.
+    pub fn new_from_raw_email_bytes_with_public_key(
+        raw_email: &[u8],
+        public_key_der_or_modulus: &[u8],
+    ) -> Result<Self> {
+        check_raw_email_size(raw_email, DEFAULT_MAX_RAW_EMAIL_BYTES)?;
+        let parsed_mail = parse_mail(raw_email).context("Failed to parse email headers")?;
+        let headers: EmailHeaders = EmailHeaders::new_from_mail(&parsed_mail);
+
+        let mut failures = Vec::new();
+        // The key is given, so the From domain only decides the order signatures are tried in
+        for signature in dkim_signature_candidates(&headers, MultipleFromPolicy::UseFirst, None)? {
+            // The a= tag of each signature decides how the key bytes are read
+            let verified = DkimAlgorithm::from_tag(&signature.algorithm).and_then(|algorithm| {
+                Self::new_with_verified_signature(
+                    raw_email,
+                    &headers,
+                    &signature,
+                    decode_public_key(public_key_der_or_modulus, algorithm)?,
+                    decode_public_key_exponent(public_key_der_or_modulus, algorithm),
+                    true,
+                )
+            });
+            match verified {
+                Ok(parsed_email) => return Ok(parsed_email),
+                Err(e) => failures.push(signature.describe_failure(&e)),
+            }
+        }
+
+        Err(anyhow!(
+            "No DKIM signature could be verified: {}",
+            failures.join("; ")
+        ))
+    }
+
+    /// Creates a new `ParsedEmail` from a raw email string and a caller-supplied DKIM public key
+    /// without verifying the DKIM signature.
+    ///
+    /// This is intended for pure input-generation use cases, where the circuit checks the
+    /// signature anyway. The first DKIM-Signature header is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_email` - A string slice representing the raw email to be parsed.
+    /// * `public_key_der_or_modulus` - The DKIM public key, either DER-encoded, as a raw ed25519
+    ///   key, or as a bare RSA modulus.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is either a `ParsedEmail` instance or an error if parsing fails.
+    pub fn new_from_raw_email_with_public_key_unverified(
+        raw_email: &str,
+        public_key_der_or_modulus: &[u8],
+    ) -> Result<Self> {
+        check_raw_email_size(raw_email.as_bytes(), DEFAULT_MAX_RAW_EMAIL_BYTES)?;
+        let parsed_mail =
+            parse_mail(raw_email.as_bytes()).context("Failed to parse email headers")?;
+        let headers: EmailHeaders = EmailHeaders::new_from_mail(&parsed_mail);
+
+        let signature_tags = headers
+            .get_header(DKIM_SIGNATURE_HEADER)
+            .and_then(|signatures| {
+                signatures
+                    .first()
+                    .map(|signature| parse_dkim_tags(signature))
+            })
+            .ok_or_else(|| ParsedEmailError::HeaderNotPresent(DKIM_SIGNATURE_HEADER.to_string()))?;
+        let algorithm = DkimAlgorithm::from_tag(
+            signature_tags
+                .get("a")
+                .map(String::as_str)
+                .unwrap_or_default(),
+        )?;
+        let public_key = decode_public_key(public_key_der_or_modulus, algorithm)?;
+        let public_key_e = decode_public_key_exponent(public_key_der_or_modulus, algorithm);
+
+        Self::new_with_public_key(raw_email.as_bytes(), headers, public_key, public_key_e)
+    }
+
+    /// Resolves the public key of the given DKIM signature and verifies the email with it.
+    async fn new_with_resolved_signature(
+        raw_email: &[u8],
+        headers: &EmailHeaders,
+        signature: &DkimSignatureCandidate,
+        options: &ParseEmailOptions,
+        attempt: &mut DkimVerificationAttempt,
+    ) -> Result<Self> {
+        signature.check_tags()?;
+        let resolver = options.resolver.clone().unwrap_or_default();
+        let record = fetch_public_key_record_with_timeout(
+            &signature.domain,
+            &signature.selector,
+            &resolver,
+            options.dns_timeout,
+        )
+        .await
+        .and_then(|record| {
+            Ok((
+                parse_dkim_public_key_record(&record)?,
+                parse_dkim_public_key_exponent(&record)?,
+            ))
+        })
+        .context("DKIM public key resolution failed")?;
+        attempt.key_parsed = true;
+
+        if options.strip_non_text_parts && signature.partial_body {
+            // Only keep the stripped email if the signed part of the body is unchanged
+            let stripped = strip_non_text_mime_parts(raw_email)?;
+            if stripped.len() < raw_email.len() {
+                if let Ok(parsed_email) = Self::new_with_checked_signature(
+                    &stripped,
+                    headers,
+                    signature,
+                    record.0.clone(),
+                    record.1.clone(),
+                    true,
+                    attempt,
+                ) {
+                    return Ok(parsed_email);
+                }
+            }
+        }
+        Self::new_with_checked_signature(
+            raw_email,
+            headers,
+            signature,
+            record.0,
+            record.1,
+            options.verify_body_hash,
+            attempt,
+        )
+    }
+
+    /// Canonicalizes the raw email for the given DKIM signature and verifies it with the key.
+    ///
+    /// The body hash is only checked if `verify_body_hash` is set.
+    fn new_with_verified_signature(
+        raw_email: &[u8],
+        headers: &EmailHeaders,
+        signature: &DkimSignatureCandidate,
+        public_key: EmailPublicKey,
+        public_key_e: Vec<u8>,
+        verify_body_hash: bool,
+    ) -> Result<Self> {
+        Self::new_with_checked_signature(
+            raw_email,
+            headers,
+            signature,
+            public_key,
+            public_key_e,
+            verify_body_hash,
+            &mut DkimVerificationAttempt::new(signature),
+        )
+    }
+
+    /// Behaves like `new_with_verified_signature`, recording the outcome of each check in
+    /// `attempt`.
+    ///
+    /// Both the body hash and the header signature are checked, so that a report shows every
+    /// check that failed.
+    fn new_with_checked_signature(
+        raw_email: &[u8],
+        headers: &EmailHeaders,
+        signature: &DkimSignatureCandidate,
+        public_key: EmailPublicKey,
+        public_key_e: Vec<u8>,
+        verify_body_hash: bool,
+        attempt: &mut DkimVerificationAttempt,
+    ) -> Result<Self> {
+        attempt.signature_verified = false;
+        attempt.body_hash_matched = None;
+
+        // The canonicalization uses the first DKIM-Signature header, so move ours to the top
+        let raw_email = with_header_terminator(raw_email);
+        let raw_email = move_header_to_top(&raw_email, DKIM_SIGNATURE_HEADER, signature.index);
+        let parsed_email =
+            Self::new_with_public_key(&raw_email, headers.clone(), public_key, public_key_e)?;
+
+        let body_hash = if verify_body_hash {
+            Some(verify_dkim_body_hash(
+                &parsed_email.canonicalized_header,
+                &parsed_email.canonicalized_body_bytes,
+            ))
+        } else {
+            None
+        };
+        attempt.body_hash_matched = body_hash.as_ref().map(Result::is_ok);
+        let header_signature = verify_dkim_header_signature(
+            &parsed_email.canonicalized_header,
+            &parsed_email.signature,
+            &parsed_email.public_key,
+            &parsed_email.public_key_e,
+        );
+        attempt.signature_verified = header_signature.is_ok();
+
+        if let Some(body_hash) = body_hash {
+            body_hash.context("DKIM signature verification failed")?;
+        }
+        header_signature.context("DKIM signature verification failed")?;
+
+        Ok(parsed_email)
+    }
+
+    /// Assembles a `ParsedEmail` directly from its canonicalized parts, without parsing or
+    /// verifying anything.
+    ///
+    /// This is intended for tests that need emails which would be impractical to sign, and is
+    /// only available in tests or with the `test-utils` feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `canonicalized_header` - The canonicalized email header.
+    /// * `canonicalized_body` - The canonicalized email body.
+    /// * `signature` - The signature bytes.
+    /// * `public_key` - The DKIM public key, either DER-encoded, as a raw ed25519 key, or as a
+    ///   bare RSA modulus.
+    ///
+    /// # Returns
+    ///
+    /// The assembled `ParsedEmail`.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn from_parts(
+        canonicalized_header: String,
+        canonicalized_body: String,
+        signature: Vec<u8>,
+        public_key: Vec<u8>,
+    ) -> ParsedEmail {
+        let headers = parse_mail(canonicalized_header.as_bytes())
+            .map(|mail| EmailHeaders::new_from_mail(&mail))
+            .unwrap_or_else(|_| EmailHeaders(HashMap::new()));
+        let canonicalized_body_bytes = canonicalized_body.as_bytes().to_vec();
+        // Fixtures without a usable a= tag are taken to be RSA signatures
+        let algorithm = find_dkim_signature_tags(&canonicalized_header)
+            .ok()
+            .and_then(|tags| DkimAlgorithm::from_tag(tags.get("a")?).ok())
+            .unwrap_or(DkimAlgorithm::RsaSha256);
+        ParsedEmail {
+            cleaned_body: String::from_utf8_lossy(
+                &remove_quoted_printable_soft_breaks(canonicalized_body_bytes.clone()).0,
+            )
+            .into_owned(),
+            canonicalized_header,
+            canonicalized_body,
+            canonicalized_body_bytes,
+            signature,
+            public_key_e: decode_public_key_exponent(&public_key, algorithm),
+            public_key: decode_public_key(&public_key, algorithm)
+                .unwrap_or_else(|_| EmailPublicKey::Rsa(public_key.clone())),
+            headers,
+        }
+    }
 
+    /// Canonicalizes the raw email and assembles a `ParsedEmail` with the given public key.
+    fn new_with_public_key(
+        raw_email: &[u8],
+        headers: EmailHeaders,
+        public_key: EmailPublicKey,
+        public_key_e: Vec<u8>,
+    ) -> Result<Self> {
         // Canonicalize the signed email to separate the header, body, and signature.
+        let raw_email = with_header_terminator(raw_email);
         let (canonicalized_header, canonicalized_body, signature_bytes) =
-            canonicalize_signed_email(raw_email.as_bytes())?;
+            canonicalize_signed_email(&raw_email).context("Failed to canonicalize email")?;
 
         // Construct the `ParsedEmail` instance.
         let parsed_email = ParsedEmail {
             canonicalized_header: String::from_utf8(canonicalized_header)?, // Convert bytes to string, may return an error if not valid UTF-8.
-            canonicalized_body: String::from_utf8(canonicalized_body.clone())?, // Convert bytes to string, may return an error if not valid UTF-8.
+            canonicalized_body: String::from_utf8_lossy(&canonicalized_body).into_owned(), // Convert bytes to string, replacing invalid UTF-8 sequences.
             signature: signature_bytes.into_iter().collect_vec(), // Collect the signature bytes into a vector.
             public_key,
-            cleaned_body: String::from_utf8(
-                remove_quoted_printable_soft_breaks(canonicalized_body).0,
-            )?, // Remove quoted-printable soft breaks from the canonicalized body.
+            public_key_e,
+            cleaned_body: String::from_utf8_lossy(
+                &remove_quoted_printable_soft_breaks(canonicalized_body.clone()).0,
+            )
+            .into_owned(), // Remove quoted-printable soft breaks from the canonicalized body.
+            canonicalized_body_bytes: canonicalized_body,
             headers,
         };
 
         Ok(parsed_email)
     }
 
+    /// Computes the derived values of this email with the getters.
+    pub fn view(&self) -> ParsedEmailView {
+        ParsedEmailView {
+            from_addr: self.get_from_addr().ok(),
+            from_addr_idxes: self.get_from_addr_idxes().ok(),
+            email_domain: self.get_email_domain().ok(),
+            email_domain_idxes: self.get_email_domain_idxes().ok(),
+            subject: self.get_subject_all().ok(),
+            subject_idxes: self.get_subject_all_idxes().ok(),
+            timestamp: self.get_best_timestamp().ok(),
+            timestamp_idxes: self.get_timestamp_idxes().ok(),
+            timestamps: self.get_timestamps().ok(),
+            message_id: self.get_message_id().ok(),
+            message_id_idxes: self.get_message_id_idxes().ok(),
+            header_canonicalization: self.canonicalization().0,
+            body_canonicalization: self.canonicalization().1,
+            dkim_signatures: self.all_dkim_signatures(),
+            dkim_domain: self.signature_tags().remove("d"),
+            dkim_selector: self.signature_tags().remove("s"),
+            public_key_exponent: self.public_key_exponent_string(),
+        }
+    }
+
+    /// Returns the canonicalization algorithms from the `c=` tag of the DKIM signature in use.
+    ///
+    /// A missing tag or body algorithm means `simple`, as specified by RFC 6376.
+    pub fn canonicalization(&self) -> (HeaderCanon, BodyCanon) {
+        parse_canonicalization_tag(self.signature_tags().get("c"))
+    }
+
+    /// Lists every DKIM-Signature header of the email, in header order.
+    ///
+    /// The signature that verified the email, whose bytes are in `signature`, is marked as such.
+    pub fn all_dkim_signatures(&self) -> Vec<DkimSignatureInfo> {
+        self.headers
+            .get_header(DKIM_SIGNATURE_HEADER)
+            .unwrap_or_default()
+            .iter()
+            .map(|header| {
+                let mut tags = parse_dkim_tags(header);
+                let (header_canonicalization, body_canonicalization) =
+                    parse_canonicalization_tag(tags.get("c"));
+                let signature = tags
+                    .get("b")
+                    .map(|b| b.split_whitespace().collect::<String>())
+                    .and_then(|b| general_purpose::STANDARD.decode(b).ok());
+                DkimSignatureInfo {
+                    domain: tags.remove("d"),
+                    selector: tags.remove("s"),
+                    algorithm: tags.remove("a"),
+                    header_canonicalization,
+                    body_canonicalization,
+                    verified: signature.as_ref() == Some(&self.signature),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the key this email was verified with and the domain and selector of the signature
+    /// it verified, e.g. to record which DKIM key an email was accepted with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `VerifiedDkimKey`, or an error if none of the DKIM-Signature
+    /// headers carries the signature of this email.
+    pub fn verified_dkim_key(&self) -> Result<VerifiedDkimKey> {
+        let (signature_index, signature) = self
+            .all_dkim_signatures()
+            .into_iter()
+            .enumerate()
+            .find(|(_, signature)| signature.verified)
+            .ok_or_else(|| anyhow!("No DKIM-Signature header carries the email signature"))?;
+        let domain = signature.domain.unwrap_or_default();
+        let from_domain = from_domain(&self.headers, MultipleFromPolicy::UseFirst)?;
+        Ok(VerifiedDkimKey {
+            public_key: self.public_key.clone(),
+            public_key_e: self.public_key_e.clone(),
+            third_party_signer: !from_domain
+                .as_deref()
+                .is_some_and(|from_domain| is_aligned(from_domain, &domain)),
+            domain,
+            selector: signature.selector.unwrap_or_default(),
+            signature_index,
+        })
+    }
+
+    /// Verifies the DKIM signature of this email with the given key and reports each check.
+    ///
+    /// Unlike the constructors, this does not stop at the first failure, so a body modified in
+    /// transit can be told apart from a wrong key.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key_der_or_modulus` - The DKIM public key, either DER-encoded, as a raw ed25519
+    ///   key, or as a bare RSA modulus.
+    /// * `check_body_hash` - Whether to check the body hash.
+    ///
+    /// # Returns
+    ///
+    /// A `DkimVerificationReport` describing the signature and the outcome of each check.
+    pub fn verify(
+        &self,
+        public_key_der_or_modulus: &[u8],
+        check_body_hash: bool,
+    ) -> DkimVerificationReport {
+        let mut tags = self.signature_tags();
+        let header_hash_matched =
+            DkimAlgorithm::from_tag(tags.get("a").map(String::as_str).unwrap_or_default())
+                .and_then(|algorithm| {
+                    verify_dkim_header_signature(
+                        &self.canonicalized_header,
+                        &self.signature,
+                        &decode_public_key(public_key_der_or_modulus, algorithm)?,
+                        &decode_public_key_exponent(public_key_der_or_modulus, algorithm),
+                    )
+                })
+                .is_ok();
+        let body_hash_matched = if check_body_hash {
+            Some(
+                verify_dkim_body_hash(&self.canonicalized_header, &self.canonicalized_body_bytes)
+                    .is_ok(),
+            )
+        } else {
+            None
+        };
+
+        DkimVerificationReport {
+            header_hash_matched,
+            body_hash_matched,
+            algorithm: tags.remove("a"),
+            domain: tags.remove("d"),
+            selector: tags.remove("s"),
+        }
+    }
+
+    /// Returns the number of body bytes covered by the DKIM signature, from its `l=` tag.
+    ///
+    /// `None` means the whole body is signed. An `l=` tag that is not a number is an error, as
+    /// the signed part of the body is then unknown.
+    pub fn signed_body_length(&self) -> Result<Option<usize>> {
+        self.signature_tags()
+            .get("l")
+            .map(|length| {
+                length
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid DKIM body length tag l={}", length))
+            })
+            .transpose()
+    }
+
+    /// Returns the part of the canonicalized body that the DKIM signature covers.
+    pub fn signed_body(&self) -> Result<&[u8]> {
+        let body = &self.canonicalized_body_bytes;
+        Ok(match self.signed_body_length()? {
+            Some(length) => &body[..length.min(body.len())],
+            None => body,
+        })
+    }
+
+    /// Parses the tags of the DKIM-Signature header in the canonicalized header.
+    fn signature_tags(&self) -> HashMap<String, String> {
+        find_dkim_signature_tags(&self.canonicalized_header).unwrap_or_default()
+    }
+
     /// Converts the signature bytes to a hex string with a "0x" prefix.
     pub fn signature_string(&self) -> String {
         "0x".to_string() + hex::encode(&self.signature).as_str()
@@ -79,62 +973,207 @@ impl ParsedEmail {
 
     /// Converts the public key bytes to a hex string with a "0x" prefix.
     pub fn public_key_string(&self) -> String {
-        "0x".to_string() + hex::encode(&self.public_key).as_str()
+        "0x".to_string() + hex::encode(self.public_key.as_bytes()).as_str()
+    }
+
+    /// Converts the RSA public exponent bytes to a hex string with a "0x" prefix.
+    pub fn public_key_exponent_string(&self) -> String {
+        "0x".to_string() + hex::encode(&self.public_key_e).as_str()
     }
 
     /// Extracts the 'From' address from the canonicalized email header.
     pub fn get_from_addr(&self) -> Result<String> {
-        let idxes = extract_from_addr_idxes(&self.canonicalized_header)?[0];
+        let idxes = self.get_from_addr_idxes()?;
         Ok(self.canonicalized_header[idxes.0..idxes.1].to_string())
     }
 
     /// Retrieves the index range of the 'From' address within the canonicalized email header.
     pub fn get_from_addr_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_from_addr_idxes(&self.canonicalized_header)?[0];
-        Ok(idxes)
+        first_match(self.get_from_addr_all_idxes()?, "From address")
+    }
+
+    /// Retrieves the index ranges of all 'From' address matches within the canonicalized email
+    /// header.
+    pub fn get_from_addr_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        Ok(extract_from_addr_idxes(&self.canonicalized_header)?)
     }
 
-    /// Extracts the 'To' address from the canonicalized email header.
+    /// Extracts the first 'To' address from the canonicalized email header.
+    #[deprecated(note = "use `get_to_addrs` to see every recipient")]
     pub fn get_to_addr(&self) -> Result<String> {
-        let idxes = extract_to_addr_idxes(&self.canonicalized_header)?[0];
-        let str = self.canonicalized_header[idxes.0..idxes.1].to_string();
-        Ok(str)
+        let idxes = first_match(self.get_to_addrs_idxes()?, "To address")?;
+        Ok(self.canonicalized_header[idxes.0..idxes.1].to_string())
     }
 
-    /// Extracts the email domain from the 'From' address in the canonicalized email header.
-    pub fn get_email_domain(&self) -> Result<String> {
-        let idxes = extract_from_addr_idxes(&self.canonicalized_header)?[0];
-        let from_addr = self.canonicalized_header[idxes.0..idxes.1].to_string();
-        let idxes = extract_email_domain_idxes(&from_addr)?[0];
-        let str = from_addr[idxes.0..idxes.1].to_string();
-        Ok(str)
+    /// Extracts all addresses from the 'To' header in the canonicalized email header.
+    pub fn get_to_addrs(&self) -> Result<Vec<String>> {
+        let idxes = self.get_to_addrs_idxes()?;
+        Ok(idxes
+            .into_iter()
+            .map(|(start, end)| self.canonicalized_header[start..end].to_string())
+            .collect())
     }
 
-    /// Retrieves the index range of the email domain within the 'From' address.
+    /// Retrieves the index ranges of all 'To' addresses within the canonicalized email header.
+    pub fn get_to_addrs_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        extract_header_addr_idxes(&self.canonicalized_header, "to")
+    }
+
+    /// Extracts the values of every occurrence of a header in the canonicalized email header.
+    ///
+    /// The header name is matched case-insensitively and folded values are unfolded.
+    pub fn get_header_values(&self, name: &str) -> Vec<String> {
+        self.get_header_value_idxes(name)
+            .into_iter()
+            .map(|idxes| unfold_header_value(&self.canonicalized_header[idxes.0..idxes.1]))
+            .collect()
+    }
+
+    /// Retrieves the index ranges of the values of every occurrence of a header within the
+    /// canonicalized email header.
+    ///
+    /// A folded value spans its continuation lines.
+    pub fn get_header_value_idxes(&self, name: &str) -> Vec<(usize, usize)> {
+        find_header_values_idxes(&self.canonicalized_header, name).unwrap_or_default()
+    }
+
+    /// Extracts all addresses from the 'Cc' header in the canonicalized email header.
+    pub fn get_cc_addrs(&self) -> Result<Vec<String>> {
+        let idxes = self.get_cc_addrs_idxes()?;
+        Ok(idxes
+            .into_iter()
+            .map(|(start, end)| self.canonicalized_header[start..end].to_string())
+            .collect())
+    }
+
+    /// Retrieves the index ranges of all 'Cc' addresses within the canonicalized email header.
+    pub fn get_cc_addrs_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        extract_header_addr_idxes(&self.canonicalized_header, "cc")
+    }
+
+    /// Extracts the 'Reply-To' address from the canonicalized email header.
+    pub fn get_reply_to_addr(&self) -> Result<String> {
+        let idxes = self.get_reply_to_addr_idxes()?;
+        Ok(self.canonicalized_header[idxes.0..idxes.1].to_string())
+    }
+
+    /// Retrieves the index range of the 'Reply-To' address within the canonicalized email header.
+    pub fn get_reply_to_addr_idxes(&self) -> Result<(usize, usize)> {
+        let idxes = extract_header_addr_idxes(&self.canonicalized_header, "reply-to")?;
+        first_match(idxes, "Reply-To address")
+    }
+
+    /// Extracts the email domain from the 'From' address in the canonicalized email header.
+    pub fn get_email_domain(&self) -> Result<String> {
+        let from_addr = self.get_from_addr()?;
+        let idxes = self.get_email_domain_idxes()?;
+        let str = from_addr[idxes.0..idxes.1].to_string();
+        Ok(str)
+    }
+
+    /// Retrieves the index range of the email domain within the 'From' address.
     pub fn get_email_domain_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_from_addr_idxes(&self.canonicalized_header)?[0];
-        let str = self.canonicalized_header[idxes.0..idxes.1].to_string();
-        let idxes = extract_email_domain_idxes(&str)?[0];
-        Ok(idxes)
+        first_match(self.get_email_domain_all_idxes()?, "email domain")
+    }
+
+    /// Retrieves the index ranges of all email domain matches within the 'From' address.
+    pub fn get_email_domain_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        let from_addr = self.get_from_addr()?;
+        Ok(extract_email_domain_idxes(&from_addr)?)
+    }
+
+    /// Extracts the email domain of the 'From' address, normalized to its lowercase ASCII form.
+    ///
+    /// Unicode labels are converted to their punycode A-label form, so `Bücher.Example` and
+    /// `xn--bcher-kva.example` both yield `xn--bcher-kva.example`.
+    pub fn get_email_domain_normalized(&self) -> Result<String> {
+        let idxes = self.get_email_domain_header_idxes()?;
+        let domain = &self.canonicalized_header[idxes.0..idxes.1];
+        domain_to_ascii(domain).map_err(|e| anyhow!("Invalid email domain {:?}: {}", domain, e))
+    }
+
+    /// Retrieves the index range of the un-normalized 'From' domain within the canonicalized
+    /// email header.
+    ///
+    /// This is the range that `get_email_domain_normalized` reads, for use in circuit inputs.
+    pub fn get_email_domain_header_idxes(&self) -> Result<(usize, usize)> {
+        let (start, end) = first_match(
+            extract_header_addr_idxes(&self.canonicalized_header, "from")?,
+            "From address",
+        )?;
+        let at = self.canonicalized_header[start..end]
+            .rfind('@')
+            .ok_or_else(|| anyhow!("No email domain found in the email"))?;
+        Ok((start + at + 1, end))
     }
 
     /// Extracts the entire subject line from the canonicalized email header.
     pub fn get_subject_all(&self) -> Result<String> {
-        let idxes = extract_subject_all_idxes(&self.canonicalized_header)?[0];
+        let idxes = self.get_subject_all_idxes()?;
         let str = self.canonicalized_header[idxes.0..idxes.1].to_string();
         Ok(str)
     }
 
+    /// Extracts the subject line and decodes any RFC 2047 encoded-words in it to UTF-8.
+    ///
+    /// Use this for matching commands against the subject; the circuit works on the raw subject
+    /// returned by `get_subject_all`.
+    pub fn get_subject_decoded(&self) -> Result<String> {
+        let subject = self.get_subject_all()?;
+        let (header, _) = parse_header(format!("Subject: {}", subject).as_bytes())
+            .context("Failed to parse the subject header")?;
+        Ok(header.get_value())
+    }
+
+    /// Retrieves the index range of the command in the subject line within the canonicalized
+    /// email header, i.e. the subject without its leading `Re:` and `Fwd:` prefixes.
+    pub fn get_subject_command_idxes(&self) -> Result<(usize, usize)> {
+        let (start, end) = self.get_subject_all_idxes()?;
+        let subject = &self.canonicalized_header[start..end];
+        Ok((end - strip_reply_prefixes(subject).len(), end))
+    }
+
+    /// Extracts the values of a subject-based command.
+    ///
+    /// The templates are matched against the subject slice given by `get_subject_command_idxes`,
+    /// whose indices go into the circuit, after decoding RFC 2047 encoded-words in it. The circuit
+    /// only sees the raw slice, so for an encoded subject the values are not the bytes it sees.
+    ///
+    /// # Arguments
+    ///
+    /// * `templates` - A vector of template strings.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a vector of `TemplateValue`s, or an error if there is no subject or
+    /// the templates do not match it.
+    pub fn extract_subject_command_vals(
+        &self,
+        templates: Vec<String>,
+    ) -> Result<Vec<TemplateValue>> {
+        let idxes = self.get_subject_command_idxes()?;
+        let command = &self.canonicalized_header[idxes.0..idxes.1];
+        let (header, _) = parse_header(format!("Subject: {}", command).as_bytes())
+            .context("Failed to parse the subject header")?;
+        // Prefixes may also be hidden inside an encoded-word
+        let decoded = header.get_value();
+        extract_template_vals_from_text(strip_reply_prefixes(&decoded), templates)
+    }
+
     /// Retrieves the index range of the entire subject line within the canonicalized email header.
     pub fn get_subject_all_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_subject_all_idxes(&self.canonicalized_header)?[0];
-        Ok(idxes)
+        first_match(self.get_subject_all_all_idxes()?, "subject")
+    }
+
+    /// Retrieves the index ranges of all subject line matches within the canonicalized email
+    /// header.
+    pub fn get_subject_all_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        Ok(extract_subject_all_idxes(&self.canonicalized_header)?)
     }
 
     /// Retrieves the index range of the body hash within the canonicalized email header.
     pub fn get_body_hash_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_body_hash_idxes(&self.canonicalized_header)?[0];
-        Ok(idxes)
+        find_dkim_body_hash_idxes(&self.canonicalized_header)
     }
 
     /// Returns the canonicalized email body as a string.
@@ -147,28 +1186,144 @@ impl ParsedEmail {
         Ok(self.cleaned_body.clone())
     }
 
+    /// Returns the text of the email body with its base64 or quoted-printable transfer encoding
+    /// decoded.
+    ///
+    /// See `get_decoded_body_with_index_map` for how the part is selected.
+    pub fn get_decoded_body(&self) -> Result<String> {
+        Ok(self.get_decoded_body_with_index_map()?.text)
+    }
+
+    /// Decodes the text of the email body and maps it back onto the canonicalized body.
+    ///
+    /// The text/html part is used if there is one, as command templates match against it, then
+    /// the text/plain part, and otherwise the whole body.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `DecodedBody`, or an error if the body cannot be decoded.
+    pub fn get_decoded_body_with_index_map(&self) -> Result<DecodedBody> {
+        // Rebuild a MIME document from the top-level content headers and the canonicalized body
+        let mut document = Vec::new();
+        for name in &["Content-Type", "Content-Transfer-Encoding"] {
+            if let Some(value) = self.headers.get_first_ignore_case(name) {
+                document.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+        }
+        document.extend_from_slice(b"\r\n");
+        let body_start = document.len();
+        document.extend_from_slice(&self.canonicalized_body_bytes);
+        let mail = parse_mail(&document).context("Failed to parse the email body")?;
+
+        let mut selected = None;
+        for mimetype in &["text/html", "text/plain"] {
+            selected = find_mime_part(&document, &mail, (body_start, document.len()), mimetype)?;
+            if selected.is_some() {
+                break;
+            }
+        }
+        let (part, (part_body_start, _)) =
+            selected.unwrap_or((&mail, (body_start, document.len())));
+        let bytes = part.get_body_raw()?;
+        let text = part.get_body()?;
+
+        let encoded_body = part.get_body_encoded();
+        let (encoded, index_map) = match &encoded_body {
+            Body::Base64(body) => (
+                body.get_raw(),
+                base64_index_map(body.get_raw(), bytes.len()),
+            ),
+            Body::QuotedPrintable(body) => {
+                (body.get_raw(), quoted_printable_index_map(body.get_raw()))
+            }
+            Body::SevenBit(body) | Body::EightBit(body) => {
+                (body.get_raw(), (0..body.get_raw().len()).collect())
+            }
+            Body::Binary(body) => (body.get_raw(), (0..body.get_raw().len()).collect()),
+        };
+        if !document[part_body_start..].starts_with(encoded) {
+            return Err(anyhow!(
+                "The body of the decoded part could not be located in the canonicalized body"
+            ));
+        }
+        if index_map.len() != bytes.len() {
+            return Err(anyhow!(
+                "The {} decoded bytes could only be mapped to {} encoded bytes",
+                bytes.len(),
+                index_map.len()
+            ));
+        }
+        let offset = part_body_start - body_start;
+
+        Ok(DecodedBody {
+            text,
+            bytes,
+            index_map: index_map.into_iter().map(|idx| idx + offset).collect(),
+        })
+    }
+
     /// Extracts the timestamp from the canonicalized email header.
     pub fn get_timestamp(&self) -> Result<u64> {
-        let idxes = extract_timestamp_idxes(&self.canonicalized_header)?[0];
+        let idxes = self.get_timestamp_idxes()?;
         let str = &self.canonicalized_header[idxes.0..idxes.1];
         Ok(str.parse()?)
     }
 
     /// Retrieves the index range of the timestamp within the canonicalized email header.
     pub fn get_timestamp_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_timestamp_idxes(&self.canonicalized_header)?[0];
-        Ok(idxes)
+        first_match(self.get_timestamp_all_idxes()?, "timestamp")
+    }
+
+    /// Retrieves the index ranges of all timestamp matches within the canonicalized email header.
+    pub fn get_timestamp_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        Ok(extract_timestamp_idxes(&self.canonicalized_header)?)
+    }
+
+    /// Parses the RFC 2822 'Date' header of the canonicalized email header into a unix timestamp.
+    pub fn get_date_timestamp(&self) -> Result<u64> {
+        let idxes = find_header_value_idxes(&self.canonicalized_header, "date")?;
+        let date = unfold_header_value(&self.canonicalized_header[idxes.0..idxes.1]);
+        let timestamp =
+            dateparse(&date).map_err(|e| anyhow!("Invalid Date header {:?}: {}", date, e))?;
+        timestamp
+            .try_into()
+            .map_err(|_| anyhow!("Date header {:?} is before the unix epoch", date))
+    }
+
+    /// Returns the DKIM `t=` timestamp, falling back to the 'Date' header when the tag is absent.
+    pub fn get_best_timestamp(&self) -> Result<u64> {
+        self.get_timestamp().or_else(|_| self.get_date_timestamp())
+    }
+
+    /// Returns both the DKIM `t=` timestamp and the 'Date' header timestamp, so that they can be
+    /// checked against each other.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `EmailTimestamps`, where a missing value is `None`, or an error
+    /// if a value is present but cannot be parsed.
+    pub fn get_timestamps(&self) -> Result<EmailTimestamps> {
+        let dkim_t = match self.get_timestamp_idxes() {
+            Ok(_) => Some(self.get_timestamp()?),
+            Err(_) => None,
+        };
+        let date_header = match find_header_value_idxes(&self.canonicalized_header, "date") {
+            Ok(_) => Some(self.get_date_timestamp()?),
+            Err(_) => None,
+        };
+        Ok(EmailTimestamps {
+            dkim_t,
+            date_header,
+        })
     }
 
     /// Extracts the invitation code from the canonicalized email body.
     pub fn get_invitation_code(&self, ignore_body_hash_check: bool) -> Result<String> {
-        let regex_config = serde_json::from_str(include_str!("../regexes/invitation_code.json"))?;
+        let idxes = self.get_invitation_code_idxes(ignore_body_hash_check)?;
         if ignore_body_hash_check {
-            let idxes = extract_substr_idxes(&self.canonicalized_header, &regex_config, false)?[0];
             let str = self.canonicalized_header[idxes.0..idxes.1].to_string();
             Ok(str)
         } else {
-            let idxes = extract_substr_idxes(&self.cleaned_body, &regex_config, false)?[0];
             let str = self.cleaned_body[idxes.0..idxes.1].to_string();
             Ok(str)
         }
@@ -179,70 +1334,145 @@ impl ParsedEmail {
         &self,
         ignore_body_hash_check: bool,
     ) -> Result<(usize, usize)> {
-        let regex_config = serde_json::from_str(include_str!("../regexes/invitation_code.json"))?;
-        if ignore_body_hash_check {
-            let idxes = extract_substr_idxes(&self.canonicalized_header, &regex_config, false)?[0];
-            Ok(idxes)
+        let regex_config = BundledRegex::InvitationCode.config()?;
+        let input = if ignore_body_hash_check {
+            &self.canonicalized_header
         } else {
-            let idxes = extract_substr_idxes(&self.cleaned_body, &regex_config, false)?[0];
-            Ok(idxes)
+            &self.cleaned_body
+        };
+        first_match(
+            extract_substr_idxes(input, regex_config, false)?,
+            "invitation code",
+        )
+    }
+
+    /// Searches the canonicalized email header and then the cleaned email body for the invitation
+    /// code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the code, where it was found and its index range there, or an error
+    /// if neither part contains an invitation code.
+    pub fn find_invitation_code(&self) -> Result<InvitationCodeLocation> {
+        let regex_config = BundledRegex::InvitationCode.config()?;
+        for (part, input) in &[
+            (EmailPart::Header, &self.canonicalized_header),
+            (EmailPart::Body, &self.cleaned_body),
+        ] {
+            let idxes = extract_substr_idxes(input, regex_config, false)
+                .map_err(anyhow::Error::from)
+                .and_then(|idxes| first_match(idxes, "invitation code"));
+            if let Ok(idxes) = idxes {
+                return Ok(InvitationCodeLocation {
+                    code: input[idxes.0..idxes.1].to_string(),
+                    part: *part,
+                    idxes,
+                });
+            }
         }
+        Err(anyhow!(
+            "No invitation code found in the email header or body"
+        ))
     }
 
     /// Extracts the email address from the subject line of the canonicalized email header.
     pub fn get_email_addr_in_subject(&self) -> Result<String> {
-        let idxes = extract_subject_all_idxes(&self.canonicalized_header)?[0];
-        let subject = self.canonicalized_header[idxes.0..idxes.1].to_string();
-        let idxes = extract_email_addr_idxes(&subject)?[0];
+        let subject = self.get_subject_all()?;
+        let idxes = self.get_email_addr_in_subject_idxes()?;
         let str = subject[idxes.0..idxes.1].to_string();
         Ok(str)
     }
 
     /// Retrieves the index range of the email address within the subject line of the canonicalized email header.
     pub fn get_email_addr_in_subject_idxes(&self) -> Result<(usize, usize)> {
-        let idxes = extract_subject_all_idxes(&self.canonicalized_header)?[0];
-        let subject = self.canonicalized_header[idxes.0..idxes.1].to_string();
-        let idxes = extract_email_addr_idxes(&subject)?[0];
-        Ok(idxes)
+        first_match(
+            self.get_email_addr_in_subject_all_idxes()?,
+            "email address in the subject",
+        )
     }
 
-    /// Extracts the message ID from the canonicalized email header.
+    /// Retrieves the index ranges of all email addresses within the subject line of the
+    /// canonicalized email header.
+    pub fn get_email_addr_in_subject_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        let subject = self.get_subject_all()?;
+        Ok(extract_email_addr_idxes(&subject)?)
+    }
+
+    /// Extracts the message ID, including its angle brackets, from the 'Message-ID' header of the
+    /// canonicalized email header.
     pub fn get_message_id(&self) -> Result<String> {
-        let idxes = extract_message_id_idxes(&self.canonicalized_header)?[0];
+        let idxes = self.get_message_id_idxes()?;
         let str = self.canonicalized_header[idxes.0..idxes.1].to_string();
         Ok(str)
     }
 
-    /// Extracts the command from the canonicalized email header or body.
-    pub fn get_command(&self, ignore_body_hash_check: bool) -> Result<String> {
-        let regex_config = serde_json::from_str(include_str!("../regexes/command.json"))?;
-        if ignore_body_hash_check {
-            Ok("".to_string())
-        } else {
-            match extract_substr_idxes(&self.canonicalized_body, &regex_config, false) {
-                Ok(idxes) => {
-                    let str = self.canonicalized_body[idxes[0].0..idxes[0].1].to_string();
-                    Ok(str.replace("=\r\n", ""))
+    /// Retrieves the index range of the message ID within the canonicalized email header.
+    pub fn get_message_id_idxes(&self) -> Result<(usize, usize)> {
+        first_match(self.get_message_id_all_idxes()?, "Message-ID")
+    }
+
+    /// Retrieves the index ranges of all valid message IDs in 'Message-ID' headers of the
+    /// canonicalized email header.
+    ///
+    /// Message IDs in other headers, such as 'References', are not included.
+    pub fn get_message_id_all_idxes(&self) -> Result<Vec<(usize, usize)>> {
+        msg_id_idxes(&self.canonicalized_header, "message-id")
+    }
+
+    /// Extracts the message IDs of this email and the emails it refers to.
+    ///
+    /// The IDs come from the signed 'Message-ID', 'In-Reply-To' and 'References' headers, in that
+    /// order, without duplicates.
+    pub fn get_all_message_ids(&self) -> Result<Vec<String>> {
+        let mut message_ids: Vec<String> = Vec::new();
+        for name in &["message-id", "in-reply-to", "references"] {
+            for idxes in msg_id_idxes(&self.canonicalized_header, name)? {
+                let message_id = &self.canonicalized_header[idxes.0..idxes.1];
+                if !message_ids.iter().any(|id| id == message_id) {
+                    message_ids.push(message_id.to_string());
                 }
-                Err(_) => match extract_substr_idxes(&self.cleaned_body, &regex_config, false) {
-                    Ok(idxes) => {
-                        let str = self.cleaned_body[idxes[0].0..idxes[0].1].to_string();
-                        Ok(str)
-                    }
-                    _ => Ok("".to_string()),
-                },
             }
         }
+        Ok(message_ids)
     }
 
-    /// Retrieves the index range of the command within the canonicalized email header or body.
+    /// Extracts the command from the cleaned email body.
+    ///
+    /// This is the command that circuit input generation uses. It is empty if the body holds no
+    /// command, or if `ignore_body_hash_check` is set, since the body is then left out of the
+    /// circuit input.
+    pub fn get_command(&self, ignore_body_hash_check: bool) -> Result<String> {
+        if ignore_body_hash_check {
+            return Ok("".to_string());
+        }
+        Ok(self
+            .find_command_idxes()?
+            .map(|idxes| self.cleaned_body[idxes.0..idxes.1].to_string())
+            .unwrap_or_default())
+    }
+
+    /// Retrieves the index range of the command within the cleaned email body, or `(0, 0)` if
+    /// `ignore_body_hash_check` is set.
     pub fn get_command_idxes(&self, ignore_body_hash_check: bool) -> Result<(usize, usize)> {
-        let regex_config = serde_json::from_str(include_str!("../regexes/command.json"))?;
         if ignore_body_hash_check {
-            Ok((0, 0))
-        } else {
-            let idxes = extract_substr_idxes(&self.cleaned_body, &regex_config, false)?[0];
-            Ok(idxes)
+            return Ok((0, 0));
+        }
+        self.find_command_idxes()?
+            .ok_or_else(|| anyhow!("No command found in the email"))
+    }
+
+    /// Finds the first command in the cleaned email body.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the index range of the command, `None` if the body holds no command,
+    /// or an error if the command regex cannot be evaluated.
+    fn find_command_idxes(&self) -> Result<Option<(usize, usize)>> {
+        let regex_config = BundledRegex::Command.config()?;
+        match extract_substr_idxes(&self.cleaned_body, regex_config, false) {
+            Ok(idxes) => Ok(idxes.first().copied()),
+            Err(ExtractSubstrssError::SubstringOfEntireNotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -252,6 +1482,129 @@ impl ParsedEmail {
     }
 }
 
+lazy_static! {
+    /// The bundled regex configs, parsed once on first use.
+    static ref INVITATION_CODE_REGEX_CONFIG: Result<DecomposedRegexConfig, String> =
+        parse_regex_config(include_str!("../regexes/invitation_code.json"));
+    static ref INVITATION_CODE_WITH_PREFIX_REGEX_CONFIG: Result<DecomposedRegexConfig, String> =
+        parse_regex_config(include_str!("../regexes/invitation_code_with_prefix.json"));
+    static ref COMMAND_REGEX_CONFIG: Result<DecomposedRegexConfig, String> =
+        parse_regex_config(include_str!("../regexes/command.json"));
+}
+
+/// The decomposed regexes bundled with the library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundledRegex {
+    /// Matches an invitation code.
+    InvitationCode,
+    /// Matches an invitation code preceded by its prefix.
+    InvitationCodeWithPrefix,
+    /// Matches a command in the email body.
+    Command,
+}
+
+impl BundledRegex {
+    /// Returns the parsed config of this regex, parsing it on first use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the shared config, or an error if the bundled JSON is malformed.
+    pub fn config(self) -> Result<&'static DecomposedRegexConfig> {
+        let config = match self {
+            Self::InvitationCode => &*INVITATION_CODE_REGEX_CONFIG,
+            Self::InvitationCodeWithPrefix => &*INVITATION_CODE_WITH_PREFIX_REGEX_CONFIG,
+            Self::Command => &*COMMAND_REGEX_CONFIG,
+        };
+        config
+            .as_ref()
+            .map_err(|e| anyhow!("Invalid bundled {:?} regex config: {}", self, e))
+    }
+}
+
+/// Parses a bundled regex config, keeping the error as a string so it can be shared.
+fn parse_regex_config(json: &str) -> Result<DecomposedRegexConfig, String> {
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+/// Returns the first of the index ranges matched by a regex.
+///
+/// # Arguments
+///
+/// * `idxes` - The index ranges of all matches.
+/// * `what` - A description of the matched value, used in the error message.
+///
+/// # Returns
+///
+/// A `Result` containing the first index range, or an error if there are no matches.
+fn first_match(idxes: Vec<(usize, usize)>, what: &str) -> Result<(usize, usize)> {
+    idxes
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No {} found in the email", what))
+}
+
+/// Maps each byte decoded from base64 data to the index of the character where it starts.
+///
+/// # Arguments
+///
+/// * `encoded` - The base64 data, possibly spread over several lines.
+/// * `decoded_len` - The number of decoded bytes.
+///
+/// # Returns
+///
+/// A vector with the index in `encoded` of each decoded byte.
+fn base64_index_map(encoded: &[u8], decoded_len: usize) -> Vec<usize> {
+    let positions: Vec<usize> = encoded
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.is_ascii_alphanumeric() || **c == b'+' || **c == b'/')
+        .map(|(i, _)| i)
+        .collect();
+    // Every 3 decoded bytes come from 4 characters, and byte k of a group starts in character k
+    (0..decoded_len)
+        .filter_map(|k| positions.get(k / 3 * 4 + k % 3).copied())
+        .collect()
+}
+
+/// Maps each byte decoded from quoted-printable data to the index it came from.
+///
+/// Soft line breaks are skipped and an `=XX` escape maps to the index of its `=`.
+///
+/// # Arguments
+///
+/// * `encoded` - The quoted-printable data.
+///
+/// # Returns
+///
+/// A vector with the index in `encoded` of each decoded byte.
+fn quoted_printable_index_map(encoded: &[u8]) -> Vec<usize> {
+    let mut index_map = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'=' {
+            if encoded[i + 1..].starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            if encoded[i + 1..].starts_with(b"\n") {
+                i += 2;
+                continue;
+            }
+            if i + 2 < encoded.len()
+                && encoded[i + 1].is_ascii_hexdigit()
+                && encoded[i + 2].is_ascii_hexdigit()
+            {
+                index_map.push(i);
+                i += 3;
+                continue;
+            }
+        }
+        index_map.push(i);
+        i += 1;
+    }
+    index_map
+}
+
 /// Removes Quoted-Printable (QP) soft line breaks (`=\r\n`) from the given byte vector while
 /// maintaining a mapping from cleaned indices back to the original positions.
 ///
@@ -275,6 +1628,12 @@ impl ParsedEmail {
 ///                 correspond to actual content, `index_map[i]` gives the original position of
 ///                 that byte in `body`. For padded bytes, the value is `usize::MAX`.
 pub fn remove_quoted_printable_soft_breaks(body: Vec<u8>) -> (Vec<u8>, Vec<usize>) {
+    remove_quoted_printable_soft_breaks_from(&body)
+}
+
+/// Removes quoted-printable soft line breaks like `remove_quoted_printable_soft_breaks`, without
+/// taking ownership of the body.
+pub(crate) fn remove_quoted_printable_soft_breaks_from(body: &[u8]) -> (Vec<u8>, Vec<usize>) {
     let original_len = body.len();
     let mut cleaned = Vec::with_capacity(original_len);
     let mut index_map = Vec::with_capacity(original_len);
@@ -303,29 +1662,688 @@ pub fn remove_quoted_printable_soft_breaks(body: Vec<u8>) -> (Vec<u8>, Vec<usize
 
 /// Finds the index of the first occurrence of a pattern in the given body.
 ///
-/// This function searches for the pattern within the body and returns the index of its first occurrence.
-/// If the pattern is not found or is empty, the function returns 0.
-///
 /// # Arguments
 ///
-/// * `body` - An `Option` wrapping a reference to a `Vec<u8>` representing the email body.
+/// * `body` - The bytes of the email body to search, usually the padded cleaned body.
 /// * `pattern` - A string slice representing the pattern to search for.
 ///
 /// # Returns
 ///
-/// The index of the first occurrence of the pattern within the body as `usize`.
-pub(crate) fn find_index_in_body(body: Option<&Vec<u8>>, pattern: &str) -> usize {
-    body.and_then(|body_bytes| {
-        if !pattern.is_empty() {
-            // Search for the pattern in the body
-            body_bytes
-                .windows(pattern.len())
-                .position(|w| w == pattern.as_bytes())
-        } else {
-            None
+/// A `Result` containing the index of the first occurrence of the pattern within the body, or
+/// an error naming the pattern and the length of the body if the pattern is empty or not found.
+pub(crate) fn find_index_in_body(body: &[u8], pattern: &str) -> Result<usize> {
+    if pattern.is_empty() {
+        return Err(anyhow!(
+            "Cannot search the {} byte body for an empty pattern",
+            body.len()
+        ));
+    }
+    body.windows(pattern.len())
+        .position(|w| w == pattern.as_bytes())
+        .ok_or_else(|| {
+            anyhow!(
+                "Pattern {:?} not found in the {} byte body",
+                pattern,
+                body.len()
+            )
+        })
+}
+
+const DKIM_SIGNATURE_HEADER: &str = "DKIM-Signature"; // The name of the DKIM signature header
+
+/// A DKIM-Signature header of an email that may be used to verify it.
+struct DkimSignatureCandidate {
+    index: usize,       // The position of the header among the DKIM-Signature headers
+    aligned: bool,      // Whether the signing domain is the From domain or one of its parents
+    domain: String,     // The signing domain from the `d=` tag
+    selector: String,   // The selector from the `s=` tag
+    algorithm: String,  // The signing algorithm from the `a=` tag
+    partial_body: bool, // Whether an `l=` tag limits the signature to a prefix of the body
+}
+
+impl DkimSignatureCandidate {
+    /// Checks that the signature names the domain and selector of its key.
+    fn check_tags(&self) -> Result<()> {
+        if self.domain.is_empty() || self.selector.is_empty() {
+            return Err(anyhow!(
+                "DKIM signature parsing failed: the d= and s= tags are required"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Describes why this signature could not be used.
+    fn describe_failure(&self, error: &anyhow::Error) -> String {
+        format!("d={}, s={}: {:#}", self.domain, self.selector, error)
+    }
+}
+
+/// Lists the DKIM signatures of an email in the order they should be tried.
+///
+/// Signatures whose `d=` domain is aligned with the From domain come first, otherwise the order
+/// of the headers is kept. A From header with several addresses is handled according to
+/// `multiple_from_policy`. If `third_party_signers` is given, signatures that are not aligned
+/// are only kept if their domain is, or is a subdomain of, one of the listed domains.
+fn dkim_signature_candidates(
+    headers: &EmailHeaders,
+    multiple_from_policy: MultipleFromPolicy,
+    third_party_signers: Option<&[String]>,
+) -> Result<Vec<DkimSignatureCandidate>> {
+    let signatures = headers
+        .get_header(DKIM_SIGNATURE_HEADER)
+        .filter(|signatures| !signatures.is_empty())
+        .ok_or_else(|| ParsedEmailError::HeaderNotPresent(DKIM_SIGNATURE_HEADER.to_string()))?;
+
+    let from_domain = from_domain(headers, multiple_from_policy)?;
+
+    let mut candidates = signatures
+        .iter()
+        .enumerate()
+        .map(|(index, signature)| {
+            let tags = parse_dkim_tags(signature);
+            let domain = tags.get("d").cloned().unwrap_or_default();
+            DkimSignatureCandidate {
+                index,
+                aligned: from_domain
+                    .as_deref()
+                    .is_some_and(|from_domain| is_aligned(from_domain, &domain)),
+                domain,
+                selector: tags.get("s").cloned().unwrap_or_default(),
+                algorithm: tags.get("a").cloned().unwrap_or_default(),
+                partial_body: tags.contains_key("l"),
+            }
+        })
+        .filter(|candidate| {
+            candidate.aligned
+                || third_party_signers.is_none_or(|signers| {
+                    signers
+                        .iter()
+                        .any(|signer| is_aligned(&candidate.domain.to_lowercase(), signer))
+                })
+        })
+        .collect_vec();
+    if candidates.is_empty() {
+        return Err(anyhow!(
+            "No DKIM signature from the From domain or an allowed third-party signer"
+        ));
+    }
+
+    // A stable sort keeps the header order among aligned and non-aligned signatures
+    candidates.sort_by_key(|candidate| !candidate.aligned);
+
+    Ok(candidates)
+}
+
+/// Checks whether a lowercase domain is the given parent domain or one of its subdomains.
+fn is_aligned(domain: &str, parent: &str) -> bool {
+    let parent = parent.to_lowercase();
+    domain == parent || domain.ends_with(&format!(".{}", parent))
+}
+
+/// Finds the domain of the From address of an email, lowercased.
+///
+/// # Arguments
+///
+/// * `headers` - The email headers.
+/// * `multiple_from_policy` - How a From header with several addresses is handled.
+///
+/// # Returns
+///
+/// A `Result` containing the domain, or `None` if there is no From header, an error if the From
+/// header holds no address, or a `ParsedEmailError::MultipleFromAddresses` error if the policy
+/// rejects the addresses.
+fn from_domain(
+    headers: &EmailHeaders,
+    multiple_from_policy: MultipleFromPolicy,
+) -> Result<Option<String>> {
+    let from = match headers
+        .get_header("From")
+        .and_then(|values| values.first().cloned())
+    {
+        Some(from) => format!("from:{}", from),
+        None => return Ok(None),
+    };
+    let addrs = extract_header_addr_idxes(&from, "from")?
+        .into_iter()
+        .map(|(start, end)| from[start..end].to_string())
+        .collect_vec();
+    let domains = addrs
+        .iter()
+        .filter_map(|addr| addr.rsplit_once('@'))
+        .map(|(_, domain)| domain.trim().to_lowercase())
+        .collect_vec();
+
+    let allowed = match multiple_from_policy {
+        _ if addrs.len() <= 1 => true,
+        MultipleFromPolicy::Reject => false,
+        MultipleFromPolicy::UseFirst => true,
+        MultipleFromPolicy::RequireAllSameDomain => domains.iter().all_equal(),
+    };
+    if !allowed {
+        return Err(ParsedEmailError::MultipleFromAddresses(addrs).into());
+    }
+    Ok(domains.into_iter().next())
+}
+
+/// Ends the header of an email that has no body with an empty line.
+///
+/// An empty body canonicalizes the same with or without the empty line, so this does not affect
+/// the DKIM body hash.
+fn with_header_terminator(raw_email: &[u8]) -> Cow<'_, [u8]> {
+    if find_subslice(raw_email, b"\r\n\r\n").is_some()
+        || find_subslice(raw_email, b"\n\n").is_some()
+    {
+        return Cow::Borrowed(raw_email);
+    }
+    let mut terminated = raw_email.to_vec();
+    if !terminated.ends_with(b"\n") {
+        terminated.extend_from_slice(b"\r\n");
+    }
+    terminated.extend_from_slice(b"\r\n");
+    Cow::Owned(terminated)
+}
+
+/// Moves the `index`-th header field with the given name to the top of the raw email.
+///
+/// Folded continuation lines are moved along with the header. The relative order of all other
+/// header fields, and the body, are left untouched.
+fn move_header_to_top(raw_email: &[u8], name: &str, index: usize) -> Vec<u8> {
+    // Split the header section from the body
+    let header_end = find_subslice(raw_email, b"\r\n\r\n")
+        .map(|i| i + 2)
+        .or_else(|| find_subslice(raw_email, b"\n\n").map(|i| i + 1))
+        .unwrap_or(raw_email.len());
+    let (header, body) = raw_email.split_at(header_end);
+
+    // Group the header lines into fields, attaching continuation lines to their field
+    let mut fields: Vec<Vec<u8>> = Vec::new();
+    for line in header.split_inclusive(|&byte| byte == b'\n') {
+        match fields.last_mut() {
+            Some(field) if line.starts_with(b" ") || line.starts_with(b"\t") => {
+                field.extend_from_slice(line)
+            }
+            _ => fields.push(line.to_vec()),
+        }
+    }
+
+    let position = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| {
+            let key_end = field.iter().position(|&byte| byte == b':');
+            key_end.is_some_and(|key_end| {
+                std::str::from_utf8(&field[..key_end]).is_ok_and(|key| key.trim() == name)
+            })
+        })
+        .nth(index)
+        .map(|(position, _)| position);
+
+    match position {
+        Some(position) if position > 0 => {
+            let field = fields.remove(position);
+            fields.insert(0, field);
+            [fields.concat(), body.to_vec()].concat()
+        }
+        _ => raw_email.to_vec(),
+    }
+}
+
+/// Strips any number of leading `Re:`, `Fw:` and `Fwd:` prefixes, in any case, from a subject.
+fn strip_reply_prefixes(subject: &str) -> &str {
+    let mut subject = subject.trim_start();
+    loop {
+        let prefix_len = ["re:", "fw:", "fwd:"].iter().find_map(|prefix| {
+            subject
+                .get(..prefix.len())
+                .filter(|start| start.eq_ignore_ascii_case(prefix))
+                .map(|_| prefix.len())
+        });
+        match prefix_len {
+            Some(len) => subject = subject[len..].trim_start(),
+            None => return subject,
+        }
+    }
+}
+
+/// Finds the first occurrence of `needle` in `haystack`.
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Returns the offset of the body of a MIME part, which follows the blank line after its headers.
+fn mime_part_body_offset(part: &[u8]) -> usize {
+    if part.starts_with(b"\r\n") {
+        return 2;
+    }
+    if part.starts_with(b"\n") {
+        return 1;
+    }
+    find_subslice(part, b"\r\n\r\n")
+        .map(|idx| idx + 4)
+        .or_else(|| find_subslice(part, b"\n\n").map(|idx| idx + 2))
+        .unwrap_or(part.len())
+}
+
+/// Locates the subparts of a multipart MIME part from its boundary delimiter lines.
+///
+/// # Arguments
+///
+/// * `data` - The document the part was parsed from.
+/// * `part` - The parsed part.
+/// * `body` - The index range of the body of the part in `data`.
+///
+/// # Returns
+///
+/// A `Result` containing each subpart with its index range in `data`, headers included, or an
+/// error if the delimiters do not match the parsed subparts.
+fn mime_subparts<'m, 'a>(
+    data: &[u8],
+    part: &'m ParsedMail<'a>,
+    body: (usize, usize),
+) -> Result<Vec<(&'m ParsedMail<'a>, (usize, usize))>> {
+    if part.subparts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let boundary = part
+        .ctype
+        .params
+        .get("boundary")
+        .ok_or_else(|| anyhow!("The multipart MIME part has no boundary"))?;
+    let delimiter = format!("--{}", boundary);
+
+    let mut ranges = Vec::new();
+    let mut part_start = None;
+    let mut line_start = body.0;
+    while line_start < body.1 {
+        let line_end = data[line_start..body.1]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(body.1, |idx| line_start + idx + 1);
+        let line = &data[line_start..line_end];
+        if line.starts_with(delimiter.as_bytes()) {
+            if let Some(start) = part_start {
+                // The line break before a delimiter belongs to the delimiter
+                let end = if data[..line_start].ends_with(b"\r\n") {
+                    line_start - 2
+                } else if data[..line_start].ends_with(b"\n") {
+                    line_start - 1
+                } else {
+                    line_start
+                };
+                ranges.push((start, end.max(start)));
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                part_start = None;
+                break;
+            }
+            part_start = Some(line_end);
+        }
+        line_start = line_end;
+    }
+    // A missing close delimiter ends the last part at the end of the body
+    if let Some(start) = part_start {
+        ranges.push((start, body.1));
+    }
+
+    if ranges.len() != part.subparts.len() {
+        return Err(anyhow!(
+            "Found {} MIME parts between the boundaries, but {} were parsed",
+            ranges.len(),
+            part.subparts.len()
+        ));
+    }
+    Ok(part.subparts.iter().zip(ranges).collect())
+}
+
+/// Finds the first MIME part with the given type, in the order of `ParsedMail::parts`.
+///
+/// # Arguments
+///
+/// * `data` - The document the part was parsed from.
+/// * `part` - The parsed part to search.
+/// * `body` - The index range of the body of the part in `data`.
+/// * `mimetype` - The MIME type to find.
+///
+/// # Returns
+///
+/// A `Result` containing the part with the index range of its body in `data`, `None` if no part
+/// has the type, or an error if the parts cannot be located.
+fn find_mime_part<'m, 'a>(
+    data: &[u8],
+    part: &'m ParsedMail<'a>,
+    body: (usize, usize),
+    mimetype: &str,
+) -> Result<Option<(&'m ParsedMail<'a>, (usize, usize))>> {
+    if part.ctype.mimetype == mimetype {
+        return Ok(Some((part, body)));
+    }
+    for (subpart, (start, end)) in mime_subparts(data, part, body)? {
+        let subpart_body = (start + mime_part_body_offset(&data[start..end]), end);
+        if let Some(found) = find_mime_part(data, subpart, subpart_body, mimetype)? {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// Errors returned by the `ParsedEmail` constructors and getters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedEmailError {
+    /// The header is not part of the signed, canonicalized email header.
+    HeaderNotPresent(String),
+    /// The raw email exceeds the maximum size accepted for parsing.
+    EmailTooLarge { size: usize, max_size: usize },
+    /// The From header lists several addresses, which the `MultipleFromPolicy` does not allow.
+    MultipleFromAddresses(Vec<String>),
+}
+
+impl fmt::Display for ParsedEmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeaderNotPresent(name) => write!(f, "Header {} not present", name),
+            Self::EmailTooLarge { size, max_size } => write!(
+                f,
+                "Raw email is {} bytes, which exceeds the maximum of {} bytes",
+                size, max_size
+            ),
+            Self::MultipleFromAddresses(addrs) => write!(
+                f,
+                "From header lists {} addresses, which is not allowed: {}",
+                addrs.len(),
+                addrs.join(", ")
+            ),
+        }
+    }
+}
+
+/// Checks that a raw email is small enough to be parsed.
+///
+/// # Arguments
+///
+/// * `raw_email` - The raw email.
+/// * `max_size` - The maximum accepted size in bytes.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok(())` if the email fits, or a `ParsedEmailError::EmailTooLarge` error.
+pub(crate) fn check_raw_email_size(raw_email: &[u8], max_size: usize) -> Result<()> {
+    if raw_email.len() > max_size {
+        return Err(ParsedEmailError::EmailTooLarge {
+            size: raw_email.len(),
+            max_size,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Removes the non-text leaf parts of a multipart email, such as inline images and attachments.
+///
+/// The MIME boundaries are kept, so each removed part is left empty. This only leaves the DKIM
+/// signature intact if it has an `l=` tag that stops before the removed parts, which is why
+/// `ParseEmailOptions::strip_non_text_parts` is ignored for other signatures.
+///
+/// # Arguments
+///
+/// * `raw_email` - The raw email.
+///
+/// # Returns
+///
+/// A `Result` containing the stripped email, or an error if the email or its MIME parts cannot be
+/// parsed.
+fn strip_non_text_mime_parts(raw_email: &[u8]) -> Result<Vec<u8>> {
+    fn collect_ranges(
+        raw_email: &[u8],
+        part: &ParsedMail,
+        body: (usize, usize),
+        ranges: &mut Vec<(usize, usize)>,
+    ) -> Result<()> {
+        for (subpart, (start, end)) in mime_subparts(raw_email, part, body)? {
+            if !subpart.subparts.is_empty() {
+                let subpart_body = (start + mime_part_body_offset(&raw_email[start..end]), end);
+                collect_ranges(raw_email, subpart, subpart_body, ranges)?;
+            } else if !subpart.ctype.mimetype.to_lowercase().starts_with("text/") {
+                ranges.push((start, end));
+            }
+        }
+        Ok(())
+    }
+
+    let parsed_mail = parse_mail(raw_email).context("Failed to parse email")?;
+    let mut ranges = Vec::new();
+    let body = (mime_part_body_offset(raw_email), raw_email.len());
+    collect_ranges(raw_email, &parsed_mail, body, &mut ranges)?;
+
+    let mut stripped = Vec::with_capacity(raw_email.len());
+    let mut last = 0;
+    for (start, end) in ranges {
+        stripped.extend_from_slice(&raw_email[last..start]);
+        last = end;
+    }
+    stripped.extend_from_slice(&raw_email[last..]);
+    Ok(stripped)
+}
+
+impl std::error::Error for ParsedEmailError {}
+
+/// Finds the index range of the value of a header within a canonicalized email header.
+///
+/// The header name is matched case-insensitively, and folded continuation lines are treated as
+/// part of the value. Surrounding whitespace is excluded from the range.
+///
+/// # Arguments
+///
+/// * `header` - The canonicalized email header.
+/// * `name` - The name of the header to find.
+///
+/// # Returns
+///
+/// A `Result` containing the index range of the header value, or a
+/// `ParsedEmailError::HeaderNotPresent` error if the header is missing.
+pub(crate) fn find_header_value_idxes(header: &str, name: &str) -> Result<(usize, usize)> {
+    find_header_values_idxes(header, name)?
+        .first()
+        .copied()
+        .ok_or_else(|| ParsedEmailError::HeaderNotPresent(name.to_string()).into())
+}
+
+/// Finds the index ranges of the values of every occurrence of a header within a canonicalized
+/// email header, in order.
+///
+/// Matching follows `find_header_value_idxes`.
+///
+/// # Arguments
+///
+/// * `header` - The canonicalized email header.
+/// * `name` - The name of the header to find.
+///
+/// # Returns
+///
+/// A `Result` containing the index ranges of the header values, which is empty if the header is
+/// missing.
+pub(crate) fn find_header_values_idxes(header: &str, name: &str) -> Result<Vec<(usize, usize)>> {
+    let re = Regex::new(&format!(r"(?im)^{}[ \t]*:", regex::escape(name)))?;
+    let bytes = header.as_bytes();
+    Ok(re
+        .find_iter(header)
+        .map(|matched| {
+            // The value ends at the first line break that is not followed by a folded continuation line
+            let mut end = matched.end();
+            while end < bytes.len() {
+                if bytes[end] == b'\r' || bytes[end] == b'\n' {
+                    let next_line = header[end..].trim_start_matches(&['\r', '\n'][..]);
+                    if !next_line.starts_with(&[' ', '\t'][..]) {
+                        break;
+                    }
+                }
+                end += 1;
+            }
+
+            let value = &header[matched.end()..end];
+            let start = matched.end() + (value.len() - value.trim_start().len());
+            let end = matched.end() + value.trim_end().len();
+            (start, end.max(start))
+        })
+        .collect())
+}
+
+/// Unfolds a header value by removing the line breaks before its continuation lines.
+pub(crate) fn unfold_header_value(value: &str) -> String {
+    value.replace(&['\r', '\n'][..], "")
+}
+
+/// Parses the `c=` tag of a DKIM signature.
+///
+/// A missing tag or body algorithm means `simple`, as specified by RFC 6376.
+fn parse_canonicalization_tag(tag: Option<&String>) -> (HeaderCanon, BodyCanon) {
+    let mut algorithms = tag
+        .map_or("", |tag| tag.as_str())
+        .splitn(2, '/')
+        .map(|algorithm| algorithm.trim());
+    let header = match algorithms.next() {
+        Some("relaxed") => HeaderCanon::Relaxed,
+        _ => HeaderCanon::Simple,
+    };
+    let body = match algorithms.next() {
+        Some("relaxed") => BodyCanon::Relaxed,
+        _ => BodyCanon::Simple,
+    };
+    (header, body)
+}
+
+/// Parses the tags of the signing DKIM-Signature header, which is the last one in the
+/// canonicalized header.
+pub(crate) fn find_dkim_signature_tags(header: &str) -> Result<HashMap<String, String>> {
+    let (start, end) = *find_header_values_idxes(header, DKIM_SIGNATURE_HEADER)?
+        .last()
+        .ok_or_else(|| ParsedEmailError::HeaderNotPresent(DKIM_SIGNATURE_HEADER.to_string()))?;
+    Ok(parse_dkim_tags(&header[start..end]))
+}
+
+/// Finds the index range of the body hash in the `bh=` tag of the signing DKIM-Signature header.
+///
+/// Unlike the circuit regex, this works for both simple and relaxed header canonicalization. The
+/// signing DKIM-Signature header is the last one in the canonicalized header.
+///
+/// # Arguments
+///
+/// * `header` - The canonicalized email header.
+///
+/// # Returns
+///
+/// A `Result` containing the index range of the body hash, or an error if there is no `bh=` tag.
+pub(crate) fn find_dkim_body_hash_idxes(header: &str) -> Result<(usize, usize)> {
+    let (value_start, value_end) = *find_header_values_idxes(header, DKIM_SIGNATURE_HEADER)?
+        .last()
+        .ok_or_else(|| ParsedEmailError::HeaderNotPresent(DKIM_SIGNATURE_HEADER.to_string()))?;
+    let value = &header[value_start..value_end];
+
+    let mut tag_start = 0;
+    for tag in value.split(';') {
+        if let Some((name, tag_value)) = tag.split_once('=') {
+            if name.trim() == "bh" {
+                let start = tag_start + name.len() + 1;
+                let offset = tag_value.len() - tag_value.trim_start().len();
+                return Ok((
+                    value_start + start + offset,
+                    value_start + start + tag_value.trim_end().len(),
+                ));
+            }
+        }
+        tag_start += tag.len() + 1;
+    }
+    Err(anyhow!("No body hash found in the DKIM signature"))
+}
+
+/// Finds the index ranges of the well-formed message IDs, `<id-left@id-right>`, in a header.
+///
+/// # Arguments
+///
+/// * `header` - The canonicalized email header.
+/// * `name` - The name of the header, such as 'Message-ID' or 'References'.
+///
+/// # Returns
+///
+/// A `Result` containing the index ranges of the message IDs, including their angle brackets.
+fn msg_id_idxes(header: &str, name: &str) -> Result<Vec<(usize, usize)>> {
+    let re = Regex::new(r"<[^<>@\s]+@[^<>@\s]+>")?;
+    Ok(find_header_values_idxes(header, name)?
+        .into_iter()
+        .flat_map(|(start, end)| {
+            re.find_iter(&header[start..end])
+                .map(move |matched| (start + matched.start(), start + matched.end()))
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Finds the index ranges of all addresses in an address header such as 'Cc' or 'Reply-To'.
+///
+/// Both bare addresses and display-name forms like `Alice <alice@example.com>` are supported. For
+/// the latter, only the address between the angle brackets is returned.
+///
+/// # Arguments
+///
+/// * `header` - The canonicalized email header.
+/// * `name` - The name of the address header.
+///
+/// # Returns
+///
+/// A `Result` containing the index ranges of the addresses within `header`, or an error if the
+/// header is missing or contains no address.
+pub(crate) fn extract_header_addr_idxes(header: &str, name: &str) -> Result<Vec<(usize, usize)>> {
+    let (value_start, value_end) = find_header_value_idxes(header, name)?;
+    let value = &header[value_start..value_end];
+
+    // Split the value on commas that are not inside a quoted display name or angle brackets
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    let mut in_quotes = false;
+    let mut in_angles = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angles = true,
+            '>' if !in_quotes => in_angles = false,
+            ',' if !in_quotes && !in_angles => {
+                parts.push((part_start, i));
+                part_start = i + 1;
+            }
+            _ => {}
         }
-    })
-    .unwrap_or(0) // Default to 0 if not found or pattern is empty
+    }
+    parts.push((part_start, value.len()));
+
+    let mut idxes = Vec::new();
+    for (start, end) in parts {
+        let part = &value[start..end];
+        let (addr_start, addr_end) = match (part.rfind('<'), part.rfind('>')) {
+            (Some(open), Some(close)) if open < close => (start + open + 1, start + close),
+            _ => {
+                let trimmed = part.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                let offset = part.len() - part.trim_start().len();
+                (start + offset, start + offset + trimmed.len())
+            }
+        };
+        let addr = value[addr_start..addr_end].trim();
+        if addr.contains('@') {
+            let offset = value[addr_start..addr_end].find(addr).unwrap_or(0);
+            idxes.push((
+                value_start + addr_start + offset,
+                value_start + addr_start + offset + addr.len(),
+            ));
+        }
+    }
+
+    if idxes.is_empty() {
+        return Err(anyhow!("No address found in the {} header", name));
+    }
+    Ok(idxes)
 }
 
 /// Represents the email headers as a collection of key-value pairs.
@@ -364,4 +2382,942 @@ impl EmailHeaders {
     pub fn get_header(&self, name: &str) -> Option<Vec<String>> {
         self.0.get(name).cloned()
     }
+
+    /// Retrieves the first value of a header, matching its name case-insensitively.
+    fn get_first_ignore_case(&self, name: &str) -> Option<&String> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{fixture, MIME_FIXTURES_PUBLIC_KEY};
+    use crate::{
+        extract_template_vals_from_command, public_key_hash, DkimCacheConfig, DkimRetryConfig,
+    };
+    use ethers::types::U256;
+
+    // The key used to sign the subject_*.eml fixtures
+    const SUBJECT_FIXTURES_PUBLIC_KEY: &str = "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtR8o0F7WliMEb+LmEnUmUhMOeKSvFP5pf0eMUZdVwseTLn3XGQwAwSwq/lo1ZlVt9uM+toOIK4hNkJF7ARXkdb6e0tcgAZs4YvgnScS3KwI0oGY8lsXpW4yJS7JGUwVnW2HGCu+/pzLQ9oG4mdQJctDOSDJaXreS1cJR1b1RbwAMFf2VRz8iwg+dhqR7gfcBYl3i96P+7Luu8XTMr516jOTVFtEPZOfo3tNERoqXR8g361uOQ2gthXSj1gBcdkdkZvcDChkt4UsC911kkaMHiuAv7LRCpY+cgfG201MTg42sAXXiafgcx74McQjmmZR4IEgsswKRV2Mw4ejfzqsRNQIDAQAB";
+
+    /// Options that resolve DKIM keys only from the archive at `addr`.
+    fn archive_only_options(addr: std::net::SocketAddr) -> ParseEmailOptions {
+        ParseEmailOptions {
+            resolver: Some(CustomResolverConfig {
+                archive_url: format!("http://{}/api/key", addr),
+                dns_fallback: false,
+                dns_server: None,
+                cache: None,
+                retry: DkimRetryConfig::default(),
+            }),
+            ..ParseEmailOptions::default()
+        }
+    }
+
+    /// Options whose archive is a closed port, so key lookups fail immediately.
+    fn unreachable_resolver_options() -> Result<ParseEmailOptions> {
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        Ok(archive_only_options(unreachable))
+    }
+
+    #[test]
+    fn test_canonicalize_ed25519_signed_email() -> Result<()> {
+        let test_file = fixture("ed25519.eml");
+        let email = std::fs::read_to_string(test_file)?;
+
+        let parsed_mail = parse_mail(email.as_bytes())?;
+        let headers = EmailHeaders::new_from_mail(&parsed_mail);
+        let dkim_headers = headers.get_header("DKIM-Signature").unwrap();
+        assert!(dkim_headers[0].contains("a=ed25519-sha256"));
+
+        // ed25519 signatures are always 64 bytes long
+        let (_, _, signature) = canonicalize_signed_email(email.as_bytes())?;
+        assert_eq!(signature.len(), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_header_addr_idxes() -> Result<()> {
+        let header = "from:Alice <alice@example.com>\r\ncc:\"Smith, Bob\" <bob@example.com>, carol@example.com,\r\n Dave <dave@example.com>\r\nreply-to:replies@example.com\r\nsubject:Hi";
+
+        let cc = extract_header_addr_idxes(header, "cc")?
+            .into_iter()
+            .map(|(start, end)| &header[start..end])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cc,
+            vec!["bob@example.com", "carol@example.com", "dave@example.com"]
+        );
+
+        let reply_to = extract_header_addr_idxes(header, "Reply-To")?[0];
+        assert_eq!(&header[reply_to.0..reply_to.1], "replies@example.com");
+
+        let err = extract_header_addr_idxes(header, "bcc").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParsedEmailError>(),
+            Some(&ParsedEmailError::HeaderNotPresent("bcc".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_new_from_raw_email_malformed() {
+        assert!(ParsedEmail::new_from_raw_email("Invalid email")
+            .await
+            .is_err());
+
+        let err = ParsedEmail::new_from_raw_email("From: a@example.com\r\n\r\nbody\r\n")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParsedEmailError>(),
+            Some(&ParsedEmailError::HeaderNotPresent(
+                DKIM_SIGNATURE_HEADER.to_string()
+            ))
+        );
+
+        let err = ParsedEmail::new_from_raw_email(
+            "DKIM-Signature: v=1; a=rsa-sha256; bh=; b=\r\nFrom: a@example.com\r\n\r\nbody\r\n",
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("DKIM signature parsing failed"));
+    }
+
+    #[tokio::test]
+    async fn test_new_from_raw_email_with_options_resolver_failures() -> Result<()> {
+        let raw_email = std::fs::read(fixture("latin1.eml"))?;
+
+        // A listener that never answers makes the lookup hang until the timeout
+        let silent = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let options = ParseEmailOptions {
+            dns_timeout: Duration::from_millis(200),
+            ..archive_only_options(silent.local_addr()?)
+        };
+        let err = ParsedEmail::new_from_raw_email_bytes_with_options(&raw_email, &options)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("d=tickets.example.org, s=test timed out after 200ms"));
+        assert!(err
+            .to_string()
+            .contains(&format!("at http://{}/api/key", silent.local_addr()?)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_new_from_raw_email_unreachable_resolver() -> Result<()> {
+        let raw_email = std::fs::read(fixture("latin1.eml"))?;
+
+        // A closed port makes the lookup fail immediately, which is an error rather than a panic
+        let options = unreachable_resolver_options()?;
+        let err = ParsedEmail::new_from_raw_email_bytes_with_options(&raw_email, &options)
+            .await
+            .unwrap_err();
+
+        // The failure names the signature and the stage that failed
+        let message = err.to_string();
+        assert!(
+            message.contains("d=tickets.example.org, s=test: DKIM public key resolution failed")
+        );
+        assert!(!message.contains("DKIM signature verification failed"));
+        assert!(!message.contains("Failed to canonicalize email"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_new_from_raw_email_bytes_with_attempts() -> Result<()> {
+        let raw_email = std::fs::read(fixture("latin1.eml"))?;
+
+        // The key cannot be resolved, so the error lists the attempt
+        let options = unreachable_resolver_options()?;
+        let err = ParsedEmail::new_from_raw_email_bytes_with_attempts(&raw_email, &options)
+            .await
+            .unwrap_err();
+        let attempts = err.downcast_ref::<DkimVerificationAttempts>().unwrap();
+        assert_eq!(attempts.attempts.len(), 1);
+        let attempt = &attempts.attempts[0];
+        assert_eq!(attempt.domain, "tickets.example.org");
+        assert_eq!(attempt.selector, "test");
+        assert!(!attempt.key_parsed);
+        assert!(!attempt.signature_verified);
+        assert_eq!(attempt.body_hash_matched, None);
+        assert!(attempt
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("DKIM public key resolution failed"));
+        assert!(attempts.verified().is_none());
+        assert!(err
+            .to_string()
+            .starts_with("No DKIM signature could be verified: d=tickets.example.org, s=test: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_email_options_from_env() -> Result<()> {
+        // The variables are looked up in a map, so tests running in parallel do not race on them
+        let env = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>();
+            move |key: &str| vars.get(key).cloned()
+        };
+
+        let options = ParseEmailOptions::from_lookup(env(&[
+            (DKIM_API_URL_KEY, "http://127.0.0.1:1/api/key"),
+            (DKIM_FETCH_TIMEOUT_MS_KEY, "250"),
+        ]))?;
+        assert_eq!(options.dns_timeout, Duration::from_millis(250));
+        assert_eq!(
+            options.resolver,
+            Some(CustomResolverConfig {
+                archive_url: "http://127.0.0.1:1/api/key".to_string(),
+                dns_fallback: false,
+                dns_server: None,
+                cache: Some(DkimCacheConfig::default()),
+                retry: DkimRetryConfig::default(),
+            })
+        );
+
+        let err = ParseEmailOptions::from_lookup(env(&[(DKIM_FETCH_TIMEOUT_MS_KEY, "soon")]))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Invalid DKIM_FETCH_TIMEOUT_MS \"soon\""));
+
+        let options = ParseEmailOptions::from_lookup(env(&[]))?;
+        assert_eq!(
+            options.dns_timeout,
+            Duration::from_millis(DEFAULT_DNS_TIMEOUT_MS)
+        );
+        assert!(options.resolver.is_none());
+        Ok(())
+    }
+
+    fn parsed_email_with_header(canonicalized_header: &str) -> ParsedEmail {
+        ParsedEmail {
+            canonicalized_header: canonicalized_header.to_string(),
+            canonicalized_body: String::new(),
+            canonicalized_body_bytes: Vec::new(),
+            signature: Vec::new(),
+            public_key: EmailPublicKey::Rsa(Vec::new()),
+            public_key_e: Vec::new(),
+            cleaned_body: String::new(),
+            headers: EmailHeaders(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn test_get_best_timestamp() -> Result<()> {
+        let without_t = parsed_email_with_header(
+            "from:a@example.com\r\ndate:Mon, 3 Jun 2024\r\n 12:00:00 +0200\r\ndkim-signature:v=1; d=example.com; s=s1; bh=; b=",
+        );
+        assert_eq!(without_t.get_date_timestamp()?, 1717408800);
+        assert!(without_t.get_timestamp().is_err());
+        assert_eq!(without_t.get_best_timestamp()?, 1717408800);
+
+        let with_t = parsed_email_with_header(
+            "date:Mon, 3 Jun 2024 10:00:00 +0000\r\ndkim-signature:v=1; d=example.com; s=s1; t=1717408860; bh=; b=",
+        );
+        assert_eq!(with_t.get_best_timestamp()?, 1717408860);
+
+        let without_date = parsed_email_with_header("from:a@example.com\r\n");
+        assert!(without_date.get_best_timestamp().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_subject_command_vals() -> Result<()> {
+        let templates = ["Send", "{uint}", "ETH", "to", "{ethAddr}"]
+            .iter()
+            .map(|template| template.to_string())
+            .collect_vec();
+        let parsed_email = parsed_email_with_header(
+            "subject:Re: FWD: Send 2 ETH to 0x0000000000000000000000000000000000000002\r\n",
+        );
+        let idxes = parsed_email.get_subject_command_idxes()?;
+        assert!(parsed_email.canonicalized_header[idxes.0..idxes.1].starts_with("Send 2 ETH"));
+        let vals = parsed_email.extract_subject_command_vals(templates.clone())?;
+        assert!(matches!(vals[0], TemplateValue::Uint(amount) if amount == U256::from(2)));
+
+        // Prefixes inside an encoded-word are stripped after decoding
+        let parsed_email = parsed_email_with_header(
+            "subject:=?UTF-8?Q?Re:_Send_3_ETH_to_0x0000000000000000000000000000000000000003?=\r\n",
+        );
+        let vals = parsed_email.extract_subject_command_vals(templates.clone())?;
+        assert!(matches!(vals[0], TemplateValue::Uint(amount) if amount == U256::from(3)));
+
+        // The subject is not searched for a text/html part
+        let parsed_email = parsed_email_with_header(
+            "subject:Send 4 ETH to 0x0000000000000000000000000000000000000004 Content-Type: text/html;\r\n",
+        );
+        let vals = parsed_email.extract_subject_command_vals(templates)?;
+        assert!(matches!(vals[0], TemplateValue::Uint(amount) if amount == U256::from(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_timestamps() -> Result<()> {
+        let timestamps = parsed_email_with_header(
+            "date:Mon, 3 Jun 2024 10:00:00 +0000\r\ndkim-signature:v=1; d=example.com; s=s1; t=1717408860; bh=; b=",
+        )
+        .get_timestamps()?;
+        assert_eq!(
+            timestamps,
+            EmailTimestamps {
+                dkim_t: Some(1717408860),
+                date_header: Some(1717408800),
+            }
+        );
+        assert_eq!(timestamps.drift_secs(), Some(60));
+        assert!(timestamps.is_within_drift(60));
+        assert!(!timestamps.is_within_drift(59));
+
+        let without_t =
+            parsed_email_with_header("date:Mon, 3 Jun 2024 10:00:00 +0000\r\n").get_timestamps()?;
+        assert_eq!(without_t.dkim_t, None);
+        assert!(without_t.is_within_drift(0));
+
+        let invalid_date = parsed_email_with_header("date:yesterday\r\n");
+        assert!(invalid_date.get_timestamps().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_idxes_getters() -> Result<()> {
+        let parsed_email = parsed_email_with_header(
+            "from:alice@example.com\r\nsubject:Send to bob@example.com and carol@example.org\r\n",
+        );
+        let subjects = parsed_email.get_email_addr_in_subject_all_idxes()?;
+        let subject = parsed_email.get_subject_all()?;
+        assert_eq!(
+            subjects
+                .iter()
+                .map(|idxes| &subject[idxes.0..idxes.1])
+                .collect::<Vec<_>>(),
+            vec!["bob@example.com", "carol@example.org"]
+        );
+        assert_eq!(parsed_email.get_email_addr_in_subject_idxes()?, subjects[0]);
+
+        let without_email = parsed_email_with_header("from:alice@example.com\r\nsubject:Hi\r\n");
+        assert!(without_email.get_email_addr_in_subject().is_err());
+        assert!(first_match(Vec::new(), "message ID")
+            .unwrap_err()
+            .to_string()
+            .contains("No message ID found"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_view() {
+        let view = parsed_email_with_header(
+            "from:Alice <alice@example.com>\r\nsubject:Hello\r\ndate:Mon, 3 Jun 2024 10:00:00 +0000\r\ndkim-signature:v=1; d=example.com; s=s1; bh=; b=",
+        )
+        .view();
+        assert_eq!(view.from_addr.as_deref(), Some("alice@example.com"));
+        assert_eq!(view.email_domain.as_deref(), Some("example.com"));
+        assert_eq!(view.subject.as_deref(), Some("Hello"));
+        assert_eq!(view.timestamp, Some(1717408800));
+        assert_eq!(view.timestamp_idxes, None);
+        assert_eq!(view.message_id, None);
+        assert_eq!(view.dkim_domain.as_deref(), Some("example.com"));
+        assert_eq!(view.dkim_selector.as_deref(), Some("s1"));
+        assert_eq!(view.public_key_exponent, "0x");
+    }
+
+    #[test]
+    fn test_get_email_domain_normalized() -> Result<()> {
+        for (from, normalized) in [
+            ("from:B <b@bücher.example>\r\n", "xn--bcher-kva.example"),
+            ("from:b@xn--bcher-kva.example\r\n", "xn--bcher-kva.example"),
+            ("from:b@Mail.Example.COM\r\n", "mail.example.com"),
+        ] {
+            let parsed_email = parsed_email_with_header(from);
+            assert_eq!(parsed_email.get_email_domain_normalized()?, normalized);
+            let idxes = parsed_email.get_email_domain_header_idxes()?;
+            assert_eq!(
+                domain_to_ascii(&from[idxes.0..idxes.1]).unwrap(),
+                normalized
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_with_verified_signature_body_hash_check() -> Result<()> {
+        let public_key = decode_public_key(
+            &general_purpose::STANDARD.decode(SUBJECT_FIXTURES_PUBLIC_KEY)?,
+            DkimAlgorithm::RsaSha256,
+        )?;
+        let email = std::fs::read_to_string(fixture("subject_de.eml"))?;
+        // Simulate a body modified in transit
+        let email = email.replace("Viele Gruesse", "Viele Gruesse!");
+        let parsed_mail = parse_mail(email.as_bytes())?;
+        let headers = EmailHeaders::new_from_mail(&parsed_mail);
+        let signature = &dkim_signature_candidates(&headers, MultipleFromPolicy::Reject, None)?[0];
+
+        let err = ParsedEmail::new_with_verified_signature(
+            email.as_bytes(),
+            &headers,
+            signature,
+            public_key.clone(),
+            Vec::new(),
+            true,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("body hash does not match"));
+
+        let parsed_email = ParsedEmail::new_with_verified_signature(
+            email.as_bytes(),
+            &headers,
+            signature,
+            public_key,
+            Vec::new(),
+            false,
+        )?;
+        assert_eq!(parsed_email.get_body()?, "Viele Gruesse!\r\n");
+
+        let key = general_purpose::STANDARD.decode(SUBJECT_FIXTURES_PUBLIC_KEY)?;
+        let report = parsed_email.verify(&key, true);
+        assert_eq!(
+            report,
+            DkimVerificationReport {
+                header_hash_matched: true,
+                body_hash_matched: Some(false),
+                algorithm: Some("rsa-sha256".to_string()),
+                domain: Some("post.example.de".to_string()),
+                selector: Some("test".to_string()),
+            }
+        );
+        assert!(!report.is_valid());
+        assert!(parsed_email.verify(&key, false).is_valid());
+
+        let wrong_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        assert!(!parsed_email.verify(&wrong_key, false).header_hash_matched);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_mime_part() -> Result<()> {
+        let document: &[u8] = b"Content-Type: multipart/mixed; boundary=outer\r\n\r\n--outer\r\nContent-Type: multipart/alternative; boundary=inner\r\n\r\n--inner\r\nContent-Type: text/plain\r\n\r\nplain\r\n--inner\r\nContent-Type: text/html\r\n\r\n<b>html</b>\r\n--inner--\r\n--outer\r\nContent-Type: image/png\r\n\r\npng\r\n--outer--\r\n";
+        let mail = parse_mail(document)?;
+        let body = (mime_part_body_offset(document), document.len());
+
+        let (part, (start, end)) = find_mime_part(document, &mail, body, "text/html")?.unwrap();
+        assert_eq!(part.ctype.mimetype, "text/html");
+        assert_eq!(&document[start..end], b"<b>html</b>");
+        let (_, (start, end)) = find_mime_part(document, &mail, body, "text/plain")?.unwrap();
+        assert_eq!(&document[start..end], b"plain");
+        assert!(find_mime_part(document, &mail, body, "text/calendar")?.is_none());
+
+        let subparts = mime_subparts(document, &mail, body)?;
+        assert_eq!(subparts.len(), 2);
+        let (_, (start, end)) = subparts[1];
+        assert_eq!(&document[start..end], b"Content-Type: image/png\r\n\r\npng");
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_decoded_body() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = std::fs::read_to_string(fixture("base64_body.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert_eq!(parsed_email.public_key_exponent_string(), "0x010001");
+        assert!(public_key_hash(parsed_email.public_key.rsa_modulus()?).is_ok());
+        assert!(!parsed_email.get_body()?.contains("Send 5 USDC"));
+
+        let decoded = parsed_email.get_decoded_body_with_index_map()?;
+        assert!(decoded.text.starts_with("<html>"));
+        assert!(decoded.text.contains("Votre commande est confirmée."));
+        assert_eq!(decoded.index_map.len(), decoded.bytes.len());
+
+        // The first decoded byte comes from the first character of the base64 data
+        let body = &parsed_email.canonicalized_body_bytes;
+        let base64_start = find_subslice(body, b"PGh0bWw+").unwrap();
+        assert_eq!(decoded.index_map[0], base64_start);
+
+        // A match in the decoded body maps back onto the base64 line it was encoded in
+        let usdc = decoded.text.find("USDC").unwrap();
+        let second_line = find_subslice(body, b"NSBVU0RD").unwrap();
+        assert_eq!(decoded.index_map[usdc], second_line + 2);
+
+        let template_vals = extract_template_vals_from_command(
+            &parsed_email.get_decoded_body()?,
+            vec!["Send".to_string(), "{uint}".to_string(), "USDC".to_string()],
+        )?;
+        assert!(
+            matches!(template_vals[..], [TemplateValue::Uint(value)] if value == U256::from(5))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted_printable_index_map() {
+        assert_eq!(
+            quoted_printable_index_map(b"a=C3=A9=\r\nb"),
+            vec![0, 1, 4, 9]
+        );
+    }
+
+    #[test]
+    fn test_get_to_addrs() -> Result<()> {
+        let parsed_email = parsed_email_with_header(
+            "from:a@example.com\r\nto:\"Doe, Jane\" <jane@x.com>, bob@y.com,\r\n Relayer <relayer@example.org>\r\n",
+        );
+        assert_eq!(
+            parsed_email.get_to_addrs()?,
+            vec!["jane@x.com", "bob@y.com", "relayer@example.org"]
+        );
+        #[allow(deprecated)]
+        let first = parsed_email.get_to_addr()?;
+        assert_eq!(first, "jane@x.com");
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalization() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+
+        let email = std::fs::read_to_string(fixture("simple.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert_eq!(
+            parsed_email.canonicalization(),
+            (HeaderCanon::Simple, BodyCanon::Simple)
+        );
+        // Simple body canonicalization keeps whitespace as sent
+        assert_eq!(
+            parsed_email.get_body()?,
+            "Hello   relayer,  \r\n\r\nsee you\r\n"
+        );
+
+        let email = std::fs::read_to_string(fixture("base64_body.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert_eq!(
+            parsed_email.canonicalization(),
+            (HeaderCanon::Relaxed, BodyCanon::Relaxed)
+        );
+
+        let parsed_email = parsed_email_with_header("dkim-signature:v=1; c=relaxed; bh=; b=");
+        assert_eq!(
+            parsed_email.canonicalization(),
+            (HeaderCanon::Relaxed, BodyCanon::Simple)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_message_ids() -> Result<()> {
+        let parsed_email = parsed_email_with_header(
+            "references:<a@x.com> <b@y.com>\r\nin-reply-to:<b@y.com>\r\nx-message-id:<c@z.com>\r\nmessage-id:<own@example.com>\r\n",
+        );
+        assert_eq!(parsed_email.get_message_id()?, "<own@example.com>");
+        assert_eq!(
+            parsed_email.get_all_message_ids()?,
+            vec!["<own@example.com>", "<b@y.com>", "<a@x.com>"]
+        );
+
+        let without_message_id = parsed_email_with_header("message-id:not-an-id\r\n");
+        assert!(without_message_id.get_message_id().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundled_regex_configs_are_parsed_once() -> Result<()> {
+        for regex in &[
+            BundledRegex::InvitationCode,
+            BundledRegex::InvitationCodeWithPrefix,
+            BundledRegex::Command,
+        ] {
+            assert!(std::ptr::eq(regex.config()?, regex.config()?));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_command() -> Result<()> {
+        let mut parsed_email = parsed_email_with_header("from:a@example.com\r\n");
+        let body = "<html><div id=3D\"zkemail\">Send 1 ETH to =\r\nalice@example.com</div></html>";
+        parsed_email.canonicalized_body = body.to_string();
+        parsed_email.cleaned_body =
+            String::from_utf8(remove_quoted_printable_soft_breaks(body.as_bytes().to_vec()).0)?;
+
+        let idxes = parsed_email.get_command_idxes(false)?;
+        let command = parsed_email.get_command(false)?;
+        assert_eq!(command, "Send 1 ETH to alice@example.com");
+        assert_eq!(&parsed_email.cleaned_body[idxes.0..idxes.1], command);
+
+        // Without the body there is no command
+        assert_eq!(parsed_email.get_command_idxes(true)?, (0, 0));
+        assert_eq!(parsed_email.get_command(true)?, "");
+
+        // A body without a command yields an empty command
+        parsed_email.cleaned_body = "<html><div>Hello</div></html>".to_string();
+        assert!(parsed_email.get_command_idxes(false).is_err());
+        assert_eq!(parsed_email.get_command(false)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_invitation_code() -> Result<()> {
+        let mut parsed_email = parsed_email_with_header("subject:Re: Code 0123abcd\r\n");
+        parsed_email.cleaned_body = "Your code 4567ef".to_string();
+        let location = parsed_email.find_invitation_code()?;
+        assert_eq!(location.code, "0123abcd");
+        assert_eq!(location.part, EmailPart::Header);
+        assert_eq!(
+            &parsed_email.canonicalized_header[location.idxes.0..location.idxes.1],
+            "0123abcd"
+        );
+
+        parsed_email.canonicalized_header = "subject:Welcome\r\n".to_string();
+        let location = parsed_email.find_invitation_code()?;
+        assert_eq!(location.code, "4567ef");
+        assert_eq!(location.part, EmailPart::Body);
+
+        parsed_email.cleaned_body = "Welcome".to_string();
+        assert!(parsed_email.find_invitation_code().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_header_values() {
+        let parsed_email = parsed_email_with_header(
+            "X-Mailer: Mail 1.0\r\nlist-unsubscribe:<mailto:u@example.com>,\r\n <https://example.com/u>\r\nx-mailer:Mail 2.0\r\n",
+        );
+        assert_eq!(
+            parsed_email.get_header_values("x-mailer"),
+            vec!["Mail 1.0", "Mail 2.0"]
+        );
+        assert_eq!(
+            parsed_email.get_header_values("List-Unsubscribe"),
+            vec!["<mailto:u@example.com>, <https://example.com/u>"]
+        );
+        let idxes = parsed_email.get_header_value_idxes("List-Unsubscribe");
+        assert!(parsed_email.canonicalized_header[idxes[0].0..idxes[0].1].ends_with("/u>"));
+        assert!(parsed_email
+            .get_header_values("Authentication-Results")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_signed_body_length() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = std::fs::read_to_string(fixture("body_length.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert_eq!(parsed_email.signed_body_length()?, Some(58));
+        assert_eq!(
+            parsed_email.signed_body()?,
+            b"Send 2 ETH to 0x0000000000000000000000000000000000000003\r\n"
+        );
+        assert!(parsed_email.verify(&public_key, true).is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_body_length_rejects_invalid_tag() {
+        let parsed_email = parsed_email_with_header(
+            "from:a@example.com\r\ndkim-signature:v=1; d=example.com; s=s1; l=58x; bh=; b=",
+        );
+        assert!(parsed_email.signed_body_length().is_err());
+        assert!(parsed_email.signed_body().is_err());
+    }
+
+    #[test]
+    fn test_header_only_email() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = std::fs::read(fixture("header_only.eml"))?;
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&email, &public_key)?;
+        assert_eq!(parsed_email.get_body()?, "");
+        assert_eq!(parsed_email.get_cleaned_body()?, "");
+        assert_eq!(parsed_email.get_decoded_body()?, "");
+        assert_eq!(parsed_email.get_command(true)?, "");
+        assert_eq!(parsed_email.get_command(false)?, "");
+        assert!(parsed_email.get_invitation_code(false).is_err());
+        assert!(parsed_email.find_invitation_code().is_err());
+        assert_eq!(
+            parsed_email.get_subject_all()?,
+            "Send 1 ETH to 0x0000000000000000000000000000000000000005"
+        );
+        assert!(parsed_email.verify(&public_key, true).is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_email_size_limit() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = "a".repeat(DEFAULT_MAX_RAW_EMAIL_BYTES + 1);
+        let err = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParsedEmailError>(),
+            Some(&ParsedEmailError::EmailTooLarge {
+                size: DEFAULT_MAX_RAW_EMAIL_BYTES + 1,
+                max_size: DEFAULT_MAX_RAW_EMAIL_BYTES,
+            })
+        );
+        assert!(check_raw_email_size(b"abc", 3).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_non_text_mime_parts() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = std::fs::read(fixture("inline_image.eml"))?;
+        let stripped = strip_non_text_mime_parts(&email)?;
+        assert!(stripped.len() < email.len());
+        assert!(!String::from_utf8_lossy(&stripped).contains("image/png"));
+        assert!(stripped.ends_with(b"\r\n--b1\r\n\r\n--b1--\r\n"));
+
+        // The signature only covers the text part, so the stripped email still verifies
+        let parsed_email =
+            ParsedEmail::new_from_raw_email_bytes_with_public_key(&stripped, &public_key)?;
+        assert_eq!(
+            parsed_email.signed_body()?,
+            b"--b1\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nSend 1 ETH to 0x0000000000000000000000000000000000000004\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_subject_decoded() -> Result<()> {
+        let public_key = general_purpose::STANDARD.decode(SUBJECT_FIXTURES_PUBLIC_KEY)?;
+
+        let email = std::fs::read_to_string(fixture("subject_ja.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert!(parsed_email.get_subject_all()?.starts_with("=?UTF-8?B?"));
+        assert_eq!(parsed_email.get_subject_decoded()?, "こんにちは、世界");
+
+        let email = std::fs::read_to_string(fixture("subject_de.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        let subject = parsed_email.get_subject_decoded()?;
+        assert_eq!(subject, "Grüße aus München: Sende 12 USDC");
+
+        let template_vals = extract_template_vals_from_command(
+            &subject,
+            vec![
+                "Sende".to_string(),
+                "{uint}".to_string(),
+                "USDC".to_string(),
+            ],
+        )?;
+        assert!(
+            matches!(template_vals[..], [TemplateValue::Uint(value)] if value == U256::from(12))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_header_to_top() {
+        let raw_email: &[u8] = b"DKIM-Signature: a=1;\r\n b=2\r\nFrom: a@b.c\r\nDKIM-Signature: a=3;\r\n\tb=4\r\n\r\nbody\r\n";
+        assert_eq!(
+            move_header_to_top(raw_email, "DKIM-Signature", 1),
+            b"DKIM-Signature: a=3;\r\n\tb=4\r\nDKIM-Signature: a=1;\r\n b=2\r\nFrom: a@b.c\r\n\r\nbody\r\n".to_vec()
+        );
+        assert_eq!(
+            move_header_to_top(raw_email, "DKIM-Signature", 0),
+            raw_email
+        );
+        assert_eq!(
+            move_header_to_top(raw_email, "DKIM-Signature", 2),
+            raw_email
+        );
+    }
+
+    #[test]
+    fn test_dkim_signature_candidates_prefer_from_domain() -> Result<()> {
+        let raw_email = "DKIM-Signature: v=1; d=example-com.gappssmtp.com; s=20230601; b=x\r\nDKIM-Signature: v=1; d=example.com; s=google; b=y\r\nFrom: Alice <alice@mail.example.com>\r\n\r\nbody\r\n";
+        let parsed_mail = parse_mail(raw_email.as_bytes())?;
+        let headers = EmailHeaders::new_from_mail(&parsed_mail);
+
+        let candidates = dkim_signature_candidates(&headers, MultipleFromPolicy::Reject, None)?;
+        assert_eq!(candidates[0].domain, "example.com");
+        assert_eq!(candidates[0].index, 1);
+        assert_eq!(candidates[1].domain, "example-com.gappssmtp.com");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dkim_signature_candidates_third_party_signers() -> Result<()> {
+        let raw_email = "DKIM-Signature: v=1; d=other.net; s=s1; b=x\r\nDKIM-Signature: v=1; d=amazonses.com; s=224i4yxa5dv7c2xz3womw6peuasteono; b=y\r\nDKIM-Signature: v=1; d=em1234.sendgrid.net; s=s1; b=z\r\nDKIM-Signature: v=1; d=example.com; s=s1; b=w\r\nFrom: Alice <alice@example.com>\r\n\r\nbody\r\n";
+        let parsed_mail = parse_mail(raw_email.as_bytes())?;
+        let headers = EmailHeaders::new_from_mail(&parsed_mail);
+        let domains = |signers: Option<&[String]>| -> Result<Vec<String>> {
+            Ok(
+                dkim_signature_candidates(&headers, MultipleFromPolicy::Reject, signers)?
+                    .into_iter()
+                    .map(|candidate| candidate.domain)
+                    .collect(),
+            )
+        };
+
+        // Without a list every signer is tried, aligned ones first
+        assert_eq!(
+            domains(None)?,
+            vec![
+                "example.com",
+                "other.net",
+                "amazonses.com",
+                "em1234.sendgrid.net"
+            ]
+        );
+
+        // SES and SendGrid sign with their own domains on behalf of the sender
+        let signers = vec!["amazonses.com".to_string(), "sendgrid.net".to_string()];
+        assert_eq!(
+            domains(Some(&signers))?,
+            vec!["example.com", "amazonses.com", "em1234.sendgrid.net"]
+        );
+
+        // An aligned signature is kept even with an empty list
+        assert_eq!(domains(Some(&[]))?, vec!["example.com"]);
+
+        let raw_email = "DKIM-Signature: v=1; d=other.net; s=s1; b=x\r\nFrom: alice@example.com\r\n\r\nbody\r\n";
+        let parsed_mail = parse_mail(raw_email.as_bytes())?;
+        let headers = EmailHeaders::new_from_mail(&parsed_mail);
+        assert!(
+            dkim_signature_candidates(&headers, MultipleFromPolicy::Reject, Some(&signers))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_from_policy() -> Result<()> {
+        let headers_with_from = |from: &str| -> Result<EmailHeaders> {
+            let raw_email = format!(
+                "From: {}\r\nDKIM-Signature: v=1; d=x.com; s=s1; b=x\r\n\r\nbody\r\n",
+                from
+            );
+            let parsed_mail = parse_mail(raw_email.as_bytes())?;
+            Ok(EmailHeaders::new_from_mail(&parsed_mail))
+        };
+        let same_domain = headers_with_from("Alice <a@X.com>, \"Bob, Jr.\" <b@x.com>")?;
+        let different_domains = headers_with_from("a@x.com, b@y.com")?;
+
+        let err = from_domain(&same_domain, MultipleFromPolicy::Reject).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParsedEmailError>(),
+            Some(&ParsedEmailError::MultipleFromAddresses(vec![
+                "a@X.com".to_string(),
+                "b@x.com".to_string()
+            ]))
+        );
+
+        assert_eq!(
+            from_domain(&different_domains, MultipleFromPolicy::UseFirst)?,
+            Some("x.com".to_string())
+        );
+
+        assert_eq!(
+            from_domain(&same_domain, MultipleFromPolicy::RequireAllSameDomain)?,
+            Some("x.com".to_string())
+        );
+        assert!(from_domain(&different_domains, MultipleFromPolicy::RequireAllSameDomain).is_err());
+
+        // A single address is accepted whatever the policy
+        assert_eq!(
+            from_domain(&headers_with_from("a@x.com")?, MultipleFromPolicy::Reject)?,
+            Some("x.com".to_string())
+        );
+
+        // A From header without an address is an error rather than an unaligned email
+        assert!(from_domain(
+            &headers_with_from("undisclosed")?,
+            MultipleFromPolicy::Reject
+        )
+        .is_err());
+
+        // The default options reject the email before any key is looked up
+        let err = ParsedEmail::new_from_raw_email_bytes_with_options(
+            b"From: a@x.com, b@x.com\r\nDKIM-Signature: v=1; d=x.com; s=s1; b=x\r\n\r\nbody\r\n",
+            &ParseEmailOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.downcast_ref::<ParsedEmailError>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_from_raw_email_with_public_key() -> Result<()> {
+        let test_file = fixture("ed25519.eml");
+        let email = std::fs::read_to_string(test_file)?;
+
+        // The ed25519 key of brisbane._domainkey.football.example.com from RFC 8463
+        let public_key =
+            general_purpose::STANDARD.decode("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=")?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        assert_eq!(
+            parsed_email.public_key,
+            EmailPublicKey::Ed25519(public_key.clone())
+        );
+        assert!(parsed_email.public_key_e.is_empty());
+
+        // Both signatures are listed, and only the ed25519 one verified
+        let signatures = parsed_email.all_dkim_signatures();
+        assert_eq!(
+            signatures
+                .iter()
+                .map(|signature| (signature.algorithm.as_deref(), signature.verified))
+                .collect::<Vec<_>>(),
+            vec![(Some("ed25519-sha256"), true), (Some("rsa-sha256"), false)]
+        );
+        assert!(signatures
+            .iter()
+            .all(|signature| signature.domain.as_deref() == Some("football.example.com")));
+
+        let verified_key = parsed_email.verified_dkim_key()?;
+        assert_eq!(verified_key.domain, "football.example.com");
+        assert_eq!(verified_key.selector, "brisbane");
+        assert_eq!(verified_key.signature_index, 0);
+        assert!(!verified_key.third_party_signer);
+        assert_eq!(verified_key.public_key, parsed_email.public_key);
+
+        // A different key must not verify the signature
+        let mut wrong_key = public_key.clone();
+        wrong_key[0] ^= 1;
+        assert!(ParsedEmail::new_from_raw_email_with_public_key(&email, &wrong_key).is_err());
+        assert!(
+            ParsedEmail::new_from_raw_email_with_public_key_unverified(&email, &wrong_key).is_ok()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parsed_email_public_key_serialization() -> Result<()> {
+        // RSA keys keep the bare byte array that JS callers read as `publicKey`
+        let public_key = general_purpose::STANDARD.decode(MIME_FIXTURES_PUBLIC_KEY)?;
+        let email = std::fs::read_to_string(fixture("base64_body.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        let serialized = serde_json::to_value(&parsed_email)?;
+        assert_eq!(
+            serialized["publicKey"],
+            serde_json::json!(parsed_email.public_key.rsa_modulus()?)
+        );
+        let deserialized: ParsedEmail = serde_json::from_value(serialized)?;
+        assert_eq!(deserialized.public_key, parsed_email.public_key);
+
+        // ed25519 keys are tagged with their type
+        let public_key =
+            general_purpose::STANDARD.decode("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=")?;
+        let email = std::fs::read_to_string(fixture("ed25519.eml"))?;
+        let parsed_email = ParsedEmail::new_from_raw_email_with_public_key(&email, &public_key)?;
+        let serialized = serde_json::to_value(&parsed_email)?;
+        assert_eq!(
+            serialized["publicKey"],
+            serde_json::json!({ "type": "ed25519", "bytes": public_key })
+        );
+        assert_eq!(serialized["publicKeyE"], serde_json::json!([]));
+        let deserialized: ParsedEmail = serde_json::from_value(serialized)?;
+        assert_eq!(deserialized.public_key, EmailPublicKey::Ed25519(public_key));
+        Ok(())
+    }
 }
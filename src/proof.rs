@@ -4,8 +4,17 @@
 use anyhow::Result;
 use ethers::abi::{self, Token};
 use ethers::types::{Bytes, U256};
+use std::fmt;
 
-use ::serde::Deserialize;
+use ::serde::{Deserialize, Serialize};
+
+use crate::runtime::sleep_ms;
+use crate::{
+    generate_circuit_inputs_with_decomposed_regexes_and_external_inputs,
+    generate_email_circuit_input, AccountCode,
+    CircuitInputWithDecomposedRegexesAndExternalInputsParams, DecomposedRegex, EmailCircuitParams,
+    ExternalInput,
+};
 
 /// Represents the response from the prover.
 #[derive(Debug, Clone, Deserialize)]
@@ -151,3 +160,358 @@ pub async fn generate_proof_gpu(
 
     Ok((proof, pub_signals))
 }
+
+/// Selects how circuit inputs are generated in [`prove_email`].
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProvingInput {
+    /// Inputs for the email auth circuit, as produced by `generate_email_circuit_input`.
+    #[serde(rename_all = "camelCase")]
+    EmailAuth {
+        account_code: AccountCode,
+        params: Option<EmailCircuitParams>,
+    },
+    /// Inputs for a blueprint circuit, as produced by
+    /// `generate_circuit_inputs_with_decomposed_regexes_and_external_inputs`.
+    #[serde(rename_all = "camelCase")]
+    Blueprint {
+        decomposed_regexes: Vec<DecomposedRegex>,
+        external_inputs: Vec<ExternalInput>,
+        params: CircuitInputWithDecomposedRegexesAndExternalInputsParams,
+    },
+}
+
+/// Selects the prover that the generated inputs are submitted to in [`prove_email`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProverConfig {
+    /// A CPU prover, called through `generate_proof`.
+    #[serde(rename_all = "camelCase")]
+    Cpu { address: String, request: String },
+    /// A GPU prover, called through `generate_proof_gpu`.
+    #[serde(rename_all = "camelCase")]
+    Gpu {
+        blueprint_id: String,
+        proof_id: String,
+        zkey_download_url: String,
+        circuit_cpp_download_url: String,
+        api_key: String,
+        prover_url: String,
+    },
+}
+
+/// Retry behaviour for the proving stage of [`prove_email`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryOptions {
+    pub max_attempts: u32, // The total number of proving attempts, including the first one
+    pub backoff_ms: u64,   // The delay before the first retry, doubled after each failed attempt
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 0,
+        }
+    }
+}
+
+/// Everything [`prove_email`] needs to go from a raw email to a proof.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvingSpec {
+    pub input: ProvingInput,         // How to generate the circuit inputs
+    pub prover: ProverConfig,        // Where to submit the circuit inputs
+    pub retry: Option<RetryOptions>, // Retry options for the proving stage
+}
+
+/// The output of [`prove_email`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofResult {
+    pub proof: Bytes,           // The proof encoded for on-chain verification
+    pub pub_signals: Vec<U256>, // The public signals of the proof
+    pub circuit_inputs: String, // The JSON circuit inputs that were submitted, for auditing
+}
+
+/// The stage of [`prove_email`] that failed.
+#[derive(Debug)]
+pub enum ProveEmailError {
+    /// Parsing the email or generating the circuit inputs failed.
+    InputGeneration(anyhow::Error),
+    /// Submitting the inputs to the prover or decoding its response failed.
+    Proving(anyhow::Error),
+}
+
+impl fmt::Display for ProveEmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputGeneration(e) => write!(f, "input generation failed: {}", e),
+            Self::Proving(e) => write!(f, "proving failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProveEmailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InputGeneration(e) | Self::Proving(e) => Some(&**e),
+        }
+    }
+}
+
+/// Generates circuit inputs for an email and proves them.
+///
+/// This reuses the existing input generators and proof functions, so the inputs are exactly
+/// what `generate_email_circuit_input` or
+/// `generate_circuit_inputs_with_decomposed_regexes_and_external_inputs` would produce.
+///
+/// # Arguments
+///
+/// * `email` - A string slice containing the raw email data.
+/// * `spec` - A `ProvingSpec` selecting the input mode, the prover, and retry options.
+///
+/// # Returns
+///
+/// A `Result` containing the proof, its public signals and the submitted circuit inputs. On
+/// failure the error wraps a `ProveEmailError` indicating which stage failed.
+pub async fn prove_email(email: &str, spec: ProvingSpec) -> Result<ProofResult> {
+    let circuit_inputs = generate_proving_input(email, spec.input)
+        .await
+        .map_err(ProveEmailError::InputGeneration)?;
+
+    let retry = spec.retry.unwrap_or_default();
+    let mut backoff_ms = retry.backoff_ms;
+    let mut attempt = 1;
+    let (proof, pub_signals) = loop {
+        match submit_proof(&circuit_inputs, &spec.prover).await {
+            Ok(res) => break res,
+            Err(e) if attempt >= retry.max_attempts => {
+                return Err(ProveEmailError::Proving(e).into());
+            }
+            Err(_) => {
+                sleep_ms(backoff_ms).await;
+                backoff_ms = backoff_ms.saturating_mul(2);
+                attempt += 1;
+            }
+        }
+    };
+
+    Ok(ProofResult {
+        proof,
+        pub_signals,
+        circuit_inputs,
+    })
+}
+
+/// Generates the JSON circuit inputs for the given input mode.
+async fn generate_proving_input(email: &str, input: ProvingInput) -> Result<String> {
+    match input {
+        ProvingInput::EmailAuth {
+            account_code,
+            params,
+        } => generate_email_circuit_input(email, &account_code, params).await,
+        ProvingInput::Blueprint {
+            decomposed_regexes,
+            external_inputs,
+            params,
+        } => {
+            let inputs = generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
+                email,
+                decomposed_regexes,
+                external_inputs,
+                params,
+            )
+            .await?;
+            inputs.to_json_string()
+        }
+    }
+}
+
+/// Submits the circuit inputs to the configured prover.
+async fn submit_proof(input: &str, prover: &ProverConfig) -> Result<(Bytes, Vec<U256>)> {
+    match prover {
+        ProverConfig::Cpu { address, request } => generate_proof(input, request, address).await,
+        ProverConfig::Gpu {
+            blueprint_id,
+            proof_id,
+            zkey_download_url,
+            circuit_cpp_download_url,
+            api_key,
+            prover_url,
+        } => {
+            generate_proof_gpu(
+                input,
+                blueprint_id,
+                proof_id,
+                zkey_download_url,
+                circuit_cpp_download_url,
+                api_key,
+                prover_url,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::fixture;
+    use crate::RegexLocation;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use zk_regex_apis::extract_substrs::RegexPartConfig;
+
+    const MOCK_PROVER_RESPONSE: &str = r#"{"proof":{"pi_a":["1","2"],"pi_b":[["3","4"],["5","6"]],"pi_c":["7","8"]},"pub_signals":["9","10"]}"#;
+
+    /// Starts a mock prover that answers every request with `MOCK_PROVER_RESPONSE`.
+    async fn start_mock_prover() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                // Read until the full request body has arrived
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some(header_end) = text.find("\r\n\r\n") {
+                        let content_length = text[..header_end]
+                            .lines()
+                            .filter_map(|line| line.split_once(':'))
+                            .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+                            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= header_end + 4 + content_length {
+                            break;
+                        }
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    MOCK_PROVER_RESPONSE.len(),
+                    MOCK_PROVER_RESPONSE
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Ok(format!("http://{}", addr))
+    }
+
+    fn read_fixture(name: &str) -> Result<String> {
+        Ok(std::fs::read_to_string(fixture(name))?)
+    }
+
+    #[tokio::test]
+    async fn test_prove_email_email_auth() -> Result<()> {
+        let email = read_fixture("test.eml")?;
+        let address = start_mock_prover().await?;
+
+        let result = prove_email(
+            &email,
+            ProvingSpec {
+                input: ProvingInput::EmailAuth {
+                    account_code: AccountCode::new(rand::thread_rng()),
+                    params: None,
+                },
+                prover: ProverConfig::Cpu {
+                    address,
+                    request: "email_auth".to_string(),
+                },
+                retry: None,
+            },
+        )
+        .await?;
+
+        assert_eq!(result.pub_signals, vec![U256::from(9), U256::from(10)]);
+        assert!(!result.proof.is_empty());
+        serde_json::from_str::<serde_json::Value>(&result.circuit_inputs)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prove_email_blueprint() -> Result<()> {
+        let email = read_fixture("test.eml")?;
+        let address = start_mock_prover().await?;
+
+        let result = prove_email(
+            &email,
+            ProvingSpec {
+                input: ProvingInput::Blueprint {
+                    decomposed_regexes: vec![DecomposedRegex {
+                        parts: vec![
+                            RegexPartConfig {
+                                is_public: false,
+                                regex_def: "Hi".to_string(),
+                            },
+                            RegexPartConfig {
+                                is_public: true,
+                                regex_def: "!".to_string(),
+                            },
+                        ],
+                        name: "hi".to_string(),
+                        max_length: 64,
+                        location: RegexLocation::Body,
+                        ..Default::default()
+                    }],
+                    external_inputs: vec![],
+                    params: CircuitInputWithDecomposedRegexesAndExternalInputsParams {
+                        max_body_length: 2816,
+                        remove_soft_lines_breaks: true,
+                        ..Default::default()
+                    },
+                },
+                prover: ProverConfig::Gpu {
+                    blueprint_id: "blueprint".to_string(),
+                    proof_id: "proof".to_string(),
+                    zkey_download_url: "https://example.com/zkey".to_string(),
+                    circuit_cpp_download_url: "https://example.com/cpp".to_string(),
+                    api_key: "key".to_string(),
+                    prover_url: address,
+                },
+                retry: Some(RetryOptions {
+                    max_attempts: 2,
+                    backoff_ms: 10,
+                }),
+            },
+        )
+        .await?;
+
+        assert_eq!(result.pub_signals, vec![U256::from(9), U256::from(10)]);
+        let inputs: serde_json::Value = serde_json::from_str(&result.circuit_inputs)?;
+        assert!(inputs.get("hiRegexIdx").is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prove_email_input_generation_error() {
+        let err = prove_email(
+            "not an email",
+            ProvingSpec {
+                input: ProvingInput::EmailAuth {
+                    account_code: AccountCode::new(rand::thread_rng()),
+                    params: None,
+                },
+                prover: ProverConfig::Cpu {
+                    address: "http://127.0.0.1:1".to_string(),
+                    request: "email_auth".to_string(),
+                },
+                retry: None,
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ProveEmailError>(),
+            Some(ProveEmailError::InputGeneration(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+//! Timer helpers that work on both native and wasm targets.
+
+use anyhow::Result;
+use std::future::Future;
+
+/// Waits for the given number of milliseconds.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sleep_ms(ms: u64) {
+    if ms > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+    }
+}
+
+/// Creates a promise that resolves after the given number of milliseconds using the JavaScript
+/// `setTimeout` function, or rejects if `setTimeout` is not available, as in some workers.
+#[cfg(target_arch = "wasm32")]
+fn set_timeout_promise(ms: u64) -> js_sys::Promise {
+    use wasm_bindgen::{JsCast, JsValue};
+
+    js_sys::Promise::new(&mut |resolve, reject| {
+        let scheduled = js_sys::Reflect::get(&js_sys::global(), &"setTimeout".into())
+            .and_then(|set_timeout| {
+                set_timeout
+                    .dyn_into::<js_sys::Function>()
+                    .map_err(|_| JsValue::from(js_sys::Error::new("setTimeout is not available")))
+            })
+            .and_then(|set_timeout| {
+                set_timeout.call2(&JsValue::NULL, &resolve, &JsValue::from_f64(ms as f64))
+            });
+        if let Err(e) = scheduled {
+            let _ = reject.call1(&JsValue::NULL, &e);
+        }
+    })
+}
+
+/// Waits for the given number of milliseconds using the JavaScript `setTimeout` function, or
+/// returns at once if it is not available.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep_ms(ms: u64) {
+    if ms == 0 {
+        return;
+    }
+    let _ = wasm_bindgen_futures::JsFuture::from(set_timeout_promise(ms)).await;
+}
+
+/// Returns the number of milliseconds since the Unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Returns the number of milliseconds since the Unix epoch using the JavaScript `Date.now`
+/// function, as the system clock is not available in wasm.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now_ms() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+/// Runs a future with a time limit.
+///
+/// # Returns
+///
+/// A `Result` containing `Some` with the output of the future, or `None` if it did not complete
+/// within `ms` milliseconds. The native timer cannot fail, so this is never an error.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn timeout_ms<F: Future>(ms: u64, future: F) -> Result<Option<F::Output>> {
+    Ok(
+        tokio::time::timeout(std::time::Duration::from_millis(ms), future)
+            .await
+            .ok(),
+    )
+}
+
+/// Runs a future with a time limit by racing it against a `setTimeout` timer.
+///
+/// # Returns
+///
+/// A `Result` containing `Some` with the output of the future, or `None` if it did not complete
+/// within `ms` milliseconds, or an error if the timer cannot be set.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn timeout_ms<F: Future>(ms: u64, future: F) -> Result<Option<F::Output>> {
+    use std::task::Poll;
+
+    let mut future = Box::pin(future);
+    let mut timer = Box::pin(wasm_bindgen_futures::JsFuture::from(set_timeout_promise(
+        ms,
+    )));
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(Some(output)));
+        }
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(Ok(_)) => Poll::Ready(Ok(None)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(anyhow::anyhow!(
+                "Failed to set the time limit: {:?}",
+                e
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
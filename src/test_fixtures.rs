@@ -0,0 +1,15 @@
+//! Helpers shared by the unit tests that read the files under `tests/fixtures`.
+
+use std::path::PathBuf;
+
+// The key used to sign the base64_body.eml, simple.eml, body_length.eml, inline_image.eml,
+// soft_break_selector.eml and header_only.eml fixtures
+pub(crate) const MIME_FIXTURES_PUBLIC_KEY: &str = "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAuu274Dnv/OiKu5juiPZNxDSqPp0giICkLUPxPiLpSPcC2waMURz/cC1iRfsfrZ4CrYFq89XFLA1Gua3IeGh247oIl5mI1PKcx2M3IdsiMGRoAxvfVDKKoRz6AR7hdsvh/WBj+h5sXPOIEGRqCHt8T3tYhzz/dd8CCAbzou/943RROOvLnIRd9GGcD//Gv+Q2D6DyLzUJk+x8ObYqEA5DwRlaZFgsFg1FUUPwh/DlJZzOWvNH2VPWBLqulmlfvxnweR1VCSpM1+C8TXQ7YiyUIyMixQBnFt8b+tmAMAetd1UzJaIHVXia+zgr6TGQoGo1AlCKnJDG8jl/BXCHasufNwIDAQAB";
+
+/// Returns the path of a file under `tests/fixtures`.
+pub(crate) fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
@@ -1,50 +1,172 @@
 #[cfg(target_arch = "wasm32")]
-use js_sys::{Array, Promise};
+use js_sys::{Array, Object, Promise};
 #[cfg(target_arch = "wasm32")]
 use rand::rngs::OsRng;
 #[cfg(target_arch = "wasm32")]
-use serde_wasm_bindgen::{from_value, to_value};
+use serde::{Deserialize, Serialize};
 #[cfg(target_arch = "wasm32")]
-use wasm_bindgen::prelude::*;
+use serde_wasm_bindgen::{from_value, to_value, Serializer};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::{prelude::*, JsCast};
 
+#[cfg(target_arch = "wasm32")]
+use crate::parse_email::{find_header_values_idxes, unfold_header_value};
 #[cfg(target_arch = "wasm32")]
 use crate::{
-    bytes_to_fields, email_nullifier, extract_rand_from_signature, field_to_hex,
+    bytes_to_fields, compute_recommended_lengths, email_nullifier, email_nullifier_v2,
+    extract_rand_from_signature, field_to_hex,
     generate_circuit_inputs_with_decomposed_regexes_and_external_inputs,
-    generate_email_circuit_input, hex_to_field, AccountCode, AccountSalt,
-    CircuitInputWithDecomposedRegexesAndExternalInputsParams, DecomposedRegex, EmailCircuitParams,
-    ExternalInput, PaddedEmailAddr, ParsedEmail,
+    generate_email_circuit_input, generate_email_circuit_inputs_batch_with_concurrency,
+    hex_to_field, pack_bytes_into_fields, prove_email, AccountCode, AccountSalt, BundledRegex,
+    CircuitInputWithDecomposedRegexesAndExternalInputsParams, DecomposedRegex,
+    DecomposedRegexCircuitInput, EmailCircuitParams, ExternalInput, MultipleFromPolicy,
+    NormalizationPolicy, PaddedEmailAddr, ParseEmailOptions, ParsedEmail, ProvingSpec,
+    RegexMatchDebug, DEFAULT_BATCH_CONCURRENCY,
 };
 #[cfg(target_arch = "wasm32")]
 use itertools::Itertools;
 #[cfg(target_arch = "wasm32")]
+use std::collections::BTreeMap;
+#[cfg(target_arch = "wasm32")]
+use std::convert::TryInto;
+#[cfg(target_arch = "wasm32")]
+use std::time::Duration;
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen_futures::future_to_promise;
 #[cfg(target_arch = "wasm32")]
-use zk_regex_apis::extractSubstrIdxes;
+use zk_regex_apis::extract_substrs::extract_substr_idxes;
+
+/// An email and its account code, as accepted by `generateEmailCircuitInputsBatch`.
+#[cfg(target_arch = "wasm32")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchEmailJs {
+    email: String,        // The raw email data
+    account_code: String, // The account code in hexadecimal format
+}
+
+/// The outcome for one email of `generateEmailCircuitInputsBatch`.
+#[cfg(target_arch = "wasm32")]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchResultJs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit_inputs: Option<String>, // The serialized circuit inputs, if generation succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>, // The error message, if generation failed
+}
+
+/// The result of `generateCircuitInputsWithDecomposedRegexesAndExternalInputs` in debug mode.
+#[cfg(target_arch = "wasm32")]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DebugCircuitInputsJs<'a> {
+    circuit_inputs: &'a DecomposedRegexCircuitInput, // The circuit inputs, the same as without debug mode
+    regex_matches: &'a BTreeMap<String, RegexMatchDebug>, // What each regex matched, keyed by name
+}
+
+/// The options accepted by `parseEmail`.
+#[cfg(target_arch = "wasm32")]
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ParseEmailJsOptions {
+    dns_timeout_ms: Option<u64>, // Time limit in milliseconds for each DKIM public key lookup
+    verify_body_hash: Option<bool>, // Whether a DKIM body hash mismatch is fatal
+    max_raw_email_size: Option<usize>, // Maximum size in bytes of the raw email
+    strip_non_text_parts: Option<bool>, // Whether non-text MIME parts outside the signed body are dropped; only applies to signatures with an `l=` tag
+    multiple_from_policy: Option<MultipleFromPolicy>, // How a From header with several addresses is handled
+    third_party_signers: Option<Vec<String>>, // Signing domains accepted when not aligned with the From domain
+}
+
+/// The options accepted by `emailAddrCommitWithSignature`.
+#[cfg(target_arch = "wasm32")]
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommitWithSignatureJsOptions {
+    chunk_bits: Option<usize>, // Bits per chunk of the signature
+    num_chunks: Option<usize>, // Number of chunks the signature is split into
+}
+
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 #[cfg(target_arch = "wasm32")]
 /// Parses a raw email string into a structured `ParsedEmail` object.
 ///
-/// This function utilizes the `ParsedEmail::new_from_raw_email` method to parse the email,
-/// and then serializes the result for JavaScript interoperability.
+/// This function utilizes the `ParsedEmail::new_from_raw_email_with_options` method to parse the
+/// email, and then serializes the result for JavaScript interoperability.
 ///
 /// # Arguments
 ///
 /// * `raw_email` - A `String` representing the raw email to be parsed.
+/// * `options` - An optional object with the fields below. A bare number is accepted as
+///   `dnsTimeoutMs`.
+///   - `dnsTimeoutMs` - The time limit for each DKIM public key lookup, 10 seconds by default.
+///   - `verifyBodyHash` - Whether a DKIM body hash mismatch is fatal, `true` by default.
+///   - `maxRawEmailSize` - The maximum size of the raw email in bytes, 4 MiB by default.
+///   - `stripNonTextParts` - Whether non-text MIME parts outside the signed body are dropped
+///     before canonicalization for signatures with an `l=` tag, `false` by default.
+///   - `multipleFromPolicy` - How a From header with several addresses is handled, one of
+///     `"reject"` (the default), `"useFirst"`, or `"requireAllSameDomain"`.
+///   - `thirdPartySigners` - The signing domains (e.g. `amazonses.com`) accepted when not aligned
+///     with the From domain, any by default.
 ///
 /// # Returns
 ///
-/// A `Promise` that resolves with the serialized `ParsedEmail` or rejects with an error message.
-pub async fn parseEmail(raw_email: String) -> Promise {
-    match ParsedEmail::new_from_raw_email(&raw_email).await {
-        Ok(parsed_email) => match to_value(&parsed_email) {
-            Ok(serialized_email) => Promise::resolve(&serialized_email),
-            Err(err) => Promise::reject(&JsValue::from_str(&format!(
-                "Failed to serialize ParsedEmail: {}",
-                err
-            ))),
-        },
+/// A `Promise` that resolves with the serialized `ParsedEmail`, extended with the fields of its
+/// `ParsedEmailView` (`null` where a value is not present), or rejects with an error message.
+pub async fn parseEmail(raw_email: String, options: JsValue) -> Promise {
+    let js_options: ParseEmailJsOptions = if let Some(dns_timeout_ms) = options.as_f64() {
+        ParseEmailJsOptions {
+            dns_timeout_ms: Some(dns_timeout_ms as u64),
+            ..Default::default()
+        }
+    } else if options.is_undefined() || options.is_null() {
+        ParseEmailJsOptions::default()
+    } else {
+        match from_value(options) {
+            Ok(js_options) => js_options,
+            Err(_) => return Promise::reject(&JsValue::from_str("Invalid parseEmail options")),
+        }
+    };
+    let mut options = ParseEmailOptions::default();
+    if let Some(dns_timeout_ms) = js_options.dns_timeout_ms {
+        options.dns_timeout = Duration::from_millis(dns_timeout_ms);
+    }
+    if let Some(verify_body_hash) = js_options.verify_body_hash {
+        options.verify_body_hash = verify_body_hash;
+    }
+    if let Some(max_raw_email_size) = js_options.max_raw_email_size {
+        options.max_raw_email_size = max_raw_email_size;
+    }
+    if let Some(strip_non_text_parts) = js_options.strip_non_text_parts {
+        options.strip_non_text_parts = strip_non_text_parts;
+    }
+    if let Some(multiple_from_policy) = js_options.multiple_from_policy {
+        options.multiple_from_policy = multiple_from_policy;
+    }
+    options.third_party_signers = js_options.third_party_signers;
+
+    match ParsedEmail::new_from_raw_email_with_options(&raw_email, &options).await {
+        Ok(parsed_email) => {
+            let serializer = Serializer::new().serialize_missing_as_null(true);
+            match (
+                to_value(&parsed_email),
+                parsed_email.view().serialize(&serializer),
+            ) {
+                (Ok(serialized_email), Ok(serialized_view)) => {
+                    // Merge the derived values into the email so JS reads the same values as Rust
+                    Object::assign(
+                        serialized_email.unchecked_ref(),
+                        serialized_view.unchecked_ref(),
+                    );
+                    Promise::resolve(&serialized_email)
+                }
+                (Err(err), _) | (_, Err(err)) => Promise::reject(&JsValue::from_str(&format!(
+                    "Failed to serialize ParsedEmail: {}",
+                    err
+                ))),
+            }
+        }
         Err(err) => Promise::reject(&JsValue::from_str(&format!(
             "Failed to parse email: {}",
             err
@@ -81,15 +203,33 @@ pub async fn generateAccountCode() -> Promise {
 ///
 /// * `email_addr` - A `String` representing the email address.
 /// * `account_code` - A `String` representing the account code in hexadecimal format.
+/// * `normalization` - An optional object with the boolean fields `trimWhitespace`,
+///   `lowercaseLocalPart` and `lowercaseDomain`. The email address is used byte-exact by default.
 ///
 /// # Returns
 ///
 /// A `Promise` that resolves with the serialized `AccountSalt` or rejects with an error message.
-pub async fn generateAccountSalt(email_addr: String, account_code: String) -> Promise {
-    let email_addr = PaddedEmailAddr::from_email_addr(&email_addr);
-    let account_code = match hex_to_field(&account_code) {
-        Ok(field) => AccountCode::from(field),
-        Err(_) => return Promise::reject(&JsValue::from_str("Failed to parse AccountCode")),
+pub async fn generateAccountSalt(
+    email_addr: String,
+    account_code: String,
+    normalization: JsValue,
+) -> Promise {
+    let policy = match normalization_policy(normalization) {
+        Ok(policy) => policy,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e)),
+    };
+    let email_addr = match PaddedEmailAddr::from_email_addr_normalized(&email_addr, policy) {
+        Ok(email_addr) => email_addr,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e.to_string())),
+    };
+    let account_code = match AccountCode::try_from_hex(&account_code) {
+        Ok(account_code) => account_code,
+        Err(e) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to parse AccountCode: {}",
+                e
+            )))
+        }
     };
     let account_salt = match AccountSalt::new(&email_addr, account_code) {
         Ok(salt) => salt,
@@ -112,21 +252,45 @@ pub async fn generateAccountSalt(email_addr: String, account_code: String) -> Pr
 /// # Arguments
 ///
 /// * `email_addr` - A `String` representing the email address to be padded.
+/// * `normalization` - An optional object with the boolean fields `trimWhitespace`,
+///   `lowercaseLocalPart` and `lowercaseDomain`. The email address is used byte-exact by default.
 ///
 /// # Returns
 ///
 /// A `Promise` that resolves with the serialized padded email address or rejects with an error message.
-pub async fn padEmailAddr(email_addr: String) -> Promise {
-    let padded_email_addr = PaddedEmailAddr::from_email_addr(&email_addr);
+pub async fn padEmailAddr(email_addr: String, normalization: JsValue) -> Promise {
+    let policy = match normalization_policy(normalization) {
+        Ok(policy) => policy,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e)),
+    };
+    let padded_email_addr = match PaddedEmailAddr::from_email_addr_normalized(&email_addr, policy) {
+        Ok(padded_email_addr) => padded_email_addr,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e.to_string())),
+    };
     match to_value(&padded_email_addr) {
         Ok(serialized_addr) => Promise::resolve(&serialized_addr),
         Err(_) => Promise::reject(&JsValue::from_str("Failed to serialize padded_email_addr")),
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+/// Reads an optional `NormalizationPolicy`, defaulting to no normalization.
+fn normalization_policy(normalization: JsValue) -> Result<NormalizationPolicy, String> {
+    if normalization.is_undefined() || normalization.is_null() {
+        return Ok(NormalizationPolicy::default());
+    }
+    from_value(normalization).map_err(|e| format!("Invalid normalization options: {}", e))
+}
+
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 #[cfg(target_arch = "wasm32")]
+/// Generates the circuit inputs of an email with decomposed regexes and external inputs.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the circuit inputs, or, if `params.debug` is set, with an
+/// object holding them as `circuitInputs` and what each regex matched as `regexMatches`.
 pub async fn generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
     email_addr: String,
     decomposed_regexes: JsValue,
@@ -147,6 +311,7 @@ pub async fn generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
         // Deserialize params
         let params: CircuitInputWithDecomposedRegexesAndExternalInputsParams =
             from_value(params).map_err(|e| format!("Invalid params input: {}", e))?;
+        let debug = params.debug;
 
         // Call the async function and await the result
         let circuit_inputs = generate_circuit_inputs_with_decomposed_regexes_and_external_inputs(
@@ -158,8 +323,18 @@ pub async fn generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
         .await
         .map_err(|err| format!("Failed to generate CircuitInputs: {}", err))?;
 
-        // Serialize the output to JsValue
-        to_value(&circuit_inputs).map_err(|_| String::from("Failed to serialize CircuitInputs"))
+        // Serialize the output to a plain object, keeping the order of the regexes and external
+        // inputs; in debug mode, the matches are returned next to the inputs rather than in them
+        if debug {
+            DebugCircuitInputsJs {
+                circuit_inputs: &circuit_inputs,
+                regex_matches: &circuit_inputs.regex_matches,
+            }
+            .serialize(&Serializer::json_compatible())
+        } else {
+            circuit_inputs.serialize(&Serializer::json_compatible())
+        }
+        .map_err(|_| String::from("Failed to serialize CircuitInputs"))
     }));
 
     match result {
@@ -200,75 +375,37 @@ pub async fn generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
 /// # Returns
 ///
 /// A `Promise` that resolves with an object containing the padded data and message length,
-/// or rejects with an error message.
+/// or rejects with an error message if the padded data is longer than `max_sha_bytes`.
 pub async fn sha256Pad(data: JsValue, max_sha_bytes: usize) -> Promise {
     use crate::sha256_pad;
 
-    // Set panic hook early
     console_error_panic_hook::set_once();
 
-    // Wrap the entire operation in catch_unwind
-    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-        // Additional validation
-        if max_sha_bytes == 0 {
-            return Err("max_sha_bytes must be greater than 0".to_string());
-        }
-
-        // Safe conversion of JsValue to Vec<u8>
-        let data_vec: Vec<u8> = match from_value(data) {
-            Ok(vec) => vec,
-            Err(e) => return Err(format!("Failed to convert input data: {}", e)),
-        };
-
-        // Validate input size
-        if data_vec.len() > max_sha_bytes {
-            return Err(format!(
-                "Input data length ({}) exceeds max_sha_bytes ({})",
-                data_vec.len(),
-                max_sha_bytes
-            ));
+    let data_vec: Vec<u8> = match from_value(data) {
+        Ok(vec) => vec,
+        Err(e) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to convert input data: {}",
+                e
+            )))
         }
+    };
 
-        // Try the padding operation within a Result
-        let padding_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            sha256_pad(data_vec, max_sha_bytes)
-        }));
-
-        match padding_result {
-            Ok((padded_data, message_len)) => {
-                // Create the result object
-                let result = serde_json::json!({
-                    "paddedData": padded_data,
-                    "messageLength": message_len
-                });
-
-                // Serialize the result
-                match to_value(&result) {
-                    Ok(serialized) => Ok(serialized),
-                    Err(e) => Err(format!("Failed to serialize result: {}", e)),
-                }
+    match sha256_pad(data_vec, max_sha_bytes) {
+        Ok((padded_data, message_len)) => {
+            let result = serde_json::json!({
+                "paddedData": padded_data,
+                "messageLength": message_len
+            });
+            match to_value(&result) {
+                Ok(serialized) => Promise::resolve(&serialized),
+                Err(e) => Promise::reject(&JsValue::from_str(&format!(
+                    "Failed to serialize result: {}",
+                    e
+                ))),
             }
-            Err(_) => Err("Internal error during padding operation".to_string()),
-        }
-    }));
-
-    // Handle the final result
-    match result {
-        Ok(Ok(serialized_result)) => Promise::resolve(&serialized_result),
-        Ok(Err(err_msg)) => Promise::reject(&JsValue::from_str(&err_msg)),
-        Err(panic) => {
-            let panic_msg = match panic.downcast::<String>() {
-                Ok(msg) => *msg,
-                Err(panic) => match panic.downcast::<&str>() {
-                    Ok(msg) => msg.to_string(),
-                    Err(_) => "Unknown panic occurred during execution".to_string(),
-                },
-            };
-            Promise::reject(&JsValue::from_str(&format!(
-                "Critical error: {}",
-                panic_msg
-            )))
         }
+        Err(e) => Promise::reject(&JsValue::from_str(&format!("Failed to pad data: {}", e))),
     }
 }
 
@@ -340,10 +477,8 @@ pub async fn generateEmailCircuitInput(
 
     let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| async move {
         // Parse account_code
-        let account_code = AccountCode::from(
-            hex_to_field(&account_code)
-                .map_err(|e| format!("Failed to parse AccountCode: {}", e))?,
-        );
+        let account_code = AccountCode::try_from_hex(&account_code)
+            .map_err(|e| format!("Failed to parse AccountCode: {}", e))?;
         // Deserialize params from JsValue
         let params: Option<EmailCircuitParams> = if params.is_null() {
             None
@@ -382,6 +517,141 @@ pub async fn generateEmailCircuitInput(
     }
 }
 
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Generates the circuit inputs for several emails concurrently.
+///
+/// # Arguments
+///
+/// * `emails` - An array of `{ email, accountCode }` objects, with the account code in hexadecimal format.
+/// * `params` - An object representing the optional parameters for the circuit, shared by all emails.
+/// * `concurrency` - The maximum number of emails processed at once, 8 by default.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with one `{ circuitInputs }` or `{ error }` object per email, in
+/// the order of `emails`, or rejects if the arguments are invalid.
+pub async fn generateEmailCircuitInputsBatch(
+    emails: JsValue,
+    params: JsValue,
+    concurrency: Option<usize>,
+) -> Promise {
+    console_error_panic_hook::set_once();
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| async move {
+        let emails: Vec<BatchEmailJs> =
+            from_value(emails).map_err(|e| format!("Invalid emails: {}", e))?;
+        let params: Option<EmailCircuitParams> = if params.is_null() || params.is_undefined() {
+            None
+        } else {
+            let params = from_value(params).map_err(|e| format!("Invalid params: {}", e))?;
+            Some(params)
+        };
+
+        // An invalid account code only fails the email it belongs to
+        let account_codes = emails
+            .iter()
+            .map(|email| {
+                AccountCode::try_from_hex(&email.account_code)
+                    .map_err(|e| format!("Failed to parse AccountCode: {}", e))
+            })
+            .collect::<Vec<_>>();
+        let valid_emails = emails
+            .iter()
+            .zip(account_codes.iter())
+            .filter_map(|(email, account_code)| {
+                account_code
+                    .as_ref()
+                    .ok()
+                    .map(|account_code| (email.email.as_str(), *account_code))
+            })
+            .collect::<Vec<_>>();
+
+        let mut generated = generate_email_circuit_inputs_batch_with_concurrency(
+            &valid_emails,
+            params,
+            concurrency.unwrap_or(DEFAULT_BATCH_CONCURRENCY),
+        )
+        .await
+        .into_iter();
+        let results = account_codes
+            .into_iter()
+            .map(|account_code| {
+                let result = account_code.and_then(|_| {
+                    generated
+                        .next()
+                        .expect("one result per valid email")
+                        .map_err(|e| format!("Error generating circuit inputs: {}", e))
+                });
+                match result {
+                    Ok(circuit_inputs) => BatchResultJs {
+                        circuit_inputs: Some(circuit_inputs),
+                        error: None,
+                    },
+                    Err(error) => BatchResultJs {
+                        circuit_inputs: None,
+                        error: Some(error),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
+        to_value(&results).map_err(|e| format!("Failed to serialize CircuitInputs: {}", e))
+    }));
+
+    match result {
+        Ok(future) => match future.await {
+            Ok(serialized_inputs) => Promise::resolve(&serialized_inputs),
+            Err(err_msg) => Promise::reject(&JsValue::from_str(&err_msg)),
+        },
+        Err(panic) => {
+            let panic_msg = match panic.downcast::<String>() {
+                Ok(msg) => *msg,
+                Err(panic) => match panic.downcast::<&str>() {
+                    Ok(msg) => msg.to_string(),
+                    Err(_) => "Unknown panic occurred".to_string(),
+                },
+            };
+            Promise::reject(&JsValue::from_str(&format!(
+                "Panic occurred: {}",
+                panic_msg
+            )))
+        }
+    }
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Computes the smallest header and body lengths a circuit needs for an email.
+///
+/// # Arguments
+///
+/// * `email` - A `String` representing the raw email data.
+/// * `sha_precompute_selector` - The optional regex selector for SHA-256 precomputation.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the serialized `RecommendedLengths`, with the canonicalized
+/// `headerLength` and `bodyLength` and the smallest valid `maxHeaderLength` and
+/// `maxBodyLength`, or rejects with an error message.
+pub async fn computeRecommendedLengths(
+    email: String,
+    sha_precompute_selector: Option<String>,
+) -> Promise {
+    match compute_recommended_lengths(&email, sha_precompute_selector.as_deref()).await {
+        Ok(lengths) => match to_value(&lengths) {
+            Ok(serialized_lengths) => Promise::resolve(&serialized_lengths),
+            Err(_) => Promise::reject(&JsValue::from_str("Failed to serialize RecommendedLengths")),
+        },
+        Err(err) => Promise::reject(&JsValue::from_str(&format!(
+            "Failed to compute recommended lengths: {}",
+            err
+        ))),
+    }
+}
+
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 #[cfg(target_arch = "wasm32")]
@@ -399,7 +669,12 @@ pub async fn extractRandFromSignature(signautre: Vec<u8>) -> Promise {
 
     let cm_rand = match extract_rand_from_signature(&signautre) {
         Ok(field) => field,
-        Err(_) => return Promise::reject(&JsValue::from_str("Failed to extract randomness")),
+        Err(err) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to extract randomness: {}",
+                err
+            )))
+        }
     };
     match to_value(&field_to_hex(&cm_rand)) {
         Ok(serialized_rand) => Promise::resolve(&serialized_rand),
@@ -416,19 +691,57 @@ pub async fn extractRandFromSignature(signautre: Vec<u8>) -> Promise {
 ///
 /// * `email_addr` - A `String` representing the email address to be committed.
 /// * `signature` - A `Uint8Array` containing the signature data to be used as randomness.
+/// * `options` - An optional object with `chunkBits` and `numChunks`, given together, to chunk the
+///   signature as a circuit with those parameters does. By default the chunking follows the
+///   signature size.
 ///
 /// # Returns
 ///
 /// A `Promise` that resolves with the commitment as a hexadecimal string, or rejects with an error message.
-pub async fn emailAddrCommitWithSignature(email_addr: String, signautre: Vec<u8>) -> Promise {
+pub async fn emailAddrCommitWithSignature(
+    email_addr: String,
+    signautre: Vec<u8>,
+    options: JsValue,
+) -> Promise {
     use crate::PaddedEmailAddr;
 
     console_error_panic_hook::set_once();
 
-    let padded_email_addr = PaddedEmailAddr::from_email_addr(&email_addr);
-    let cm = match padded_email_addr.to_commitment_with_signature(&signautre) {
+    let options: CommitWithSignatureJsOptions = if options.is_undefined() || options.is_null() {
+        CommitWithSignatureJsOptions::default()
+    } else {
+        match from_value(options) {
+            Ok(options) => options,
+            Err(e) => {
+                return Promise::reject(&JsValue::from_str(&format!(
+                    "Invalid emailAddrCommitWithSignature options: {}",
+                    e
+                )))
+            }
+        }
+    };
+    let padded_email_addr = match PaddedEmailAddr::try_from_email_addr(&email_addr) {
+        Ok(padded_email_addr) => padded_email_addr,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e.to_string())),
+    };
+    let cm = match (options.chunk_bits, options.num_chunks) {
+        (None, None) => padded_email_addr.to_commitment_with_signature(&signautre),
+        (Some(chunk_bits), Some(num_chunks)) => padded_email_addr
+            .to_commitment_with_signature_params(&signautre, chunk_bits, num_chunks),
+        _ => {
+            return Promise::reject(&JsValue::from_str(
+                "chunkBits and numChunks must be given together",
+            ))
+        }
+    };
+    let cm = match cm {
         Ok(cm) => cm,
-        Err(_) => return Promise::reject(&JsValue::from_str("Failed to commit email address")),
+        Err(err) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to commit email address: {}",
+                err
+            )))
+        }
     };
 
     match to_value(&field_to_hex(&cm)) {
@@ -437,6 +750,54 @@ pub async fn emailAddrCommitWithSignature(email_addr: String, signautre: Vec<u8>
     }
 }
 
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Commits an email address using a 32-byte nonce, e.g. one chosen by a contract.
+///
+/// # Arguments
+///
+/// * `email_addr` - A `String` representing the email address to be committed.
+/// * `nonce` - A `Uint8Array` containing the 32-byte big-endian nonce, which must be below the
+///   field modulus.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the commitment as a hexadecimal string, or rejects with an error message.
+pub async fn emailAddrCommitWithNonce(email_addr: String, nonce: Vec<u8>) -> Promise {
+    use crate::PaddedEmailAddr;
+
+    console_error_panic_hook::set_once();
+
+    let nonce: [u8; 32] = match nonce.try_into() {
+        Ok(nonce) => nonce,
+        Err(nonce) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "The nonce must be 32 bytes but is {} bytes",
+                nonce.len()
+            )))
+        }
+    };
+    let padded_email_addr = match PaddedEmailAddr::try_from_email_addr(&email_addr) {
+        Ok(padded_email_addr) => padded_email_addr,
+        Err(e) => return Promise::reject(&JsValue::from_str(&e.to_string())),
+    };
+    let cm = match padded_email_addr.to_commitment_with_nonce_bytes(&nonce) {
+        Ok(cm) => cm,
+        Err(err) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to commit email address: {}",
+                err
+            )))
+        }
+    };
+
+    match to_value(&field_to_hex(&cm)) {
+        Ok(cm) => Promise::resolve(&cm),
+        Err(_) => Promise::reject(&JsValue::from_str("Failed to serialize commitment")),
+    }
+}
+
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 #[cfg(target_arch = "wasm32")]
@@ -468,6 +829,40 @@ pub async fn bytesToFields(bytes: JsValue) -> Promise {
     }
 }
 
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Packs a byte array into the field elements of a signal, as done for external inputs.
+///
+/// # Arguments
+///
+/// * `bytes` - A `Uint8Array` containing the byte array to pack.
+/// * `max_length` - The maximum number of bytes the signal holds.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the field elements as decimal strings, padded with zeros to the
+/// signal length for `max_length`, or rejects with an error message if the bytes are longer than
+/// `max_length`.
+pub async fn packBytesIntoFields(bytes: JsValue, max_length: usize) -> Promise {
+    console_error_panic_hook::set_once();
+
+    let bytes: Vec<u8> = match from_value(bytes) {
+        Ok(bytes) => bytes,
+        Err(_) => return Promise::reject(&JsValue::from_str("Failed to convert input to bytes")),
+    };
+    match pack_bytes_into_fields(&bytes, max_length) {
+        Ok(fields) => match to_value(&fields) {
+            Ok(serialized_fields) => Promise::resolve(&serialized_fields),
+            Err(_) => Promise::reject(&JsValue::from_str("Failed to serialize fields")),
+        },
+        Err(err) => Promise::reject(&JsValue::from_str(&format!(
+            "Failed to pack bytes into fields: {}",
+            err
+        ))),
+    }
+}
+
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 #[cfg(target_arch = "wasm32")]
@@ -491,7 +886,46 @@ pub async fn emailNullifier(mut signautre: Vec<u8>) -> Promise {
     signautre.reverse();
     match email_nullifier(&signautre) {
         Ok(field) => Promise::resolve(&JsValue::from_str(&field_to_hex(&field))),
-        Err(_) => Promise::reject(&JsValue::from_str("Failed to compute email nullifier")),
+        Err(err) => Promise::reject(&JsValue::from_str(&format!(
+            "Failed to compute email nullifier: {}",
+            err
+        ))),
+    }
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Computes the domain-separated nullifier of an email, as `email_nullifier_v2` does.
+///
+/// # Arguments
+///
+/// * `signature` - A `Uint8Array` containing the signature data to be used for the nullifier.
+/// * `domain_tag` - The field element identifying the application, as 0x-prefixed hex.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the email nullifier as a hexadecimal string, or rejects with an error message.
+pub async fn emailNullifierV2(mut signature: Vec<u8>, domain_tag: String) -> Promise {
+    console_error_panic_hook::set_once();
+
+    let domain_tag = match hex_to_field(&domain_tag) {
+        Ok(domain_tag) => domain_tag,
+        Err(err) => {
+            return Promise::reject(&JsValue::from_str(&format!(
+                "Failed to parse domain tag: {}",
+                err
+            )))
+        }
+    };
+    // Reverse the bytes for little-endian format
+    signature.reverse();
+    match email_nullifier_v2(&signature, &domain_tag) {
+        Ok(field) => Promise::resolve(&JsValue::from_str(&field_to_hex(&field))),
+        Err(err) => Promise::reject(&JsValue::from_str(&format!(
+            "Failed to compute email nullifier: {}",
+            err
+        ))),
     }
 }
 
@@ -508,8 +942,7 @@ pub async fn emailNullifier(mut signautre: Vec<u8>) -> Promise {
 ///
 /// A `Promise` that resolves with an array of arrays containing the start and end indices of the invitation code substrings,
 pub fn extractInvitationCodeIdxes(inputStr: &str) -> Result<Array, JsValue> {
-    let regex_config = include_str!("../regexes/invitation_code.json");
-    extractSubstrIdxes(inputStr, JsValue::from_str(regex_config), false)
+    bundled_regex_idxes(inputStr, BundledRegex::InvitationCode)
 }
 
 #[wasm_bindgen]
@@ -525,6 +958,100 @@ pub fn extractInvitationCodeIdxes(inputStr: &str) -> Result<Array, JsValue> {
 ///
 /// A `Promise` that resolves with an array of arrays containing the start and end indices of the invitation code substrings,
 pub fn extractInvitationCodeWithPrefixIdxes(inputStr: &str) -> Result<Array, JsValue> {
-    let regex_config = include_str!("../regexes/invitation_code_with_prefix.json");
-    extractSubstrIdxes(inputStr, JsValue::from_str(regex_config), false)
+    bundled_regex_idxes(inputStr, BundledRegex::InvitationCodeWithPrefix)
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Extracts the values of every occurrence of a header in a canonicalized email header.
+///
+/// # Arguments
+///
+/// * `canonicalizedHeader` - The canonicalized email header, as in the parsed email.
+/// * `name` - The header name, matched case-insensitively.
+///
+/// # Returns
+///
+/// An array of the unfolded header values.
+pub fn extractHeaderValues(canonicalizedHeader: &str, name: &str) -> Result<Array, JsValue> {
+    let idxes = find_header_values_idxes(canonicalizedHeader, name)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(idxes
+        .into_iter()
+        .map(|(start, end)| {
+            JsValue::from_str(&unfold_header_value(&canonicalizedHeader[start..end]))
+        })
+        .collect())
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Extracts the indices of the values of every occurrence of a header in a canonicalized email
+/// header.
+///
+/// # Arguments
+///
+/// * `canonicalizedHeader` - The canonicalized email header, as in the parsed email.
+/// * `name` - The header name, matched case-insensitively.
+///
+/// # Returns
+///
+/// An array of arrays containing the start and end indices of the header values.
+pub fn extractHeaderValueIdxes(canonicalizedHeader: &str, name: &str) -> Result<Array, JsValue> {
+    let idxes = find_header_values_idxes(canonicalizedHeader, name)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(idxes
+        .into_iter()
+        .map(|(start, end)| Array::of2(&JsValue::from(start), &JsValue::from(end)))
+        .collect())
+}
+
+#[cfg(target_arch = "wasm32")]
+/// Extracts the indices matched by a bundled regex as an array of `[start, end]` arrays.
+fn bundled_regex_idxes(input: &str, regex: BundledRegex) -> Result<Array, JsValue> {
+    let regex_config = regex
+        .config()
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let idxes = extract_substr_idxes(input, regex_config, false)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(idxes
+        .into_iter()
+        .map(|(start, end)| Array::of2(&JsValue::from(start), &JsValue::from(end)))
+        .collect())
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+#[cfg(target_arch = "wasm32")]
+/// Generates circuit inputs for an email and submits them to a prover.
+///
+/// # Arguments
+///
+/// * `email` - A `String` representing the raw email data.
+/// * `spec` - An object describing the input mode, the prover, and retry options.
+///
+/// # Returns
+///
+/// A `Promise` that resolves with the proof, its public signals and the submitted circuit inputs,
+/// or rejects with an error message naming the stage that failed.
+pub async fn proveEmail(email: String, spec: JsValue) -> Promise {
+    console_error_panic_hook::set_once();
+
+    let spec: ProvingSpec = match from_value(spec) {
+        Ok(spec) => spec,
+        Err(e) => return Promise::reject(&JsValue::from_str(&format!("Invalid spec: {}", e))),
+    };
+
+    match prove_email(&email, spec).await {
+        Ok(proof_result) => match to_value(&proof_result) {
+            Ok(serialized) => Promise::resolve(&serialized),
+            Err(e) => Promise::reject(&JsValue::from_str(&format!(
+                "Failed to serialize ProofResult: {}",
+                e
+            ))),
+        },
+        Err(e) => Promise::reject(&JsValue::from_str(&format!("Failed to prove email: {}", e))),
+    }
 }
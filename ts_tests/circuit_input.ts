@@ -87,4 +87,74 @@ describe("generateCircuitInputsWithDecomposedRegexesAndExternalInputs test suite
     );
     expect(inputs).toBeDefined();
   });
+
+  test("Should return the regex matches next to the inputs in debug mode", async () => {
+    const decomposedRegexes = [
+      {
+        parts: [
+          {
+            isPublic: true,
+            regexDef: "Hi",
+          },
+          {
+            isPublic: true,
+            regexDef: "!",
+          },
+        ],
+        name: "hi",
+        maxLength: 64,
+        location: "body",
+      },
+    ];
+
+    const params = {
+      maxHeaderLength: 2816,
+      maxBodyLength: 1024,
+      ignoreBodyHashCheck: false,
+      removeSoftLinesBreaks: true,
+    };
+
+    const inputs = await generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
+      helloEml,
+      decomposedRegexes,
+      [],
+      params
+    );
+    const debugInputs = await generateCircuitInputsWithDecomposedRegexesAndExternalInputs(
+      helloEml,
+      decomposedRegexes,
+      [],
+      { ...params, debug: true }
+    );
+    expect(debugInputs.circuitInputs).toEqual(inputs);
+    expect(debugInputs.regexMatches.hi.location).toBe("body");
+    expect(debugInputs.regexMatches.hi.substrings.join("")).toContain("Hi!");
+  });
+
+  test("Should reject an unknown regex location", async () => {
+    const decomposedRegexes = [
+      {
+        parts: [
+          {
+            isPublic: true,
+            regexDef: "Hi",
+          },
+        ],
+        name: "hi",
+        maxLength: 64,
+        location: "Body",
+      },
+    ];
+
+    const params = {
+      maxHeaderLength: 2816,
+      maxBodyLength: 1024,
+      ignoreBodyHashCheck: false,
+      removeSoftLinesBreaks: true,
+    };
+
+    expect(
+      generateCircuitInputsWithDecomposedRegexesAndExternalInputs(helloEml, decomposedRegexes, [], params)
+    ).rejects.toContain("Invalid decomposed_regexes input");
+  });
 });
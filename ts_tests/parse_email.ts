@@ -16,6 +16,20 @@ describe("Parse email test suite", async () => {
     expect(parsedEmail).not.toBeUndefined();
   });
 
+  test("Should return the canonicalized body and signer details", async () => {
+    const parsedEmail = await parseEmail(airbnbEmail);
+    expect(typeof parsedEmail.canonicalizedBody).toBe("string");
+    expect(parsedEmail.canonicalizedBody.length).toBeGreaterThan(0);
+    expect(parsedEmail.canonicalizedHeader).not.toBeUndefined();
+    expect(parsedEmail.signature).not.toBeUndefined();
+    // RSA keys are the bare modulus bytes
+    expect(Array.isArray(parsedEmail.publicKey)).toBe(true);
+    expect(parsedEmail.publicKey.length).toBeGreaterThan(0);
+    expect(parsedEmail.publicKeyExponent).toBe("0x010001");
+    expect(parsedEmail.dkimDomain).toBe("email.airbnb.com");
+    expect(parsedEmail.dkimSelector).toBe("s20150428");
+  });
+
   test("Should throw a js error on invalid email", async () => {
     try {
       await parseEmail("Invalid email");